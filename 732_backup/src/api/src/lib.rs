@@ -23,7 +23,7 @@ impl Cli {
                 Arg::with_name("memory")
                     .long("memory")
                     .takes_value(true)
-                    .help("Guest memory configuration.\n\tFormat: \"size_mib=<u32>\""),
+                    .help("Guest memory configuration.\n\tFormat: \"size_mib=<u32>[,memfd=<bool>]\""),
             )
             .arg(
                 Arg::with_name("vcpu")
@@ -41,7 +41,7 @@ impl Cli {
                 Arg::with_name("net")
                     .long("net")
                     .takes_value(true)
-                    .help("Network device configuration. \n\tFormat: \"tap=<string>\"")
+                    .help("Network device configuration. \n\tFormat: \"tap=<string>[,mac=<xx:xx:xx:xx:xx:xx>]\"")
             )
             .arg(
                 Arg::with_name("memory_path")
@@ -57,6 +57,14 @@ impl Cli {
                     .takes_value(true)
                     .help("Cpu snapshot path configuration. \n\tFormat: \"--cpu_path <cpu snapshot path>\"")
             )
+            .arg(
+                Arg::with_name("snapshot_id")
+                    .long("snapshot_id")
+                    .required(false)
+                    .takes_value(true)
+                    .conflicts_with("cpu_path")
+                    .help("Restore the cataloged snapshot with this id, with the memory, vCPUs and kernel it was taken with. \n\tFormat: \"--snapshot_id <id>\"")
+            )
             .arg(
                 Arg::with_name("port")
                     .long("port")
@@ -70,6 +78,41 @@ impl Cli {
                     .required(false)
                     .takes_value(true)
                     .help("Block device configuration. \n\tFormat: \"path=<string>\"")
+            )
+            .arg(
+                Arg::with_name("migration")
+                    .long("migration")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Live migration configuration. \n\tFormat: \"storage=<bool>,reconnect_timeout_ms=<u64>\"")
+            )
+            .arg(
+                Arg::with_name("ha")
+                    .long("ha")
+                    .required(false)
+                    .takes_value(true)
+                    .help("High availability configuration. \n\tFormat: \"role=primary|standby,addr=<host:port>[,interval_ms=<u64>,timeout_ms=<u64>]\"")
+            )
+            .arg(
+                Arg::with_name("restore")
+                    .long("restore")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Restore configuration. \n\tFormat: \"clock_correction=<bool>,paused=<bool>,lazy=<bool>,prefetch=<bool>,from_disk=<bool>\"")
+            )
+            .arg(
+                Arg::with_name("live_update")
+                    .long("live_update")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Take over the guest of another VMM on this host. \n\tFormat: \"socket=<path>\"")
+            )
+            .arg(
+                Arg::with_name("clone")
+                    .long("clone")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Start the snapshot as one of its clones, with a new identity. \n\tFormat: \"id=<u32>\"")
             );
 
         // Save the usage beforehand as a string, because `get_matches` consumes the `App`.
@@ -90,7 +133,13 @@ impl Cli {
             .net_config(matches.value_of("net"))
             .block_config(matches.value_of("block"))
             .snapshot_path_config(matches.value_of("cpu_path"), matches.value_of("memory_path"))
+            .snapshot_id_config(matches.value_of("snapshot_id"))
             .rpc_config(matches.value_of("port"))
+            .migration_config(matches.value_of("migration"))
+            .ha_config(matches.value_of("ha"))
+            .restore_config(matches.value_of("restore"))
+            .live_update_config(matches.value_of("live_update"))
+            .clone_config(matches.value_of("clone"))
             .build()
             .map_err(|e| format!("{:?}", e))
     }
@@ -0,0 +1,83 @@
+// Prints, compares and extracts from snapshots, offline.
+//
+//   snapshot-inspect show <snapshot> [legacy memory file]
+//   snapshot-inspect diff <snapshot> <other snapshot>
+//   snapshot-inspect extract <snapshot> <address> <length> <output file, - for stdout>
+//   snapshot-inspect core <snapshot> <output file>
+//
+// Addresses and lengths are in hex with a 0x prefix, or in decimal.
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::process;
+
+use anyhow::{anyhow, Result};
+use vmm::core_dump;
+use vmm::snapshot_inspect::{self, InspectedSnapshot};
+
+fn open(path: &str, memory_path: Option<&str>) -> Result<InspectedSnapshot> {
+    InspectedSnapshot::open(path, memory_path)
+        .map_err(|e| anyhow!("failed to open snapshot {}: {:?}", path, e))
+}
+
+fn number(s: &str) -> Result<u64> {
+    snapshot_inspect::parse_number(s).ok_or_else(|| anyhow!("invalid number {}", s))
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match args.iter().map(|arg| arg.as_str()).collect::<Vec<_>>()[..] {
+        ["show", path] | ["show", path, _] => {
+            let snapshot = open(path, args.get(2).map(|s| s.as_str()))?;
+            snapshot_inspect::print(&snapshot, path, &mut out)?;
+        }
+        ["diff", path_a, path_b] => {
+            let a = open(path_a, None)?;
+            let b = open(path_b, None)?;
+            snapshot_inspect::print_diff(&a, &b, &mut out)
+                .map_err(|e| anyhow!("failed to compare the snapshots: {:?}", e))?;
+        }
+        ["extract", path, addr, len, output] => {
+            let snapshot = open(path, None)?;
+            let mut file;
+            let writer: &mut dyn Write = if output == "-" {
+                &mut out
+            } else {
+                file = File::create(output)?;
+                &mut file
+            };
+            snapshot
+                .extract(number(addr)?, number(len)?, writer)
+                .map_err(|e| anyhow!("failed to extract the memory: {:?}", e))?;
+        }
+        ["core", path, output] => {
+            let snapshot = open(path, None)?;
+            let memory = snapshot
+                .memory
+                .as_ref()
+                .ok_or_else(|| anyhow!("snapshot {} has no memory", path))?;
+            let mut writer = BufWriter::new(File::create(output)?);
+            core_dump::write_core(&mut writer, memory, &snapshot.vm_state)
+                .map_err(|e| anyhow!("failed to write the core file: {:?}", e))?;
+            writer.flush()?;
+        }
+        _ => {
+            return Err(anyhow!(
+                "usage: snapshot-inspect show <snapshot> [legacy memory file]\n       \
+                 snapshot-inspect diff <snapshot> <other snapshot>\n       \
+                 snapshot-inspect extract <snapshot> <address> <length> <output file|->\n       \
+                 snapshot-inspect core <snapshot> <output file>"
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -7,3 +7,4 @@
 
 pub mod legacy;
 pub mod virtio;
+pub mod vm_identity;
@@ -4,7 +4,7 @@
 use std::borrow::{Borrow, BorrowMut};
 use std::fs::OpenOptions;
 use std::ops::DerefMut;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 use virtio_blk::stdio_executor::StdIoBackend;
@@ -20,7 +20,7 @@ use crate::virtio::{CommonConfig, Env, SingleFdSignalQueue, QUEUE_MAX_SIZE};
 
 use super::inorder_handler::InOrderQueueHandler;
 use super::queue_handler::QueueHandler;
-use super::{build_config_space, BlockArgs, Error, Result};
+use super::{build_config_space, BlockArgs, DirtyBlockTracker, Error, Result};
 
 // This Block device can only use the MMIO transport for now, but we plan to reuse large parts of
 // the functionality when we implement virtio PCI as well, for example by having a base generic
@@ -29,6 +29,8 @@ pub struct Block<M: GuestAddressSpace> {
     cfg: CommonConfig<M>,
     file_path: PathBuf,
     read_only: bool,
+    // Shared with the queue handler once the device is activated.
+    dirty_blocks: DirtyBlockTracker,
     // We'll prob need to remember this for state save/restore unless we pass the info from
     // the outside.
     _root_device: bool,
@@ -53,6 +55,7 @@ where
             cfg: common_cfg,
             file_path: args.file_path.clone(),
             read_only: args.read_only,
+            dirty_blocks: DirtyBlockTracker::default(),
             _root_device: args.root_device,
         })
     }
@@ -77,6 +80,22 @@ where
 
         Ok(block)
     }
+
+    // Path of the file backing this device.
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    // Whether the driver has set the device up, after which its queues hold guest state.
+    pub fn is_activated(&self) -> bool {
+        self.cfg.virtio.device_activated
+    }
+
+    // Handle to the tracker recording which blocks of the backing file are written by the
+    // driver. Tracking has to be explicitly started on the returned handle.
+    pub fn dirty_blocks(&self) -> DirtyBlockTracker {
+        self.dirty_blocks.clone()
+    }
 }
 
 impl<M: GuestAddressSpace + Clone + Send + 'static> Borrow<VirtioConfig<M>> for Block<M> {
@@ -128,6 +147,7 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Bloc
             driver_notify,
             queue: self.cfg.virtio.queues.remove(0),
             disk,
+            dirty_blocks: self.dirty_blocks.clone(),
         };
 
         let handler = Arc::new(Mutex::new(QueueHandler {
@@ -5,13 +5,15 @@ use std::fs::File;
 use std::result;
 
 use log::warn;
-use virtio_blk::request::Request;
+use virtio_blk::request::{Request, RequestType};
 use virtio_blk::stdio_executor::{self, StdIoBackend};
 use virtio_queue::{DescriptorChain, Queue};
 use vm_memory::{self, GuestAddressSpace};
 
 use crate::virtio::SignalUsedQueue;
 
+use super::{DirtyBlockTracker, SECTOR_SHIFT};
+
 #[derive(Debug)]
 pub enum Error {
     GuestMemory(vm_memory::GuestMemoryError),
@@ -46,6 +48,9 @@ pub struct InOrderQueueHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
     pub driver_notify: S,
     pub queue: Queue<M>,
     pub disk: StdIoBackend<File>,
+    // Records the blocks touched by write requests, so they can be copied again when the disk
+    // is being mirrored to another host.
+    pub dirty_blocks: DirtyBlockTracker,
 }
 
 impl<M, S> InOrderQueueHandler<M, S>
@@ -55,7 +60,20 @@ where
 {
     fn process_chain(&mut self, mut chain: DescriptorChain<M::T>) -> result::Result<(), Error> {
         let used_len = match Request::parse(&mut chain) {
-            Ok(request) => self.disk.process_request(chain.memory(), &request)?,
+            Ok(request) => {
+                let used_len = self.disk.process_request(chain.memory(), &request)?;
+                // The write is only recorded after it reached the backing file, so whoever
+                // drains the tracker is guaranteed to read the new content.
+                if let RequestType::Out = request.request_type() {
+                    let len = request
+                        .data()
+                        .iter()
+                        .map(|&(_, len)| u64::from(len))
+                        .sum();
+                    self.dirty_blocks.mark(request.sector() << SECTOR_SHIFT, len);
+                }
+                used_len
+            }
             Err(e) => {
                 warn!("block request parse error: {:?}", e);
                 0
@@ -5,9 +5,11 @@ mod device;
 mod inorder_handler;
 mod queue_handler;
 
+use std::collections::BTreeSet;
 use std::fs::File;
 use std::io::{self, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use virtio_blk::stdio_executor;
 
@@ -28,6 +30,9 @@ pub const VIRTIO_BLK_F_FLUSH: u64 = 9;
 // The sector size is 512 bytes (1 << 9).
 const SECTOR_SHIFT: u8 = 9;
 
+// Granularity at which driver writes are tracked while the backing file is being mirrored.
+pub const DIRTY_BLOCK_SIZE: u64 = 4096;
+
 #[derive(Debug)]
 pub enum Error {
     Backend(stdio_executor::Error),
@@ -55,6 +60,47 @@ fn build_config_space<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
     Ok(num_sectors.to_le_bytes().to_vec())
 }
 
+// Keeps track of the `DIRTY_BLOCK_SIZE` blocks of the backing file that were written by the
+// driver since the last call to `drain`. Tracking is off by default and is only turned on while
+// the disk is being copied to another host (i.e. during storage migration), so that regular
+// operation doesn't pay for it. Clones share the same underlying set.
+#[derive(Clone, Default)]
+pub struct DirtyBlockTracker {
+    inner: Arc<Mutex<Option<BTreeSet<u64>>>>,
+}
+
+impl DirtyBlockTracker {
+    // Start recording writes. Blocks written before this call are not reported.
+    pub fn start(&self) {
+        *self.inner.lock().unwrap() = Some(BTreeSet::new());
+    }
+
+    // Stop recording writes and drop any blocks that were not drained yet.
+    pub fn stop(&self) {
+        *self.inner.lock().unwrap() = None;
+    }
+
+    // Record a write of `len` bytes starting at byte `offset` of the backing file.
+    pub fn mark(&self, offset: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        if let Some(blocks) = self.inner.lock().unwrap().as_mut() {
+            let first = offset / DIRTY_BLOCK_SIZE;
+            let last = (offset + len - 1) / DIRTY_BLOCK_SIZE;
+            blocks.extend(first..=last);
+        }
+    }
+
+    // Return the blocks written since the last call, in ascending order, and clear the set.
+    pub fn drain(&self) -> Vec<u64> {
+        match self.inner.lock().unwrap().as_mut() {
+            Some(blocks) => std::mem::take(blocks).into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
 // Arguments required when building a block device.
 pub struct BlockArgs {
     pub file_path: PathBuf,
@@ -182,4 +228,31 @@ mod tests {
         args.read_only = false;
         assert_eq!(args.cmdline_config_substring(), "root=/dev/vda rw");
     }
+
+    #[test]
+    fn test_dirty_block_tracker() {
+        let tracker = DirtyBlockTracker::default();
+
+        // Nothing is recorded until tracking is started.
+        tracker.mark(0, 512);
+        assert!(tracker.drain().is_empty());
+
+        tracker.start();
+        // A single sector at the start of the second block.
+        tracker.mark(DIRTY_BLOCK_SIZE, 512);
+        // A write crossing the boundary between the fourth and fifth blocks.
+        tracker.mark(4 * DIRTY_BLOCK_SIZE - 512, 1024);
+        // Empty writes don't dirty anything.
+        tracker.mark(10 * DIRTY_BLOCK_SIZE, 0);
+        assert_eq!(tracker.drain(), vec![1, 3, 4]);
+
+        // The set is cleared after being drained, and clones share it.
+        assert!(tracker.drain().is_empty());
+        tracker.clone().mark(0, DIRTY_BLOCK_SIZE);
+        assert_eq!(tracker.drain(), vec![0]);
+
+        tracker.mark(0, 1);
+        tracker.stop();
+        assert!(tracker.drain().is_empty());
+    }
 }
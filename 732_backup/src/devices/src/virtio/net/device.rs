@@ -2,6 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 use std::borrow::{Borrow, BorrowMut};
+use std::io::Write;
 use std::ops::DerefMut;
 use std::sync::{Arc, Mutex};
 
@@ -14,7 +15,10 @@ use vm_memory::GuestAddressSpace;
 
 use crate::virtio::features::{VIRTIO_F_IN_ORDER, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1};
 use crate::virtio::net::features::*;
-use crate::virtio::net::{Error, NetArgs, Result, NET_DEVICE_ID, VIRTIO_NET_HDR_SIZE};
+use crate::virtio::net::{
+    rarp_announce_frame, Error, MacAddr, NetArgs, Result, TxBuffer, NET_DEVICE_ID,
+    VIRTIO_NET_HDR_SIZE,
+};
 use crate::virtio::{CommonConfig, Env, SingleFdSignalQueue, QUEUE_MAX_SIZE};
 
 use super::bindings;
@@ -25,6 +29,13 @@ use super::tap::Tap;
 pub struct Net<M: GuestAddressSpace> {
     cfg: CommonConfig<M>,
     tap_name: String,
+    mac: Option<MacAddr>,
+    // Extra handle to the tap, used to inject announcement frames and to send the frames held
+    // back by `tx_buffer`. It's either a clone of the one owned by the queue handler, or opened
+    // on demand when the device wasn't activated.
+    aux_tap: Option<Tap>,
+    // Shared with the queue handler once the device is activated.
+    tx_buffer: TxBuffer,
 }
 
 impl<M> Net<M>
@@ -38,7 +49,7 @@ where
         B: DerefMut,
         B::Target: MmioManager<D = Arc<dyn DeviceMmio + Send + Sync>>,
     {
-        let device_features = (1 << VIRTIO_F_VERSION_1)
+        let mut device_features = (1 << VIRTIO_F_VERSION_1)
             | (1 << VIRTIO_F_RING_EVENT_IDX)
             | (1 << VIRTIO_F_IN_ORDER)
             | (1 << VIRTIO_NET_F_CSUM)
@@ -56,9 +67,15 @@ where
             Queue::new(env.mem.clone(), QUEUE_MAX_SIZE),
         ];
 
-        // TODO: We'll need a minimal config space to support setting an explicit MAC addr
-        // on the guest interface at least. We use an empty one for now.
-        let config_space = Vec::new();
+        // The MAC address is the first field of `struct virtio_net_config`, and the only one we
+        // expose for now. Without an explicit address, we use an empty config space.
+        let config_space = match args.mac {
+            Some(mac) => {
+                device_features |= 1 << VIRTIO_NET_F_MAC;
+                mac.0.to_vec()
+            }
+            None => Vec::new(),
+        };
         let virtio_cfg = VirtioConfig::new(device_features, queues, config_space);
 
         let common_cfg = CommonConfig::new(virtio_cfg, env).map_err(Error::Virtio)?;
@@ -66,6 +83,9 @@ where
         let net = Arc::new(Mutex::new(Net {
             cfg: common_cfg,
             tap_name: args.tap_name.clone(),
+            mac: args.mac,
+            aux_tap: None,
+            tx_buffer: TxBuffer::default(),
         }));
 
         env.register_mmio_device(net.clone())
@@ -77,6 +97,60 @@ where
     pub fn act(&mut self) {
         self.activate();
     }
+
+    // Sends a RARP broadcast with the guest MAC address on the tap, so the network learns the
+    // guest is now reachable through this host. Does nothing when the MAC address is unknown.
+    pub fn announce_self(&mut self) -> Result<()> {
+        let mac = match self.mac {
+            Some(mac) => mac,
+            None => return Ok(()),
+        };
+
+        if self.aux_tap.is_none() {
+            let tap = Tap::open_named(self.tap_name.as_str()).map_err(Error::Tap)?;
+            tap.set_vnet_hdr_size(VIRTIO_NET_HDR_SIZE as i32)
+                .map_err(Error::Tap)?;
+            self.aux_tap = Some(tap);
+        }
+
+        let frame = rarp_announce_frame(&mac);
+        self.aux_tap
+            .as_mut()
+            .unwrap()
+            .write_all(&frame)
+            .map_err(Error::TapWrite)
+    }
+
+    // Whether the driver has set the device up, after which its queues hold guest state.
+    pub fn is_activated(&self) -> bool {
+        self.cfg.virtio.device_activated
+    }
+
+    // Handle to the buffer holding back the frames transmitted by the guest. Buffering has to
+    // be explicitly enabled on the returned handle.
+    pub fn tx_buffer(&self) -> TxBuffer {
+        self.tx_buffer.clone()
+    }
+
+    // Sends the buffered frames that belong to checkpoints up to `epoch`.
+    pub fn release_tx_frames(&mut self, epoch: u64) -> Result<()> {
+        match self.aux_tap.as_mut() {
+            Some(tap) => self.tx_buffer.release(epoch, tap).map_err(Error::TapWrite),
+            // Frames can only be buffered by an activated device, which always has a tap.
+            None => Ok(()),
+        }
+    }
+
+    // Stops buffering, sending out whatever was held back.
+    pub fn flush_tx_frames(&mut self) -> Result<()> {
+        match self.aux_tap.as_mut() {
+            Some(tap) => self.tx_buffer.disable(tap).map_err(Error::TapWrite),
+            None => self
+                .tx_buffer
+                .disable(&mut std::io::sink())
+                .map_err(Error::TapWrite),
+        }
+    }
 }
 
 impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceType for Net<M> {
@@ -101,6 +175,9 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Net<
     type E = Error;
 
     fn activate(&mut self) -> Result<()> {
+        // A handle opened by `announce_self` before activation would keep the tap busy, so
+        // close it first. It's replaced below by a clone of the one used by the queue handler.
+        self.aux_tap = None;
         let tap = Tap::open_named(self.tap_name.as_str()).map_err(Error::Tap)?;
 
 
@@ -126,9 +203,11 @@ impl<M: GuestAddressSpace + Clone + Send + 'static> VirtioDeviceActions for Net<
 
         let mut ioevents = self.cfg.prepare_activate().map_err(Error::Virtio)?;
 
+        self.aux_tap = Some(tap.try_clone().map_err(Error::Tap)?);
+
         let rxq = self.cfg.virtio.queues.remove(0);
         let txq = self.cfg.virtio.queues.remove(0);
-        let inner = SimpleHandler::new(driver_notify, rxq, txq, tap);
+        let inner = SimpleHandler::new(driver_notify, rxq, txq, tap, self.tx_buffer.clone());
 
         let handler = Arc::new(Mutex::new(QueueHandler {
             inner,
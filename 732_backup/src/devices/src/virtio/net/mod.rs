@@ -6,8 +6,13 @@ mod device;
 mod queue_handler;
 mod simple_handler;
 pub mod tap;
+mod tx_buffer;
+
+use std::fmt;
+use std::str::FromStr;
 
 pub use device::Net;
+pub use tx_buffer::TxBuffer;
 
 // TODO: Move relevant defines to vm-virtio crate.
 
@@ -15,6 +20,7 @@ pub use device::Net;
 pub mod features {
     pub const VIRTIO_NET_F_CSUM: u64 = 0;
     pub const VIRTIO_NET_F_GUEST_CSUM: u64 = 1;
+    pub const VIRTIO_NET_F_MAC: u64 = 5;
     pub const VIRTIO_NET_F_GUEST_TSO4: u64 = 7;
     pub const VIRTIO_NET_F_GUEST_TSO6: u64 = 8;
     pub const VIRTIO_NET_F_GUEST_UFO: u64 = 10;
@@ -35,14 +41,117 @@ pub const NET_DEVICE_ID: u32 = 1;
 const RXQ_INDEX: u16 = 0;
 const TXQ_INDEX: u16 = 1;
 
+// EtherType of the Reverse ARP protocol.
+const ETH_P_RARP: u16 = 0x8035;
+
 #[derive(Debug)]
 pub enum Error {
     Virtio(crate::virtio::Error),
     Tap(tap::Error),
+    TapWrite(std::io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+// A MAC address, written as six colon separated hex bytes (i.e. "12:34:56:78:9a:bc").
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl FromStr for MacAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut bytes = [0u8; 6];
+        let mut parts = s.split(':');
+        for byte in bytes.iter_mut() {
+            let part = parts
+                .next()
+                .ok_or_else(|| format!("MAC address too short: {}", s))?;
+            if part.len() != 2 {
+                return Err(format!("Invalid MAC address byte: {}", part));
+            }
+            *byte = u8::from_str_radix(part, 16)
+                .map_err(|_| format!("Invalid MAC address byte: {}", part))?;
+        }
+        if parts.next().is_some() {
+            return Err(format!("MAC address too long: {}", s));
+        }
+        Ok(MacAddr(bytes))
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            b[0], b[1], b[2], b[3], b[4], b[5]
+        )
+    }
+}
+
 pub struct NetArgs {
     pub tap_name: String,
+    // When present, the address is exposed to the driver through the config space, and used
+    // to announce the guest on the network after it's moved to another host.
+    pub mac: Option<MacAddr>,
+}
+
+// Builds a RARP request broadcast on behalf of `mac`, preceded by an empty virtio net header
+// (the tap expects one in front of every frame). This is the same frame QEMU uses to announce
+// a guest after migration, so that switches and bridges learn its new location.
+fn rarp_announce_frame(mac: &MacAddr) -> Vec<u8> {
+    let mut frame = vec![0u8; VIRTIO_NET_HDR_SIZE];
+
+    // Ethernet header.
+    frame.extend_from_slice(&[0xff; 6]);
+    frame.extend_from_slice(&mac.0);
+    frame.extend_from_slice(&ETH_P_RARP.to_be_bytes());
+
+    // Hardware type (Ethernet), protocol type (IPv4), address lengths and opcode (request
+    // reverse).
+    frame.extend_from_slice(&[0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x03]);
+    // Sender and target hardware/protocol addresses.
+    frame.extend_from_slice(&mac.0);
+    frame.extend_from_slice(&[0; 4]);
+    frame.extend_from_slice(&mac.0);
+    frame.extend_from_slice(&[0; 4]);
+
+    // Pad to the minimum Ethernet frame size (without the FCS).
+    frame.resize(VIRTIO_NET_HDR_SIZE + 60, 0);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mac_addr() {
+        let mac = MacAddr::from_str("12:34:56:78:9a:BC").unwrap();
+        assert_eq!(mac, MacAddr([0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]));
+        assert_eq!(mac.to_string(), "12:34:56:78:9a:bc");
+
+        assert!(MacAddr::from_str("").is_err());
+        assert!(MacAddr::from_str("12:34:56:78:9a").is_err());
+        assert!(MacAddr::from_str("12:34:56:78:9a:bc:de").is_err());
+        assert!(MacAddr::from_str("12:34:56:78:9a:zz").is_err());
+        assert!(MacAddr::from_str("123:4:56:78:9a:bc").is_err());
+    }
+
+    #[test]
+    fn test_rarp_announce_frame() {
+        let mac = MacAddr([0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+        let frame = rarp_announce_frame(&mac);
+        assert_eq!(frame.len(), VIRTIO_NET_HDR_SIZE + 60);
+
+        let eth = &frame[VIRTIO_NET_HDR_SIZE..];
+        assert_eq!(&eth[0..6], &[0xff; 6]);
+        assert_eq!(&eth[6..12], &mac.0);
+        assert_eq!(&eth[12..14], &[0x80, 0x35]);
+        assert_eq!(&eth[20..22], &[0x00, 0x03]);
+        assert_eq!(&eth[22..28], &mac.0);
+        assert_eq!(&eth[32..38], &mac.0);
+    }
 }
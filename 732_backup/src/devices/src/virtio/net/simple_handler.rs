@@ -10,7 +10,7 @@ use virtio_queue::{DescriptorChain, Queue};
 use vm_memory::{Bytes, GuestAddressSpace};
 
 use crate::virtio::net::tap::Tap;
-use crate::virtio::net::{RXQ_INDEX, TXQ_INDEX};
+use crate::virtio::net::{TxBuffer, RXQ_INDEX, TXQ_INDEX};
 use crate::virtio::SignalUsedQueue;
 
 // According to the standard: "If the VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_TSO6 or
@@ -47,10 +47,17 @@ pub struct SimpleHandler<M: GuestAddressSpace, S: SignalUsedQueue> {
     pub txq: Queue<M>,
     pub txbuf: [u8; MAX_BUFFER_SIZE],
     pub tap: Tap,
+    pub tx_buffer: TxBuffer,
 }
 
 impl<M: GuestAddressSpace, S: SignalUsedQueue> SimpleHandler<M, S> {
-    pub fn new(driver_notify: S, rxq: Queue<M>, txq: Queue<M>, tap: Tap) -> Self {
+    pub fn new(
+        driver_notify: S,
+        rxq: Queue<M>,
+        txq: Queue<M>,
+        tap: Tap,
+        tx_buffer: TxBuffer,
+    ) -> Self {
         SimpleHandler {
             driver_notify,
             rxq,
@@ -59,6 +66,7 @@ impl<M: GuestAddressSpace, S: SignalUsedQueue> SimpleHandler<M, S> {
             txq,
             txbuf: [0u8; MAX_BUFFER_SIZE],
             tap,
+            tx_buffer,
         }
     }
 
@@ -154,7 +162,9 @@ impl<M: GuestAddressSpace, S: SignalUsedQueue> SimpleHandler<M, S> {
             count += len;
         }
 
-        self.tap.write(&self.txbuf[..count]).map_err(Error::Tap)?;
+        if !self.tx_buffer.push(&self.txbuf[..count]) {
+            self.tap.write(&self.txbuf[..count]).map_err(Error::Tap)?;
+        }
 
         Ok(count as u32)
     }
@@ -146,6 +146,14 @@ impl Tap {
         })
     }
 
+    /// Creates a new handle referring to the same tap queue.
+    pub fn try_clone(&self) -> Result<Tap> {
+        Ok(Tap {
+            tap_file: self.tap_file.try_clone().map_err(Error::CreateTap)?,
+            if_name: self.if_name,
+        })
+    }
+
     pub fn if_name_as_str(&self) -> &str {
         let len = self
             .if_name
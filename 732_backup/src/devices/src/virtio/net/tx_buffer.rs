@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+// Holds back the frames transmitted by the guest while the VMM replicates its state to a
+// standby host. Frames are grouped by the checkpoint epoch they were sent in, and can only leave
+// the host once that epoch was acknowledged by the standby; otherwise a failover could expose
+// network state the standby has never seen. Buffering is off by default, and clones share the
+// same frames.
+#[derive(Clone, Default)]
+pub struct TxBuffer {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    enabled: bool,
+    // Frames sent since the last checkpoint.
+    open: Vec<Vec<u8>>,
+    // Frames of the checkpoints that were not acknowledged yet, oldest first.
+    sealed: VecDeque<(u64, Vec<Vec<u8>>)>,
+}
+
+impl TxBuffer {
+    pub fn enable(&self) {
+        self.inner.lock().unwrap().enabled = true;
+    }
+
+    // Stops buffering, and writes out everything that was held back so far.
+    pub fn disable<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.enabled = false;
+        let sealed = std::mem::take(&mut inner.sealed);
+        let open = std::mem::take(&mut inner.open);
+        for frame in sealed.into_iter().flat_map(|(_, frames)| frames).chain(open) {
+            out.write_all(&frame)?;
+        }
+        Ok(())
+    }
+
+    // Keeps a copy of `frame` if buffering is enabled. Returns `false` when the caller has to
+    // send the frame itself.
+    pub fn push(&self, frame: &[u8]) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.enabled {
+            return false;
+        }
+        inner.open.push(frame.to_vec());
+        true
+    }
+
+    // Assigns the frames sent since the previous call to the checkpoint `epoch`.
+    pub fn seal(&self, epoch: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let open = std::mem::take(&mut inner.open);
+        if !open.is_empty() {
+            inner.sealed.push_back((epoch, open));
+        }
+    }
+
+    // Writes out the frames of all the checkpoints up to (and including) `epoch`.
+    pub fn release<W: Write>(&self, epoch: u64, out: &mut W) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        while inner.sealed.front().map_or(false, |(e, _)| *e <= epoch) {
+            let (_, frames) = inner.sealed.pop_front().unwrap();
+            for frame in frames {
+                out.write_all(&frame)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keeps each write separate, the same way a tap sees one frame per write.
+    #[derive(Default)]
+    struct Frames(Vec<Vec<u8>>);
+
+    impl Write for Frames {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_tx_buffer() {
+        let buffer = TxBuffer::default();
+        let mut out = Frames::default();
+
+        // Nothing is held back until buffering is enabled.
+        assert!(!buffer.push(&[0]));
+
+        buffer.enable();
+        assert!(buffer.push(&[1]));
+        buffer.seal(1);
+        assert!(buffer.push(&[2]));
+        buffer.seal(2);
+        assert!(buffer.push(&[3]));
+
+        buffer.release(0, &mut out).unwrap();
+        assert!(out.0.is_empty());
+        buffer.release(1, &mut out).unwrap();
+        assert_eq!(out.0, vec![vec![1]]);
+
+        // Frames that are not part of an acknowledged checkpoint yet are flushed on disable.
+        buffer.disable(&mut out).unwrap();
+        assert_eq!(out.0, vec![vec![1], vec![2], vec![3]]);
+        assert!(!buffer.push(&[4]));
+    }
+}
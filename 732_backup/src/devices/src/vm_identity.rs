@@ -0,0 +1,151 @@
+// A read-only MMIO page telling the guest who it is, along the lines of the ACPI VM generation ID
+// device. Clones of a snapshot all resume with the same memory, so the guest needs a way to find
+// out it was cloned: a new generation ID, along with a fresh seed for its RNG and the MAC address
+// its network interface should take. Nothing in the guest reads it on its own; an agent is
+// expected to poll the generation ID (e.g. through /dev/mem), and apply the rest when it changes.
+//
+// Layout, little endian:
+//
+//   0x00  magic "VMID"
+//   0x04  version (u32)
+//   0x08  clone id (u32)
+//   0x0c  flags (u32), see `FLAG_*`
+//   0x10  generation id (16 bytes)
+//   0x20  RNG seed (32 bytes)
+//   0x40  MAC address (6 bytes)
+//
+// The rest of the page reads as zeroes, and writes are ignored.
+
+use vm_device::bus::MmioAddress;
+use vm_device::MutDeviceMmio;
+
+use utils::debug;
+
+pub const MAGIC: &[u8; 4] = b"VMID";
+pub const VERSION: u32 = 1;
+/// Size of the MMIO range of the device.
+pub const PAGE_SIZE: u64 = 0x1000;
+
+/// The guest is a clone, started from a snapshot along with others.
+pub const FLAG_CLONE: u32 = 1 << 0;
+/// The MAC address is set.
+pub const FLAG_MAC: u32 = 1 << 1;
+
+const CLONE_ID_OFFSET: usize = 0x08;
+const FLAGS_OFFSET: usize = 0x0c;
+const GENERATION_ID_OFFSET: usize = 0x10;
+const RNG_SEED_OFFSET: usize = 0x20;
+const MAC_OFFSET: usize = 0x40;
+const LAYOUT_SIZE: usize = 0x48;
+
+/// What the guest learns about itself.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VmIdentity {
+    /// Index of the clone, `None` if the guest isn't one.
+    pub clone_id: Option<u32>,
+    /// Changes whenever the guest is started as a new copy of a snapshot. All zeroes otherwise.
+    pub generation_id: [u8; 16],
+    pub rng_seed: [u8; 32],
+    pub mac: Option<[u8; 6]>,
+}
+
+impl VmIdentity {
+    fn layout(&self) -> [u8; LAYOUT_SIZE] {
+        let mut layout = [0u8; LAYOUT_SIZE];
+        let mut flags = 0;
+        layout[..4].copy_from_slice(MAGIC);
+        layout[4..8].copy_from_slice(&VERSION.to_le_bytes());
+        if let Some(clone_id) = self.clone_id {
+            flags |= FLAG_CLONE;
+            layout[CLONE_ID_OFFSET..CLONE_ID_OFFSET + 4].copy_from_slice(&clone_id.to_le_bytes());
+        }
+        if let Some(mac) = self.mac {
+            flags |= FLAG_MAC;
+            layout[MAC_OFFSET..MAC_OFFSET + 6].copy_from_slice(&mac);
+        }
+        layout[FLAGS_OFFSET..FLAGS_OFFSET + 4].copy_from_slice(&flags.to_le_bytes());
+        layout[GENERATION_ID_OFFSET..GENERATION_ID_OFFSET + 16]
+            .copy_from_slice(&self.generation_id);
+        layout[RNG_SEED_OFFSET..RNG_SEED_OFFSET + 32].copy_from_slice(&self.rng_seed);
+        layout
+    }
+}
+
+pub struct VmIdentityDevice {
+    layout: [u8; LAYOUT_SIZE],
+}
+
+impl VmIdentityDevice {
+    pub fn new(identity: &VmIdentity) -> Self {
+        VmIdentityDevice {
+            layout: identity.layout(),
+        }
+    }
+}
+
+impl MutDeviceMmio for VmIdentityDevice {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: u64, data: &mut [u8]) {
+        let end = offset.checked_add(data.len() as u64);
+        if end.map_or(true, |end| end > PAGE_SIZE) {
+            debug!("Invalid VM identity read offset: {}", offset);
+            return;
+        }
+        for (index, byte) in data.iter_mut().enumerate() {
+            *byte = self
+                .layout
+                .get(offset as usize + index)
+                .copied()
+                .unwrap_or(0);
+        }
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: u64, _data: &[u8]) {
+        debug!("Ignoring VM identity write at offset: {}", offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(device: &mut VmIdentityDevice, offset: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0xff; len];
+        device.mmio_read(MmioAddress(0), offset, &mut data);
+        data
+    }
+
+    #[test]
+    fn test_layout() {
+        let mut device = VmIdentityDevice::new(&VmIdentity {
+            clone_id: Some(3),
+            generation_id: [1; 16],
+            rng_seed: [2; 32],
+            mac: Some([6, 0, 0, 0, 0, 3]),
+        });
+        assert_eq!(read(&mut device, 0, 4), MAGIC);
+        assert_eq!(read(&mut device, 4, 4), VERSION.to_le_bytes());
+        assert_eq!(read(&mut device, 8, 4), 3u32.to_le_bytes());
+        assert_eq!(
+            read(&mut device, 0xc, 4),
+            (FLAG_CLONE | FLAG_MAC).to_le_bytes()
+        );
+        assert_eq!(read(&mut device, 0x10, 16), [1; 16]);
+        assert_eq!(read(&mut device, 0x20, 32), vec![2; 32]);
+        assert_eq!(read(&mut device, 0x40, 8), [6, 0, 0, 0, 0, 3, 0, 0]);
+        // Past the layout, and past the page.
+        assert_eq!(read(&mut device, 0x800, 4), [0; 4]);
+        assert_eq!(read(&mut device, PAGE_SIZE - 2, 4), [0xff; 4]);
+        assert_eq!(read(&mut device, u64::MAX, 4), [0xff; 4]);
+
+        // Writes don't change anything.
+        device.mmio_write(MmioAddress(0), 0x10, &[0; 4]);
+        assert_eq!(read(&mut device, 0x10, 4), [1; 4]);
+    }
+
+    #[test]
+    fn test_not_a_clone() {
+        let mut device = VmIdentityDevice::new(&VmIdentity::default());
+        assert_eq!(read(&mut device, 0xc, 4), [0; 4]);
+        assert_eq!(read(&mut device, 0x10, 16), [0; 16]);
+    }
+}
@@ -10,8 +10,14 @@ use tarpc::{
 };
 
 use api::Cli;
-use std::sync::{atomic::Ordering, Arc, Mutex};
-use vmm::{RpcController, Vmm};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use vmm::dirty_log::DirtyRateMonitor;
+use vmm::live_update::LIVE_UPDATE_EVENT;
+use vmm::{
+    RpcController, Vmm, BACKGROUND_SNAPSHOT_EVENT, CORE_DUMP_EVENT, DIFF_SNAPSHOT_EVENT,
+    RESUME_VM_EVENT,
+};
 
 /// This is the service definition. It looks a lot like a trait definition.
 /// It defines one RPC, hello, which takes one arg, name, and returns a String.
@@ -28,11 +34,46 @@ pub trait World {
         port: u16,
         resume: bool,
     ) -> String;
+    /// Samples the guest dirty rate over `num_windows` windows of `window_ms` each, and
+    /// returns the report as JSON. At most 100 windows, and 5 minutes in total.
+    async fn measure_dirty_rate(window_ms: u64, num_windows: u32) -> String;
+    /// Hands the guest over to the VMM waiting on the Unix socket `socket_path`, and exits.
+    async fn live_update(socket_path: String) -> String;
+    /// Starts the vCPUs of a guest restored paused.
+    async fn resume_vm() -> String;
+    /// Snapshots the pages dirtied since the previous snapshot to `snapshot_path`, and resumes.
+    async fn diff_snapshot(snapshot_path: String) -> String;
+    /// Snapshots the guest to `snapshot_path`, pausing it only while its vCPU state is saved.
+    async fn background_snapshot(snapshot_path: String) -> String;
+    /// Writes the guest memory and vCPU registers to `path` as an ELF core file.
+    async fn core_dump(path: String) -> String;
+    /// Lists the cataloged snapshots as JSON, only those tagged `tag` if there's one.
+    async fn list_snapshots(tag: Option<String>) -> String;
+    /// Describes the cataloged snapshot `id` as JSON.
+    async fn describe_snapshot(id: String) -> String;
+    /// Names (unless `name` is empty) and tags the cataloged snapshot `id`.
+    async fn label_snapshot(id: String, name: String, tags: Vec<String>) -> String;
+    /// Deletes the cataloged snapshot `id`, unless diff snapshots are based on it.
+    async fn delete_snapshot(id: String) -> String;
 }
 
 #[derive(Clone)]
 struct RPCServer {
     rpc_controller: Arc<Mutex<RpcController>>,
+    dirty_rate_monitor: Arc<DirtyRateMonitor>,
+}
+
+// Reply to a request arriving before the run loop picked up the previous one.
+const BUSY: &str = "Error: busy, another request is pending";
+
+// Waits for the run loop to take the snapshot, and turns the outcome into the RPC reply.
+async fn snapshot_reply(result_rx: mpsc::Receiver<vmm::snapshot_file::Result<()>>) -> String {
+    match tokio::task::spawn_blocking(move || result_rx.recv()).await {
+        Ok(Ok(Ok(()))) => "Success".to_string(),
+        Ok(Ok(Err(e))) => format!("Error: {:?}", e),
+        Ok(Err(e)) => format!("Error: {}", e),
+        Err(e) => format!("Error: {}", e),
+    }
 }
 
 #[tarpc::server]
@@ -46,17 +87,18 @@ impl World for RPCServer {
         resume: bool,
     ) -> String {
         println!("RPC Call: Snapshot and Pause");
-        let mut rpc_controller = self.rpc_controller.lock().unwrap();
-        rpc_controller.cpu_snapshot_path = cpu_snapshot_path;
-        rpc_controller.memory_snapshot_path = memory_snapshot_path;
-        if resume {
-            rpc_controller.pause_or_resume.store(2, Ordering::Relaxed);
-        } else {
-            rpc_controller.pause_or_resume.store(1, Ordering::Relaxed);
+        let (result_tx, result_rx) = mpsc::channel();
+        {
+            let mut rpc_controller = self.rpc_controller.lock().unwrap();
+            if !rpc_controller.try_request(if resume { 2 } else { 1 }) {
+                return BUSY.to_string();
+            }
+            rpc_controller.cpu_snapshot_path = cpu_snapshot_path;
+            rpc_controller.memory_snapshot_path = memory_snapshot_path;
+            rpc_controller.snapshot_result_tx = Some(result_tx);
+            rpc_controller.event_fd.write(1).unwrap();
         }
-
-        rpc_controller.event_fd.write(1).unwrap();
-        "Success".to_string()
+        snapshot_reply(result_rx).await
     }
     async fn snapshot_and_resume(
         self,
@@ -66,13 +108,136 @@ impl World for RPCServer {
         port: u16,
     ) -> String {
         println!("RPC Call: Snapshot and Resume");
+        let (result_tx, result_rx) = mpsc::channel();
+        {
+            let mut rpc_controller = self.rpc_controller.lock().unwrap();
+            if !rpc_controller.try_request(2) {
+                return BUSY.to_string();
+            }
+            rpc_controller.cpu_snapshot_path = cpu_snapshot_path;
+            rpc_controller.memory_snapshot_path = memory_snapshot_path;
+            rpc_controller.snapshot_result_tx = Some(result_tx);
+            rpc_controller.event_fd.write(1).unwrap();
+        }
+        snapshot_reply(result_rx).await
+    }
+    async fn measure_dirty_rate(
+        self,
+        _: context::Context,
+        window_ms: u64,
+        num_windows: u32,
+    ) -> String {
+        println!("RPC Call: Measure Dirty Rate");
+        // The measurement sleeps between samples, so keep it off the async workers.
+        let monitor = self.dirty_rate_monitor.clone();
+        let report = tokio::task::spawn_blocking(move || {
+            monitor.measure(Duration::from_millis(window_ms), num_windows.max(1))
+        })
+        .await;
+        match report {
+            Ok(Ok(report)) => serde_json::to_string(&report).unwrap(),
+            Ok(Err(e)) => format!("Error: {:?}", e),
+            Err(e) => format!("Error: {}", e),
+        }
+    }
+    async fn live_update(self, _: context::Context, socket_path: String) -> String {
+        println!("RPC Call: Live Update");
         let mut rpc_controller = self.rpc_controller.lock().unwrap();
-        rpc_controller.cpu_snapshot_path = cpu_snapshot_path;
-        rpc_controller.memory_snapshot_path = memory_snapshot_path;
-        rpc_controller.pause_or_resume.store(2, Ordering::Relaxed);
+        if !rpc_controller.try_request(LIVE_UPDATE_EVENT) {
+            return BUSY.to_string();
+        }
+        rpc_controller.live_update_path = socket_path;
+        rpc_controller.event_fd.write(1).unwrap();
+        "Success".to_string()
+    }
+    async fn resume_vm(self, _: context::Context) -> String {
+        println!("RPC Call: Resume VM");
+        let rpc_controller = self.rpc_controller.lock().unwrap();
+        if !rpc_controller.try_request(RESUME_VM_EVENT) {
+            return BUSY.to_string();
+        }
         rpc_controller.event_fd.write(1).unwrap();
         "Success".to_string()
     }
+    async fn diff_snapshot(self, _: context::Context, snapshot_path: String) -> String {
+        println!("RPC Call: Diff Snapshot");
+        let (result_tx, result_rx) = mpsc::channel();
+        {
+            let mut rpc_controller = self.rpc_controller.lock().unwrap();
+            if !rpc_controller.try_request(DIFF_SNAPSHOT_EVENT) {
+                return BUSY.to_string();
+            }
+            rpc_controller.cpu_snapshot_path = snapshot_path;
+            rpc_controller.snapshot_result_tx = Some(result_tx);
+            rpc_controller.event_fd.write(1).unwrap();
+        }
+        snapshot_reply(result_rx).await
+    }
+    async fn background_snapshot(self, _: context::Context, snapshot_path: String) -> String {
+        println!("RPC Call: Background Snapshot");
+        let (result_tx, result_rx) = mpsc::channel();
+        {
+            let mut rpc_controller = self.rpc_controller.lock().unwrap();
+            if !rpc_controller.try_request(BACKGROUND_SNAPSHOT_EVENT) {
+                return BUSY.to_string();
+            }
+            rpc_controller.cpu_snapshot_path = snapshot_path;
+            rpc_controller.snapshot_result_tx = Some(result_tx);
+            rpc_controller.event_fd.write(1).unwrap();
+        }
+        snapshot_reply(result_rx).await
+    }
+    async fn core_dump(self, _: context::Context, path: String) -> String {
+        println!("RPC Call: Core Dump");
+        let (result_tx, result_rx) = mpsc::channel();
+        {
+            let mut rpc_controller = self.rpc_controller.lock().unwrap();
+            if !rpc_controller.try_request(CORE_DUMP_EVENT) {
+                return BUSY.to_string();
+            }
+            rpc_controller.cpu_snapshot_path = path;
+            rpc_controller.snapshot_result_tx = Some(result_tx);
+            rpc_controller.event_fd.write(1).unwrap();
+        }
+        snapshot_reply(result_rx).await
+    }
+    async fn list_snapshots(self, _: context::Context, tag: Option<String>) -> String {
+        println!("RPC Call: List Snapshots");
+        match Vmm::snapshot_catalog().list(tag.as_deref()) {
+            Ok(entries) => serde_json::to_string(&entries).unwrap(),
+            Err(e) => format!("Error: {:?}", e),
+        }
+    }
+    async fn describe_snapshot(self, _: context::Context, id: String) -> String {
+        println!("RPC Call: Describe Snapshot");
+        match Vmm::snapshot_catalog().get(&id) {
+            Ok(entry) => serde_json::to_string(&entry).unwrap(),
+            Err(e) => format!("Error: {:?}", e),
+        }
+    }
+    async fn label_snapshot(
+        self,
+        _: context::Context,
+        id: String,
+        name: String,
+        tags: Vec<String>,
+    ) -> String {
+        println!("RPC Call: Label Snapshot");
+        match Vmm::snapshot_catalog().label(&id, &name, tags) {
+            Ok(_) => "Success".to_string(),
+            Err(e) => format!("Error: {:?}", e),
+        }
+    }
+    async fn delete_snapshot(self, _: context::Context, id: String) -> String {
+        println!("RPC Call: Delete Snapshot");
+        match Vmm::delete_snapshot(&id) {
+            Ok(entry) => {
+                println!("deleted snapshot {}", entry.path);
+                "Success".to_string()
+            }
+            Err(e) => format!("Error: {:?}", e),
+        }
+    }
 }
 
 #[tokio::main]
@@ -85,7 +250,15 @@ async fn main() {
             .collect(),
     ) {
         Ok(mut vmm_config) => {
-            vmm_config.migrating = true;
+            // Clones, snapshots picked from the catalog and those asked for with `from_disk` are
+            // restored from disk, rather than from a migration.
+            let from_disk = vmm_config.restore_config.from_disk
+                || vmm_config.clone_config.is_some()
+                || vmm_config
+                    .snapshot_config
+                    .as_ref()
+                    .map_or(false, |cfg| cfg.id.is_some());
+            vmm_config.migrating = !from_disk;
             let config = vmm_config.clone();
 
             let mut vmm =
@@ -94,6 +267,7 @@ async fn main() {
             let ip = "127.0.0.1";
             let port = config.rpc_config.as_ref().unwrap().port.clone();
             let rpc_controller = vmm.rpc_controller.clone();
+            let dirty_rate_monitor = Arc::new(vmm.dirty_rate_monitor());
             let handle = tokio::spawn(async move {
                 let server_addr = (IpAddr::V4(ip.parse().unwrap()), port);
                 // JSON transport is provided by the json_transport tarpc module. It makes it easy
@@ -115,6 +289,7 @@ async fn main() {
                     .map(|channel| {
                         let server = RPCServer {
                             rpc_controller: Arc::clone(&rpc_controller),
+                            dirty_rate_monitor: Arc::clone(&dirty_rate_monitor),
                         };
                         channel.execute(server.serve())
                     })
@@ -5,8 +5,10 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+use std::ffi::CStr;
+use std::fs::File;
 use std::io::Error as IoError;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 
 use vm_memory_upstream::bitmap::AtomicBitmap;
 pub use vm_memory_upstream::bitmap::Bitmap;
@@ -138,6 +140,41 @@ pub fn create_guest_memory(
     GuestMemoryMmap::from_regions(mmap_regions)
 }
 
+/// Helper for creating the guest memory out of shared file mappings (i.e. of a memfd, see
+/// `create_memfd`), so that the memory can be handed over to another process.
+pub fn create_shared_guest_memory(
+    regions: &[(FileOffset, GuestAddress, usize)],
+    track_dirty_pages: bool,
+) -> std::result::Result<GuestMemoryMmap, Error> {
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let flags = libc::MAP_NORESERVE | libc::MAP_SHARED;
+    let mut mmap_regions = Vec::with_capacity(regions.len());
+
+    for region in regions {
+        let mmap_region =
+            build_guarded_region(Some(region.0.clone()), region.2, prot, flags, track_dirty_pages)
+                .map_err(Error::MmapRegion)?;
+
+        mmap_regions.push(GuestRegionMmap::new(mmap_region, region.1)?);
+    }
+
+    GuestMemoryMmap::from_regions(mmap_regions)
+}
+
+/// Creates an anonymous memory file of `size` bytes, to back the guest memory.
+pub fn create_memfd(size: usize) -> std::result::Result<File, IoError> {
+    let name = CStr::from_bytes_with_nul(b"guest_mem\0").unwrap();
+    // Safe because the name is a valid C string and we check the return value.
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(IoError::last_os_error());
+    }
+    // Safe because we just created the fd, and nothing else owns it.
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.set_len(size as u64)?;
+    Ok(file)
+}
+
 pub fn mark_dirty_mem(mem: &GuestMemoryMmap, addr: GuestAddress, len: usize) {
     let _ = mem.try_access(len, addr, |_total, count, caddr, region| {
         if let Some(bitmap) = region.bitmap() {
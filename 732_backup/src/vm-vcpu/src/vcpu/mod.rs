@@ -196,6 +196,9 @@ pub enum Error {
     /// Failed to set KVM vcpu regs.
     #[error("Failed to set KVM vcpu regs: {0}")]
     VcpuSetRegs(kvm_ioctls::Error),
+    /// Failed to set the KVM vcpu TSC frequency.
+    #[error("Failed to set the KVM vcpu TSC frequency: {0}")]
+    VcpuSetTscKhz(kvm_ioctls::Error),
     /// Failed to set KVM vcpu sregs.
     #[error("Failed to set KVM vcpu sregs: {0}")]
     VcpuSetSregs(kvm_ioctls::Error),
@@ -288,6 +291,17 @@ pub struct VcpuState {
     pub xcrs: kvm_xcrs,
     pub xsave: kvm_xsave,
     pub config: VcpuConfig,
+    /// TSC frequency of the vCPU, unknown if the host didn't report it.
+    #[version(start = 2, default_fn = "default_tsc_khz")]
+    pub tsc_khz: Option<u32>,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl VcpuState {
+    // Snapshots from before version 2 didn't record the frequency.
+    fn default_tsc_khz(_source_version: u16) -> Option<u32> {
+        None
+    }
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -413,6 +427,16 @@ impl KvmVcpu {
         self.vcpu_fd
             .set_lapic(&state.lapic)
             .map_err(Error::VcpuSetLapic)?;
+        // The TSC frequency has to be in place before the TSC itself is restored (as part of the
+        // MSRs). Keeping the one of the source avoids a drifting guest clock when this host runs
+        // at a different frequency, provided the CPU supports TSC scaling.
+        if let Some(tsc_khz) = state.tsc_khz {
+            if self.vcpu_fd.get_tsc_khz().ok() != Some(tsc_khz) {
+                self.vcpu_fd
+                    .set_tsc_khz(tsc_khz)
+                    .map_err(Error::VcpuSetTscKhz)?;
+            }
+        }
         self.vcpu_fd
             .set_msrs(&state.msrs)
             .map_err(Error::VcpuSetMsrs)?;
@@ -1000,6 +1024,8 @@ impl KvmVcpu {
             .get_cpuid2(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
             .map_err(Error::VcpuGetCpuid)?;
 
+        let tsc_khz = self.vcpu_fd.get_tsc_khz().ok();
+
         Ok(VcpuState {
             cpuid,
             msrs,
@@ -1012,6 +1038,7 @@ impl KvmVcpu {
             xcrs,
             xsave,
             config: self.config.clone(),
+            tsc_khz,
         })
     }
 
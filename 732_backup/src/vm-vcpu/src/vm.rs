@@ -6,6 +6,8 @@ use std::io::{self, ErrorKind};
 use std::sync::mpsc::{self, Receiver};
 use std::sync::{Arc, Barrier, Mutex};
 use std::thread::{self, JoinHandle};
+#[cfg(target_arch = "x86_64")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
@@ -30,6 +32,8 @@ use crate::vcpu::{self, KvmVcpu, VcpuConfigList, VcpuRunState, VcpuState};
 use vm_vcpu_ref::aarch64::interrupts::{self, Gic, GicConfig};
 #[cfg(target_arch = "x86_64")]
 use vm_vcpu_ref::x86_64::mptable::{self, MpTable};
+#[cfg(target_arch = "x86_64")]
+use vm_vcpu_ref::x86_64::msr_index::MSR_IA32_TSC;
 
 /// Defines the configuration of this VM.
 #[derive(Clone, Versionize)]
@@ -58,6 +62,51 @@ pub struct VmState {
     pub ioapic: kvm_irqchip,
     pub config: VmConfig,
     pub vcpus_state: Vec<VcpuState>,
+    /// Wall clock time at which the state was saved, in nanoseconds since the Unix epoch. 0 if
+    /// unknown.
+    #[version(start = 2, default_fn = "default_saved_at_ns")]
+    pub saved_at_ns: u64,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl VmState {
+    /// Time elapsed since the state was saved, according to the wall clock of this host. Only
+    /// meaningful across hosts when their clocks are synchronized (i.e. through NTP). Returns
+    /// `None` when the clock of this host is behind the one of the saving host, or when the
+    /// time of the save is unknown.
+    pub fn downtime(&self) -> Option<Duration> {
+        if self.saved_at_ns == 0 {
+            return None;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        now.checked_sub(Duration::from_nanos(self.saved_at_ns))
+    }
+
+    // Snapshots from before version 2 didn't record when they were taken.
+    fn default_saved_at_ns(_source_version: u16) -> u64 {
+        0
+    }
+
+    /// Moves the guest clocks forward by `elapsed`, so that the guest sees the time it spent
+    /// stopped instead of resuming at the moment it was saved. Both kvmclock and the TSC of
+    /// every vCPU are advanced, keeping them consistent with each other.
+    pub fn advance_clock(&mut self, elapsed: Duration) {
+        self.clock.clock = self.clock.clock.wrapping_add(elapsed.as_nanos() as u64);
+
+        for vcpu_state in self.vcpus_state.iter_mut() {
+            // Without the frequency, there's no telling how many ticks the TSC missed.
+            let tsc_khz = match vcpu_state.tsc_khz {
+                Some(tsc_khz) => tsc_khz,
+                None => continue,
+            };
+            let ticks = (elapsed.as_nanos() * tsc_khz as u128 / 1_000_000) as u64;
+            for entry in vcpu_state.msrs.as_mut_slice() {
+                if entry.index == MSR_IA32_TSC {
+                    entry.data = entry.data.wrapping_add(ticks);
+                }
+            }
+        }
+    }
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -551,6 +600,10 @@ impl<EH: 'static + ExitHandler + Send> KvmVm<EH> {
         let mut clock = self.fd.get_clock().map_err(Error::VmGetClock)?;
         // This bit is not accepted in SET_CLOCK, clear it.
         clock.flags &= !KVM_CLOCK_TSC_STABLE;
+        // Taken along with the clock, to tell how long the guest was stopped on restore.
+        let saved_at_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_nanos() as u64);
 
         let mut pic_master = kvm_irqchip {
             chip_id: KVM_IRQCHIP_PIC_MASTER,
@@ -589,6 +642,7 @@ impl<EH: 'static + ExitHandler + Send> KvmVm<EH> {
             ioapic,
             config: self.config.clone(),
             vcpus_state,
+            saved_at_ns,
         })
     }
 }
@@ -0,0 +1,249 @@
+// Background snapshots: the guest only stops for its vCPU state to be saved, and runs while its
+// memory is.
+//
+// At the snapshot instant, the guest memory is write protected with userfaultfd. It's then saved a
+// dedup chunk at a time, each chunk being unprotected once read. A write to a chunk that wasn't
+// saved yet, by the guest or a device, is held until the chunk is copied aside, and the copy goes
+// into the snapshot in its place. So the snapshot holds the memory as it was at the instant, and
+// the guest is held up at most once per chunk. The copies wait in memory until they're saved, which
+// for a guest writing all over its memory can take up to as much memory again.
+//
+// Only pages that are mapped can be write protected, so the memory is touched beforehand, mapping
+// the zero page where it's still missing. Write protecting anonymous memory takes Linux 5.7 (5.19
+// for a memfd), and the memory can't already have a userfaultfd, as it has after a lazy restore.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+use crate::dirty_log::PAGE_SIZE;
+use crate::snapshot_file::{Result, SnapshotError};
+use crate::uffd::{Uffd, FEATURE_PAGEFAULT_FLAG_WP, REGISTER_MODE_WP};
+use crate::CHUNK_SIZE;
+
+// How long the fault handler waits for a fault before checking whether the snapshot is done.
+const FAULT_POLL_MS: i32 = 100;
+
+/// Write protection of the guest memory, keeping what it held at the snapshot instant.
+pub struct MemoryProtection {
+    memory: Arc<ProtectedMemory>,
+    handler: Option<JoinHandle<()>>,
+}
+
+impl MemoryProtection {
+    /// Prepares `guest_memory` to be write protected, failing if the kernel can't.
+    pub fn new(guest_memory: &GuestMemoryMmap) -> Result<Self> {
+        let uffd = Uffd::new(FEATURE_PAGEFAULT_FLAG_WP)?;
+        let mut regions = Vec::new();
+        for region in guest_memory.iter() {
+            let host_addr = guest_memory
+                .get_host_address(region.start_addr())
+                .map_err(SnapshotError::Memory)? as u64;
+            uffd.register(host_addr, region.len(), REGISTER_MODE_WP)?;
+            regions.push(ProtectedRegion {
+                host_addr,
+                start_addr: region.start_addr(),
+                size: region.len(),
+            });
+        }
+
+        for region in regions.iter() {
+            for offset in (0..region.size).step_by(PAGE_SIZE) {
+                guest_memory
+                    .read_obj::<u8>(region.start_addr.unchecked_add(offset))
+                    .map_err(SnapshotError::Memory)?;
+            }
+        }
+
+        Ok(MemoryProtection {
+            memory: Arc::new(ProtectedMemory {
+                uffd,
+                guest_memory: guest_memory.clone(),
+                regions,
+                chunks: Mutex::new(Chunks::default()),
+                done: AtomicBool::new(false),
+                failed: AtomicBool::new(false),
+            }),
+            handler: None,
+        })
+    }
+
+    /// Write protects the memory, so that `save` writes what it holds at this point. The vCPUs
+    /// must not run meanwhile.
+    pub fn protect(&mut self) -> Result<()> {
+        for region in self.memory.regions.iter() {
+            self.memory
+                .uffd
+                .write_protect(region.host_addr, region.size, true)?;
+        }
+        let memory = self.memory.clone();
+        self.handler = Some(thread::spawn(move || memory.handle_faults()));
+        Ok(())
+    }
+
+    /// Writes the memory as it was when protected, in the same order as `SnapshotMemory::dump`,
+    /// lifting the protection as it goes.
+    pub fn save(&self, writer: &mut dyn Write) -> Result<()> {
+        for (index, region) in self.memory.regions.iter().enumerate() {
+            for chunk in 0..(region.size + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64 {
+                let data = self.memory.take(index, chunk)?;
+                writer.write_all(&data)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MemoryProtection {
+    fn drop(&mut self) {
+        self.memory.done.store(true, Ordering::Release);
+        self.memory.unprotect_all();
+        if let Some(handler) = self.handler.take() {
+            let _ = handler.join();
+        }
+    }
+}
+
+struct ProtectedRegion {
+    host_addr: u64,
+    start_addr: GuestAddress,
+    size: u64,
+}
+
+#[derive(Default)]
+struct Chunks {
+    // Chunks that are no longer protected, as (region, chunk of the region).
+    saved: HashSet<(usize, u64)>,
+    // Chunks copied aside before being written, waiting to be saved.
+    copies: HashMap<(usize, u64), Vec<u8>>,
+}
+
+struct ProtectedMemory {
+    uffd: Uffd,
+    guest_memory: GuestMemoryMmap,
+    regions: Vec<ProtectedRegion>,
+    chunks: Mutex<Chunks>,
+    done: AtomicBool,
+    // Set when the fault handler gave up, and the memory may have changed since the instant.
+    failed: AtomicBool,
+}
+
+impl ProtectedMemory {
+    // Guest address and length of `chunk` of `region`.
+    fn chunk_range(&self, region: usize, chunk: u64) -> (GuestAddress, u64) {
+        let region = &self.regions[region];
+        let offset = chunk * CHUNK_SIZE as u64;
+        (
+            region.start_addr.unchecked_add(offset),
+            (region.size - offset).min(CHUNK_SIZE as u64),
+        )
+    }
+
+    fn read_chunk(&self, region: usize, chunk: u64) -> Result<Vec<u8>> {
+        let (addr, len) = self.chunk_range(region, chunk);
+        let mut data = vec![0u8; len as usize];
+        self.guest_memory
+            .read_slice(&mut data, addr)
+            .map_err(SnapshotError::Memory)?;
+        Ok(data)
+    }
+
+    // Lifts the protection of `chunk`, waking up whoever is held on it.
+    fn unprotect(&self, region: usize, chunk: u64) -> io::Result<()> {
+        let (_, len) = self.chunk_range(region, chunk);
+        let host_addr = self.regions[region].host_addr + chunk * CHUNK_SIZE as u64;
+        self.uffd.write_protect(host_addr, len, false)
+    }
+
+    fn unprotect_all(&self) {
+        for region in self.regions.iter() {
+            if let Err(e) = self
+                .uffd
+                .write_protect(region.host_addr, region.size, false)
+            {
+                eprintln!(
+                    "background snapshot: failed to unprotect the guest memory: {}",
+                    e
+                );
+            }
+        }
+    }
+
+    // The content of `chunk` at the snapshot instant, unprotecting it.
+    fn take(&self, region: usize, chunk: u64) -> Result<Vec<u8>> {
+        let mut chunks = self.chunks.lock().unwrap();
+        if let Some(data) = chunks.copies.remove(&(region, chunk)) {
+            return Ok(data);
+        }
+        let data = self.read_chunk(region, chunk)?;
+        if self.failed.load(Ordering::SeqCst) {
+            return Err(SnapshotError::Io(io::Error::new(
+                io::ErrorKind::Other,
+                "lost the write protection of the guest memory",
+            )));
+        }
+        chunks.saved.insert((region, chunk));
+        self.unprotect(region, chunk)?;
+        Ok(data)
+    }
+
+    // Copies `chunk` aside, unless it was saved already, and lets the write through.
+    fn copy_aside(&self, region: usize, chunk: u64) -> Result<()> {
+        let mut chunks = self.chunks.lock().unwrap();
+        if chunks.saved.insert((region, chunk)) {
+            let data = self.read_chunk(region, chunk)?;
+            chunks.copies.insert((region, chunk), data);
+        }
+        // Possibly again, if the write faulted before `take` unprotected the chunk.
+        self.unprotect(region, chunk)?;
+        Ok(())
+    }
+
+    // Region and chunk of the region holding the page at `host_addr`.
+    fn find(&self, host_addr: u64) -> Option<(usize, u64)> {
+        let index = self.regions.iter().position(|region| {
+            host_addr >= region.host_addr && host_addr < region.host_addr + region.size
+        })?;
+        Some((
+            index,
+            (host_addr - self.regions[index].host_addr) / CHUNK_SIZE as u64,
+        ))
+    }
+
+    fn handle_faults(&self) {
+        while !self.done.load(Ordering::Acquire) {
+            let fault = match self.uffd.read_fault(FAULT_POLL_MS) {
+                Ok(Some(fault)) => fault,
+                Ok(None) => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("background snapshot: failed to read a page fault: {}", e);
+                    break;
+                }
+            };
+            if !fault.write_protect {
+                continue;
+            }
+            let (region, chunk) = match self.find(fault.addr) {
+                Some(found) => found,
+                None => continue,
+            };
+            if let Err(e) = self.copy_aside(region, chunk) {
+                eprintln!(
+                    "background snapshot: failed to copy the guest memory at {:#x}: {:?}",
+                    fault.addr, e
+                );
+                break;
+            }
+        }
+        // Nobody is left to let writes through, the snapshot fails rather than the guest hangs.
+        if !self.done.load(Ordering::Acquire) {
+            self.failed.store(true, Ordering::SeqCst);
+            self.unprotect_all();
+        }
+    }
+}
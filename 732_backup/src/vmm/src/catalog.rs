@@ -0,0 +1,380 @@
+// Catalog of the snapshots taken by the VMMs sharing a database directory: an id for each one,
+// along with a name and tags to find it by, the snapshot it's a diff of, and the configuration of
+// the VM it was taken of. Snapshots are still written to, and restored from, their path; the
+// catalog is kept on the side, in a JSON file.
+//
+// VMMs record their snapshots as they finish them. Updates hold the lock of the database
+// directory, shared with the dedup store, and replace the catalog with a rename, so that VMMs
+// snapshotting at the same time don't lose each other's entries, and a crash never leaves a
+// truncated catalog behind.
+//
+// There's a single entry per path: a snapshot taken to the path of a cataloged one replaces it,
+// under a new id.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::dedup::{lock_database, DedupManager};
+use crate::snapshot_crypto::fill_random;
+use crate::snapshot_file::{SnapshotError, SnapshotMetadata, SnapshotReader};
+use crate::VMMConfig;
+
+/// Errors of the snapshot catalog.
+#[derive(Debug)]
+pub enum Error {
+    /// I/O error on the catalog, or on a snapshot.
+    Io(io::Error),
+    /// Failed to parse or write the catalog.
+    Json(serde_json::Error),
+    /// Failed to read the snapshot to record.
+    Snapshot(SnapshotError),
+    /// The file to record is not a snapshot container.
+    NotASnapshot(String),
+    /// No snapshot has this id.
+    NotFound(String),
+    /// These diff snapshots are based on the snapshot to delete.
+    HasDiffs(Vec<String>),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<SnapshotError> for Error {
+    fn from(e: SnapshotError) -> Self {
+        Error::Snapshot(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Configuration of the VM a snapshot was taken of, to restore it alike.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SourceVm {
+    pub kernel_path: String,
+    pub kernel_cmdline: String,
+    pub mem_size_mib: u32,
+    pub num_vcpus: u8,
+    pub block_path: Option<String>,
+    pub tap_name: Option<String>,
+    pub mac: Option<String>,
+}
+
+impl SourceVm {
+    pub fn new(config: &VMMConfig) -> Self {
+        SourceVm {
+            kernel_path: config.kernel_config.path.to_string_lossy().into_owned(),
+            kernel_cmdline: config.kernel_config.cmdline.as_str().to_string(),
+            mem_size_mib: config.memory_config.size_mib,
+            num_vcpus: config.vcpu_config.num,
+            block_path: config
+                .block_config
+                .as_ref()
+                .map(|cfg| cfg.path.to_string_lossy().into_owned()),
+            tap_name: config.net_config.as_ref().map(|cfg| cfg.tap_name.clone()),
+            mac: config
+                .net_config
+                .as_ref()
+                .and_then(|cfg| cfg.mac)
+                .map(|mac| mac.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CatalogEntry {
+    pub id: String,
+    /// The file name of the snapshot, unless it was named since.
+    pub name: String,
+    pub tags: Vec<String>,
+    pub path: String,
+    /// Id of the snapshot a diff snapshot is based on. `None` for a full snapshot, or a diff of
+    /// a snapshot that isn't cataloged.
+    pub parent: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    /// Size of the snapshot file, in bytes.
+    pub size: u64,
+    pub encrypted: bool,
+    pub source: SourceVm,
+    /// What the snapshot itself says about the VM.
+    pub metadata: Option<SnapshotMetadata>,
+}
+
+/// The catalog file, at `path`.
+pub struct Catalog {
+    path: PathBuf,
+}
+
+impl Catalog {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Catalog {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Adds the snapshot at `snapshot_path`, taken of a VM configured as `source`, in place of
+    /// what was cataloged at that path.
+    pub fn record(&self, snapshot_path: &str, source: &SourceVm) -> Result<CatalogEntry> {
+        let snapshot = SnapshotReader::open(snapshot_path)?
+            .ok_or_else(|| Error::NotASnapshot(snapshot_path.to_string()))?;
+        let metadata = snapshot.metadata()?;
+        let parent = snapshot.parent()?;
+        let size = fs::metadata(snapshot_path)?.len();
+
+        let _lock = self.lock()?;
+        let mut entries = self.load()?;
+        entries.retain(|entry| entry.path != snapshot_path);
+        let entry = CatalogEntry {
+            id: new_id()?,
+            name: Path::new(snapshot_path).file_name().map_or_else(
+                || snapshot_path.to_string(),
+                |name| name.to_string_lossy().into_owned(),
+            ),
+            tags: vec![],
+            path: snapshot_path.to_string(),
+            parent: parent.and_then(|parent| {
+                entries
+                    .iter()
+                    .find(|entry| entry.path == parent)
+                    .map(|entry| entry.id.clone())
+            }),
+            created_at: metadata
+                .as_ref()
+                .map_or_else(now, |metadata| metadata.created_at),
+            size,
+            encrypted: snapshot.is_encrypted(),
+            source: source.clone(),
+            metadata,
+        };
+        entries.push(entry.clone());
+        self.store(&entries)?;
+        Ok(entry)
+    }
+
+    /// The cataloged snapshots, oldest first, only those tagged `tag` if there's one.
+    pub fn list(&self, tag: Option<&str>) -> Result<Vec<CatalogEntry>> {
+        let mut entries = self.load()?;
+        if let Some(tag) = tag {
+            entries.retain(|entry| entry.tags.iter().any(|entry_tag| entry_tag == tag));
+        }
+        entries.sort_by_key(|entry| entry.created_at);
+        Ok(entries)
+    }
+
+    pub fn get(&self, id: &str) -> Result<CatalogEntry> {
+        self.load()?
+            .into_iter()
+            .find(|entry| entry.id == id)
+            .ok_or_else(|| Error::NotFound(id.to_string()))
+    }
+
+    /// Names and tags the snapshot `id`. An empty `name` leaves its name as it is.
+    pub fn label(&self, id: &str, name: &str, tags: Vec<String>) -> Result<CatalogEntry> {
+        let _lock = self.lock()?;
+        let mut entries = self.load()?;
+        let entry = entries
+            .iter_mut()
+            .find(|entry| entry.id == id)
+            .ok_or_else(|| Error::NotFound(id.to_string()))?;
+        if !name.is_empty() {
+            entry.name = name.to_string();
+        }
+        entry.tags = tags;
+        let entry = entry.clone();
+        self.store(&entries)?;
+        Ok(entry)
+    }
+
+    /// Deletes the snapshot `id`, from its path and from the dedup database, unless diff
+    /// snapshots are based on it.
+    pub fn delete(&self, id: &str, dedup_mgr: &DedupManager) -> Result<CatalogEntry> {
+        let _lock = self.lock()?;
+        let mut entries = self.load()?;
+        let index = entries
+            .iter()
+            .position(|entry| entry.id == id)
+            .ok_or_else(|| Error::NotFound(id.to_string()))?;
+        let diffs: Vec<String> = entries
+            .iter()
+            .filter(|entry| entry.parent.as_deref() == Some(id))
+            .map(|entry| entry.id.clone())
+            .collect();
+        if !diffs.is_empty() {
+            return Err(Error::HasDiffs(diffs));
+        }
+
+        // Forgotten first, so that a crash can only leave an unlisted snapshot behind, never an
+        // entry whose file is gone.
+        let entry = entries.remove(index);
+        self.store(&entries)?;
+        match fs::remove_file(&entry.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        dedup_mgr.remove_file_locked(&entry.path)?;
+        Ok(entry)
+    }
+
+    // Held until dropped, by whoever updates the catalog.
+    fn lock(&self) -> Result<File> {
+        Ok(lock_database(self.dir())?)
+    }
+
+    fn load(&self) -> Result<Vec<CatalogEntry>> {
+        match fs::read(&self.path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(Error::Json),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn store(&self, entries: &[CatalogEntry]) -> Result<()> {
+        let data = serde_json::to_vec_pretty(entries).map_err(Error::Json)?;
+        let tmp_path = self.sibling(".tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        // The rename itself only lasts once the directory is synced.
+        File::open(self.dir())?.sync_all()?;
+        Ok(())
+    }
+
+    fn dir(&self) -> &Path {
+        match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+    }
+
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+}
+
+fn new_id() -> Result<String> {
+    let mut id = [0u8; 8];
+    fill_random(&mut id)?;
+    Ok(id.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    use crate::snapshot_file::{SectionKind, SnapshotWriter};
+
+    fn write_snapshot(path: &Path, parent: Option<&Path>) -> String {
+        let mut writer = SnapshotWriter::create_with_key(path, None).unwrap();
+        let metadata = SnapshotMetadata::new(1 << 20, 1, vec![]);
+        writer
+            .add_section(
+                SectionKind::Metadata,
+                &serde_json::to_vec(&metadata).unwrap(),
+            )
+            .unwrap();
+        if let Some(parent) = parent {
+            writer
+                .add_section(SectionKind::Parent, parent.to_str().unwrap().as_bytes())
+                .unwrap();
+        }
+        writer.finish().unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_catalog() {
+        let dir = TempDir::new().unwrap();
+        let catalog = Catalog::new(dir.as_path().join("database").join("catalog.json"));
+        let source = SourceVm {
+            mem_size_mib: 1,
+            num_vcpus: 1,
+            ..Default::default()
+        };
+        let base = write_snapshot(&dir.as_path().join("base"), None);
+        let base_entry = catalog.record(&base, &source).unwrap();
+        let diff = write_snapshot(&dir.as_path().join("diff"), Some(Path::new(&base)));
+        let diff_entry = catalog.record(&diff, &source).unwrap();
+
+        assert_eq!(base_entry.name, "base");
+        assert_eq!(base_entry.parent, None);
+        assert_eq!(base_entry.size, fs::metadata(&base).unwrap().len());
+        assert_eq!(base_entry.source, source);
+        assert_eq!(diff_entry.parent, Some(base_entry.id.clone()));
+        assert_eq!(catalog.get(&diff_entry.id).unwrap(), diff_entry);
+        assert_eq!(catalog.list(None).unwrap().len(), 2);
+
+        let labeled = catalog
+            .label(&base_entry.id, "golden", vec!["warm".to_string()])
+            .unwrap();
+        assert_eq!(labeled.name, "golden");
+        assert_eq!(catalog.list(Some("warm")).unwrap(), vec![labeled.clone()]);
+        assert!(catalog.list(Some("cold")).unwrap().is_empty());
+        // Tags only.
+        let relabeled = catalog.label(&base_entry.id, "", vec![]).unwrap();
+        assert_eq!(relabeled.name, "golden");
+
+        // Snapshotting to the same path again replaces the entry.
+        write_snapshot(&dir.as_path().join("diff"), Some(Path::new(&base)));
+        let new_diff_entry = catalog.record(&diff, &source).unwrap();
+        assert_ne!(new_diff_entry.id, diff_entry.id);
+        assert!(matches!(
+            catalog.get(&diff_entry.id),
+            Err(Error::NotFound(_))
+        ));
+        assert_eq!(catalog.list(None).unwrap().len(), 2);
+
+        let missing = dir.as_path().join("missing");
+        assert!(matches!(
+            catalog.record(missing.to_str().unwrap(), &source),
+            Err(Error::Snapshot(_))
+        ));
+    }
+
+    #[test]
+    fn test_delete() {
+        let dir = TempDir::new().unwrap();
+        let catalog = Catalog::new(dir.as_path().join("database").join("catalog.json"));
+        let dedup_mgr = DedupManager::in_dir(dir.as_path());
+        let base = write_snapshot(&dir.as_path().join("base"), None);
+        let base_entry = catalog.record(&base, &SourceVm::default()).unwrap();
+        dedup_mgr.save_file(&base);
+        let diff = write_snapshot(&dir.as_path().join("diff"), Some(Path::new(&base)));
+        let diff_entry = catalog.record(&diff, &SourceVm::default()).unwrap();
+
+        match catalog.delete(&base_entry.id, &dedup_mgr) {
+            Err(Error::HasDiffs(diffs)) => assert_eq!(diffs, vec![diff_entry.id.clone()]),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(Path::new(&base).exists());
+
+        catalog.delete(&diff_entry.id, &dedup_mgr).unwrap();
+        assert!(!Path::new(&diff).exists());
+        catalog.delete(&base_entry.id, &dedup_mgr).unwrap();
+        assert!(!Path::new(&base).exists());
+        assert!(dedup_mgr.file_chunks(&base).is_none());
+        assert!(dedup_mgr.chunk_files().is_empty());
+        assert!(catalog.list(None).unwrap().is_empty());
+        assert!(matches!(
+            catalog.delete(&base_entry.id, &dedup_mgr),
+            Err(Error::NotFound(_))
+        ));
+    }
+}
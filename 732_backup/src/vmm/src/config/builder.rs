@@ -3,9 +3,16 @@
 
 //! Config builder
 use std::convert::TryFrom;
+use std::path::PathBuf;
+
+use linux_loader::cmdline::Cmdline;
+
+use crate::catalog::Catalog;
+use crate::CATALOG_PATH;
 
 use super::{
-    BlockConfig, ConversionError, KernelConfig, MemoryConfig, NetConfig, VMMConfig, VcpuConfig, SnapshotConfig, RpcConfig
+    BlockConfig, ConversionError, HaConfig, KernelConfig, MemoryConfig, MigrationConfig, NetConfig, VMMConfig, VcpuConfig, SnapshotConfig, RpcConfig, RestoreConfig, LiveUpdateConfig, CloneConfig,
+    KERNEL_CMDLINE_CAPACITY,
 };
 
 /// Builder structure for VMMConfig
@@ -36,7 +43,8 @@ impl Builder {
                         self.and_then(|mut config|{
                             config.snapshot_config = Some(SnapshotConfig{
                                 cpu_snapshot_path: cpu_path.to_string(),
-                                memory_snapshot_path: memory_path.to_string()
+                                memory_snapshot_path: memory_path.to_string(),
+                                id: None,
                             });  
                             Ok(config)
                         })
@@ -52,6 +60,45 @@ impl Builder {
         } 
     }
 
+    /// Configure Builder to restore the snapshot cataloged as `id`, see `catalog`.
+    pub fn snapshot_id_config(self, id: Option<&str>) -> Self {
+        match id {
+            Some(id) => self.and_then(|mut config| {
+                let entry = Catalog::new(CATALOG_PATH)
+                    .get(id)
+                    .map_err(|e| ConversionError::ParseSnapshotId(format!("{:?}", e)))?;
+                // The guest must be restored on a VM like the one it was taken of, whatever the
+                // other options say. Entries that didn't record it are left to them.
+                let source = &entry.source;
+                if source.mem_size_mib != 0 {
+                    config.memory_config.size_mib = source.mem_size_mib;
+                }
+                if source.num_vcpus != 0 {
+                    config.vcpu_config.num = source.num_vcpus;
+                }
+                if !source.kernel_path.is_empty() {
+                    config.kernel_config.path = PathBuf::from(&source.kernel_path);
+                }
+                if !source.kernel_cmdline.is_empty() {
+                    let mut cmdline = Cmdline::new(KERNEL_CMDLINE_CAPACITY);
+                    cmdline.insert_str(&source.kernel_cmdline).map_err(|_| {
+                        ConversionError::ParseSnapshotId(
+                            "Kernel cmdline capacity error".to_string(),
+                        )
+                    })?;
+                    config.kernel_config.cmdline = cmdline;
+                }
+                config.snapshot_config = Some(SnapshotConfig {
+                    cpu_snapshot_path: entry.path,
+                    memory_snapshot_path: String::new(),
+                    id: Some(entry.id),
+                });
+                Ok(config)
+            }),
+            None => self,
+        }
+    }
+
     pub fn rpc_config(self, port: Option<&str>) -> Self {
         match port {
             Some(port) => {
@@ -98,6 +145,11 @@ impl Builder {
                         "Kernel Image Path is Empty.".to_string(),
                     ));
                 }
+                if vc.clone_config.is_some() && vc.snapshot_config.is_none() {
+                    return Err(ConversionError::ParseClone(
+                        "Clones start from a snapshot.".to_string(),
+                    ));
+                }
             }
             Err(_) => {}
         }
@@ -207,6 +259,108 @@ impl Builder {
         }
     }
 
+    /// Configure Builder with Live Migration Configuration for the VMM.
+    ///
+    /// # Example
+    ///
+    /// You can see example of how to use this function in [`Example` section from
+    /// `build`](#method.build)
+    pub fn migration_config<T>(self, migration: Option<T>) -> Self
+    where
+        MigrationConfig: TryFrom<T>,
+        <MigrationConfig as TryFrom<T>>::Error: Into<ConversionError>,
+    {
+        match migration {
+            Some(m) => self.and_then(|mut config| {
+                config.migration_config = TryFrom::try_from(m).map_err(Into::into)?;
+                Ok(config)
+            }),
+            None => self,
+        }
+    }
+
+    /// Configure Builder with High Availability Configuration for the VMM.
+    ///
+    /// # Example
+    ///
+    /// You can see example of how to use this function in [`Example` section from
+    /// `build`](#method.build)
+    pub fn ha_config<T>(self, ha: Option<T>) -> Self
+    where
+        HaConfig: TryFrom<T>,
+        <HaConfig as TryFrom<T>>::Error: Into<ConversionError>,
+    {
+        match ha {
+            Some(h) => self.and_then(|mut config| {
+                config.ha_config = Some(TryFrom::try_from(h).map_err(Into::into)?);
+                Ok(config)
+            }),
+            None => self,
+        }
+    }
+
+    /// Configure Builder with Restore Configuration for the VMM.
+    ///
+    /// # Example
+    ///
+    /// You can see example of how to use this function in [`Example` section from
+    /// `build`](#method.build)
+    pub fn restore_config<T>(self, restore: Option<T>) -> Self
+    where
+        RestoreConfig: TryFrom<T>,
+        <RestoreConfig as TryFrom<T>>::Error: Into<ConversionError>,
+    {
+        match restore {
+            Some(r) => self.and_then(|mut config| {
+                config.restore_config = TryFrom::try_from(r).map_err(Into::into)?;
+                Ok(config)
+            }),
+            None => self,
+        }
+    }
+
+    /// Configure Builder with Live Update Configuration for the VMM.
+    ///
+    /// # Example
+    ///
+    /// You can see example of how to use this function in [`Example` section from
+    /// `build`](#method.build)
+    pub fn live_update_config<T>(self, live_update: Option<T>) -> Self
+    where
+        LiveUpdateConfig: TryFrom<T>,
+        <LiveUpdateConfig as TryFrom<T>>::Error: Into<ConversionError>,
+    {
+        match live_update {
+            Some(l) => self.and_then(|mut config| {
+                config.live_update_config = Some(TryFrom::try_from(l).map_err(Into::into)?);
+                Ok(config)
+            }),
+            None => self,
+        }
+    }
+
+    /// Configure Builder with Clone Configuration for the VMM.
+    ///
+    /// Note: a clone is restored from a snapshot, which has to be configured as well.
+    ///
+    /// # Example
+    ///
+    /// You can see example of how to use this function in [`Example` section from
+    /// `build`](#method.build)
+    pub fn clone_config<T>(self, clone: Option<T>) -> Self
+    where
+        CloneConfig: TryFrom<T>,
+        <CloneConfig as TryFrom<T>>::Error: Into<ConversionError>,
+    {
+        match clone {
+            Some(c) => self.and_then(|mut config| {
+                config.clone_config = Some(TryFrom::try_from(c).map_err(Into::into)?);
+                Ok(config)
+            }),
+            None => self,
+        }
+    }
+
     fn and_then<F>(self, func: F) -> Self
     where
         F: FnOnce(VMMConfig) -> Result<VMMConfig, ConversionError>,
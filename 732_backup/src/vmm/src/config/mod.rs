@@ -6,7 +6,9 @@ use std::fmt;
 use std::num;
 use std::path::PathBuf;
 use std::result;
+use std::str::FromStr;
 
+use devices::virtio::net::MacAddr;
 use linux_loader::cmdline::Cmdline;
 
 use arg_parser::CfgArgParser;
@@ -18,6 +20,9 @@ mod arg_parser;
 mod builder;
 
 const KERNEL_CMDLINE_CAPACITY: usize = 4096;
+const DEFAULT_HA_INTERVAL_MS: u64 = 100;
+const DEFAULT_HA_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_MIGRATION_RECONNECT_TIMEOUT_MS: u64 = 60_000;
 
 /// Errors encountered converting the `*Config` objects.
 #[derive(Clone, Debug, PartialEq)]
@@ -32,6 +37,18 @@ pub enum ConversionError {
     ParseNet(String),
     /// Failed to parse the string representation for the block.
     ParseBlock(String),
+    /// Failed to parse the string representation for the migration.
+    ParseMigration(String),
+    /// Failed to parse the string representation for high availability.
+    ParseHa(String),
+    /// Failed to parse the string representation for the restore.
+    ParseRestore(String),
+    /// Failed to parse the string representation for the live update.
+    ParseLiveUpdate(String),
+    /// Failed to parse the string representation for the clone.
+    ParseClone(String),
+    /// Failed to find the snapshot to restore in the catalog.
+    ParseSnapshotId(String),
 }
 
 impl ConversionError {
@@ -50,6 +67,21 @@ impl ConversionError {
     fn new_net<T: fmt::Display>(err: T) -> Self {
         Self::ParseNet(err.to_string())
     }
+    fn new_migration<T: fmt::Display>(err: T) -> Self {
+        Self::ParseMigration(err.to_string())
+    }
+    fn new_ha<T: fmt::Display>(err: T) -> Self {
+        Self::ParseHa(err.to_string())
+    }
+    fn new_restore<T: fmt::Display>(err: T) -> Self {
+        Self::ParseRestore(err.to_string())
+    }
+    fn new_live_update<T: fmt::Display>(err: T) -> Self {
+        Self::ParseLiveUpdate(err.to_string())
+    }
+    fn new_clone<T: fmt::Display>(err: T) -> Self {
+        Self::ParseClone(err.to_string())
+    }
 }
 
 impl VMMConfig {
@@ -68,6 +100,12 @@ impl fmt::Display for ConversionError {
             ParseVcpus(ref s) => write!(f, "Invalid input for vCPUs: {}", s),
             ParseNet(ref s) => write!(f, "Invalid input for network: {}", s),
             ParseBlock(ref s) => write!(f, "Invalid input for block: {}", s),
+            ParseMigration(ref s) => write!(f, "Invalid input for migration: {}", s),
+            ParseHa(ref s) => write!(f, "Invalid input for high availability: {}", s),
+            ParseRestore(ref s) => write!(f, "Invalid input for restore: {}", s),
+            ParseLiveUpdate(ref s) => write!(f, "Invalid input for live update: {}", s),
+            ParseClone(ref s) => write!(f, "Invalid input for clone: {}", s),
+            ParseSnapshotId(ref s) => write!(f, "Invalid input for snapshot id: {}", s),
         }
     }
 }
@@ -77,11 +115,17 @@ impl fmt::Display for ConversionError {
 pub struct MemoryConfig {
     /// Guest memory size in MiB.
     pub size_mib: u32,
+    /// Back the guest memory with a memfd, which allows handing it over to a new VMM process
+    /// on a live update.
+    pub memfd: bool,
 }
 
 impl Default for MemoryConfig {
     fn default() -> Self {
-        MemoryConfig { size_mib: 256u32 }
+        MemoryConfig {
+            size_mib: 256u32,
+            memfd: false,
+        }
     }
 }
 
@@ -89,17 +133,21 @@ impl TryFrom<&str> for MemoryConfig {
     type Error = ConversionError;
 
     fn try_from(mem_cfg_str: &str) -> result::Result<Self, Self::Error> {
-        // Supported options: `size=<u32>`
+        // Supported options: `size=<u32>,memfd=<bool>`
         let mut arg_parser = CfgArgParser::new(mem_cfg_str);
 
         let size_mib = arg_parser
             .value_of("size_mib")
             .map_err(ConversionError::new_memory)?
             .unwrap_or(256);
+        let memfd = arg_parser
+            .value_of("memfd")
+            .map_err(ConversionError::new_memory)?
+            .unwrap_or(false);
         arg_parser
             .all_consumed()
             .map_err(ConversionError::new_memory)?;
-        Ok(MemoryConfig { size_mib })
+        Ok(MemoryConfig { size_mib, memfd })
     }
 }
 
@@ -137,6 +185,8 @@ impl TryFrom<&str> for VcpuConfig {
 pub struct SnapshotConfig {
     pub cpu_snapshot_path: String,
     pub memory_snapshot_path: String,
+    /// Catalog id of the snapshot, when it's restored by id.
+    pub id: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -229,13 +279,15 @@ impl TryFrom<&str> for KernelConfig {
 pub struct NetConfig {
     /// Name of tap device.
     pub tap_name: String,
+    /// MAC address of the guest interface.
+    pub mac: Option<MacAddr>,
 }
 
 impl TryFrom<&str> for NetConfig {
     type Error = ConversionError;
 
     fn try_from(net_config_str: &str) -> Result<Self, Self::Error> {
-        // Supported options: `tap=String`
+        // Supported options: `tap=String,mac=MacAddr`
         let mut arg_parser = CfgArgParser::new(net_config_str);
 
         let tap_name = arg_parser
@@ -243,10 +295,14 @@ impl TryFrom<&str> for NetConfig {
             .map_err(ConversionError::new_net)?
             .ok_or_else(|| ConversionError::new_net("Missing required argument: tap"))?;
 
+        let mac = arg_parser
+            .value_of("mac")
+            .map_err(ConversionError::new_net)?;
+
         arg_parser
             .all_consumed()
             .map_err(ConversionError::new_net)?;
-        Ok(NetConfig { tap_name })
+        Ok(NetConfig { tap_name, mac })
     }
 }
 
@@ -276,6 +332,245 @@ impl TryFrom<&str> for BlockConfig {
     }
 }
 
+/// Live migration configuration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MigrationConfig {
+    /// Also copy the block device backing file, for hosts that don't share storage.
+    pub storage: bool,
+    /// How long the source waits for the destination to come back after losing the connection,
+    /// in milliseconds, before giving up on the migration.
+    pub reconnect_timeout_ms: u64,
+}
+
+impl Default for MigrationConfig {
+    fn default() -> Self {
+        MigrationConfig {
+            storage: false,
+            reconnect_timeout_ms: DEFAULT_MIGRATION_RECONNECT_TIMEOUT_MS,
+        }
+    }
+}
+
+impl TryFrom<&str> for MigrationConfig {
+    type Error = ConversionError;
+
+    fn try_from(migration_cfg_str: &str) -> Result<Self, Self::Error> {
+        // Supported options: `storage=<bool>,reconnect_timeout_ms=<u64>`
+        let mut arg_parser = CfgArgParser::new(migration_cfg_str);
+
+        let storage = arg_parser
+            .value_of("storage")
+            .map_err(ConversionError::new_migration)?
+            .unwrap_or(false);
+
+        let reconnect_timeout_ms = arg_parser
+            .value_of("reconnect_timeout_ms")
+            .map_err(ConversionError::new_migration)?
+            .unwrap_or(DEFAULT_MIGRATION_RECONNECT_TIMEOUT_MS);
+
+        arg_parser
+            .all_consumed()
+            .map_err(ConversionError::new_migration)?;
+        Ok(MigrationConfig {
+            storage,
+            reconnect_timeout_ms,
+        })
+    }
+}
+
+/// Configuration for resuming a guest from a saved state (snapshot, migration or failover).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RestoreConfig {
+    /// Advance the guest clocks by the time elapsed since the state was saved, instead of
+    /// resuming the guest at the time it was stopped.
+    pub clock_correction: bool,
+    /// Keep the vCPUs stopped after restoring a snapshot or a migrated guest, until a resume
+    /// RPC. A paused guest can still be migrated.
+    pub paused: bool,
+    /// Start the guest before its memory is read, loading it from the snapshot as it's touched.
+    pub lazy: bool,
+    /// With `lazy`, also load the rest of the memory in the background.
+    pub prefetch: bool,
+    /// Restore the snapshot from disk, instead of receiving the guest from a live migration.
+    /// Implied by `lazy`.
+    pub from_disk: bool,
+}
+
+impl TryFrom<&str> for RestoreConfig {
+    type Error = ConversionError;
+
+    fn try_from(restore_cfg_str: &str) -> Result<Self, Self::Error> {
+        // Supported options: `clock_correction=<bool>,paused=<bool>,lazy=<bool>,prefetch=<bool>,
+        // from_disk=<bool>`
+        let mut arg_parser = CfgArgParser::new(restore_cfg_str);
+
+        let clock_correction = arg_parser
+            .value_of("clock_correction")
+            .map_err(ConversionError::new_restore)?
+            .unwrap_or(false);
+        let paused = arg_parser
+            .value_of("paused")
+            .map_err(ConversionError::new_restore)?
+            .unwrap_or(false);
+        let lazy = arg_parser
+            .value_of("lazy")
+            .map_err(ConversionError::new_restore)?
+            .unwrap_or(false);
+        let prefetch = arg_parser
+            .value_of("prefetch")
+            .map_err(ConversionError::new_restore)?
+            .unwrap_or(false);
+        let from_disk = arg_parser
+            .value_of("from_disk")
+            .map_err(ConversionError::new_restore)?
+            .unwrap_or(false);
+
+        arg_parser
+            .all_consumed()
+            .map_err(ConversionError::new_restore)?;
+        Ok(RestoreConfig {
+            clock_correction,
+            paused,
+            lazy,
+            prefetch,
+            from_disk: from_disk || lazy,
+        })
+    }
+}
+
+/// Live update configuration, for a VMM taking over the guest of another VMM process on the
+/// same host.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LiveUpdateConfig {
+    /// Unix socket on which to wait for the guest.
+    pub socket: PathBuf,
+}
+
+impl TryFrom<&str> for LiveUpdateConfig {
+    type Error = ConversionError;
+
+    fn try_from(live_update_cfg_str: &str) -> Result<Self, Self::Error> {
+        // Supported options: `socket=<path>`
+        let mut arg_parser = CfgArgParser::new(live_update_cfg_str);
+
+        let socket = arg_parser
+            .value_of("socket")
+            .map_err(ConversionError::new_live_update)?
+            .ok_or_else(|| ConversionError::new_live_update("Missing required argument: socket"))?;
+
+        arg_parser
+            .all_consumed()
+            .map_err(ConversionError::new_live_update)?;
+        Ok(LiveUpdateConfig { socket })
+    }
+}
+
+/// Clone configuration, for a VMM started from a snapshot along with other copies of the same
+/// guest. The clone gets a new generation ID and RNG seed, which the guest reads along with its
+/// MAC address from the VM identity device. Clones share the clean pages of the snapshot through
+/// the page cache, unless it's restored lazily or encrypted, which copies the memory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CloneConfig {
+    /// Index of the clone among the copies of the snapshot.
+    pub id: u32,
+}
+
+impl TryFrom<&str> for CloneConfig {
+    type Error = ConversionError;
+
+    fn try_from(clone_cfg_str: &str) -> Result<Self, Self::Error> {
+        // Supported options: `id=<u32>`
+        let mut arg_parser = CfgArgParser::new(clone_cfg_str);
+
+        let id = arg_parser
+            .value_of("id")
+            .map_err(ConversionError::new_clone)?
+            .ok_or_else(|| ConversionError::new_clone("Missing required argument: id"))?;
+
+        arg_parser
+            .all_consumed()
+            .map_err(ConversionError::new_clone)?;
+        Ok(CloneConfig { id })
+    }
+}
+
+/// Role of the VMM in a high availability pair.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HaRole {
+    /// Runs the guest and replicates its state to the standby.
+    Primary,
+    /// Keeps a copy of the guest state, and runs it once the primary stops responding.
+    Standby,
+}
+
+impl FromStr for HaRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "primary" => Ok(HaRole::Primary),
+            "standby" => Ok(HaRole::Standby),
+            _ => Err(format!("Invalid role: {}", s)),
+        }
+    }
+}
+
+/// High availability (checkpoint replication) configuration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HaConfig {
+    /// Role of this VMM.
+    pub role: HaRole,
+    /// Address of the standby. The primary connects to it, the standby listens on it.
+    pub addr: String,
+    /// Time between two checkpoints, in milliseconds.
+    pub interval_ms: u64,
+    /// Time without checkpoints after which the standby takes over, in milliseconds.
+    pub timeout_ms: u64,
+}
+
+impl TryFrom<&str> for HaConfig {
+    type Error = ConversionError;
+
+    fn try_from(ha_cfg_str: &str) -> Result<Self, Self::Error> {
+        // Supported options:
+        // `role=primary|standby,addr=<host:port>,interval_ms=<u64>,timeout_ms=<u64>`, the
+        // interval and timeout being greater than 0
+        // Required: role, addr
+        let mut arg_parser = CfgArgParser::new(ha_cfg_str);
+
+        let role = arg_parser
+            .value_of("role")
+            .map_err(ConversionError::new_ha)?
+            .ok_or_else(|| ConversionError::new_ha("Missing required argument: role"))?;
+
+        let addr = arg_parser
+            .value_of("addr")
+            .map_err(ConversionError::new_ha)?
+            .ok_or_else(|| ConversionError::new_ha("Missing required argument: addr"))?;
+
+        // A zero timeout is not a valid socket timeout.
+        let interval_ms = arg_parser
+            .value_of("interval_ms")
+            .map_err(ConversionError::new_ha)?
+            .map_or(DEFAULT_HA_INTERVAL_MS, num::NonZeroU64::get);
+
+        let timeout_ms = arg_parser
+            .value_of("timeout_ms")
+            .map_err(ConversionError::new_ha)?
+            .map_or(DEFAULT_HA_TIMEOUT_MS, num::NonZeroU64::get);
+
+        arg_parser
+            .all_consumed()
+            .map_err(ConversionError::new_ha)?;
+        Ok(HaConfig {
+            role,
+            addr,
+            interval_ms,
+            timeout_ms,
+        })
+    }
+}
+
 /// VMM configuration.
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct VMMConfig {
@@ -293,10 +588,63 @@ pub struct VMMConfig {
     pub snapshot_config: Option<SnapshotConfig>,
     /// RPC configuration
     pub rpc_config: Option<RpcConfig>,
+    /// Live migration configuration.
+    pub migration_config: MigrationConfig,
+    /// High availability configuration.
+    pub ha_config: Option<HaConfig>,
+    /// Restore configuration.
+    pub restore_config: RestoreConfig,
+    /// Live update configuration.
+    pub live_update_config: Option<LiveUpdateConfig>,
+    /// Clone configuration.
+    pub clone_config: Option<CloneConfig>,
     
     pub migrating: bool
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ha_config() {
+        let ha_str = "role=primary,addr=10.0.0.2:7000,interval_ms=50";
+        let expected_cfg = HaConfig {
+            role: HaRole::Primary,
+            addr: "10.0.0.2:7000".to_string(),
+            interval_ms: 50,
+            timeout_ms: DEFAULT_HA_TIMEOUT_MS,
+        };
+        assert_eq!(HaConfig::try_from(ha_str).unwrap(), expected_cfg);
+
+        // Test case: missing address.
+        assert!(HaConfig::try_from("role=standby").is_err());
+
+        // Test case: invalid role.
+        assert!(HaConfig::try_from("role=witness,addr=10.0.0.2:7000").is_err());
+
+        // Test case: zero interval or timeout.
+        assert!(matches!(
+            HaConfig::try_from("role=primary,addr=10.0.0.2:7000,interval_ms=0"),
+            Err(ConversionError::ParseHa(_))
+        ));
+        assert!(matches!(
+            HaConfig::try_from("role=standby,addr=10.0.0.2:7000,timeout_ms=0"),
+            Err(ConversionError::ParseHa(_))
+        ));
+    }
+
+    #[test]
+    fn test_restore_config() {
+        assert!(!RestoreConfig::try_from("paused=true").unwrap().from_disk);
+        assert!(RestoreConfig::try_from("from_disk=true").unwrap().from_disk);
+
+        // Test case: only snapshots can be restored lazily.
+        let restore_cfg = RestoreConfig::try_from("lazy=true,prefetch=true").unwrap();
+        assert!(restore_cfg.lazy && restore_cfg.from_disk);
+    }
+}
+
 // #[cfg(test)]
 // mod tests {
 //     use super::*;
@@ -0,0 +1,333 @@
+// Dumps the guest as an ELF core file, which `crash` and `gdb` can open, like QEMU's
+// dump-guest-memory.
+//
+// Layout:
+//
+//   ELF header
+//   program headers  a PT_NOTE covering the notes, then a PT_LOAD for each guest memory region,
+//                    with the guest physical address as both its virtual and physical address
+//   notes            for each vCPU, an NT_PRSTATUS note with its general purpose registers (what
+//                    gdb reads), then a "QEMU" note with its control and segment registers (what
+//                    `crash` needs to find the kernel page tables)
+//   memory           the regions, one after the other as written by `SnapshotMemory::dump`,
+//                    from the first page boundary after the notes
+//
+// Only x86_64 vCPUs get notes, an aarch64 dump has the memory only.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use vm_memory::GuestMemoryMmap;
+use vm_vcpu::vm::VmState;
+
+use crate::dirty_log::PAGE_SIZE;
+use crate::memory_snapshot::{GuestMemoryState, SnapshotMemory};
+use crate::snapshot_file::{Result, SnapshotError};
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+const ET_CORE: u16 = 4;
+#[cfg(target_arch = "x86_64")]
+const EM_MACHINE: u16 = 62;
+#[cfg(target_arch = "aarch64")]
+const EM_MACHINE: u16 = 183;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_RWX: u32 = 7;
+
+#[cfg(target_arch = "x86_64")]
+const NT_PRSTATUS: u32 = 1;
+// Type of the "QEMU" notes.
+#[cfg(target_arch = "x86_64")]
+const NT_QEMU: u32 = 0;
+#[cfg(target_arch = "x86_64")]
+const QEMU_CPU_STATE_VERSION: u32 = 1;
+#[cfg(target_arch = "x86_64")]
+const MSR_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// Writes the guest memory and the vCPUs of `vm_state` as an ELF core file to `writer`.
+pub fn write_core<W: Write>(
+    writer: &mut W,
+    guest_memory: &GuestMemoryMmap,
+    vm_state: &VmState,
+) -> Result<()> {
+    let notes = vcpu_notes(vm_state);
+    let headers = core_headers(&guest_memory.describe(), notes.len());
+    writer.write_all(&headers)?;
+    writer.write_all(&notes)?;
+    let padding = data_offset(headers.len(), notes.len()) as usize - headers.len() - notes.len();
+    writer.write_all(&vec![0u8; padding])?;
+    guest_memory.dump(writer).map_err(SnapshotError::Memory)?;
+    Ok(())
+}
+
+/// Same as `write_core`, to a new file at `path`.
+pub fn write_core_file(
+    path: &str,
+    guest_memory: &GuestMemoryMmap,
+    vm_state: &VmState,
+) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_core(&mut writer, guest_memory, vm_state)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(())
+}
+
+// Offset of the memory in the file, after `headers_len` bytes of headers and `notes_len` bytes
+// of notes.
+fn data_offset(headers_len: usize, notes_len: usize) -> u64 {
+    let end = (headers_len + notes_len) as u64;
+    (end + PAGE_SIZE as u64 - 1) / PAGE_SIZE as u64 * PAGE_SIZE as u64
+}
+
+// ELF header and program headers of a core with the memory regions of `layout`, and
+// `notes_len` bytes of notes.
+fn core_headers(layout: &GuestMemoryState, notes_len: usize) -> Vec<u8> {
+    let phnum = 1 + layout.regions.len();
+    let headers_len = EHDR_SIZE + phnum * PHDR_SIZE;
+    let data_offset = data_offset(headers_len, notes_len);
+
+    let mut buf = Vec::with_capacity(headers_len);
+    // e_ident: magic, 64 bits, little endian, current version, System V ABI.
+    buf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    buf.extend_from_slice(&[0u8; 8]);
+    buf.extend_from_slice(&ET_CORE.to_le_bytes());
+    buf.extend_from_slice(&EM_MACHINE.to_le_bytes());
+    buf.extend_from_slice(&1u32.to_le_bytes()); // e_version
+    buf.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+    buf.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes()); // e_phoff
+    buf.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    buf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    buf.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    buf.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    buf.extend_from_slice(&(phnum as u16).to_le_bytes());
+    buf.extend_from_slice(&[0u8; 6]); // no section headers
+
+    push_phdr(
+        &mut buf,
+        PT_NOTE,
+        0,
+        headers_len as u64,
+        0,
+        notes_len as u64,
+    );
+    for region in layout.regions.iter() {
+        push_phdr(
+            &mut buf,
+            PT_LOAD,
+            PF_RWX,
+            data_offset + region.offset,
+            region.base_address,
+            region.size as u64,
+        );
+    }
+    buf
+}
+
+fn push_phdr(buf: &mut Vec<u8>, kind: u32, flags: u32, offset: u64, addr: u64, size: u64) {
+    buf.extend_from_slice(&kind.to_le_bytes());
+    buf.extend_from_slice(&flags.to_le_bytes());
+    buf.extend_from_slice(&offset.to_le_bytes());
+    buf.extend_from_slice(&addr.to_le_bytes()); // p_vaddr
+    buf.extend_from_slice(&addr.to_le_bytes()); // p_paddr
+    buf.extend_from_slice(&size.to_le_bytes()); // p_filesz
+    buf.extend_from_slice(&size.to_le_bytes()); // p_memsz
+    buf.extend_from_slice(&0u64.to_le_bytes()); // p_align
+}
+
+// An ELF note, with its name and description padded to 4 bytes.
+#[cfg_attr(target_arch = "aarch64", allow(dead_code))]
+fn note(name: &str, kind: u32, desc: &[u8]) -> Vec<u8> {
+    let pad = |len: usize| (len + 3) / 4 * 4;
+    let name_len = name.len() + 1;
+    let mut buf = Vec::with_capacity(12 + pad(name_len) + pad(desc.len()));
+    buf.extend_from_slice(&(name_len as u32).to_le_bytes());
+    buf.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&kind.to_le_bytes());
+    buf.extend_from_slice(name.as_bytes());
+    buf.resize(12 + pad(name_len), 0);
+    buf.extend_from_slice(desc);
+    buf.resize(12 + pad(name_len) + pad(desc.len()), 0);
+    buf
+}
+
+#[cfg(target_arch = "x86_64")]
+fn vcpu_notes(vm_state: &VmState) -> Vec<u8> {
+    let mut notes = vec![];
+    for (index, vcpu) in vm_state.vcpus_state.iter().enumerate() {
+        notes.extend(note("CORE", NT_PRSTATUS, &prstatus(index, vcpu)));
+        notes.extend(note("QEMU", NT_QEMU, &qemu_cpu_state(vcpu)));
+    }
+    notes
+}
+
+#[cfg(target_arch = "aarch64")]
+fn vcpu_notes(_vm_state: &VmState) -> Vec<u8> {
+    vec![]
+}
+
+// `struct elf_prstatus` of x86_64, with only the pid (the vCPU number, from 1) and the registers
+// filled in.
+#[cfg(target_arch = "x86_64")]
+fn prstatus(index: usize, vcpu: &vm_vcpu::vcpu::VcpuState) -> Vec<u8> {
+    let r = &vcpu.regs;
+    let s = &vcpu.sregs;
+    let mut buf = vec![0u8; 32];
+    buf.extend_from_slice(&(index as i32 + 1).to_le_bytes()); // pr_pid
+    buf.resize(112, 0);
+    // `struct user_regs_struct`.
+    for reg in [
+        r.r15,
+        r.r14,
+        r.r13,
+        r.r12,
+        r.rbp,
+        r.rbx,
+        r.r11,
+        r.r10,
+        r.r9,
+        r.r8,
+        r.rax,
+        r.rcx,
+        r.rdx,
+        r.rsi,
+        r.rdi,
+        0, // orig_rax
+        r.rip,
+        s.cs.selector as u64,
+        r.rflags,
+        r.rsp,
+        s.ss.selector as u64,
+        s.fs.base,
+        s.gs.base,
+        s.ds.selector as u64,
+        s.es.selector as u64,
+        s.fs.selector as u64,
+        s.gs.selector as u64,
+    ]
+    .iter()
+    {
+        buf.extend_from_slice(&reg.to_le_bytes());
+    }
+    // pr_fpvalid and padding.
+    buf.resize(336, 0);
+    buf
+}
+
+// `QEMUCPUState`, as read by `crash`.
+#[cfg(target_arch = "x86_64")]
+fn qemu_cpu_state(vcpu: &vm_vcpu::vcpu::VcpuState) -> Vec<u8> {
+    let r = &vcpu.regs;
+    let s = &vcpu.sregs;
+    let mut buf = vec![];
+    buf.extend_from_slice(&QEMU_CPU_STATE_VERSION.to_le_bytes());
+    // Size, filled in below.
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    for reg in [
+        r.rax, r.rbx, r.rcx, r.rdx, r.rsi, r.rdi, r.rsp, r.rbp, r.r8, r.r9, r.r10, r.r11, r.r12,
+        r.r13, r.r14, r.r15, r.rip, r.rflags,
+    ]
+    .iter()
+    {
+        buf.extend_from_slice(&reg.to_le_bytes());
+    }
+    for segment in [&s.cs, &s.ds, &s.es, &s.fs, &s.gs, &s.ss, &s.ldt, &s.tr].iter() {
+        // Flags as in the high dword of the descriptor.
+        let flags = (segment.type_ as u32) << 8
+            | (segment.s as u32) << 12
+            | (segment.dpl as u32) << 13
+            | (segment.present as u32) << 15
+            | (segment.avl as u32) << 20
+            | (segment.l as u32) << 21
+            | (segment.db as u32) << 22
+            | (segment.g as u32) << 23;
+        buf.extend_from_slice(&(segment.selector as u32).to_le_bytes());
+        buf.extend_from_slice(&segment.limit.to_le_bytes());
+        buf.extend_from_slice(&flags.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&segment.base.to_le_bytes());
+    }
+    for table in [&s.gdt, &s.idt].iter() {
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&(table.limit as u32).to_le_bytes());
+        buf.extend_from_slice(&[0u8; 8]);
+        buf.extend_from_slice(&table.base.to_le_bytes());
+    }
+    // CR1 doesn't exist, and is always 0.
+    for cr in [s.cr0, 0, s.cr2, s.cr3, s.cr4].iter() {
+        buf.extend_from_slice(&cr.to_le_bytes());
+    }
+    let kernel_gs_base = vcpu
+        .msrs
+        .as_slice()
+        .iter()
+        .find(|entry| entry.index == MSR_KERNEL_GS_BASE)
+        .map_or(0, |entry| entry.data);
+    buf.extend_from_slice(&kernel_gs_base.to_le_bytes());
+
+    let size = buf.len() as u32;
+    buf[4..8].copy_from_slice(&size.to_le_bytes());
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryInto;
+
+    use crate::memory_snapshot::GuestMemoryRegionState;
+
+    fn u64_at(buf: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_note() {
+        let note = note("CORE", 1, &[1, 2, 3, 4, 5]);
+        assert_eq!(note.len(), 12 + 8 + 8);
+        assert_eq!(&note[0..12], &[5, 0, 0, 0, 5, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(&note[12..20], b"CORE\0\0\0\0");
+        assert_eq!(&note[20..28], &[1, 2, 3, 4, 5, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_core_headers() {
+        let layout = GuestMemoryState {
+            regions: vec![
+                GuestMemoryRegionState {
+                    base_address: 0,
+                    size: 0x3000,
+                    offset: 0,
+                },
+                GuestMemoryRegionState {
+                    base_address: 1 << 32,
+                    size: 0x1000,
+                    offset: 0x3000,
+                },
+            ],
+        };
+        let headers = core_headers(&layout, 100);
+        assert_eq!(headers.len(), EHDR_SIZE + 3 * PHDR_SIZE);
+        assert_eq!(&headers[0..4], b"\x7fELF");
+        assert_eq!(u16::from_le_bytes([headers[16], headers[17]]), ET_CORE);
+        assert_eq!(u16::from_le_bytes([headers[56], headers[57]]), 3);
+
+        // The notes right after the headers.
+        let note = EHDR_SIZE;
+        assert_eq!(&headers[note..note + 4], &PT_NOTE.to_le_bytes());
+        assert_eq!(u64_at(&headers, note + 8), headers.len() as u64);
+        assert_eq!(u64_at(&headers, note + 32), 100);
+
+        // The memory on the next page, at its guest address.
+        let load = EHDR_SIZE + 2 * PHDR_SIZE;
+        assert_eq!(&headers[load..load + 4], &PT_LOAD.to_le_bytes());
+        assert_eq!(u64_at(&headers, load + 8), 0x1000 + 0x3000);
+        assert_eq!(u64_at(&headers, load + 16), 1 << 32);
+        assert_eq!(u64_at(&headers, load + 24), 1 << 32);
+        assert_eq!(u64_at(&headers, load + 32), 0x1000);
+    }
+}
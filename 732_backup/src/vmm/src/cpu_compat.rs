@@ -0,0 +1,335 @@
+// Checks that the destination host of a live migration can run the vCPUs of the source.
+//
+// The destination restores the `CpuId` and `Msrs` saved on the source as they are, so a feature
+// its KVM doesn't support only shows up once the source is already stopped: either restoring the
+// vCPUs fails, or the guest crashes the first time it uses the feature. Instead, the source sends
+// its `CpuProfile` right after the migration handshake, and the destination answers with every
+// feature it's missing. The migration only goes on when that list is empty.
+//
+// Only the CPUID registers holding feature flags are compared, the others (family, cache and
+// topology descriptions, ...) are free to differ between hosts.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{CpuId, Msrs};
+#[cfg(target_arch = "x86_64")]
+use kvm_ioctls::Kvm;
+
+#[cfg(target_arch = "x86_64")]
+use crate::{Error, Result};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct CpuidLeaf {
+    pub function: u32,
+    pub index: u32,
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Reg {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Reg::Eax => "EAX",
+            Reg::Ebx => "EBX",
+            Reg::Ecx => "ECX",
+            Reg::Edx => "EDX",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl CpuidLeaf {
+    fn reg(&self, reg: Reg) -> u32 {
+        match reg {
+            Reg::Eax => self.eax,
+            Reg::Ebx => self.ebx,
+            Reg::Ecx => self.ecx,
+            Reg::Edx => self.edx,
+        }
+    }
+}
+
+// CPUID registers holding feature flags, as (function, index, register, ignored bits). The
+// ignored bits are the ones `filter_cpuid` sets on top of what KVM reports as supported, and the
+// ones KVM derives from what the guest OS enabled in CR4 (they show up in the CPUID of a booted
+// vCPU). Neither ever shows up in the supported CPUID of the destination.
+const FEATURE_REGS: &[(u32, u32, Reg, u32)] = &[
+    // Hypervisor (31), OSXSAVE (27) and TSC deadline timer (24).
+    (0x1, 0, Reg::Ecx, (1 << 31) | (1 << 27) | (1 << 24)),
+    // Hyper threading (28).
+    (0x1, 0, Reg::Edx, 1 << 28),
+    (0x7, 0, Reg::Ebx, 0),
+    // OSPKE (4).
+    (0x7, 0, Reg::Ecx, 1 << 4),
+    (0x7, 0, Reg::Edx, 0),
+    // XSAVE state components.
+    (0xd, 0, Reg::Eax, 0),
+    (0xd, 1, Reg::Eax, 0),
+    // KVM paravirtual features.
+    (0x4000_0001, 0, Reg::Eax, 0),
+    (0x8000_0001, 0, Reg::Ecx, 0),
+    (0x8000_0001, 0, Reg::Edx, 0),
+];
+
+// Names of the most common features, the others are reported by their CPUID position only.
+fn feature_name(function: u32, index: u32, reg: Reg, bit: u32) -> Option<&'static str> {
+    let name = match (function, index, reg, bit) {
+        (0x1, 0, Reg::Ecx, 0) => "sse3",
+        (0x1, 0, Reg::Ecx, 1) => "pclmulqdq",
+        (0x1, 0, Reg::Ecx, 9) => "ssse3",
+        (0x1, 0, Reg::Ecx, 12) => "fma",
+        (0x1, 0, Reg::Ecx, 13) => "cx16",
+        (0x1, 0, Reg::Ecx, 19) => "sse4.1",
+        (0x1, 0, Reg::Ecx, 20) => "sse4.2",
+        (0x1, 0, Reg::Ecx, 21) => "x2apic",
+        (0x1, 0, Reg::Ecx, 22) => "movbe",
+        (0x1, 0, Reg::Ecx, 23) => "popcnt",
+        (0x1, 0, Reg::Ecx, 25) => "aes",
+        (0x1, 0, Reg::Ecx, 26) => "xsave",
+        (0x1, 0, Reg::Ecx, 28) => "avx",
+        (0x1, 0, Reg::Ecx, 29) => "f16c",
+        (0x1, 0, Reg::Ecx, 30) => "rdrand",
+        (0x1, 0, Reg::Edx, 4) => "tsc",
+        (0x1, 0, Reg::Edx, 5) => "msr",
+        (0x1, 0, Reg::Edx, 9) => "apic",
+        (0x1, 0, Reg::Edx, 25) => "sse",
+        (0x1, 0, Reg::Edx, 26) => "sse2",
+        (0x7, 0, Reg::Ebx, 0) => "fsgsbase",
+        (0x7, 0, Reg::Ebx, 3) => "bmi1",
+        (0x7, 0, Reg::Ebx, 4) => "hle",
+        (0x7, 0, Reg::Ebx, 5) => "avx2",
+        (0x7, 0, Reg::Ebx, 7) => "smep",
+        (0x7, 0, Reg::Ebx, 8) => "bmi2",
+        (0x7, 0, Reg::Ebx, 9) => "erms",
+        (0x7, 0, Reg::Ebx, 10) => "invpcid",
+        (0x7, 0, Reg::Ebx, 11) => "rtm",
+        (0x7, 0, Reg::Ebx, 16) => "avx512f",
+        (0x7, 0, Reg::Ebx, 18) => "rdseed",
+        (0x7, 0, Reg::Ebx, 19) => "adx",
+        (0x7, 0, Reg::Ebx, 20) => "smap",
+        (0x7, 0, Reg::Ebx, 29) => "sha",
+        (0x7, 0, Reg::Ecx, 1) => "avx512vbmi",
+        (0x7, 0, Reg::Ecx, 2) => "umip",
+        (0x7, 0, Reg::Ecx, 3) => "pku",
+        (0x7, 0, Reg::Ecx, 8) => "gfni",
+        (0x7, 0, Reg::Ecx, 9) => "vaes",
+        (0x7, 0, Reg::Ecx, 22) => "rdpid",
+        (0x7, 0, Reg::Edx, 10) => "md-clear",
+        (0x7, 0, Reg::Edx, 26) => "ibrs",
+        (0x7, 0, Reg::Edx, 31) => "ssbd",
+        (0xd, 0, Reg::Eax, 2) => "xsave-avx",
+        (0xd, 0, Reg::Eax, 5) => "xsave-avx512-opmask",
+        (0xd, 0, Reg::Eax, 9) => "xsave-pkru",
+        (0xd, 1, Reg::Eax, 0) => "xsaveopt",
+        (0xd, 1, Reg::Eax, 1) => "xsavec",
+        (0xd, 1, Reg::Eax, 3) => "xsaves",
+        (0x4000_0001, 0, Reg::Eax, 3) => "kvmclock",
+        (0x8000_0001, 0, Reg::Ecx, 0) => "lahf-lm",
+        (0x8000_0001, 0, Reg::Ecx, 5) => "abm",
+        (0x8000_0001, 0, Reg::Ecx, 8) => "prefetchw",
+        (0x8000_0001, 0, Reg::Edx, 11) => "syscall",
+        (0x8000_0001, 0, Reg::Edx, 20) => "nx",
+        (0x8000_0001, 0, Reg::Edx, 26) => "pdpe1gb",
+        (0x8000_0001, 0, Reg::Edx, 27) => "rdtscp",
+        (0x8000_0001, 0, Reg::Edx, 29) => "lm",
+        _ => return None,
+    };
+    Some(name)
+}
+
+fn vendor(leaf: Option<&CpuidLeaf>) -> String {
+    let leaf = match leaf {
+        Some(leaf) => leaf,
+        None => return "unknown".to_string(),
+    };
+    let mut bytes = vec![];
+    for reg in [leaf.ebx, leaf.edx, leaf.ecx].iter() {
+        bytes.extend_from_slice(&reg.to_le_bytes());
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// The CPUID leaves and MSRs a vCPU is configured with, or a host supports.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CpuProfile {
+    pub cpuid: Vec<CpuidLeaf>,
+    pub msrs: Vec<u32>,
+}
+
+impl CpuProfile {
+    #[cfg(target_arch = "x86_64")]
+    pub fn new(cpuid: &CpuId, msrs: &Msrs) -> Self {
+        CpuProfile {
+            cpuid: cpuid
+                .as_slice()
+                .iter()
+                .map(|entry| CpuidLeaf {
+                    function: entry.function,
+                    index: entry.index,
+                    eax: entry.eax,
+                    ebx: entry.ebx,
+                    ecx: entry.ecx,
+                    edx: entry.edx,
+                })
+                .collect(),
+            msrs: msrs.as_slice().iter().map(|entry| entry.index).collect(),
+        }
+    }
+
+    /// What the KVM of this host can offer to a vCPU.
+    #[cfg(target_arch = "x86_64")]
+    pub fn host(kvm: &Kvm) -> Result<Self> {
+        let cpuid = kvm
+            .get_supported_cpuid(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
+            .map_err(Error::KvmIoctl)?;
+        let msrs =
+            vm_vcpu_ref::x86_64::msrs::supported_guest_msrs(kvm).map_err(Error::GetSupportedMsrs)?;
+        Ok(Self::new(&cpuid, &msrs))
+    }
+
+    fn leaf(&self, function: u32, index: u32) -> Option<&CpuidLeaf> {
+        self.cpuid
+            .iter()
+            .find(|leaf| leaf.function == function && leaf.index == index)
+    }
+
+    /// Lists every feature used by this (source) profile that `host` doesn't support. An empty
+    /// list means the vCPUs can be restored on `host`.
+    pub fn missing_features(&self, host: &CpuProfile) -> Vec<String> {
+        let mut missing = vec![];
+
+        // Guests are not prepared to switch between Intel and AMD specific instructions.
+        let src_vendor = vendor(self.leaf(0, 0));
+        let host_vendor = vendor(host.leaf(0, 0));
+        if self.leaf(0, 0).is_some() && src_vendor != host_vendor {
+            missing.push(format!("CPU vendor {} (host is {})", src_vendor, host_vendor));
+        }
+
+        for &(function, index, reg, ignored) in FEATURE_REGS {
+            let used = match self.leaf(function, index) {
+                Some(leaf) => leaf.reg(reg) & !ignored,
+                None => continue,
+            };
+            let supported = host.leaf(function, index).map_or(0, |leaf| leaf.reg(reg));
+            let unsupported = used & !supported;
+            for bit in (0..32).filter(|bit| unsupported & (1 << bit) != 0) {
+                let position = format!(
+                    "CPUID.(EAX={:#x},ECX={}):{}[{}]",
+                    function, index, reg, bit
+                );
+                missing.push(match feature_name(function, index, reg, bit) {
+                    Some(name) => format!("{} ({})", name, position),
+                    None => position,
+                });
+            }
+        }
+
+        for msr in self.msrs.iter().filter(|msr| !host.msrs.contains(msr)) {
+            missing.push(format!("MSR {:#x}", msr));
+        }
+
+        missing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(function: u32, index: u32, eax: u32, ebx: u32, ecx: u32, edx: u32) -> CpuidLeaf {
+        CpuidLeaf {
+            function,
+            index,
+            eax,
+            ebx,
+            ecx,
+            edx,
+        }
+    }
+
+    // "GenuineIntel", split across ebx, edx and ecx.
+    fn intel() -> CpuidLeaf {
+        leaf(0, 0, 0xd, 0x756e_6547, 0x6c65_746e, 0x4965_6e69)
+    }
+
+    fn host() -> CpuProfile {
+        CpuProfile {
+            cpuid: vec![
+                intel(),
+                leaf(0x1, 0, 0x906ea, 0, 0x1000_0000, 0x0600_0000),
+                leaf(0x7, 0, 0, 1 << 5, 0, 0),
+            ],
+            msrs: vec![0x10, 0x174],
+        }
+    }
+
+    #[test]
+    fn test_compatible() {
+        let mut src = host();
+        // Family/model and the bits set by `filter_cpuid` don't matter.
+        src.cpuid[1].eax = 0x306c3;
+        src.cpuid[1].ecx |= (1 << 31) | (1 << 24);
+        src.cpuid[1].edx |= 1 << 28;
+        // Using less than what the host offers is fine.
+        src.cpuid[2].ebx = 0;
+        src.msrs = vec![0x174];
+
+        assert!(src.missing_features(&host()).is_empty());
+        assert!(CpuProfile::default().missing_features(&host()).is_empty());
+    }
+
+    #[test]
+    fn test_os_enabled_features() {
+        // A booted guest enabled XSAVE and protection keys in CR4, which KVM reflects in the
+        // CPUID of its vCPUs but never reports as supported.
+        let mut src = host();
+        src.cpuid[1].ecx |= 1 << 27;
+        src.cpuid[2].ecx |= 1 << 4;
+
+        assert!(src.missing_features(&host()).is_empty());
+    }
+
+    #[test]
+    fn test_missing_features() {
+        let mut src = host();
+        src.cpuid[1].ecx |= 1 << 3;
+        src.cpuid[2].ebx |= 1 << 16;
+        src.cpuid.push(leaf(0x8000_0001, 0, 0, 0, 0, 1 << 29));
+        src.msrs.push(0x4b56_4d00);
+
+        assert_eq!(
+            src.missing_features(&host()),
+            vec![
+                "CPUID.(EAX=0x1,ECX=0):ECX[3]".to_string(),
+                "avx512f (CPUID.(EAX=0x7,ECX=0):EBX[16])".to_string(),
+                "lm (CPUID.(EAX=0x80000001,ECX=0):EDX[29])".to_string(),
+                "MSR 0x4b564d00".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vendor_mismatch() {
+        let mut src = host();
+        // "AuthenticAMD".
+        src.cpuid[0] = leaf(0, 0, 0xd, 0x6874_7541, 0x444d_4163, 0x6974_6e65);
+
+        assert_eq!(
+            src.missing_features(&host()),
+            vec!["CPU vendor AuthenticAMD (host is GenuineIntel)".to_string()]
+        );
+    }
+}
@@ -2,16 +2,18 @@
 // and compression
 
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::{File, OpenOptions};
 use std::hash::{Hash, Hasher};
-use std::io::{Read, Write};
-use std::path::Path;
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::fs;
 // define constant
 
 
 
+#[derive(Clone)]
 pub struct DedupManager {
     pub CHUNK_SIZE: usize,
     pub DATABASE_PATH: String,
@@ -19,17 +21,64 @@ pub struct DedupManager {
     pub MAP1_PATH: String,
     pub MAP2_PATH: String
 }
-//get hash of an vector (input type is Vec<u8>)
-fn get_hash(input: &Vec<u8>) -> u64{
-    // let mut hasher = Sha256::new();
-    let mut hasher = DefaultHasher::new();
-    // hasher.update(input);
-    // hasher.finalize() 
-    input.hash(&mut hasher);
-    let hash = hasher.finish();
-    hash
+//get hash of a chunk, also used to tell which chunks a migration destination already has.
+// SHA-256, as hex, so that no two chunks are ever taken for one another
+pub fn get_hash(input: &[u8]) -> String {
+    sha256::digest(input)
 }
 
+// stores written before chunks were hashed with SHA-256 keyed them by a 64-bit hash
+fn is_legacy_hash(hash: &str) -> bool {
+    hash.parse::<u64>().is_ok()
+}
+
+// whether `chunk` is the one stored under `hash`, whichever way it was hashed
+pub fn chunk_matches(hash: &str, chunk: &[u8]) -> bool {
+    if is_legacy_hash(hash) {
+        let mut hasher = DefaultHasher::new();
+        chunk.hash(&mut hasher);
+        hasher.finish().to_string() == hash
+    } else {
+        get_hash(chunk) == hash
+    }
+}
+
+
+// lock file of the database directory, held by whoever changes the dedup store or the snapshot
+// catalog (see `catalog`), which go together when a snapshot is deleted. it's named after the
+// catalog, which had it first
+pub(crate) const LOCK_FILE: &str = "catalog.json.lock";
+
+// locks the database directory `dir` until the returned file is dropped
+pub(crate) fn lock_database(dir: &Path) -> io::Result<File> {
+    fs::create_dir_all(dir)?;
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(LOCK_FILE))?;
+    // Safe because the descriptor stays open for as long as `file` lives.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+// Replaces the content of the map at `path`, which is either entirely old or entirely new after a
+// crash.
+fn replace_map(path: &str, content: &str) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    // The rename itself only lasts once the directory is synced.
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
 
 // Check if the map exists and if it does load it
 fn checkifexist(file_path: &str)
@@ -123,8 +172,8 @@ impl DedupManager {
     fn get_hashes
     // return vector of tupe {chunk, hash} from vector of bytes
     (&self, input: &Vec<u8>) -> 
-    Vec<(Vec<u8>, u64)> {
-        let mut hashes: Vec<(Vec<u8>, u64)> = Vec::new();
+    Vec<(Vec<u8>, String)> {
+        let mut hashes: Vec<(Vec<u8>, String)> = Vec::new();
         let mut start = 0;
         let mut end = self.CHUNK_SIZE;
         while start < input.len() {
@@ -141,8 +190,13 @@ impl DedupManager {
         hashes
     }
 
+    fn lock(&self) -> io::Result<File> {
+        lock_database(Path::new(&self.DATABASE_PATH))
+    }
+
     // saves the entire file to the database
     pub fn save_file(&self, path: &str) {
+        let _lock = self.lock().unwrap();
         self.setup();
         // let mut file = File::open(path).unwrap();
         // read file from path
@@ -168,7 +222,7 @@ impl DedupManager {
         file.write_all(path.as_bytes()).unwrap();
         file.write_all(b",").unwrap();
         for hash in &hashes {
-            file.write_all(hash.1.to_string().as_bytes()).unwrap();
+            file.write_all(hash.1.as_bytes()).unwrap();
             // file.write_all(hash.to_string().as_bytes()).unwrap();
             file.write_all(b",").unwrap();
         }
@@ -185,10 +239,10 @@ impl DedupManager {
         // save only chunks which are not in disk
 
         for chunk_hash in &hashes{
-            if !hashtochunk.contains_key(&chunk_hash.1.to_string()) {
+            if !hashtochunk.contains_key(&chunk_hash.1) {
                 let mut file = File::create(format!("{}chunks/{}.chunk",self.DATABASE_PATH, i)).unwrap();
                 file.write_all(&chunk_hash.0).unwrap();
-                hashtochunk.insert(chunk_hash.1.to_string(), vec![format!("{}.chunk", i)]);
+                hashtochunk.insert(chunk_hash.1.clone(), vec![format!("{}.chunk", i)]);
                 // write to map1.txt
                 let mut file = OpenOptions::new()
                     .write(true)
@@ -196,7 +250,7 @@ impl DedupManager {
                     .create(true)
                     .open(&self.MAP1_PATH[..])
                     .unwrap();
-                file.write_all(chunk_hash.1.to_string().as_bytes()).unwrap();
+                file.write_all(chunk_hash.1.as_bytes()).unwrap();
                 file.write_all(b",").unwrap();
                 file.write_all(format!("{}.chunk", i).as_bytes()).unwrap();
                 file.write_all(b"\n").unwrap();
@@ -210,7 +264,99 @@ impl DedupManager {
 
     }
 
+    // returns the chunks in the database, by hash. Those of a legacy store are left out, their
+    // hash is too weak to stand for them
+    pub fn chunk_files(&self) -> HashMap<String, PathBuf> {
+        let mut chunks = HashMap::new();
+        if !Path::new(&self.MAP1_PATH).exists() {
+            return chunks;
+        }
+        for (hash, files) in checkifexist(&self.MAP1_PATH) {
+            if let (false, Some(file)) = (is_legacy_hash(&hash), files.get(0)) {
+                chunks.insert(hash, Path::new(&self.DATABASE_PATH).join("chunks").join(file));
+            }
+        }
+        chunks
+    }
+
+    // returns the hash and location of each chunk of a saved file, in order, so that it
+    // can be rebuilt piece by piece. None if the file was never saved
+    pub fn file_chunks(&self, path: &str) -> Option<Vec<(String, PathBuf)>> {
+        if !Path::new(&self.MAP1_PATH).exists() || !Path::new(&self.MAP2_PATH).exists() {
+            return None;
+        }
+        let hashtochunk = checkifexist(&self.MAP1_PATH);
+        let filetohashes = checkifexist(&self.MAP2_PATH);
+        let mut chunks = Vec::new();
+        for hash in filetohashes.get(path)? {
+            let file = hashtochunk.get(hash)?.get(0)?;
+            chunks.push((
+                hash.clone(),
+                Path::new(&self.DATABASE_PATH).join("chunks").join(file),
+            ));
+        }
+        Some(chunks)
+    }
+
+    // forgets a saved file, and deletes the chunks no other file uses
+    pub fn remove_file(&self, path: &str) -> io::Result<()> {
+        let _lock = self.lock()?;
+        self.remove_file_locked(path)
+    }
+
+    // like `remove_file`, for a caller holding `lock_database` already
+    pub(crate) fn remove_file_locked(&self, path: &str) -> io::Result<()> {
+        if !Path::new(&self.MAP1_PATH).exists() || !Path::new(&self.MAP2_PATH).exists() {
+            return Ok(());
+        }
+        let mut removed = false;
+        let mut kept = String::new();
+        let mut used_hashes = HashSet::new();
+        for line in fs::read_to_string(&self.MAP2_PATH)?.lines() {
+            let mut fields = line.split(',');
+            if fields.next() == Some(path) {
+                removed = true;
+                continue;
+            }
+            used_hashes.extend(
+                fields
+                    .filter(|hash| !hash.is_empty())
+                    .map(|hash| hash.to_string()),
+            );
+            kept.push_str(line);
+            kept.push('\n');
+        }
+        if !removed {
+            return Ok(());
+        }
+        replace_map(&self.MAP2_PATH, &kept)?;
+
+        // The chunks are only deleted once no map refers to them anymore.
+        let mut kept = String::new();
+        let mut unused_chunks = vec![];
+        for line in fs::read_to_string(&self.MAP1_PATH)?.lines() {
+            let mut fields = line.split(',');
+            let hash = fields.next().unwrap_or("");
+            if used_hashes.contains(hash) {
+                kept.push_str(line);
+                kept.push('\n');
+            } else if let Some(chunk) = fields.next() {
+                unused_chunks.push(Path::new(&self.DATABASE_PATH).join("chunks").join(chunk));
+            }
+        }
+        replace_map(&self.MAP1_PATH, &kept)?;
+        for chunk_path in unused_chunks {
+            if let Err(e) = fs::remove_file(chunk_path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn load_file(&self, path: &str) {
+        let _lock = self.lock().unwrap();
         let mut hashtochunk= checkifexist(&self.MAP1_PATH);
         let mut filetohashes = checkifexist(&self.MAP2_PATH);
         // println!("{:?}", hashtochunk);
@@ -247,6 +393,21 @@ impl DedupManager {
 
 }
 
+#[cfg(test)]
+impl DedupManager {
+    // A database in the `database` directory under `dir`, with small chunks.
+    pub(crate) fn in_dir(dir: &Path) -> Self {
+        let database = format!("{}/database/", dir.display());
+        DedupManager {
+            CHUNK_SIZE: 4096,
+            STATE_PATH: format!("{}state", database),
+            MAP1_PATH: format!("{}map1", database),
+            MAP2_PATH: format!("{}map2", database),
+            DATABASE_PATH: database,
+        }
+    }
+}
+
 // fn main() {
 //     println!("Hello, world!");
 
@@ -0,0 +1,316 @@
+// Helpers around the KVM dirty page log, shared by live migration, diff snapshots and the dirty
+// rate measurement RPC.
+//
+// Reading the log through `KVM_GET_DIRTY_LOG` also clears it, so there can only be one consumer
+// at a time. Whoever reads it (the measurement, a migration, the HA replication or a snapshot)
+// first takes a `DirtyLogOwner`, and holds it for as long as it uses the log. The measurement
+// also counts its reads, so the next diff snapshot knows the log misses pages.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use kvm_ioctls::VmFd;
+use serde::{Deserialize, Serialize};
+use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+use crate::migration::simulator::RecordedTrace;
+use crate::{Error, Result};
+
+pub const PAGE_SIZE: usize = 4096;
+
+// Bounds of a dirty rate measurement. The pages dirtied in every window are kept until the end,
+// and nobody else can use the dirty log in the meantime (i.e. migrate or snapshot the guest).
+pub const MAX_DIRTY_RATE_WINDOWS: u32 = 100;
+pub const MAX_DIRTY_RATE_DURATION: Duration = Duration::from_secs(300);
+
+// Converts a KVM dirty bitmap (one bit per page, 64 pages per word) into the list of dirty
+// page numbers, in ascending order.
+pub fn dirty_pages_from_bitmap(bitmap: &[u64]) -> Vec<usize> {
+    let mut dirty_pages = vec![];
+    for (word_idx, word) in bitmap.iter().enumerate() {
+        for bit in 0..64 {
+            if (word & (1 << bit)) != 0 {
+                dirty_pages.push(word_idx * 64 + bit);
+            }
+        }
+    }
+    dirty_pages
+}
+
+// Fetches (and clears) the dirty log of the whole guest memory. KVM has a memory slot for each
+// region of `guest_memory`, in order, and the pages are numbered by guest address, so those past
+// the MMIO gap keep their place.
+pub fn read_dirty_log(
+    vm_fd: &VmFd,
+    guest_memory: &GuestMemoryMmap,
+) -> std::result::Result<Vec<usize>, kvm_ioctls::Error> {
+    let mut dirty_pages = vec![];
+    for (slot, region) in guest_memory.iter().enumerate() {
+        let bitmap = vm_fd.get_dirty_log(slot as u32, region.len() as usize)?;
+        let first_page = region.start_addr().raw_value() as usize / PAGE_SIZE;
+        dirty_pages.extend(
+            dirty_pages_from_bitmap(&bitmap)
+                .into_iter()
+                .map(|page| first_page + page),
+        );
+    }
+    Ok(dirty_pages)
+}
+
+/// Exclusive use of the dirty log, given back when dropped.
+pub struct DirtyLogOwner {
+    in_use: Arc<AtomicBool>,
+}
+
+impl DirtyLogOwner {
+    /// Takes the dirty log, unless someone else already has it.
+    pub fn take(in_use: &Arc<AtomicBool>) -> Option<Self> {
+        in_use
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .ok()
+            .map(|_| DirtyLogOwner {
+                in_use: in_use.clone(),
+            })
+    }
+}
+
+impl Drop for DirtyLogOwner {
+    fn drop(&mut self) {
+        self.in_use.store(false, Ordering::Release);
+    }
+}
+
+pub fn collect_dirty_pages(vm_fd: &VmFd, guest_memory: &GuestMemoryMmap) -> Result<Vec<usize>> {
+    read_dirty_log(vm_fd, guest_memory).map_err(Error::KvmIoctl)
+}
+
+// Every page of `guest_memory`, skipping the holes between its regions.
+pub fn guest_pages(guest_memory: &GuestMemoryMmap) -> Vec<usize> {
+    let mut pages = vec![];
+    for region in guest_memory.iter() {
+        let first_page = region.start_addr().raw_value() as usize / PAGE_SIZE;
+        pages.extend(first_page..first_page + region.len() as usize / PAGE_SIZE);
+    }
+    pages
+}
+
+// Returns the pages written by the VMM itself (i.e. by the emulated devices), which don't show
+// up in the KVM log, and clears their record. Only accurate when no device is running
+// concurrently, since a write landing between the scan and the reset would be lost.
+pub fn collect_vmm_dirty_pages(guest_memory: &GuestMemoryMmap) -> Vec<usize> {
+    let mut dirty_pages = vec![];
+    for region in guest_memory.iter() {
+        if let Some(bitmap) = region.bitmap() {
+            let first_page = region.start_addr().raw_value() as usize / PAGE_SIZE;
+            for page in 0..region.len() as usize / PAGE_SIZE {
+                if bitmap.is_addr_set(page * PAGE_SIZE) {
+                    dirty_pages.push(first_page + page);
+                }
+            }
+            bitmap.reset();
+        }
+    }
+    dirty_pages
+}
+
+// Dirty pages observed over a single sampling window.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DirtyRateSample {
+    pub dirty_pages: usize,
+    pub pages_per_sec: f64,
+    pub mib_per_sec: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DirtyRateReport {
+    pub window_ms: u64,
+    pub samples: Vec<DirtyRateSample>,
+    pub avg_pages_per_sec: f64,
+    pub avg_mib_per_sec: f64,
+    // Number of distinct pages written over all the windows, which approximates the writable
+    // working set of the guest when sampling for long enough.
+    pub working_set_pages: usize,
+    pub working_set_mib: f64,
+}
+
+impl DirtyRateReport {
+    // Builds the report out of the dirty pages seen in each window, and how long each window
+    // actually lasted.
+    pub fn new(window_ms: u64, windows: &[(Vec<usize>, Duration)]) -> Self {
+        let mut working_set = HashSet::new();
+        let mut samples = Vec::with_capacity(windows.len());
+
+        for (pages, elapsed) in windows {
+            working_set.extend(pages.iter().copied());
+            let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+            let pages_per_sec = pages.len() as f64 / secs;
+            samples.push(DirtyRateSample {
+                dirty_pages: pages.len(),
+                pages_per_sec,
+                mib_per_sec: pages_to_mib(pages_per_sec),
+            });
+        }
+
+        let avg_pages_per_sec = if samples.is_empty() {
+            0.0
+        } else {
+            samples.iter().map(|s| s.pages_per_sec).sum::<f64>() / samples.len() as f64
+        };
+
+        DirtyRateReport {
+            window_ms,
+            samples,
+            avg_pages_per_sec,
+            avg_mib_per_sec: pages_to_mib(avg_pages_per_sec),
+            working_set_pages: working_set.len(),
+            working_set_mib: pages_to_mib(working_set.len() as f64),
+        }
+    }
+}
+
+fn pages_to_mib(pages: f64) -> f64 {
+    pages * PAGE_SIZE as f64 / (1 << 20) as f64
+}
+
+// Samples the dirty log of a running VM, without migrating it.
+pub struct DirtyRateMonitor {
+    vm_fd: Arc<VmFd>,
+    guest_memory: GuestMemoryMmap,
+    dirty_log_in_use: Arc<AtomicBool>,
+    log_reads: Arc<AtomicU64>,
+}
+
+// Refuses the measurements that would hold too much memory, or the dirty log for too long.
+fn check_dirty_rate_limits(window: Duration, num_windows: u32) -> Result<()> {
+    let too_long = window
+        .checked_mul(num_windows)
+        .map_or(true, |total| total > MAX_DIRTY_RATE_DURATION);
+    if num_windows > MAX_DIRTY_RATE_WINDOWS || too_long {
+        return Err(Error::DirtyRateLimits);
+    }
+    Ok(())
+}
+
+impl DirtyRateMonitor {
+    pub fn new(
+        vm_fd: Arc<VmFd>,
+        guest_memory: GuestMemoryMmap,
+        dirty_log_in_use: Arc<AtomicBool>,
+        log_reads: Arc<AtomicU64>,
+    ) -> Self {
+        DirtyRateMonitor {
+            vm_fd,
+            guest_memory,
+            dirty_log_in_use,
+            log_reads,
+        }
+    }
+
+    fn collect_dirty_pages(&self) -> Result<Vec<usize>> {
+        self.log_reads.fetch_add(1, Ordering::AcqRel);
+        collect_dirty_pages(&self.vm_fd, &self.guest_memory)
+    }
+
+    // Measures the pages dirtied during `num_windows` consecutive windows of `window` each.
+    // Blocks for the whole measurement.
+    pub fn measure(&self, window: Duration, num_windows: u32) -> Result<DirtyRateReport> {
+        let windows = self.sample(window, num_windows)?;
+        Ok(DirtyRateReport::new(window.as_millis() as u64, &windows))
+    }
+
+    // Same as `measure`, but keeps the pages dirtied in each window, to be replayed by the
+    // migration simulator.
+    pub fn record(&self, window: Duration, num_windows: u32) -> Result<RecordedTrace> {
+        let windows = self.sample(window, num_windows)?;
+        Ok(RecordedTrace {
+            window_ms: window.as_millis() as u64,
+            windows: windows.into_iter().map(|(pages, _)| pages).collect(),
+        })
+    }
+
+    fn sample(&self, window: Duration, num_windows: u32) -> Result<Vec<(Vec<usize>, Duration)>> {
+        check_dirty_rate_limits(window, num_windows)?;
+
+        // Held until the last window is read, so that a migration can't start in the middle.
+        let _dirty_log =
+            DirtyLogOwner::take(&self.dirty_log_in_use).ok_or(Error::MigrationInProgress)?;
+
+        // Start from a clean log, whatever was dirtied before is not part of the measurement.
+        self.collect_dirty_pages()?;
+        let mut start = Instant::now();
+
+        let mut windows = Vec::with_capacity(num_windows as usize);
+        for _ in 0..num_windows {
+            thread::sleep(window);
+            let pages = self.collect_dirty_pages()?;
+            let now = Instant::now();
+            windows.push((pages, now - start));
+            start = now;
+        }
+
+        Ok(windows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_pages_from_bitmap() {
+        assert!(dirty_pages_from_bitmap(&[0, 0]).is_empty());
+        assert_eq!(dirty_pages_from_bitmap(&[0b101, 1 << 63]), vec![0, 2, 127]);
+    }
+
+    #[test]
+    fn test_guest_pages() {
+        let regions = vec![
+            (None, vm_memory::GuestAddress(0), 2 * PAGE_SIZE),
+            (None, vm_memory::GuestAddress(0x10000), 3 * PAGE_SIZE),
+        ];
+        let guest_memory = vm_memory::create_guest_memory(&regions, false).unwrap();
+        assert_eq!(guest_pages(&guest_memory), vec![0, 1, 16, 17, 18]);
+    }
+
+    #[test]
+    fn test_dirty_log_owner() {
+        let in_use = Arc::new(AtomicBool::new(false));
+        let owner = DirtyLogOwner::take(&in_use).unwrap();
+        assert!(in_use.load(Ordering::Acquire));
+        assert!(DirtyLogOwner::take(&in_use).is_none());
+        drop(owner);
+        assert!(!in_use.load(Ordering::Acquire));
+        assert!(DirtyLogOwner::take(&in_use).is_some());
+    }
+
+    #[test]
+    fn test_dirty_rate_limits() {
+        assert!(check_dirty_rate_limits(Duration::from_secs(3), 100).is_ok());
+        assert!(check_dirty_rate_limits(Duration::from_millis(10), 101).is_err());
+        assert!(check_dirty_rate_limits(Duration::from_secs(301), 1).is_err());
+        assert!(check_dirty_rate_limits(Duration::from_secs(u64::MAX), 2).is_err());
+    }
+
+    #[test]
+    fn test_dirty_rate_report() {
+        let windows = vec![
+            (vec![0, 1, 2, 3], Duration::from_millis(500)),
+            (vec![2, 3, 4, 5], Duration::from_millis(500)),
+        ];
+        let report = DirtyRateReport::new(500, &windows);
+
+        assert_eq!(report.samples.len(), 2);
+        assert_eq!(report.samples[0].dirty_pages, 4);
+        assert!((report.samples[0].pages_per_sec - 8.0).abs() < 1e-9);
+        assert!((report.avg_pages_per_sec - 8.0).abs() < 1e-9);
+        assert!((report.avg_mib_per_sec - 8.0 * 4096.0 / 1048576.0).abs() < 1e-9);
+        assert_eq!(report.working_set_pages, 6);
+
+        let report = DirtyRateReport::new(500, &[]);
+        assert_eq!(report.avg_pages_per_sec, 0.0);
+        assert_eq!(report.working_set_pages, 0);
+    }
+}
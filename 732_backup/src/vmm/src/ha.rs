@@ -0,0 +1,268 @@
+// High availability through continuous checkpoint replication (in the style of Remus).
+//
+// The primary stops the vCPUs every `interval_ms`, collects the pages dirtied since the previous
+// checkpoint along with the `VmState`, resumes the guest and ships the checkpoint (an "epoch")
+// to the standby. Frames transmitted by the guest are held back by the net devices until the
+// epoch they belong to is acknowledged, so the outside world never sees state the standby
+// doesn't have. Checkpoints double as heartbeats: when none arrives for `timeout_ms`, the standby
+// restores the last complete epoch and runs the guest.
+//
+// Block devices are not replicated, both hosts are expected to use shared storage.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryMmap};
+
+use crate::dirty_log::{guest_pages, PAGE_SIZE};
+use crate::migration::read_migration_frame;
+use crate::{HaConfig, Net, RpcController};
+
+// Number of attempts the primary makes to reach the standby, one second apart.
+const CONNECT_ATTEMPTS: u32 = 30;
+
+// Room left in a checkpoint for everything but the pages, i.e. the `VmState`.
+const MAX_CHECKPOINT_OVERHEAD: u64 = 64 << 20;
+
+/// State of the guest at the end of an epoch.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Checkpoint {
+    pub epoch: u64,
+    /// Pages dirtied during the epoch (every page for the first one).
+    pub pages: Vec<u64>,
+    /// Content of `pages`, `PAGE_SIZE` bytes each.
+    pub data: Vec<u8>,
+    /// Serialized `VmState`.
+    pub vm_state: Vec<u8>,
+}
+
+fn send_checkpoint(stream: &mut TcpStream, checkpoint: &Checkpoint) -> io::Result<()> {
+    let data =
+        bincode::serialize(checkpoint).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    stream.write_all(&(data.len() as u64).to_le_bytes())?;
+    stream.write_all(&data)
+}
+
+// Checkpoints are framed like the migration messages, and can't be larger than the whole guest
+// memory along with the vm state.
+fn recv_checkpoint(stream: &mut TcpStream, max_len: u64) -> io::Result<Checkpoint> {
+    let data = read_migration_frame(stream, max_len)?;
+    bincode::deserialize(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Writes the pages of `checkpoint` to `guest_memory`. Everything is checked before the first
+// write, so a rejected checkpoint leaves the memory as the previous one left it.
+fn apply_checkpoint(checkpoint: &Checkpoint, guest_memory: &GuestMemoryMmap) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    if Some(checkpoint.data.len()) != checkpoint.pages.len().checked_mul(PAGE_SIZE) {
+        return Err(invalid(format!(
+            "{} bytes of data for {} pages",
+            checkpoint.data.len(),
+            checkpoint.pages.len()
+        )));
+    }
+    // The regions are page aligned, a page starting in one ends in it as well.
+    let mut addrs = Vec::with_capacity(checkpoint.pages.len());
+    for page in checkpoint.pages.iter() {
+        match page.checked_mul(PAGE_SIZE as u64).map(GuestAddress) {
+            Some(addr) if guest_memory.address_in_range(addr) => addrs.push(addr),
+            _ => return Err(invalid(format!("page {} is not in the guest memory", page))),
+        }
+    }
+
+    for (addr, data) in addrs
+        .into_iter()
+        .zip(checkpoint.data.chunks_exact(PAGE_SIZE))
+    {
+        guest_memory
+            .write_slice(data, addr)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+    Ok(())
+}
+
+fn wait_ack(stream: &mut TcpStream, epoch: u64) -> io::Result<()> {
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf)?;
+    let acked = u64::from_le_bytes(buf);
+    if acked != epoch {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected ack for epoch {}, got {}", epoch, acked),
+        ));
+    }
+    Ok(())
+}
+
+fn connect(addr: &str) -> io::Result<TcpStream> {
+    let mut attempt = 0;
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if attempt + 1 >= CONNECT_ATTEMPTS => return Err(e),
+            Err(_) => {
+                attempt += 1;
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+// Asks the run loop for a checkpoint. The request has a flag of its own, so it never waits behind
+// (or gets overwritten by) the ones coming from the RPCs.
+fn request_checkpoint(rpc_controller: &Mutex<RpcController>) {
+    let rpc_controller = rpc_controller.lock().unwrap();
+    rpc_controller
+        .checkpoint_requested
+        .store(true, Ordering::Release);
+    rpc_controller.event_fd.write(1).unwrap();
+}
+
+/// Drives the replication on the primary: triggers the checkpoints, ships them to the standby
+/// and releases the network frames of the acknowledged epochs. When the standby becomes
+/// unreachable, the guest keeps running unprotected.
+pub fn run_primary(
+    cfg: HaConfig,
+    rpc_controller: Arc<Mutex<RpcController>>,
+    checkpoints: Receiver<Checkpoint>,
+    net_devices: Vec<Arc<Mutex<Net>>>,
+) {
+    let connected = connect(&cfg.addr).and_then(|stream| {
+        stream.set_read_timeout(Some(Duration::from_millis(cfg.timeout_ms)))?;
+        Ok(stream)
+    });
+    let mut standby = match connected {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("HA: cannot reach the standby at {}: {}", cfg.addr, e);
+            return;
+        }
+    };
+    println!("HA: replicating to {}", cfg.addr);
+
+    for net in net_devices.iter() {
+        net.lock().unwrap().tx_buffer().enable();
+    }
+
+    loop {
+        thread::sleep(Duration::from_millis(cfg.interval_ms));
+
+        request_checkpoint(&rpc_controller);
+        // The run loop may be busy for a while (i.e. writing a snapshot), or have skipped the
+        // request because the guest is paused, keep asking until it gets to it.
+        let checkpoint = loop {
+            match checkpoints.recv_timeout(Duration::from_millis(cfg.timeout_ms)) {
+                Ok(checkpoint) => break Some(checkpoint),
+                Err(RecvTimeoutError::Timeout) => request_checkpoint(&rpc_controller),
+                // The VMM is going away.
+                Err(RecvTimeoutError::Disconnected) => break None,
+            }
+        };
+        let checkpoint = match checkpoint {
+            Some(checkpoint) => checkpoint,
+            None => break,
+        };
+
+        let epoch = checkpoint.epoch;
+        let shipped =
+            send_checkpoint(&mut standby, &checkpoint).and_then(|_| wait_ack(&mut standby, epoch));
+        if let Err(e) = shipped {
+            eprintln!("HA: lost the standby at epoch {}: {}", epoch, e);
+            break;
+        }
+
+        for net in net_devices.iter() {
+            if let Err(e) = net.lock().unwrap().release_tx_frames(epoch) {
+                eprintln!("HA: failed to release network frames: {:?}", e);
+            }
+        }
+    }
+
+    for net in net_devices.iter() {
+        if let Err(e) = net.lock().unwrap().flush_tx_frames() {
+            eprintln!("HA: failed to flush network frames: {:?}", e);
+        }
+    }
+}
+
+/// Receives the checkpoints of the primary into `guest_memory` until it stops sending them,
+/// then returns the `VmState` of the last complete epoch.
+pub fn run_standby(cfg: &HaConfig, guest_memory: &GuestMemoryMmap) -> io::Result<Vec<u8>> {
+    let listener = TcpListener::bind(&cfg.addr)?;
+    println!("HA: waiting for the primary on {}", cfg.addr);
+    let (mut primary, peer) = listener.accept()?;
+    println!("HA: replicating from {}", peer);
+    primary.set_read_timeout(Some(Duration::from_millis(cfg.timeout_ms)))?;
+
+    let max_len = (guest_pages(guest_memory).len() as u64)
+        .saturating_mul(PAGE_SIZE as u64 + 8)
+        .saturating_add(MAX_CHECKPOINT_OVERHEAD);
+    let mut vm_state = None;
+    loop {
+        // A checkpoint is only applied once fully received and checked, so a primary failing in
+        // the middle of an epoch (or sending garbage) leaves us with the previous one.
+        let checkpoint = match recv_checkpoint(&mut primary, max_len) {
+            Ok(checkpoint) => checkpoint,
+            Err(e) => {
+                println!("HA: primary is gone ({}), taking over", e);
+                break;
+            }
+        };
+        if let Err(e) = apply_checkpoint(&checkpoint, guest_memory) {
+            println!(
+                "HA: rejected checkpoint {} ({}), taking over",
+                checkpoint.epoch, e
+            );
+            break;
+        }
+        vm_state = Some(checkpoint.vm_state);
+
+        if primary.write_all(&checkpoint.epoch.to_le_bytes()).is_err() {
+            println!("HA: primary is gone, taking over");
+            break;
+        }
+    }
+
+    vm_state.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "primary failed before the first checkpoint",
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_checkpoint() {
+        let regions = vec![(None, GuestAddress(0), 2 * PAGE_SIZE)];
+        let guest_memory = vm_memory::create_guest_memory(&regions, false).unwrap();
+        let checkpoint = |pages: Vec<u64>, len: usize| Checkpoint {
+            pages,
+            data: vec![0xaa; len],
+            ..Default::default()
+        };
+
+        // Short payload, and a page past the end of the memory.
+        assert!(apply_checkpoint(&checkpoint(vec![0, 1], PAGE_SIZE), &guest_memory).is_err());
+        assert!(apply_checkpoint(&checkpoint(vec![0, 2], 2 * PAGE_SIZE), &guest_memory).is_err());
+        assert!(apply_checkpoint(&checkpoint(vec![u64::MAX], PAGE_SIZE), &guest_memory).is_err());
+        let mut buf = [0u8; 1];
+        guest_memory.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        assert_eq!(buf[0], 0);
+
+        apply_checkpoint(&checkpoint(vec![1], PAGE_SIZE), &guest_memory).unwrap();
+        guest_memory
+            .read_slice(&mut buf, GuestAddress(PAGE_SIZE as u64))
+            .unwrap();
+        assert_eq!(buf[0], 0xaa);
+    }
+}
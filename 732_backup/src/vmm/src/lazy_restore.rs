@@ -0,0 +1,380 @@
+// Lazy restore of snapshots: the guest starts right away on empty memory, which is filled in from
+// the snapshot as it gets touched, instead of once all of it was read.
+//
+// Faults on the missing guest pages, of the vCPUs (through KVM) as well as of the VMM threads, are
+// caught with userfaultfd and resolved a dedup chunk at a time: the chunk holding the page is read,
+// from the snapshot file, or from the dedup store when that's the only place the snapshot lives,
+// and copied into the guest memory in one go. Optionally, a prefetch thread copies the rest of the
+// memory in the background, starting with the chunks following the last fault.
+//
+// Only the memory of the full snapshot at the root of a chain is restored lazily. Diff snapshots
+// are small, and written over it as usual (faulting in the chunks they land on). The guest memory
+// isn't checked against the snapshot checksum, which would mean reading all of it, but chunks
+// coming from the dedup store are checked against their hash.
+//
+// Encrypted snapshots have their memory decrypted and checked up front, as usual.
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
+
+use crate::dedup::{chunk_matches, DedupManager};
+use crate::dirty_log::PAGE_SIZE;
+use crate::memory_snapshot::GuestMemoryState;
+use crate::snapshot_file::{
+    read_section_table, Result, SectionKind, SnapshotError, SnapshotReader,
+};
+use crate::uffd::{Uffd, REGISTER_MODE_MISSING};
+
+// Number of chunks following the last fault that the prefetch copies before going on in order.
+const PREFETCH_AHEAD: u64 = 4;
+
+/// A file that's read from the dedup store a chunk at a time, as it's accessed. Files on disk are
+/// read as they are.
+pub struct LazyFile {
+    file: File,
+    chunk_size: u64,
+    // Hash and location of each chunk, when the file comes from the dedup store.
+    chunks: Vec<(String, PathBuf)>,
+    loaded: Mutex<Vec<bool>>,
+}
+
+impl LazyFile {
+    pub fn open(path: &str, dedup_mgr: &DedupManager) -> io::Result<Self> {
+        let chunk_size = dedup_mgr.CHUNK_SIZE as u64;
+        if Path::new(path).exists() {
+            return Ok(LazyFile {
+                file: File::open(path)?,
+                chunk_size,
+                chunks: vec![],
+                loaded: Mutex::new(vec![]),
+            });
+        }
+
+        let chunks = dedup_mgr.file_chunks(path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is neither on disk nor in the dedup store", path),
+            )
+        })?;
+        let size = match chunks.last() {
+            Some((_, last)) => (chunks.len() as u64 - 1) * chunk_size + fs::metadata(last)?.len(),
+            None => 0,
+        };
+        // The chunks are gathered in a sparse scratch file, unlinked right away so that it's never
+        // mistaken for the snapshot.
+        let scratch_path = format!("{}.lazy", path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&scratch_path)?;
+        fs::remove_file(&scratch_path)?;
+        file.set_len(size)?;
+        Ok(LazyFile {
+            file,
+            chunk_size,
+            loaded: Mutex::new(vec![false; chunks.len()]),
+            chunks,
+        })
+    }
+
+    pub fn size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// The file, where only the chunks loaded so far are filled in.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Fetches the chunks covering `len` bytes at `offset` that weren't already.
+    pub fn load(&self, offset: u64, len: u64) -> io::Result<()> {
+        if self.chunks.is_empty() || len == 0 {
+            return Ok(());
+        }
+        let first = (offset / self.chunk_size) as usize;
+        let last = ((offset + len - 1) / self.chunk_size) as usize;
+        let mut loaded = self.loaded.lock().unwrap();
+        for index in first..=last.min(self.chunks.len() - 1) {
+            if loaded[index] {
+                continue;
+            }
+            let (hash, chunk_path) = &self.chunks[index];
+            let chunk = fs::read(chunk_path)?;
+            if !chunk_matches(hash, &chunk) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("chunk {} is corrupted", chunk_path.display()),
+                ));
+            }
+            self.file
+                .write_all_at(&chunk, index as u64 * self.chunk_size)?;
+            loaded[index] = true;
+        }
+        Ok(())
+    }
+
+    pub fn load_all(&self) -> io::Result<()> {
+        self.load(0, self.size()?)
+    }
+
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.load(offset, buf.len() as u64)?;
+        self.file.read_exact_at(buf, offset)
+    }
+}
+
+/// Opens the snapshot at `path` for a lazy restore. When it comes from the dedup store, everything
+/// but the guest memory of a full snapshot is fetched up front. The reader is `None` for a legacy
+/// snapshot, which is fetched whole.
+pub fn open_snapshot(
+    path: &str,
+    dedup_mgr: &DedupManager,
+) -> Result<(Arc<LazyFile>, Option<SnapshotReader>)> {
+    let file = LazyFile::open(path, dedup_mgr)?;
+    let size = file.size()?;
+    // The header comes first, and the section table last.
+    file.load(0, 1)?;
+    file.load(size.saturating_sub(file.chunk_size), file.chunk_size)?;
+
+    // The other sections are all needed to open the snapshot, encrypted ones included.
+    let sections = read_section_table(file.file())?.unwrap_or_default();
+    let find = |kind: SectionKind| sections.iter().find(|section| section.kind == kind as u32);
+    match (find(SectionKind::MemoryData), find(SectionKind::Parent)) {
+        (Some(data), None) => {
+            let end = data.offset + data.len;
+            file.load(0, data.offset)?;
+            file.load(end, size.saturating_sub(end))?;
+        }
+        _ => file.load_all()?,
+    }
+    let snapshot = SnapshotReader::from_file(file.file().try_clone()?)?;
+    Ok((Arc::new(file), snapshot))
+}
+
+/// Fills in `guest_memory`, laid out as `state`, from `file` as it gets touched. It must not have
+/// been touched yet. With `prefetch`, the rest of it is copied in the background.
+pub fn start(
+    guest_memory: &GuestMemoryMmap,
+    state: &GuestMemoryState,
+    file: Arc<LazyFile>,
+    prefetch: bool,
+) -> Result<()> {
+    let size = file.size()?;
+    let uffd = Uffd::new(0)?;
+    let mut regions = Vec::with_capacity(state.regions.len());
+    for region in state.regions.iter() {
+        if region.offset + region.size as u64 > size {
+            return Err(SnapshotError::Corrupted(
+                "guest memory goes past the end of the snapshot".to_string(),
+            ));
+        }
+        let host_addr = guest_memory
+            .get_host_address(GuestAddress(region.base_address))
+            .map_err(SnapshotError::Memory)? as u64;
+        uffd.register(host_addr, region.size as u64, REGISTER_MODE_MISSING)?;
+        regions.push(LazyRegion {
+            host_addr,
+            size: region.size as u64,
+            offset: region.offset,
+        });
+    }
+
+    let memory = Arc::new(LazyMemory {
+        uffd,
+        chunk_size: file.chunk_size,
+        file,
+        regions,
+        populated: Mutex::new(HashSet::new()),
+        last_fault: Mutex::new(None),
+    });
+    let handler = memory.clone();
+    let _ = thread::spawn(move || handler.handle_faults());
+    if prefetch {
+        let _ = thread::spawn(move || memory.prefetch());
+    }
+    Ok(())
+}
+
+// Guest memory region, and where its content starts in the snapshot.
+struct LazyRegion {
+    host_addr: u64,
+    size: u64,
+    offset: u64,
+}
+
+struct LazyMemory {
+    uffd: Uffd,
+    file: Arc<LazyFile>,
+    chunk_size: u64,
+    regions: Vec<LazyRegion>,
+    // Chunks copied in so far, as (region, chunk of the file).
+    populated: Mutex<HashSet<(usize, u64)>>,
+    // Chunk of the last fault, where the prefetch goes on from.
+    last_fault: Mutex<Option<(usize, u64)>>,
+}
+
+impl LazyMemory {
+    // Region and chunk of the file holding the page at `host_addr`.
+    fn find(&self, host_addr: u64) -> Option<(usize, u64)> {
+        let index = self.regions.iter().position(|region| {
+            host_addr >= region.host_addr && host_addr < region.host_addr + region.size
+        })?;
+        let region = &self.regions[index];
+        Some((
+            index,
+            (region.offset + host_addr - region.host_addr) / self.chunk_size,
+        ))
+    }
+
+    // Chunks of the file overlapping region `index`.
+    fn chunks(&self, index: usize) -> Range<u64> {
+        let region = &self.regions[index];
+        region.offset / self.chunk_size
+            ..(region.offset + region.size + self.chunk_size - 1) / self.chunk_size
+    }
+
+    // Copies the part of `chunk` that goes into `region`, unless it's there already.
+    fn populate(&self, region: usize, chunk: u64) -> io::Result<()> {
+        let mut populated = self.populated.lock().unwrap();
+        if populated.contains(&(region, chunk)) {
+            return Ok(());
+        }
+        let lazy_region = &self.regions[region];
+        let start = (chunk * self.chunk_size).max(lazy_region.offset);
+        let end = ((chunk + 1) * self.chunk_size).min(lazy_region.offset + lazy_region.size);
+        let mut data = vec![0u8; (end - start) as usize];
+        self.file.read_exact_at(&mut data, start)?;
+        self.fill(lazy_region.host_addr + start - lazy_region.offset, &data)?;
+        populated.insert((region, chunk));
+        Ok(())
+    }
+
+    // Copies `data` to `dst`, waking up whoever faulted on it.
+    fn fill(&self, dst: u64, data: &[u8]) -> io::Result<()> {
+        match self.fill_range(dst, data) {
+            // Some of the pages are there already, copy the others one by one.
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => {
+                for (index, page) in data.chunks(PAGE_SIZE).enumerate() {
+                    match self.fill_range(dst + (index * PAGE_SIZE) as u64, page) {
+                        Err(e) if e.raw_os_error() == Some(libc::EEXIST) => {}
+                        result => result?,
+                    }
+                }
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn fill_range(&self, dst: u64, data: &[u8]) -> io::Result<()> {
+        // Zeroed pages are mapped to the zero page rather than copied.
+        if data.iter().all(|&byte| byte == 0) {
+            self.uffd.zeropage(dst, data.len() as u64)
+        } else {
+            self.uffd.copy(dst, data)
+        }
+    }
+
+    fn handle_faults(&self) {
+        loop {
+            let host_addr = match self.uffd.read_fault(-1) {
+                Ok(Some(fault)) => fault.addr,
+                Ok(None) => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("lazy restore: failed to read a page fault: {}", e);
+                    return;
+                }
+            };
+            let (region, chunk) = match self.find(host_addr) {
+                Some(found) => found,
+                None => continue,
+            };
+            *self.last_fault.lock().unwrap() = Some((region, chunk));
+            if let Err(e) = self.populate(region, chunk) {
+                // Whoever faulted can't go on without the page.
+                eprintln!(
+                    "lazy restore: failed to load the guest memory at {:#x}: {}",
+                    host_addr, e
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    fn prefetch(&self) {
+        for region in 0..self.regions.len() {
+            for chunk in self.chunks(region) {
+                // The guest is likely to touch what follows the last fault soon.
+                let last_fault = self.last_fault.lock().unwrap().take();
+                if let Some((fault_region, fault_chunk)) = last_fault {
+                    let end = (fault_chunk + 1 + PREFETCH_AHEAD).min(self.chunks(fault_region).end);
+                    for next in fault_chunk + 1..end {
+                        if let Err(e) = self.populate(fault_region, next) {
+                            eprintln!("lazy restore: prefetch failed: {}", e);
+                            return;
+                        }
+                    }
+                }
+                if let Err(e) = self.populate(region, chunk) {
+                    eprintln!("lazy restore: prefetch failed: {}", e);
+                    return;
+                }
+            }
+        }
+        println!("lazy restore: guest memory fully loaded");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_lazy_file() {
+        let dir = TempDir::new().unwrap();
+        let dedup_mgr = DedupManager::in_dir(dir.as_path());
+        let path = format!("{}/snapshot", dir.as_path().display());
+        let content: Vec<u8> = (0..3 * 4096 + 100).map(|i| (i / 4096) as u8 + 1).collect();
+        File::create(&path).unwrap().write_all(&content).unwrap();
+        dedup_mgr.save_file(&path);
+
+        // On disk, read as is.
+        let file = LazyFile::open(&path, &dedup_mgr).unwrap();
+        let mut buf = vec![0u8; 8];
+        file.read_exact_at(&mut buf, 4096 + 10).unwrap();
+        assert_eq!(buf, vec![2; 8]);
+
+        // From the dedup store, a chunk at a time.
+        fs::remove_file(&path).unwrap();
+        let file = LazyFile::open(&path, &dedup_mgr).unwrap();
+        assert!(!Path::new(&path).exists());
+        assert_eq!(file.size().unwrap(), content.len() as u64);
+        file.read_exact_at(&mut buf, 2 * 4096 + 10).unwrap();
+        assert_eq!(buf, vec![3; 8]);
+        // Only the chunk that was read is there.
+        file.file().read_exact_at(&mut buf, 10).unwrap();
+        assert_eq!(buf, vec![0; 8]);
+        file.load_all().unwrap();
+        let mut all = vec![0u8; content.len()];
+        file.file().read_exact_at(&mut all, 0).unwrap();
+        assert_eq!(all, content);
+
+        let missing = format!("{}/missing", dir.as_path().display());
+        assert!(LazyFile::open(&missing, &dedup_mgr).is_err());
+    }
+}
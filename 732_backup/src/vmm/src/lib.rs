@@ -3,6 +3,7 @@
 //! Reference VMM built with rust-vmm components and minimal glue.
 #![allow(missing_docs)]
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
 
 use core::num;
@@ -11,15 +12,16 @@ use std::convert::{TryFrom, TryInto};
 
 use serde::{Serialize, Deserialize};
 use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
 
 #[cfg(target_arch = "aarch64")]
 use std::convert::TryInto;
 use std::fs::File;
-use std::io::{self, stdin, stdout, Read, BufReader};
+use std::io::{self, stdin, stdout, BufReader};
 use std::ops::DerefMut;
 use std::path::{PathBuf, Path};
-use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, SystemTime};
 use std::sync::mpsc::{Sender, Receiver};
@@ -58,7 +60,6 @@ use vm_device::bus::{MmioAddress, MmioRange};
 #[cfg(target_arch = "x86_64")]
 use vm_device::bus::{PioAddress, PioRange};
 use vm_device::device_manager::IoManager;
-#[cfg(target_arch = "aarch64")]
 use vm_device::device_manager::MmioManager;
 #[cfg(target_arch = "x86_64")]
 use vm_device::device_manager::PioManager;
@@ -81,11 +82,52 @@ pub use config::*;
 use devices::virtio::block::{self, BlockArgs};
 use devices::virtio::net::{self, NetArgs};
 use devices::virtio::{Env, MmioConfig};
+#[cfg(target_arch = "x86_64")]
+use devices::vm_identity::{self, VmIdentity, VmIdentityDevice};
+pub mod background_snapshot;
+pub mod catalog;
+pub mod core_dump;
+pub mod cpu_compat;
 pub mod dedup;
+pub mod dirty_log;
+pub mod ha;
+pub mod lazy_restore;
+pub mod live_update;
 pub mod memory_snapshot;
+pub mod migration;
+pub mod page_heat;
+pub mod snapshot_compat;
+pub mod snapshot_crypto;
+pub mod snapshot_file;
+pub mod snapshot_inspect;
+pub mod storage_migration;
+pub mod uffd;
+pub mod version_map;
 
 use crate::memory_snapshot::{GuestMemoryRegionState, GuestMemoryState, SnapshotMemory};
+use crate::snapshot_file::{
+    Result as SnapshotResult, SectionKind, SnapshotError, SnapshotMetadata, SnapshotReader,
+    SnapshotWriter,
+};
+use crate::background_snapshot::MemoryProtection;
+use crate::catalog::{Catalog, SourceVm};
+use crate::cpu_compat::CpuProfile;
+use crate::snapshot_compat::RestoreTarget;
+#[cfg(target_arch = "x86_64")]
+use crate::snapshot_crypto::fill_random;
+use crate::snapshot_crypto::SnapshotKey;
 use crate::dedup::DedupManager;
+use crate::dirty_log::{
+    collect_dirty_pages, collect_vmm_dirty_pages, DirtyLogOwner, DirtyRateMonitor,
+};
+use crate::ha::Checkpoint;
+use crate::lazy_restore::LazyFile;
+use crate::live_update::LIVE_UPDATE_EVENT;
+use crate::migration::{
+    connect_migration, max_migration_frame_len, read_migration_frame, write_guest_pages,
+    ConvergencePolicy, KvmGuest, MigrationMessage, PreCopy, TcpTransport,
+};
+use crate::storage_migration::DiskMirror;
 // use memory_snapshot::{GuestMemoryRegionState, GuestMemoryState};
 #[cfg(target_arch = "x86_64")]
 use devices::legacy::I8042Wrapper;
@@ -100,7 +142,6 @@ use arch::{FdtBuilder, AARCH64_FDT_MAX_SIZE, AARCH64_MMIO_BASE, AARCH64_PHYS_MEM
 mod boot;
 mod config;
 
-use versionize::{VersionMap, Versionize};
 
 /// First address past 32 bits is where the MMIO gap ends.
 pub(crate) const MMIO_GAP_END: u64 = 1 << 32;
@@ -108,6 +149,9 @@ pub(crate) const MMIO_GAP_END: u64 = 1 << 32;
 pub(crate) const MMIO_GAP_SIZE: u64 = 768 << 20;
 /// The start of the MMIO gap (memory area reserved for MMIO devices).
 pub(crate) const MMIO_GAP_START: u64 = MMIO_GAP_END - MMIO_GAP_SIZE;
+/// Address of the VM identity device, past the virtio devices.
+#[cfg(target_arch = "x86_64")]
+pub const VM_IDENTITY_ADDR: u64 = MMIO_GAP_START + 0x4000;
 /// Address of the zeropage, where Linux kernel boot parameters are written.
 #[cfg(target_arch = "x86_64")]
 const ZEROPG_START: u64 = 0x7000;
@@ -148,6 +192,8 @@ const STATE_PATH: &str = "./database/state";
 const MAP1_PATH: &str = "./database/map1";
 // map2 path is database path + map2
 const MAP2_PATH: &str = "./database/map2";
+// snapshot catalog, next to the dedup database
+const CATALOG_PATH: &str = "./database/catalog.json";
 
 /// VMM memory related errors.
 #[derive(Debug)]
@@ -201,6 +247,17 @@ pub enum Error {
     #[cfg(target_arch = "aarch64")]
     /// Cannot setup the FDT for booting.
     SetupFdt(arch::Error),
+    /// The dirty log is already in use, e.g. by a live migration.
+    MigrationInProgress,
+    /// The dirty rate measurement asks for more than `MAX_DIRTY_RATE_WINDOWS` windows, or lasts
+    /// longer than `MAX_DIRTY_RATE_DURATION`.
+    DirtyRateLimits,
+    /// The vCPUs of the migration source use features this host doesn't support.
+    IncompatibleCpu(Vec<String>),
+    /// Storage migration is enabled, but the VM has no block device.
+    MissingBlockDevice,
+    /// Failed to restore a snapshot.
+    Snapshot(SnapshotError),
 }
 
 impl std::convert::From<vm::Error> for Error {
@@ -209,19 +266,30 @@ impl std::convert::From<vm::Error> for Error {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct MigrationMessage {
-    pub data: Vec<u8>,
-    pub data_len: usize,
-    pub dirty_pages: Vec<u64>,
-    pub dirty_pages_len: usize,
-    pub cpu_state_file_path: String,
-    pub is_last: bool,
-    pub init_migration: bool
-} 
+impl std::convert::From<SnapshotError> for Error {
+    fn from(snapshot_error: SnapshotError) -> Self {
+        Self::Snapshot(snapshot_error)
+    }
+}
 
 static MIGRATION_PORT: i32 = 1989;
 
+// Value of `RpcController::pause_or_resume` asking the run loop to start the vCPUs of a guest
+// restored paused.
+pub const RESUME_VM_EVENT: u16 = 5;
+// Value of `RpcController::pause_or_resume` asking the run loop for a diff snapshot.
+pub const DIFF_SNAPSHOT_EVENT: u16 = 6;
+// Value of `RpcController::pause_or_resume` asking the run loop for a background snapshot.
+pub const BACKGROUND_SNAPSHOT_EVENT: u16 = 7;
+// Value of `RpcController::pause_or_resume` asking the run loop for an ELF core dump of the guest.
+pub const CORE_DUMP_EVENT: u16 = 8;
+
+// Number of network announcements sent after the guest resumes on a new host, and the delay
+// before the first one. The delay grows by `ANNOUNCE_STEP_MS` after each round (same as QEMU).
+const ANNOUNCE_ROUNDS: u64 = 5;
+const ANNOUNCE_INITIAL_MS: u64 = 50;
+const ANNOUNCE_STEP_MS: u64 = 100;
+
 
 
 /// Dedicated [`Result`](https://doc.rust-lang.org/std/result/) type.
@@ -235,6 +303,13 @@ pub struct RpcController {
     pub pause_or_resume: AtomicU16,
     pub cpu_snapshot_path: String,
     pub memory_snapshot_path: String,
+    // Socket of the VMM taking over the guest on a live update.
+    pub live_update_path: String,
+    // Where the outcome of the requested snapshot goes, if anyone waits for it.
+    pub snapshot_result_tx: Option<Sender<SnapshotResult<()>>>,
+    // Set by the HA replication when it wants a checkpoint, apart from `pause_or_resume` so
+    // requests from the RPCs can't get in its way.
+    pub checkpoint_requested: AtomicBool,
 }
 
 impl RpcController {
@@ -246,20 +321,56 @@ impl RpcController {
             pause_or_resume: AtomicU16::new(0),
             cpu_snapshot_path: "".to_string(),
             memory_snapshot_path: "".to_string(),
+            live_update_path: "".to_string(),
+            snapshot_result_tx: None,
+            checkpoint_requested: AtomicBool::new(false),
             // 0 mean nothing, 1 mean pause, 2 mean resume.
         }
     }
+    /// Claims `pause_or_resume` for `event`, unless a previous request is still pending. The
+    /// caller fills in the request and writes to `event_fd` before releasing the controller.
+    pub fn try_request(&self, event: u16) -> bool {
+        self.pause_or_resume
+            .compare_exchange(0, event, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+
+    // Reports the outcome of the requested snapshot.
+    fn snapshot_done(&mut self, result: SnapshotResult<()>) {
+        report_snapshot(self.snapshot_result_tx.take(), result);
+    }
+
     fn which_event(&self) -> &'static str {
         let val = self.pause_or_resume.load(Ordering::Acquire);
         if val == 1 {
             return "PAUSE";
         } else if val == 2 {
             return "RESUME";
+        } else if val == LIVE_UPDATE_EVENT {
+            return "LIVE_UPDATE";
+        } else if val == RESUME_VM_EVENT {
+            return "RESUME_VM";
+        } else if val == DIFF_SNAPSHOT_EVENT {
+            return "DIFF_SNAPSHOT";
+        } else if val == BACKGROUND_SNAPSHOT_EVENT {
+            return "BACKGROUND_SNAPSHOT";
+        } else if val == CORE_DUMP_EVENT {
+            return "CORE_DUMP";
         }
         "5 star"
     }
 }
 
+// Reports the outcome of a snapshot to whoever waits for it.
+fn report_snapshot(result_tx: Option<Sender<SnapshotResult<()>>>, result: SnapshotResult<()>) {
+    if let Err(e) = result.as_ref() {
+        eprintln!("Snapshot failed: {:?}", e);
+    }
+    if let Some(tx) = result_tx {
+        let _ = tx.send(result);
+    }
+}
+
 impl MutEventSubscriber for RpcController {
     fn process(&mut self, events: Events, ops: &mut EventOps) {
         if events.event_set().contains(EventSet::IN) {
@@ -277,6 +388,26 @@ impl MutEventSubscriber for RpcController {
     }
 }
 
+// Where the migration thread is at, for the run loop to tell a pause for the last iteration of a
+// migration from a plain one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MigrationStatus {
+    // Waiting for a destination.
+    Idle,
+    // A destination connected, the vCPUs must stay around until the migration is through.
+    Running,
+    // The guest runs on the destination now.
+    Done,
+}
+
+type SharedMigrationStatus = Arc<(Mutex<MigrationStatus>, Condvar)>;
+
+fn set_migration_status(shared: &SharedMigrationStatus, status: MigrationStatus) {
+    let (lock, cvar) = &**shared;
+    *lock.lock().unwrap() = status;
+    cvar.notify_all();
+}
+
 /// A live VMM.
 pub struct Vmm {
     pub vm: KvmVm<WrappedExitHandler>,
@@ -301,6 +432,33 @@ pub struct Vmm {
     pub is_resume: bool,
     pub start_migration_thread: bool,
     pub dedup_mgr: DedupManager,
+    pub migration_config: MigrationConfig,
+    // Set while someone owns the dirty log, see `DirtyLogOwner`.
+    pub dirty_log_in_use: Arc<AtomicBool>,
+    // Held by the HA replication, for as long as the VMM runs.
+    ha_dirty_log: Option<DirtyLogOwner>,
+    pub ha_config: Option<HaConfig>,
+    // Where the run loop hands the checkpoints over to the replication thread (primary only).
+    checkpoint_tx: Option<Sender<Checkpoint>>,
+    ha_epoch: u64,
+    // Backing file of the guest memory, when it's a memfd.
+    memfd: Option<File>,
+    // Connection to the VMM that took over the guest, held until this process exits.
+    live_update_conn: Option<UnixStream>,
+    // Serialized state of the vCPUs while the guest is restored paused. Taken by whoever comes
+    // first of the resume RPC and the migration thread.
+    paused_state: Arc<Mutex<Option<Vec<u8>>>>,
+    // Number of times the dirty log was read by someone other than the snapshots (i.e. the dirty
+    // rate measurement).
+    dirty_log_reads: Arc<AtomicU64>,
+    // Last snapshot with memory, which the next diff snapshot is based on, along with
+    // `dirty_log_reads` when it was taken. The log only holds what was dirtied since then if the
+    // count didn't move.
+    diff_parent: Option<(String, u64)>,
+    // Set while a background snapshot is being written.
+    background_snapshot_running: Arc<AtomicBool>,
+    // Configuration of the VM, recorded in the catalog along with its snapshots.
+    source_vm: SourceVm,
     // pub kvm: Kvm
 }
 
@@ -322,16 +480,40 @@ struct VmmExitHandler {
 //         // .map_err(Error::)
 // }
 
-fn get_memory_state(size: usize) -> GuestMemoryState {
-    let region_state = GuestMemoryRegionState {
-        base_address: 0,
-        size: size,
-        offset: 0,
-    };
-
-    GuestMemoryState {
-        regions: vec![region_state],
+// Where `mem_size` bytes of guest memory go: from address 0 up to the MMIO gap, and whatever is
+// left right after it. KVM gets a memory slot for each region, in this order.
+fn guest_memory_regions(mem_size: usize) -> Vec<(GuestAddress, usize)> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let below_gap = mem_size.min(MMIO_GAP_START as usize);
+        let mut regions = vec![(GuestAddress(0), below_gap)];
+        if mem_size > below_gap {
+            regions.push((GuestAddress(MMIO_GAP_END), mem_size - below_gap));
+        }
+        regions
     }
+    #[cfg(target_arch = "aarch64")]
+    vec![(GuestAddress(0), mem_size)]
+}
+
+// Layout of the guest memory of `size` bytes, as dumped region after region. Only needed for
+// legacy snapshots, the others carry their own layout.
+pub(crate) fn get_memory_state(size: usize) -> GuestMemoryState {
+    let mut offset = 0;
+    let regions = guest_memory_regions(size)
+        .into_iter()
+        .map(|(addr, size)| {
+            let region_state = GuestMemoryRegionState {
+                base_address: addr.0,
+                size,
+                offset,
+            };
+            offset += size as u64;
+            region_state
+        })
+        .collect();
+
+    GuestMemoryState { regions }
 }
 
 // fn restore(file: File, size: usize) -> GuestMemoryMmap {
@@ -423,19 +605,68 @@ impl TryFrom<VMMConfig> for Vmm {
 
         let guest_memory;
         let mut is_resume = false;
+        let mut paused_state = None;
+        let mut diff_parent = None;
         let mem_size = ((config.memory_config.size_mib as u64) << 20) as usize;
 
-        let dedup_mgr : DedupManager = DedupManager{
-            CHUNK_SIZE,
-            DATABASE_PATH: DATABASE_PATH.to_string(),
-            STATE_PATH: STATE_PATH.to_string(),
-            MAP1_PATH: MAP1_PATH.to_string(),
-            MAP2_PATH: MAP2_PATH.to_string()
-        };
+        let dedup_mgr = Self::dedup_manager();
+        // Checked up front, rather than when the first snapshot is taken.
+        if SnapshotKey::from_env().map_err(Error::IO)?.is_some() {
+            println!("snapshots are encrypted");
+        }
+        #[cfg(target_arch = "x86_64")]
+        let identity = Self::vm_identity(&config)?;
+        let source_vm = SourceVm::new(&config);
+
+        let ha_standby = config
+            .ha_config
+            .clone()
+            .filter(|cfg| cfg.role == HaRole::Standby);
+
+        let memfd;
 
-        let my_vm = if config.snapshot_config.is_none() {
-            let mem_regions = vec![(None, GuestAddress(0), mem_size)];
-            guest_memory = vm_memory::create_guest_memory(&mem_regions[..], true).unwrap();
+        let my_vm = if let Some(live_update_cfg) = config.live_update_config.as_ref() {
+            // Take over the guest of the VMM we're replacing, memory included.
+            is_resume = true;
+            let (file, vm_state) =
+                live_update::receive_guest(&live_update_cfg.socket).map_err(Error::IO)?;
+            let (shared_memory, file) = Self::shared_guest_memory(file)?;
+            guest_memory = shared_memory;
+            memfd = Some(file);
+            let mut vm_state = Self::vm_state_from_bytes(&vm_state)?;
+            Self::correct_guest_clock(&mut vm_state, &config.restore_config);
+            KvmVm::from_state(
+                &kvm,
+                vm_state,
+                &guest_memory,
+                wrapped_exit_handler.clone(),
+                device_mgr.clone(),
+            )
+            .unwrap()
+        } else if let Some(ha_cfg) = ha_standby {
+            // Follow the primary until it fails, then run the guest from its last checkpoint.
+            is_resume = true;
+            start_migrating_thread = false;
+            let (new_memory, file) =
+                Self::allocate_guest_memory(mem_size, config.memory_config.memfd)?;
+            guest_memory = new_memory;
+            memfd = file;
+            let vm_state = ha::run_standby(&ha_cfg, &guest_memory).map_err(Error::IO)?;
+            let mut vm_state = Self::vm_state_from_bytes(&vm_state)?;
+            Self::correct_guest_clock(&mut vm_state, &config.restore_config);
+            KvmVm::from_state(
+                &kvm,
+                vm_state,
+                &guest_memory,
+                wrapped_exit_handler.clone(),
+                device_mgr.clone(),
+            )
+            .unwrap()
+        } else if config.snapshot_config.is_none() {
+            let (new_memory, file) =
+                Self::allocate_guest_memory(mem_size, config.memory_config.memfd)?;
+            guest_memory = new_memory;
+            memfd = file;
             KvmVm::new(
                 &kvm,
                 vm_config,
@@ -447,6 +678,7 @@ impl TryFrom<VMMConfig> for Vmm {
         } else {
             // resume
             is_resume = true;
+            let target = Self::restore_target(&kvm, &config)?;
             let memory_snapshot_path = config.snapshot_config.clone().unwrap().memory_snapshot_path;
             let cpu_snapshot_path = config.snapshot_config.unwrap().cpu_snapshot_path;
 
@@ -454,72 +686,141 @@ impl TryFrom<VMMConfig> for Vmm {
 
             // guest_memory = GuestMemoryMmap::restore(Some(memory_file.as_file()), &memory_state, false);
 
-            let vmstate;
-
-            if !config.migrating {
-                vmstate = Self::restore_cpu(&cpu_snapshot_path[..]);
-                
-                let memory_state = get_memory_state(mem_size);
-                    dedup_mgr.load_file(&memory_snapshot_path);
-                    let file = File::options()
-                        .write(true)
-                        .read(true)
-                        .open(memory_snapshot_path)
-                        .unwrap();
-                guest_memory = GuestMemoryMmap::restore(Some(&file), &memory_state, false);
+            let mut vmstate;
+
+            if !config.migrating && config.restore_config.lazy {
+                let (state, memory, is_container) = Self::restore_snapshot_lazy(
+                    &cpu_snapshot_path,
+                    &memory_snapshot_path,
+                    &target,
+                    &dedup_mgr,
+                    config.restore_config.prefetch,
+                )?;
+                vmstate = state;
+                guest_memory = memory;
+                if is_container {
+                    diff_parent = Some((cpu_snapshot_path.clone(), 0));
+                }
+                memfd = None;
+            } else if !config.migrating {
+                // The snapshot only lives in the dedup database once it's been moved around.
+                if !Path::new(&cpu_snapshot_path).exists() {
+                    dedup_mgr.load_file(&cpu_snapshot_path);
+                }
+                if let Some(snapshot) = SnapshotReader::open(&cpu_snapshot_path)? {
+                    if let Some(metadata) = snapshot.metadata()? {
+                        target.check_metadata(&metadata)?;
+                    }
+                }
+                vmstate = Self::restore_cpu(&cpu_snapshot_path[..])?;
+                target.check_vm_state(&vmstate)?;
+
+                guest_memory = match Self::restore_snapshot_memory(&cpu_snapshot_path, &dedup_mgr)? {
+                    Some(guest_memory) => {
+                        // The dirty log starts out clean, so it tracks the changes from the
+                        // snapshot.
+                        diff_parent = Some((cpu_snapshot_path.clone(), 0));
+                        guest_memory
+                    }
+                    // Legacy snapshot, with the memory dumped in its own file.
+                    None => {
+                        let memory_state = get_memory_state(mem_size);
+                        dedup_mgr.load_file(&memory_snapshot_path);
+                        let file = File::options()
+                            .write(true)
+                            .read(true)
+                            .open(memory_snapshot_path)
+                            .unwrap();
+                        target.check_memory_size(file.metadata().map_err(Error::IO)?.len())?;
+                        GuestMemoryMmap::restore(Some(&file), &memory_state, true)
+                    }
+                };
+                memfd = None;
                 // println!("snapshot restored");
             } 
             else {
                 println!("restoring after migration....");
 
-                let mem_regions = vec![(None, GuestAddress(0), mem_size)];
-                guest_memory = vm_memory::create_guest_memory(&mem_regions[..], true).unwrap();
+                let (new_memory, file) =
+                    Self::allocate_guest_memory(mem_size, config.memory_config.memfd)?;
+                guest_memory = new_memory;
+                memfd = file;
 
                 let mut cpu_live_migration_snapshot_path = "cpu_live.txt".to_string();
+                // Sent along with the last iteration when the source was paused.
+                let mut cpu_live_migration_state = None;
 
 
                 let addr = "0.0.0.0:".to_string() + &MIGRATION_PORT.to_string();
-                let mut migrator_conn = TcpStream::connect(addr).unwrap();
 
-                let mut itr = 0;
+                // Last iteration applied to the guest memory, reported to the source when
+                // reconnecting so it only resends what we're missing.
+                let mut last_acked_itr: Option<u64> = None;
 
+                #[cfg(target_arch = "x86_64")]
+                let host_cpu = CpuProfile::host(&kvm)?;
+                #[cfg(target_arch = "aarch64")]
+                let host_cpu = CpuProfile::default();
 
-                loop {
-                    println!("Migration: itr = {}", itr);
-                    
-                    let buf = &mut [0; 8];
-
-                    migrator_conn.read_exact(buf).unwrap();
+                // Chunks the source doesn't need to send, e.g. from a snapshot of the same base
+                // image.
+                let known_chunks = dedup_mgr.chunk_files();
+                let known_hashes: Vec<String> = known_chunks.keys().cloned().collect();
 
-                    let data_len = u64::from_le_bytes(*buf);
-                    
+                let mut migrator_conn =
+                    connect_migration(&addr, last_acked_itr, &known_hashes, &host_cpu)?;
 
-                    println!("data_len: {}", data_len);    
+                let mut disk_copy = None;
+                // Grows to fit the disk once the source tells its size.
+                let mut max_frame_len = max_migration_frame_len(&guest_memory, None);
+                // The full copy comes as these pages, the MMIO gap left out.
+                let guest_pages = dirty_log::guest_pages(&guest_memory);
 
-                    let mut data_buf : Vec<u8> = vec![0; data_len as usize];
+                loop {
+                    println!("Migration: next itr = {}", last_acked_itr.map_or(0, |itr| itr + 1));
+
+                    let data_buf = match read_migration_frame(&mut migrator_conn, max_frame_len) {
+                        Ok(data_buf) => data_buf,
+                        Err(e) => {
+                            println!("migration connection lost: {}, reconnecting", e);
+                            migrator_conn =
+                                connect_migration(&addr, last_acked_itr, &known_hashes, &host_cpu)?;
+                            continue;
+                        }
+                    };
+                    let data_len = data_buf.len() as u64;
 
-                    migrator_conn.read_exact(&mut data_buf).unwrap();
+                    println!("data_len: {}", data_len);    
 
                     println!("Received data");
 
                     let mut done = false;
 
-
-                    if itr == 0 {
-                        // first itr directly sends all the guest memory(unserialized)
-                        let num_pages = data_len / 4096;
-
-                        for i in 0..num_pages {
-                            let page_addr = i * 4096;
-                            let page = &data_buf[page_addr as usize..(page_addr + 4096) as usize];
-                            guest_memory.write_slice(page, GuestAddress(i * 4096)).unwrap();
+                    let itr;
+
+                    if last_acked_itr.is_none() && known_hashes.is_empty() {
+                        itr = 0;
+                        // first itr directly sends all the guest memory(unserialized), its pages
+                        // back to back
+                        if data_len != (guest_pages.len() * dirty_log::PAGE_SIZE) as u64 {
+                            return Err(Error::IO(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!(
+                                    "full copy of {} bytes, the guest memory has {} pages",
+                                    data_len,
+                                    guest_pages.len()
+                                ),
+                            )));
                         }
+                        write_guest_pages(&guest_memory, &guest_pages, &data_buf)?;
 
                     }
                     else {
 
                         let migration_msg : MigrationMessage = bincode::deserialize(&data_buf).unwrap();
 
+                        itr = migration_msg.itr;
+
                         let dirty_pages_data = migration_msg.data;
 
                         println!("num dirty pages: {}", migration_msg.dirty_pages_len);
@@ -532,7 +833,68 @@ impl TryFrom<VMMConfig> for Vmm {
                             let page = &dirty_pages_data[i * 4096..(i + 1) * 4096];
                             guest_memory.write_slice(page, GuestAddress(page_addr)).unwrap();
                         }
+
+                        for (offset, hash) in migration_msg.chunk_refs.iter() {
+                            // Only chunks we advertised can be referenced, and they must still
+                            // be the same.
+                            let chunk_path = known_chunks.get(hash).ok_or_else(|| {
+                                Error::IO(io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    format!("unknown chunk {} referenced by the source", hash),
+                                ))
+                            })?;
+                            let chunk = fs::read(chunk_path).map_err(Error::IO)?;
+                            if !dedup::chunk_matches(hash, &chunk) {
+                                return Err(Error::IO(io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    format!("chunk {} is corrupted", chunk_path.display()),
+                                )));
+                            }
+                            let first = *offset as usize / dirty_log::PAGE_SIZE;
+                            let num_pages = chunk.len() / dirty_log::PAGE_SIZE;
+                            let chunk_pages =
+                                guest_pages.get(first..first + num_pages).ok_or_else(|| {
+                                    Error::IO(io::Error::new(
+                                        io::ErrorKind::InvalidData,
+                                        format!("chunk {} is past the guest memory", offset),
+                                    ))
+                                })?;
+                            write_guest_pages(&guest_memory, chunk_pages, &chunk)?;
+                        }
+                        if !migration_msg.chunk_refs.is_empty() {
+                            println!("num deduped chunks: {}", migration_msg.chunk_refs.len());
+                        }
+
                         cpu_live_migration_snapshot_path = migration_msg.cpu_state_file_path;
+                        if !migration_msg.cpu_state.is_empty() {
+                            cpu_live_migration_state = Some(migration_msg.cpu_state);
+                        }
+
+                        if let Some(disk_size) = migration_msg.disk_size {
+                            if disk_copy.is_none() {
+                                let path = &config
+                                    .block_config
+                                    .as_ref()
+                                    .ok_or(Error::MissingBlockDevice)?
+                                    .path;
+                                println!("receiving disk into {:?}", path);
+                                disk_copy = Some(
+                                    storage_migration::open_disk_copy(path, disk_size)
+                                        .map_err(Error::IO)?,
+                                );
+                                max_frame_len =
+                                    max_migration_frame_len(&guest_memory, Some(disk_size));
+                            }
+                            let disk_file = disk_copy.as_ref().ok_or(Error::MissingBlockDevice)?;
+                            storage_migration::apply_disk_blocks(
+                                disk_file,
+                                disk_size,
+                                &migration_msg.disk_blocks,
+                                &migration_msg.disk_data,
+                            )
+                            .map_err(Error::IO)?;
+                            println!("num disk blocks: {}", migration_msg.disk_blocks.len());
+                        }
 
                         // print sha hash of first dirty page
                         // if migration_msg.dirty_pages_len > 0 {
@@ -558,26 +920,46 @@ impl TryFrom<VMMConfig> for Vmm {
 
                     // write the guest mem to file for debugging
 
+                    last_acked_itr = Some(itr);
+                    if let Err(e) = migrator_conn.write_all(&itr.to_le_bytes()) {
+                        // Reconnecting tells the source about the ack it missed.
+                        println!("migration connection lost: {}, reconnecting", e);
+                        migrator_conn =
+                            connect_migration(&addr, last_acked_itr, &known_hashes, &host_cpu)?;
+                    }
 
                     if done {
+                        if let Some(disk_file) = disk_copy.take() {
+                            disk_file.sync_all().map_err(Error::IO)?;
+                        }
                         break;
                     }
-
-                    itr += 1;
                 }
 
                 println!("restored memory");
 
                 
-                println!("restoring cpu from: {}", cpu_live_migration_snapshot_path);
-
-                vmstate = Self::restore_cpu(&cpu_live_migration_snapshot_path[..]);
+                vmstate = match cpu_live_migration_state {
+                    Some(cpu_state) => Self::vm_state_from_bytes(&cpu_state)?,
+                    None => {
+                        println!("restoring cpu from: {}", cpu_live_migration_snapshot_path);
+                        Self::restore_cpu(&cpu_live_migration_snapshot_path[..])?
+                    }
+                };
 
 
                 start_migrating_thread = false;
 
             }
 
+            // Kept as restored, so that migrating the paused guest doesn't correct its clock
+            // twice.
+            if config.restore_config.paused {
+                paused_state = Some(Self::vm_state_to_bytes(&vmstate));
+            }
+
+            Self::correct_guest_clock(&mut vmstate, &config.restore_config);
+
             KvmVm::from_state(
                 &kvm,
                 vmstate,
@@ -604,7 +986,20 @@ impl TryFrom<VMMConfig> for Vmm {
             num_vcpus: config.vcpu_config.num as u64,
             is_resume: is_resume,
             dedup_mgr: dedup_mgr,
-            start_migration_thread: start_migrating_thread
+            start_migration_thread: start_migrating_thread,
+            migration_config: config.migration_config,
+            dirty_log_in_use: Arc::new(AtomicBool::new(false)),
+            ha_dirty_log: None,
+            ha_config: config.ha_config,
+            checkpoint_tx: None,
+            ha_epoch: 0,
+            memfd,
+            live_update_conn: None,
+            paused_state: Arc::new(Mutex::new(paused_state)),
+            dirty_log_reads: Arc::new(AtomicU64::new(0)),
+            diff_parent,
+            background_snapshot_running: Arc::new(AtomicBool::new(false)),
+            source_vm,
             // kvm: kvm
         };
 
@@ -616,6 +1011,8 @@ impl TryFrom<VMMConfig> for Vmm {
         vmm.add_i8042_device()?;
         #[cfg(target_arch = "aarch64")]
         vmm.add_rtc_device();
+        #[cfg(target_arch = "x86_64")]
+        vmm.add_identity_device(&identity);
 
         // Adding the virtio devices. We'll come up with a cleaner abstraction for `Env`.
         if let Some(cfg) = config.block_config.as_ref() {
@@ -659,24 +1056,17 @@ impl Vmm {
         // .map_err(|_| Error::Serial(std::io::Error::last_os_error()))?;
         Ok(())
     }
-    ///
-    pub fn save_snapshot(
-        &mut self,
-        cpu_snapshot_path: String,
-        memory_snapshot_path: String,
-        resume: bool,
-    ) {
+    /// Snapshots the guest, which keeps running afterwards if `resume`, or if the snapshot failed.
+    pub fn save_snapshot(&mut self, snapshot_path: String, resume: bool) -> SnapshotResult<()> {
         if resume {
-            // self.vm.snapshot_and_resume(cpu_snapshot_path, memory_snapshot_path);
-            self.snapshot_and_resume(&cpu_snapshot_path[..], &memory_snapshot_path[..]);
+            self.snapshot_and_resume(&snapshot_path[..])
         } else {
-            // self.vm.snapshot_and_pause(cpu_snapshot_path, memory_snapshot_path);
-            self.snapshot_and_pause(&cpu_snapshot_path[..], &memory_snapshot_path[..]);
+            self.snapshot_and_pause(&snapshot_path[..])
         }
     }
 
-    pub fn snapshot_and_resume(&mut self, snapshot_path: &str, memory_snapshot_path: &str) {
-        // NOTE: 1. Kicking all the vcpus out of their run loop in suspending state
+    // Kicks all the vcpus out of their run loop, and waits for them to park in suspending state.
+    fn suspend_vcpus(&mut self) {
         self.vm
             .vcpu_run_state
             .set_and_notify(VmRunState::Suspending);
@@ -689,20 +1079,224 @@ impl Vmm {
             r.recv().unwrap();
             println!("Received message from {i}th cpu");
         }
+    }
 
-        let vm_state = self.vm.save_state().unwrap();
+    pub fn snapshot_and_resume(&mut self, snapshot_path: &str) -> SnapshotResult<()> {
+        // NOTE: 1. Kicking all the vcpus out of their run loop in suspending state
+        self.suspend_vcpus();
+
+        // Whatever was dirtied so far is part of this snapshot, the diffs on top of it start from
+        // a clean log. There are none if the log belongs to someone else.
+        self.diff_parent = None;
+        let dirty_log = DirtyLogOwner::take(&self.dirty_log_in_use);
+        let result = if dirty_log.is_some() {
+            self.take_dirty_pages().map(|_| ())
+        } else {
+            Ok(())
+        }
+        .and_then(|()| {
+            self.snapshot_suspended(snapshot_path, true)?;
+            if dirty_log.is_some() {
+                self.diff_parent = Some((snapshot_path.to_string(), self.dirty_log_reads()));
+            }
+            Ok(())
+        });
+        drop(dirty_log);
+
+        // NOTE: 4. Set and notify all vcpus to Running state so that they breaks out of their wait loop and resumes
+        self.vm.vcpu_run_state.set_and_notify(VmRunState::Running);
+        // self.vm.run(Some(GuestAddress(0)), true).unwrap();
+        result
+    }
+
+    /// Snapshots only the pages dirtied since the previous snapshot taken with
+    /// `snapshot_and_resume` or `diff_snapshot`, which the new one is based on. The guest keeps
+    /// running.
+    pub fn diff_snapshot(&mut self, snapshot_path: &str) -> SnapshotResult<()> {
+        self.suspend_vcpus();
+        let result = self.diff_snapshot_suspended(snapshot_path);
+        self.vm.vcpu_run_state.set_and_notify(VmRunState::Running);
+        result
+    }
+
+    fn diff_snapshot_suspended(&mut self, snapshot_path: &str) -> SnapshotResult<()> {
+        // Held from checking that the log wasn't read since the parent until it's read here.
+        let _dirty_log =
+            DirtyLogOwner::take(&self.dirty_log_in_use).ok_or(SnapshotError::NoDiffParent)?;
+        let parent = match self.diff_parent.as_ref() {
+            Some((parent, reads)) if *reads == self.dirty_log_reads() => parent.clone(),
+            _ => return Err(SnapshotError::NoDiffParent),
+        };
+        // Deleted from the catalog since.
+        if !Path::new(&parent).exists() && self.dedup_mgr.file_chunks(&parent).is_none() {
+            return Err(SnapshotError::NoDiffParent);
+        }
+        // Restoring this snapshot, or any other based on the same chain, goes through every
+        // ancestor, none of them can be replaced.
+        // The memory of the full snapshot isn't read, and ancestors left in the dedup store stay
+        // there.
+        let ancestors = Self::open_snapshot_chain(&parent, |path| {
+            Ok(lazy_restore::open_snapshot(path, &self.dedup_mgr)?.1)
+        })?
+        .ok_or(SnapshotError::NoDiffParent)?;
+        if ancestors
+            .iter()
+            .any(|(ancestor, _)| Path::new(ancestor) == Path::new(snapshot_path))
+        {
+            return Err(SnapshotError::OverwritesParent);
+        }
+
+        // The log is consumed from here on, so the parent is lost unless this snapshot succeeds.
+        self.diff_parent = None;
+        let pages = self.take_dirty_pages()?;
+        let vm_state = self.vm.save_state().map_err(SnapshotError::Vm)?;
+        Self::write_snapshot(snapshot_path, &self.snapshot_metadata(), &vm_state, |writer| {
+            writer.add_diff(&parent, &self.guest_memory, &pages)
+        })?;
+        self.dedup_mgr.save_file(snapshot_path);
+        println!("diff snapshot of {} pages done", pages.len());
+        Self::catalog_snapshot(snapshot_path, &self.source_vm);
+
+        self.diff_parent = Some((snapshot_path.to_string(), self.dirty_log_reads()));
+        Ok(())
+    }
+
+    /// Snapshots the guest with the vCPUs stopped only for their state to be saved, the memory
+    /// being written while the guest runs, see `background_snapshot`. The outcome goes to
+    /// `result_tx` once the snapshot is on disk. When the memory can't be write protected, falls
+    /// back to `snapshot_and_resume`.
+    pub fn background_snapshot(
+        &mut self,
+        snapshot_path: &str,
+        result_tx: Option<Sender<SnapshotResult<()>>>,
+    ) {
+        let mut protection = match MemoryProtection::new(&self.guest_memory) {
+            Ok(protection) => protection,
+            Err(e) => {
+                println!(
+                    "background snapshot not possible ({:?}), stopping the guest for it",
+                    e
+                );
+                report_snapshot(result_tx, self.snapshot_and_resume(snapshot_path));
+                return;
+            }
+        };
+
+        self.suspend_vcpus();
+        let vm_state = self
+            .vm
+            .save_state()
+            .map_err(SnapshotError::Vm)
+            .and_then(|vm_state| protection.protect().map(|()| vm_state));
+        self.vm.vcpu_run_state.set_and_notify(VmRunState::Running);
+        let vm_state = match vm_state {
+            Ok(vm_state) => vm_state,
+            Err(e) => {
+                report_snapshot(result_tx, Err(e));
+                return;
+            }
+        };
+
+        // The dirty log is left alone, so diff snapshots go on from the previous parent, unless
+        // this one replaces it.
+        let replaces_parent = matches!(
+            self.diff_parent.as_ref(),
+            Some((parent, _)) if Path::new(parent) == Path::new(snapshot_path)
+        );
+        if replaces_parent {
+            self.diff_parent = None;
+        }
+        self.background_snapshot_running.store(true, Ordering::Release);
+        let running = self.background_snapshot_running.clone();
+        let snapshot_path = snapshot_path.to_string();
+        let guest_memory = self.guest_memory.clone();
+        let dedup_mgr = self.dedup_mgr.clone();
+        let metadata = self.snapshot_metadata();
+        let source_vm = self.source_vm.clone();
+        let _ = thread::spawn(move || {
+            let result = Self::write_snapshot(&snapshot_path, &metadata, &vm_state, |writer| {
+                writer.add_memory_with(&guest_memory, |out| protection.save(out))
+            });
+            drop(protection);
+            if result.is_ok() {
+                dedup_mgr.save_file(&snapshot_path);
+                println!("background snapshot done");
+                Self::catalog_snapshot(&snapshot_path, &source_vm);
+            }
+            running.store(false, Ordering::Release);
+            report_snapshot(result_tx, result);
+        });
+    }
+
+    // Describes the VM being snapshotted, for restore to check it's configured the same way.
+    fn snapshot_metadata(&self) -> SnapshotMetadata {
+        let mem_size = self.guest_memory.iter().map(|region| region.len()).sum();
+        // Added in this order by `try_from`.
+        let devices = self
+            .block_devices
+            .iter()
+            .map(|_| "block".to_string())
+            .chain(self.net_devices.iter().map(|_| "net".to_string()))
+            .collect();
+        SnapshotMetadata::new(mem_size, self.vm.config.num_vcpus, devices)
+    }
+
+    /// Writes the guest memory and the registers of the vCPUs to `path` as an ELF core file, see
+    /// `core_dump`. The guest is stopped meanwhile.
+    pub fn dump_guest_core(&mut self, path: &str) -> SnapshotResult<()> {
+        self.suspend_vcpus();
+        let result = self
+            .vm
+            .save_state()
+            .map_err(SnapshotError::Vm)
+            .and_then(|vm_state| core_dump::write_core_file(path, &self.guest_memory, &vm_state));
+        self.vm.vcpu_run_state.set_and_notify(VmRunState::Running);
+        if result.is_ok() {
+            println!("guest core dumped to {}", path);
+        }
+        result
+    }
+
+    fn dirty_log_reads(&self) -> u64 {
+        self.dirty_log_reads.load(Ordering::Acquire)
+    }
+
+    // Reads and clears the pages dirtied since the last call, by the vcpus or the devices. The
+    // caller owns the dirty log.
+    fn take_dirty_pages(&mut self) -> SnapshotResult<Vec<usize>> {
+        let mut pages = dirty_log::read_dirty_log(&self.vm.vm_fd(), &self.guest_memory)
+            .map_err(SnapshotError::DirtyLog)?;
+        pages.extend(collect_vmm_dirty_pages(&self.guest_memory));
+        pages.sort_unstable();
+        pages.dedup();
+        Ok(pages)
+    }
+
+    // Snapshots the guest once its vcpus are suspended.
+    fn snapshot_suspended(&mut self, snapshot_path: &str, save_mem: bool) -> SnapshotResult<()> {
+        let vm_state = self.vm.save_state().map_err(SnapshotError::Vm)?;
         Self::take_snapshot(
             snapshot_path,
-            memory_snapshot_path,
+            &self.snapshot_metadata(),
             &vm_state,
             &self.guest_memory,
             &self.dedup_mgr,
-            true
-        );
+            save_mem,
+        )?;
+        // Without the memory, the snapshot only carries the vCPUs of a migrating guest.
+        if save_mem {
+            Self::catalog_snapshot(snapshot_path, &self.source_vm);
+        }
+        Ok(())
+    }
 
-        // NOTE: 4. Set and notify all vcpus to Running state so that they breaks out of their wait loop and resumes
-        self.vm.vcpu_run_state.set_and_notify(VmRunState::Running);
-        // self.vm.run(Some(GuestAddress(0)), true).unwrap();
+    // Adds a snapshot that made it to disk to the catalog. The snapshot is good without it, so
+    // failing to catalog it doesn't fail the snapshot.
+    fn catalog_snapshot(snapshot_path: &str, source_vm: &SourceVm) {
+        match Self::snapshot_catalog().record(snapshot_path, source_vm) {
+            Ok(entry) => println!("snapshot {} cataloged as {}", snapshot_path, entry.id),
+            Err(e) => println!("failed to catalog snapshot {}: {:?}", snapshot_path, e),
+        }
     }
 
 
@@ -744,13 +1338,28 @@ impl Vmm {
     // }
 
 
-    pub fn snapshot_and_pause(&mut self, snapshot_path: &str, memory_snapshot_path: &str) {
-        // NOTE: 1. Kicking all the vcpus out of their run loop in suspending state
-        self.vm.vcpu_run_state.set_and_notify(VmRunState::Exiting);
-        for handle in self.vm.vcpu_handles.iter() {
-            let _ = handle.kill(SIGRTMIN() + 0);
+    pub fn snapshot_and_pause(&mut self, snapshot_path: &str) -> SnapshotResult<()> {
+        // The vcpus are only told to exit once the snapshot is safely on disk, and keep running
+        // otherwise.
+        self.snapshot_and_suspend(snapshot_path)?;
+        self.exit_vcpus();
+        Ok(())
+    }
+
+    // Snapshots the guest and leaves its vcpus suspended, or running if the snapshot failed.
+    fn snapshot_and_suspend(&mut self, snapshot_path: &str) -> SnapshotResult<()> {
+        self.suspend_vcpus();
+
+        if let Err(e) = self.snapshot_suspended(snapshot_path, false) {
+            self.vm.vcpu_run_state.set_and_notify(VmRunState::Running);
+            return Err(e);
         }
+        Ok(())
+    }
 
+    // Tells the suspended vcpus to exit, and the VMM along with them.
+    fn exit_vcpus(&mut self) {
+        self.vm.vcpu_run_state.set_and_notify(VmRunState::Exiting);
         for i in 0..self.vm.config.num_vcpus {
             let r = self.vm.vcpu_rx.as_ref().unwrap();
             match r.recv() {
@@ -762,63 +1371,366 @@ impl Vmm {
             println!("Received message from {i}th cpu");
         }
 
-        let vm_state = self.vm.save_state().unwrap();
-        Self::take_snapshot(
-            snapshot_path,
-            memory_snapshot_path,
-            &vm_state,
-            &self.guest_memory,
-            &self.dedup_mgr,
-            false
-        );
-
         // Now, make the vmm exit out of run loop
         let _ = self.vm.exit_handler.kick();
     }
 
+    /// Writes the vm state, and the guest memory if `save_mem`, to the snapshot container at
+    /// `snapshot_path`.
     pub fn take_snapshot(
         snapshot_path: &str,
-        memory_path: &str,
+        metadata: &SnapshotMetadata,
         vm_state: &VmState,
         guest_memory: &GuestMemoryMmap,
         dedup_mgr: &DedupManager,
         save_mem: bool
-    ) {
-        Self::save_cpu(snapshot_path, vm_state);
+    ) -> SnapshotResult<()> {
+        Self::write_snapshot(snapshot_path, metadata, vm_state, |writer| {
+            if save_mem {
+                writer.add_memory(guest_memory)?;
+            }
+            Ok(())
+        })?;
 
         if save_mem {
-            println!("Dedup saving memory");
-            // std::fs::copy("memory.txt", memory_path);
-            let mut writer = File::options()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(memory_path)
-                .unwrap();
-            SnapshotMemory::dump(guest_memory, &mut writer);
-            writer.flush().unwrap();
-            writer.sync_all().unwrap();
-            dedup_mgr.save_file(memory_path);
-            println!("deduped memory snapshot done");
+            println!("Dedup saving snapshot");
+            dedup_mgr.save_file(snapshot_path);
+            println!("deduped snapshot done");
+        }
+        Ok(())
+    }
+
+    // Writes a snapshot of `vm_state`, with the memory added by `add_memory`.
+    fn write_snapshot<F>(
+        snapshot_path: &str,
+        metadata: &SnapshotMetadata,
+        vm_state: &VmState,
+        add_memory: F,
+    ) -> SnapshotResult<()>
+    where
+        F: FnOnce(&mut SnapshotWriter) -> SnapshotResult<()>,
+    {
+        let mut writer = SnapshotWriter::create(snapshot_path)?;
+        let metadata = serde_json::to_vec(metadata).map_err(SnapshotError::Metadata)?;
+        writer.add_section(SectionKind::Metadata, &metadata)?;
+        writer.add_section(SectionKind::VmState, &version_map::vm_state_to_bytes(vm_state)?)?;
+        add_memory(&mut writer)?;
+        writer.finish()
+    }
+
+    // Maps the guest memory of the snapshot at `snapshot_path`, layering the diff snapshots of
+    // the chain over the full snapshot it starts from. `None` if the snapshot is a legacy one,
+    // or has no memory.
+    pub(crate) fn restore_snapshot_memory(
+        snapshot_path: &str,
+        dedup_mgr: &DedupManager,
+    ) -> SnapshotResult<Option<GuestMemoryMmap>> {
+        let chain = Self::open_snapshot_chain(snapshot_path, |path| {
+            if !Path::new(path).exists() && dedup_mgr.file_chunks(path).is_some() {
+                dedup_mgr.load_file(path);
+            }
+            SnapshotReader::open(path)
+        })?;
+        match chain {
+            // Tracking the dirty pages of the devices as well, for the next diff snapshots.
+            Some(chain) => Self::chain_memory(chain, true),
+            None => Ok(None),
+        }
+    }
+
+    // Opens the snapshot container at `snapshot_path` and each of its ancestors with `open`, from
+    // the newest to the full snapshot they are all based on. None for a raw cpu snapshot.
+    pub(crate) fn open_snapshot_chain<F>(
+        snapshot_path: &str,
+        open: F,
+    ) -> SnapshotResult<Option<Vec<(String, SnapshotReader)>>>
+    where
+        F: Fn(&str) -> SnapshotResult<Option<SnapshotReader>>,
+    {
+        let snapshot = match open(snapshot_path)? {
+            Some(snapshot) => snapshot,
+            None => return Ok(None),
+        };
+        let mut chain = vec![(snapshot_path.to_string(), snapshot)];
+        while let Some(parent) = chain.last().unwrap().1.parent()? {
+            if chain.iter().any(|(path, _)| *path == parent) {
+                return Err(SnapshotError::Corrupted(format!(
+                    "snapshot chain of {} loops at {}",
+                    snapshot_path, parent
+                )));
+            }
+            let parent_snapshot = open(&parent)?.ok_or_else(|| {
+                SnapshotError::Corrupted(format!("parent {} is not a snapshot", parent))
+            })?;
+            chain.push((parent, parent_snapshot));
+        }
+        Ok(Some(chain))
+    }
+
+    // Maps the guest memory of a chain opened by `open_snapshot_chain`. `None` if the full
+    // snapshot has no memory.
+    pub(crate) fn chain_memory(
+        mut chain: Vec<(String, SnapshotReader)>,
+        track_dirty_pages: bool,
+    ) -> SnapshotResult<Option<GuestMemoryMmap>> {
+        let (_, base) = chain.pop().unwrap();
+        let guest_memory = match base.restore_memory(track_dirty_pages)? {
+            Some(guest_memory) => guest_memory,
+            None => return Ok(None),
+        };
+        for (_, diff) in chain.iter().rev() {
+            diff.apply_diff(&guest_memory)?;
         }
+        println!("restored memory from {} snapshot(s)", chain.len() + 1);
+        Ok(Some(guest_memory))
     }
 
-    ///
-    pub fn save_cpu(snapshot_path: &str, vm_state: &VmState) {
-        let mut snapshot_file = File::create(snapshot_path).unwrap();
-        let mut mem = Vec::new();
-        let version_map = VersionMap::new();
-        vm_state.serialize(&mut mem, &version_map, 1).unwrap();
-        snapshot_file.write_all(&mem).unwrap();
+    // Restores the snapshot at `snapshot_path` without reading its guest memory up front, see
+    // `lazy_restore`. Legacy snapshots have their memory in `memory_snapshot_path`. Also returns
+    // whether the memory came from a snapshot container, which diff snapshots can be based on.
+    fn restore_snapshot_lazy(
+        snapshot_path: &str,
+        memory_snapshot_path: &str,
+        target: &RestoreTarget,
+        dedup_mgr: &DedupManager,
+        prefetch: bool,
+    ) -> Result<(VmState, GuestMemoryMmap, bool)> {
+        let mem_size = target.mem_size as usize;
+        let (file, snapshot) = lazy_restore::open_snapshot(snapshot_path, dedup_mgr)?;
+        let mut snapshot = match snapshot {
+            Some(snapshot) => snapshot,
+            None => {
+                let mut bytes = vec![0u8; file.size().map_err(Error::IO)? as usize];
+                file.read_exact_at(&mut bytes, 0).map_err(Error::IO)?;
+                let vm_state = version_map::legacy_vm_state_from_bytes(&bytes)
+                    .map_err(SnapshotError::from)?;
+                target.check_vm_state(&vm_state)?;
+                let memory_file =
+                    LazyFile::open(memory_snapshot_path, dedup_mgr).map_err(Error::IO)?;
+                target.check_memory_size(memory_file.size().map_err(Error::IO)?)?;
+                let guest_memory = Self::lazy_guest_memory(
+                    Arc::new(memory_file),
+                    &get_memory_state(mem_size),
+                    prefetch,
+                )?;
+                return Ok((vm_state, guest_memory, false));
+            }
+        };
+        if let Some(metadata) = snapshot.metadata()? {
+            target.check_metadata(&metadata)?;
+        }
+        let vm_state = snapshot
+            .read_section(SectionKind::VmState)?
+            .ok_or(SnapshotError::MissingSection(SectionKind::VmState))?;
+        let vm_state = version_map::vm_state_from_bytes(&vm_state).map_err(SnapshotError::from)?;
+        target.check_vm_state(&vm_state)?;
+
+        let mut root_file = file;
+        let mut chain = vec![snapshot_path.to_string()];
+        let mut diffs = vec![];
+        while let Some(parent) = snapshot.parent()? {
+            if chain.contains(&parent) {
+                return Err(SnapshotError::Corrupted(format!(
+                    "snapshot chain of {} loops at {}",
+                    snapshot_path, parent
+                ))
+                .into());
+            }
+            let (parent_file, parent_snapshot) = lazy_restore::open_snapshot(&parent, dedup_mgr)?;
+            let parent_snapshot = parent_snapshot.ok_or_else(|| {
+                SnapshotError::Corrupted(format!("parent {} is not a snapshot", parent))
+            })?;
+            diffs.push(snapshot);
+            chain.push(parent);
+            snapshot = parent_snapshot;
+            root_file = parent_file;
+        }
+
+        let (guest_memory, is_container) = match snapshot.memory_layout()? {
+            // Encrypted memory can't be copied in as it's touched, it's decrypted up front.
+            Some(_) if snapshot.is_encrypted() => {
+                println!("snapshot is encrypted, restoring its memory up front");
+                root_file.load_all().map_err(Error::IO)?;
+                let guest_memory = snapshot
+                    .restore_memory(true)?
+                    .ok_or(SnapshotError::MissingSection(SectionKind::MemoryData))?;
+                (guest_memory, true)
+            }
+            Some(memory_state) => (
+                Self::lazy_guest_memory(root_file, &memory_state, prefetch)?,
+                true,
+            ),
+            // Snapshot without memory, which is in the legacy memory file.
+            None => {
+                let memory_file =
+                    LazyFile::open(memory_snapshot_path, dedup_mgr).map_err(Error::IO)?;
+                target.check_memory_size(memory_file.size().map_err(Error::IO)?)?;
+                let memory_state = get_memory_state(mem_size);
+                (
+                    Self::lazy_guest_memory(Arc::new(memory_file), &memory_state, prefetch)?,
+                    false,
+                )
+            }
+        };
+        for diff in diffs.iter().rev() {
+            diff.apply_diff(&guest_memory)?;
+        }
+        println!("restoring memory lazily from {} snapshot(s)", chain.len());
+        Ok((vm_state, guest_memory, is_container))
     }
 
-    /// restore cpu
-    pub fn restore_cpu(snapshot_path: &str) -> VmState {
-        let mut snapshot_file = File::open(snapshot_path).unwrap();
-        let version_map = VersionMap::new();
-        let mut bytes = Vec::new();
-        snapshot_file.read_to_end(&mut bytes).unwrap();
-        VmState::deserialize(&mut bytes.as_slice(), &version_map, 1).unwrap()
+    // Maps empty guest memory laid out as `memory_state`, filled in from `file` as it's touched.
+    fn lazy_guest_memory(
+        file: Arc<LazyFile>,
+        memory_state: &GuestMemoryState,
+        prefetch: bool,
+    ) -> Result<GuestMemoryMmap> {
+        let regions: Vec<_> = memory_state
+            .regions
+            .iter()
+            .map(|region| (None, GuestAddress(region.base_address), region.size))
+            .collect();
+        let guest_memory = vm_memory::create_guest_memory(&regions, true)
+            .map_err(|e| Error::Memory(MemoryError::VmMemory(e)))?;
+        lazy_restore::start(&guest_memory, memory_state, file, prefetch)?;
+        Ok(guest_memory)
+    }
+
+    // The dedup database shared by the snapshots and migrations of this host.
+    pub(crate) fn dedup_manager() -> DedupManager {
+        DedupManager {
+            CHUNK_SIZE,
+            DATABASE_PATH: DATABASE_PATH.to_string(),
+            STATE_PATH: STATE_PATH.to_string(),
+            MAP1_PATH: MAP1_PATH.to_string(),
+            MAP2_PATH: MAP2_PATH.to_string(),
+        }
+    }
+
+    /// Catalog of the snapshots taken by the VMMs started from this directory, see `catalog`.
+    pub fn snapshot_catalog() -> Catalog {
+        Catalog::new(CATALOG_PATH)
+    }
+
+    /// Deletes the cataloged snapshot `id`, along with its chunks in the dedup database.
+    pub fn delete_snapshot(id: &str) -> catalog::Result<catalog::CatalogEntry> {
+        Self::snapshot_catalog().delete(id, &Self::dedup_manager())
+    }
+
+    // What a snapshot restored with `config` must have been taken of.
+    #[cfg_attr(target_arch = "aarch64", allow(unused_variables))]
+    fn restore_target(kvm: &Kvm, config: &VMMConfig) -> Result<RestoreTarget> {
+        let mut devices = vec![];
+        if config.block_config.is_some() {
+            devices.push("block".to_string());
+        }
+        if config.net_config.is_some() {
+            devices.push("net".to_string());
+        }
+        #[cfg(target_arch = "x86_64")]
+        let host_cpu = CpuProfile::host(kvm)?;
+        #[cfg(target_arch = "aarch64")]
+        let host_cpu = CpuProfile::default();
+        Ok(RestoreTarget {
+            mem_size: (config.memory_config.size_mib as u64) << 20,
+            num_vcpus: config.vcpu_config.num,
+            devices,
+            host_cpu,
+        })
+    }
+
+    /// Reads the vm state of a snapshot container, or of a raw cpu snapshot.
+    pub fn restore_cpu(snapshot_path: &str) -> SnapshotResult<VmState> {
+        match SnapshotReader::open(snapshot_path)? {
+            Some(snapshot) => {
+                let bytes = snapshot
+                    .read_section(SectionKind::VmState)?
+                    .ok_or(SnapshotError::MissingSection(SectionKind::VmState))?;
+                Ok(version_map::vm_state_from_bytes(&bytes)?)
+            }
+            None => {
+                let bytes = fs::read(snapshot_path)?;
+                Ok(version_map::legacy_vm_state_from_bytes(&bytes)?)
+            }
+        }
+    }
+
+    /// Serializes the vm state, in the same format as the vm state section of snapshots.
+    pub fn vm_state_to_bytes(vm_state: &VmState) -> Vec<u8> {
+        version_map::vm_state_to_bytes(vm_state).unwrap()
+    }
+
+    /// Deserializes a vm state produced by `vm_state_to_bytes`, possibly by an older VMM. The
+    /// bytes usually come from another host, and are rejected rather than trusted.
+    pub fn vm_state_from_bytes(bytes: &[u8]) -> SnapshotResult<VmState> {
+        Ok(version_map::vm_state_from_bytes(bytes)?)
+    }
+
+    // Takes a checkpoint of the guest for the HA standby, see `ha`. The vcpus are only stopped
+    // for the time it takes to copy the dirty pages out of the guest memory.
+    fn checkpoint(&mut self) {
+        let checkpoint_tx = match self.checkpoint_tx.as_ref() {
+            Some(tx) => tx.clone(),
+            None => return,
+        };
+        self.suspend_vcpus();
+
+        let checkpoint = self.collect_checkpoint();
+        if checkpoint.is_ok() {
+            // Frames sent by the guest so far can leave once this checkpoint is acknowledged.
+            for net in self.net_devices.iter() {
+                net.lock().unwrap().tx_buffer().seal(self.ha_epoch);
+            }
+        }
+
+        self.vm.vcpu_run_state.set_and_notify(VmRunState::Running);
+
+        match checkpoint {
+            Ok(checkpoint) => {
+                self.ha_epoch += 1;
+                // The replication thread is gone if the standby was lost, nothing left to do
+                // then.
+                let _ = checkpoint_tx.send(checkpoint);
+            }
+            Err(e) => {
+                // The dirty log may have been cleared already, the next checkpoint would miss
+                // pages. Hanging up on the replication thread has it release the network
+                // frames, and the guest runs on unprotected.
+                eprintln!("HA: checkpoint failed: {:?}, no longer replicating", e);
+                self.checkpoint_tx = None;
+                self.ha_dirty_log = None;
+            }
+        }
+    }
+
+    // Collects the state of the suspended guest for the next checkpoint.
+    fn collect_checkpoint(&mut self) -> Result<Checkpoint> {
+        let vm_state = self.vm.save_state().map_err(Error::Vm)?;
+
+        // The device emulation runs on this thread, so nothing touches the memory behind KVM's
+        // back while we're here.
+        let mut pages = collect_dirty_pages(&self.vm.vm_fd(), &self.guest_memory)?;
+        pages.extend(collect_vmm_dirty_pages(&self.guest_memory));
+        if self.ha_epoch == 0 {
+            // The standby starts from an empty memory.
+            pages = dirty_log::guest_pages(&self.guest_memory);
+        }
+        pages.sort_unstable();
+        pages.dedup();
+
+        let mut data = vec![0u8; pages.len() * dirty_log::PAGE_SIZE];
+        for (page, buf) in pages.iter().zip(data.chunks_exact_mut(dirty_log::PAGE_SIZE)) {
+            self.guest_memory
+                .read_slice(buf, GuestAddress((page * dirty_log::PAGE_SIZE) as u64))
+                .map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+        }
+
+        Ok(Checkpoint {
+            epoch: self.ha_epoch,
+            pages: pages.into_iter().map(|page| page as u64).collect(),
+            data,
+            vm_state: Self::vm_state_to_bytes(&vm_state),
+        })
     }
 
     /// Run the VMM.
@@ -838,21 +1750,38 @@ impl Vmm {
         
         println!("resuming..? {}", self.is_resume);
         // println!("FLOW: Starting VM");
-        self.vm
-            .run(Some(kernel_load_addr), self.is_resume)
-            .map_err(Error::Vm)?;
-
-        
+        if self.paused_state.lock().unwrap().is_some() {
+            println!("VM restored paused, waiting for a resume or a migration");
+        } else {
+            self.start_vcpus(kernel_load_addr)?;
+        }
 
         let (migration_save_done_tx, migration_save_done_rx) : (Sender<i32>, Receiver<i32>) = mpsc::channel();
 
         let (migration_save_do_tx, migration_save_do_rx) : (Sender<i32>, Receiver<i32>) = mpsc::channel();
 
-        let (exit_vmm_tx, exit_vmm_rx) : (Sender<i32>, Receiver<i32>) = mpsc::channel();
-
-
-        if self.start_migration_thread {
-            self.live_migrate(migration_save_do_tx, migration_save_done_rx, exit_vmm_tx);
+        let migration_status: SharedMigrationStatus =
+            Arc::new((Mutex::new(MigrationStatus::Idle), Condvar::new()));
+
+
+        let ha_primary = self
+            .ha_config
+            .clone()
+            .filter(|cfg| cfg.role == HaRole::Primary);
+        if let Some(ha_cfg) = ha_primary {
+            // Replication owns the dirty log, the guest can't be migrated at the same time.
+            let dirty_log =
+                DirtyLogOwner::take(&self.dirty_log_in_use).ok_or(Error::MigrationInProgress)?;
+            self.ha_dirty_log = Some(dirty_log);
+            let (checkpoint_tx, checkpoint_rx) = mpsc::channel();
+            self.checkpoint_tx = Some(checkpoint_tx);
+            let rpc_controller = self.rpc_controller.clone();
+            let net_devices = self.net_devices.clone();
+            let _ = thread::spawn(move || {
+                ha::run_primary(ha_cfg, rpc_controller, checkpoint_rx, net_devices)
+            });
+        } else if self.start_migration_thread {
+            self.live_migrate(migration_save_do_tx, migration_save_done_rx, migration_status.clone())?;
         }
 
 
@@ -865,20 +1794,96 @@ impl Vmm {
             }
             // NOTE: checking if need to snapshot or not
             let rpc = self.rpc_controller.clone();
-            let rpc_controller = rpc.lock().unwrap();
+            let mut rpc_controller = rpc.lock().unwrap();
             let cpu_snapshot_path = rpc_controller.cpu_snapshot_path.clone();
-            let memory_snapshot_path = rpc_controller.memory_snapshot_path.clone();
+
+            let event = rpc_controller.which_event();
+            // Everything but resuming needs the vCPUs, which a paused guest doesn't have yet.
+            let is_snapshot = matches!(
+                event,
+                "PAUSE" | "RESUME" | "DIFF_SNAPSHOT" | "BACKGROUND_SNAPSHOT" | "CORE_DUMP"
+            );
+            if self.vm.vcpu_handles.is_empty() && (is_snapshot || event == "LIVE_UPDATE") {
+                println!("VM is paused, ignoring {}", event);
+                rpc_controller.pause_or_resume.store(0, Ordering::Relaxed);
+                if is_snapshot {
+                    rpc_controller.snapshot_done(Err(SnapshotError::NotRunning));
+                }
+            } else if is_snapshot && self.background_snapshot_running.load(Ordering::Acquire) {
+                // Snapshots are taken one at a time, and the guest must stay around until the
+                // background one is written.
+                println!("background snapshot in progress, ignoring {}", event);
+                rpc_controller.pause_or_resume.store(0, Ordering::Relaxed);
+                rpc_controller.snapshot_done(Err(SnapshotError::InProgress));
+            }
+
+            // Left pending while the guest is paused, the replication asks again anyway. Taken
+            // before the RPC request, which may stop the vcpus for good.
+            if self.exit_handler.keep_running()
+                && !self.vm.vcpu_handles.is_empty()
+                && rpc_controller
+                    .checkpoint_requested
+                    .swap(false, Ordering::AcqRel)
+            {
+                self.checkpoint();
+            }
 
             match rpc_controller.which_event() {
                 "PAUSE" => {
-                    self.save_snapshot(cpu_snapshot_path, memory_snapshot_path, false);
+                    // The vcpus of a guest being migrated stay suspended until the last iteration
+                    // is through, and run again if the migration fails.
+                    let migrating = *migration_status.0.lock().unwrap() == MigrationStatus::Running;
+                    let result = if migrating {
+                        self.snapshot_and_suspend(&cpu_snapshot_path)
+                    } else {
+                        self.save_snapshot(cpu_snapshot_path, false)
+                    };
+                    let paused = result.is_ok();
                     rpc_controller.pause_or_resume.store(0, Ordering::Relaxed);
-                    if self.start_migration_thread {
-                        exit_vmm_rx.recv().unwrap();
+                    rpc_controller.snapshot_done(result);
+                    if paused && migrating {
+                        let (lock, cvar) = &*migration_status;
+                        let status = cvar
+                            .wait_while(lock.lock().unwrap(), |status| {
+                                *status == MigrationStatus::Running
+                            })
+                            .unwrap();
+                        if *status == MigrationStatus::Done {
+                            drop(status);
+                            self.exit_vcpus();
+                        } else {
+                            println!("migration failed, resuming the VM");
+                            self.vm.vcpu_run_state.set_and_notify(VmRunState::Running);
+                        }
                     }
                 }
                 "RESUME" => {
-                    self.save_snapshot(cpu_snapshot_path, memory_snapshot_path, true);
+                    let result = self.save_snapshot(cpu_snapshot_path, true);
+                    rpc_controller.pause_or_resume.store(0, Ordering::Relaxed);
+                    rpc_controller.snapshot_done(result);
+                }
+                "DIFF_SNAPSHOT" => {
+                    let result = self.diff_snapshot(&cpu_snapshot_path);
+                    rpc_controller.pause_or_resume.store(0, Ordering::Relaxed);
+                    rpc_controller.snapshot_done(result);
+                }
+                "BACKGROUND_SNAPSHOT" => {
+                    let result_tx = rpc_controller.snapshot_result_tx.take();
+                    self.background_snapshot(&cpu_snapshot_path, result_tx);
+                    rpc_controller.pause_or_resume.store(0, Ordering::Relaxed);
+                }
+                "CORE_DUMP" => {
+                    let result = self.dump_guest_core(&cpu_snapshot_path);
+                    rpc_controller.pause_or_resume.store(0, Ordering::Relaxed);
+                    rpc_controller.snapshot_done(result);
+                }
+                "LIVE_UPDATE" => {
+                    let socket_path = rpc_controller.live_update_path.clone();
+                    self.live_update(&socket_path);
+                    rpc_controller.pause_or_resume.store(0, Ordering::Relaxed);
+                }
+                "RESUME_VM" => {
+                    self.resume_paused();
                     rpc_controller.pause_or_resume.store(0, Ordering::Relaxed);
                 }
                 _ => {
@@ -897,209 +1902,315 @@ impl Vmm {
         Ok(())
     }
 
-    fn live_migrate(&mut self, cpu_save_do: Sender<i32>, cpu_save_done: Receiver<i32>, exit_vmm: Sender<i32>) {
+    fn start_vcpus(&mut self, kernel_load_addr: GuestAddress) -> Result<()> {
+        self.vm
+            .run(Some(kernel_load_addr), self.is_resume)
+            .map_err(Error::Vm)?;
 
+        // The guest may have been moved to another host, while the network still sends its
+        // traffic to the old one until the guest talks again.
+        if self.is_resume {
+            self.announce_net_devices();
+        }
+        Ok(())
+    }
 
-        let mem_size = usize::try_from(self.guest_memory.last_addr().0 + 1).unwrap();
+    // Starts the vCPUs of a guest restored paused, unless it is being migrated away.
+    fn resume_paused(&mut self) {
+        if self.paused_state.lock().unwrap().take().is_none() {
+            println!("VM is not paused, or is being migrated");
+            return;
+        }
+        println!("resuming paused VM");
+        if let Err(e) = self.start_vcpus(GuestAddress(0)) {
+            eprintln!("Failed to resume the VM: {:?}", e);
+        }
+    }
 
-        let vm_fd = self.vm.vm_fd().clone();
+    /// Returns a handle that can sample the guest dirty rate while the VM is running.
+    pub fn dirty_rate_monitor(&self) -> DirtyRateMonitor {
+        DirtyRateMonitor::new(
+            self.vm.vm_fd(),
+            self.guest_memory.clone(),
+            self.dirty_log_in_use.clone(),
+            self.dirty_log_reads.clone(),
+        )
+    }
 
+    fn live_migrate(
+        &mut self,
+        cpu_save_do: Sender<i32>,
+        cpu_save_done: Receiver<i32>,
+        migration_status: SharedMigrationStatus,
+    ) -> Result<()> {
+        let vm_fd = self.vm.vm_fd();
         let guest_memory = self.guest_memory.clone();
 
-            let _ = std::thread::spawn(move || {
-
-                let addr = "0.0.0.0:".to_string() + &MIGRATION_PORT.to_string();
-
-                println!("Waiting for migration request on {}", addr);
-
-
-                let listener = TcpListener::bind(addr).unwrap();
-        
-                let mut migrate_host = listener.accept().unwrap().0;
-        
-                println!("Recevied migration request, Initializing migration...");
-
-
-                let mut buf = vec![0; mem_size];
-
-                let mut migration_itr = 0;
-
-                let mut cpu_snap_path = "";
-
-
-                let mut dirty_pages_in_iters: Vec<i64> = vec![];
-
-                let mut last_itr = -1;
-
-        
-                loop {
-                    
-                    let dirty_pages_bitmap = vm_fd.get_dirty_log(0, mem_size).unwrap();
-
-
-                    let mut dirty_pages = vec![];
+        let dirty_log_in_use = self.dirty_log_in_use.clone();
+        let paused_state = self.paused_state.clone();
+        let exit_handler = self.exit_handler.clone();
+
+        let cpu = self.cpu_profile();
+        let reconnect_timeout = Duration::from_millis(self.migration_config.reconnect_timeout_ms);
+
+        // The disk is only looked up here, so the block device handle doesn't have to be sent
+        // across. Its writes are tracked once a destination connects.
+        let mut disk = None;
+        if self.migration_config.storage {
+            let block = self
+                .block_devices
+                .get(0)
+                .ok_or(Error::MissingBlockDevice)?
+                .lock()
+                .unwrap();
+            disk = Some((block.file_path().to_path_buf(), block.dirty_blocks()));
+        }
 
-                    for page in 0..dirty_pages_bitmap.len() {
-                        let dirty_bit = dirty_pages_bitmap.get(page).unwrap();
-                        // if dirty bit is set increase count
-                        
-                        // iterate over set ones
-                        for i in 0..64 {
-                            if (*dirty_bit & (1 << i)) != 0 {
-                                dirty_pages.push(page * 64 + i);
+        let addr = "0.0.0.0:".to_string() + &MIGRATION_PORT.to_string();
+        let listener = TcpListener::bind(&addr).map_err(Error::IO)?;
+
+        let _ = std::thread::spawn(move || {
+            println!("Waiting for migration request on {}", addr);
+            // A failed migration leaves the guest here, waiting for the next destination.
+            let (stats, paused) = loop {
+                let accepted = listener.try_clone().and_then(|listener| {
+                    TcpTransport::accept(listener, cpu.clone(), reconnect_timeout)
+                });
+                let (transport, handshake) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        println!("failed to wait for a migration request: {}", e);
+                        return;
+                    }
+                };
+                // The dirty log is ours for the rest of the migration.
+                let dirty_log = match DirtyLogOwner::take(&dirty_log_in_use) {
+                    Some(dirty_log) => dirty_log,
+                    // Dropping the connection, the destination tries again.
+                    None => {
+                        println!("refusing migration: {:?}", Error::MigrationInProgress);
+                        continue;
+                    }
+                };
+                let disk_mirror = match &disk {
+                    Some((path, dirty_blocks)) => {
+                        match DiskMirror::new(path, dirty_blocks.clone()) {
+                            Ok(disk_mirror) => Some(disk_mirror),
+                            Err(e) => {
+                                println!("refusing migration, can't open the disk: {}", e);
+                                continue;
                             }
                         }
-
                     }
+                    None => None,
+                };
 
-                    // println!("migration itr: {}, dirty pages: {}",migration_itr, dirty_pages.len());
-
-                    assert!(guest_memory.regions.len() == 1);
-
-                    let region = guest_memory.regions.get(0).unwrap();
-                    
-                    if migration_itr == 0 {
-                        let start_addr = vm_memory::MemoryRegionAddress(0x0);
-                        region.read(&mut buf, start_addr).unwrap();
-
-                        let mut dpages = vec![];
-                        for i in 0..dirty_pages_bitmap.len() * 64 {
-                            dpages.push(i as u64);
+                println!("Recevied migration request, Initializing migration...");
+                set_migration_status(&migration_status, MigrationStatus::Running);
+
+                let guest = KvmGuest::new(vm_fd.clone(), guest_memory.clone());
+                let mut migration =
+                    PreCopy::new(guest, transport, disk_mirror, ConvergencePolicy::default())
+                        .known_chunks(handshake.known_chunks);
+                // The guest can't be resumed from here on if it's paused, unless the migration
+                // fails.
+                let cpu_state = paused_state.lock().unwrap().take();
+                if let Some(cpu_state) = cpu_state.clone() {
+                    println!("VM is paused, sending it in one go");
+                    migration = migration.paused(cpu_state);
+                }
+                match migration.run() {
+                    Ok(stats) => break (stats, cpu_state.is_some()),
+                    Err(e) => {
+                        println!("migration failed: {:?}", e);
+                        // The guest carries on here, and the dirty log is free again.
+                        drop(dirty_log);
+                        if cpu_state.is_some() {
+                            *paused_state.lock().unwrap() = cpu_state;
                         }
-
-                        dirty_pages_in_iters.push(dpages.len().try_into().unwrap());
-
-
-                        let send_data = &buf[..];
-
-                        // let migration_message = MigrationMessage {
-                        //     data: send_data.to_vec(),
-                        //     data_len: send_data.len(),
-                        //     dirty_pages_len: dpages.len(),
-                        //     dirty_pages: dpages,
-                        //     cpu_state_file_path: "".to_string(),
-                        //     is_last: false,
-                        //     init_migration: false,
-                        // };
-                        
-                        // DONT SERIALIZE IN FIRST ITR
-                        // let data = bincode::serialize(&migration_message).unwrap();
-
-
-                        let data_len = send_data.len() as u64;
-
-                        migrate_host.write_all(&data_len.to_le_bytes()).unwrap();
-
-                        // println!("Sending data of size: {}", send_data.len());
-                        migrate_host.write_all(&send_data).unwrap();
-                        // println!("itr: {}, sent data of size: {}", migration_itr, send_data.len());
-                        
+                        // Resumes the vcpus if they were stopped for the last iteration.
+                        set_migration_status(&migration_status, MigrationStatus::Idle);
                     }
-                    else {
-
-
-                        let mut send_data : Vec<u8> = vec![];
-                        let mut dpages = vec![];
-                        
-                        for page in dirty_pages.iter() {
-                            let start_addr = vm_memory::MemoryRegionAddress((page * 4096).try_into().unwrap());
-                            region.read(&mut buf[page * 4096..(page + 1) * 4096], start_addr).unwrap();
-                            for i in 0..4096 {
-                                send_data.push(buf.get((page * 4096 + i) as usize).unwrap().clone());
-                            }
-                            dpages.push(*page as u64);
-                        }
-
-                        dirty_pages_in_iters.push(dpages.len().try_into().unwrap());
-
-                        let migration_message = MigrationMessage {
-                            data_len: send_data.len(),
-                            data: send_data,
-                            dirty_pages_len: dpages.len(),
-                            dirty_pages: dpages.clone(),
-                            cpu_state_file_path: cpu_snap_path.to_string(),
-                            is_last: (migration_itr == last_itr),
-                            init_migration: false,
-                        };
-
-
-                        let data = bincode::serialize(&migration_message).unwrap();
-
-                        // println!("Created migration message...");
-
-                        let data_len = (data.len() as u64).to_le_bytes();
+                }
+            };
+
+            println!(
+                "migration done: {} iterations, {} pages ({} bytes) sent, {} deduped, downtime {:?}, total {:?}",
+                stats.iterations,
+                stats.pages_sent,
+                stats.bytes_sent,
+                stats.pages_deduped,
+                stats.downtime,
+                stats.total_time
+            );
+            set_migration_status(&migration_status, MigrationStatus::Done);
+            // A running guest stops the VMM when it pauses for the last iteration, a paused
+            // one has nothing to stop it.
+            if paused {
+                let _ = exit_handler.kick();
+            }
+        });
+        Ok(())
+    }
 
-                        migrate_host.write_all(&data_len).unwrap();
 
-                        // println!("Sending data of size: {}", data.len());
-                        migrate_host.write_all(&data).unwrap();
-                        // println!("itr: {}, sent data: {}", migration_itr, data.len());
-                        
+    // Allocates `mem_size` bytes of guest memory, around the MMIO gap. When `memfd` is set, the
+    // memory is backed by a memfd, which is returned as well (see `live_update`).
+    fn allocate_guest_memory(
+        mem_size: usize,
+        memfd: bool,
+    ) -> Result<(GuestMemoryMmap, Option<File>)> {
+        if !memfd {
+            let mem_regions: Vec<_> = guest_memory_regions(mem_size)
+                .into_iter()
+                .map(|(addr, size)| (None, addr, size))
+                .collect();
+            let guest_memory = vm_memory::create_guest_memory(&mem_regions[..], true).unwrap();
+            return Ok((guest_memory, None));
+        }
+        let file = vm_memory::create_memfd(mem_size).map_err(Error::IO)?;
+        let (guest_memory, file) = Self::shared_guest_memory(file)?;
+        Ok((guest_memory, Some(file)))
+    }
 
-                        // println!("dirty_pages: {:?}", dirty_pages);
-                        // print  sha hash of first dirty page
+    // Maps the whole of `file` as the guest memory, shared with whoever else has it mapped. The
+    // regions follow each other in the file.
+    fn shared_guest_memory(file: File) -> Result<(GuestMemoryMmap, File)> {
+        let size = file.metadata().map_err(Error::IO)?.len() as usize;
+        let mut mem_regions = vec![];
+        let mut offset = 0;
+        for (addr, region_size) in guest_memory_regions(size) {
+            let file_offset =
+                vm_memory::FileOffset::new(file.try_clone().map_err(Error::IO)?, offset);
+            mem_regions.push((file_offset, addr, region_size));
+            offset += region_size as u64;
+        }
+        let guest_memory = vm_memory::create_shared_guest_memory(&mem_regions, true)
+            .map_err(|e| Error::Memory(MemoryError::VmMemory(e)))?;
+        Ok((guest_memory, file))
+    }
 
-                        // assert_eq!(dpages[0] as u64, dirty_pages[0] as u64);
+    // Hands the guest over to the VMM waiting on `socket_path`, see `live_update`. On success
+    // this VMM exits, otherwise the guest keeps running here.
+    fn live_update(&mut self, socket_path: &str) {
+        if self.memfd.is_none() {
+            eprintln!("live update requires the guest memory to be backed by a memfd");
+            return;
+        }
+        // The new VMM sets the devices up from scratch, the requests in flight in the virtqueues
+        // would be lost and the driver would find a reset device.
+        let virtio_active = self
+            .block_devices
+            .iter()
+            .any(|block| block.lock().unwrap().is_activated())
+            || self
+                .net_devices
+                .iter()
+                .any(|net| net.lock().unwrap().is_activated());
+        if virtio_active {
+            eprintln!("live update is not supported once the guest uses virtio devices");
+            return;
+        }
 
-                        // println!("first dirty page_num: {}", dirty_pages[0]);
-                        // println!("hash: {:?}", sha256::digest(&buf[dirty_pages[0] * 4096..(dirty_pages[0] + 1) * 4096]));
-                        // println!("send  data first page hash: {}", first_page_in_send_data_hash);
-                        // // print sha hash of first 4096 bytes of send_data
+        self.suspend_vcpus();
+        let vm_state = match self.vm.save_state() {
+            Ok(vm_state) => Self::vm_state_to_bytes(&vm_state),
+            Err(e) => {
+                eprintln!("live update failed: {:?}, resuming the guest", e);
+                self.vm.vcpu_run_state.set_and_notify(VmRunState::Running);
+                return;
+            }
+        };
 
-                        let mut is_stabilized = dirty_pages_in_iters.len() >= 6;
-                        let diff_threshold = 100;
-                        if is_stabilized {
-                            let mut diff = 0;
-                            for i in 0..5 {
-                                diff += (dirty_pages_in_iters[dirty_pages_in_iters.len() - 1 - i] -
-                                            dirty_pages_in_iters[dirty_pages_in_iters.len() - 2 - i]).abs();
-                            }
-                            is_stabilized = (diff) / 5 < diff_threshold;
-                        }
+        let memfd = self.memfd.as_ref().unwrap();
+        match live_update::send_guest(Path::new(socket_path), memfd, &vm_state) {
+            Ok(conn) => {
+                println!("guest handed over to {}, exiting", socket_path);
+                // The new VMM waits for this connection to close, i.e. for us to be gone.
+                self.live_update_conn = Some(conn);
+                let _ = self.vm.exit_handler.kick();
+            }
+            Err(e) => {
+                eprintln!("live update failed: {}, resuming the guest", e);
+                self.vm.vcpu_run_state.set_and_notify(VmRunState::Running);
+            }
+        }
+    }
 
-                        let require_alteast = 3;
+    // Gets the guest clocks up to date with the time the guest spent stopped, if configured to.
+    #[cfg(target_arch = "x86_64")]
+    fn correct_guest_clock(vm_state: &mut VmState, restore_config: &RestoreConfig) {
+        if !restore_config.clock_correction {
+            return;
+        }
+        match vm_state.downtime() {
+            Some(downtime) => {
+                println!("advancing the guest clock by {:?}", downtime);
+                vm_state.advance_clock(downtime);
+            }
+            None => {
+                println!("host clock is behind the saved state, not correcting the guest clock")
+            }
+        }
+    }
 
+    #[cfg(target_arch = "aarch64")]
+    fn correct_guest_clock(_vm_state: &mut VmState, _restore_config: &RestoreConfig) {}
 
-                        let cpu_snap_cond = !(migration_itr == last_itr)
-                                         && (migration_itr > require_alteast)
-                                         || (migration_itr >= require_alteast + 10)
-                                         && is_stabilized;
+    // CPUID leaves and MSRs the vCPUs run with. They only differ in the topology bits set by
+    // `filter_cpuid`, so the first vCPU speaks for all of them.
+    #[cfg(target_arch = "x86_64")]
+    fn cpu_profile(&self) -> CpuProfile {
+        let config = &self.vm.config.vcpus_config.configs[0];
+        CpuProfile::new(&config.cpuid, &config.msrs)
+    }
 
-                        if cpu_snap_cond {
-                            cpu_snap_path = "./cpu_live.txt";
-                            Self::save_snapshot_rpc(cpu_snap_path.to_string());
-                            last_itr = migration_itr + 1;
-                        }
-                    }
+    #[cfg(target_arch = "aarch64")]
+    fn cpu_profile(&self) -> CpuProfile {
+        CpuProfile::default()
+    }
 
-                    thread::sleep(Duration::from_millis(500));
+    // Announces the guest MAC addresses on the taps of this host, in a few spaced out rounds in
+    // case some of the frames get lost.
+    fn announce_net_devices(&self) {
+        let net_devices = self.net_devices.clone();
+        if net_devices.is_empty() {
+            return;
+        }
 
-                    if migration_itr == last_itr {
-                        break;
+        let _ = thread::spawn(move || {
+            for round in 0..ANNOUNCE_ROUNDS {
+                thread::sleep(Duration::from_millis(
+                    ANNOUNCE_INITIAL_MS + round * ANNOUNCE_STEP_MS,
+                ));
+                for net in net_devices.iter() {
+                    if let Err(e) = net.lock().unwrap().announce_self() {
+                        eprintln!("Failed to announce guest on the network: {:?}", e);
                     }
-
-                    migration_itr += 1;
                 }
-                    
-                // save memory to file (for debugging only)
-                // let mut file = File::create("mem_live.txt").unwrap();
-                // file.write_all(&buf).unwrap();
-    
-                println!("migration done");
-                exit_vmm.send(true as i32).unwrap();
-            });
+            }
+        });
     }
 
-
-    fn save_snapshot_rpc(cpu_snap_path: String) {
-        Command::new("./snapshot/target/debug/col732_project_webserver")
+    // Pauses the guest through the RPC server and waits for its vcpu state to be saved to
+    // `cpu_snap_path`.
+    fn save_snapshot_rpc(cpu_snap_path: String) -> io::Result<()> {
+        let output = Command::new("./snapshot/target/debug/col732_project_webserver")
         .arg("resume")
         .arg(cpu_snap_path)
         .arg("./mem_snap.txt") // ignored 
         .arg("1100")
         .arg("false")
-        .spawn()
-        .expect("cpu snapshot failed");
+        .output()?;
+        let reply = String::from_utf8_lossy(&output.stdout);
+        if reply.lines().any(|line| line == "Success") {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("cpu snapshot failed: {}", reply.trim()),
+            ))
+        }
     }
 
 
@@ -1261,6 +2372,35 @@ impl Vmm {
             .unwrap();
     }
 
+    // What the guest learns about itself from the VM identity device. Clones get a new
+    // generation ID and RNG seed every time they're started.
+    #[cfg(target_arch = "x86_64")]
+    fn vm_identity(config: &VMMConfig) -> Result<VmIdentity> {
+        let mut identity = VmIdentity {
+            mac: config.net_config.as_ref().and_then(|cfg| cfg.mac).map(|mac| mac.0),
+            ..Default::default()
+        };
+        if let Some(clone_cfg) = config.clone_config.as_ref() {
+            identity.clone_id = Some(clone_cfg.id);
+            fill_random(&mut identity.generation_id).map_err(Error::IO)?;
+            fill_random(&mut identity.rng_seed).map_err(Error::IO)?;
+            println!("starting clone {}", clone_cfg.id);
+        }
+        Ok(identity)
+    }
+
+    // The device sits in the MMIO gap, which aarch64 guests don't have.
+    #[cfg(target_arch = "x86_64")]
+    fn add_identity_device(&mut self, identity: &VmIdentity) {
+        let device = Arc::new(Mutex::new(VmIdentityDevice::new(identity)));
+        let range = MmioRange::new(MmioAddress(VM_IDENTITY_ADDR), vm_identity::PAGE_SIZE).unwrap();
+        self.device_mgr
+            .lock()
+            .unwrap()
+            .register_mmio(range, device)
+            .unwrap();
+    }
+
     // All methods that add a virtio device use hardcoded addresses and interrupts for now, and
     // only support a single device. We need to expand this, but it looks like a good match if we
     // can do it after figuring out how to better separate concerns and make the VMM agnostic of
@@ -1315,6 +2455,7 @@ impl Vmm {
 
         let args = NetArgs {
             tap_name: cfg.tap_name.clone(),
+            mac: cfg.mac,
         };
 
         // We can also hold this somewhere if we need to keep the handle for later.
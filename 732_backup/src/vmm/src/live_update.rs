@@ -0,0 +1,130 @@
+// Live update: hands a running guest over to a new VMM process on the same host, so the VMM
+// binary can be upgraded without going through a migration.
+//
+// The guest memory has to be backed by a memfd (`memfd=true` in the memory configuration). The
+// old VMM stops the vCPUs, then sends the memfd (as `SCM_RIGHTS`) and the serialized `VmState`
+// over a Unix socket to the new VMM, which maps the same memory instead of copying it. Devices
+// are set up from scratch by the new VMM, the same way as when restoring a snapshot.
+//
+// Device state isn't handed over: the virtqueues of a virtio device activated by the guest driver
+// (and whatever requests are in flight in them) would be lost, so live update is refused once the
+// guest uses its block or net devices. The serial console and the other legacy devices come back
+// in their reset state.
+//
+// The new VMM acknowledges the state once received, and then waits for the old one to exit
+// (which closes the socket) before going on, since both can't hold the tap or the RPC port at
+// the same time.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+// Value of `RpcController::pause_or_resume` asking the run loop for a live update.
+pub const LIVE_UPDATE_EVENT: u16 = 4;
+
+const ACK: u8 = 1;
+
+fn errno_to_io(e: vmm_sys_util::errno::Error) -> io::Error {
+    io::Error::from_raw_os_error(e.errno())
+}
+
+/// Sends the guest memory and `vm_state` to the VMM waiting on `socket_path`. Returns once the
+/// new VMM has everything; the returned stream must be kept open until this process exits.
+pub fn send_guest(socket_path: &Path, memfd: &File, vm_state: &[u8]) -> io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(socket_path)?;
+    let len = (vm_state.len() as u64).to_le_bytes();
+    stream
+        .send_with_fd(&len[..], memfd.as_raw_fd())
+        .map_err(errno_to_io)?;
+    stream.write_all(vm_state)?;
+
+    let mut ack = [0u8; 1];
+    stream.read_exact(&mut ack)?;
+    if ack[0] != ACK {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected live update acknowledgement",
+        ));
+    }
+    Ok(stream)
+}
+
+/// Waits on `socket_path` for a VMM to hand its guest over. Returns the memfd backing the guest
+/// memory and the serialized `VmState`, once the old VMM is gone.
+pub fn receive_guest(socket_path: &Path) -> io::Result<(File, Vec<u8>)> {
+    // Left behind by a previous live update.
+    if socket_path.exists() {
+        fs::remove_file(socket_path)?;
+    }
+    let listener = UnixListener::bind(socket_path)?;
+    println!("live update: waiting for the guest on {:?}", socket_path);
+    let (mut stream, _) = listener.accept()?;
+    drop(listener);
+    let _ = fs::remove_file(socket_path);
+
+    let mut len = [0u8; 8];
+    let (received, memfd) = stream.recv_with_fd(&mut len[..]).map_err(errno_to_io)?;
+    if received < len.len() {
+        stream.read_exact(&mut len[received..])?;
+    }
+    let memfd = memfd.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "live update without guest memory")
+    })?;
+    let mut vm_state = vec![0u8; u64::from_le_bytes(len) as usize];
+    stream.read_exact(&mut vm_state)?;
+
+    stream.write_all(&[ACK])?;
+    // The old VMM closes its end when exiting.
+    let mut rest = vec![];
+    stream.read_to_end(&mut rest)?;
+    println!("live update: took over the guest");
+
+    Ok((memfd, vm_state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::FileExt;
+    use std::thread;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_live_update_handoff() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.as_path().join("live_update.sock");
+
+        let receiver = {
+            let socket_path = socket_path.clone();
+            thread::spawn(move || receive_guest(&socket_path).unwrap())
+        };
+
+        let memfd = vm_memory::create_memfd(4096).unwrap();
+        memfd.write_all_at(b"guest", 0).unwrap();
+        let stream = loop {
+            match send_guest(&socket_path, &memfd, b"state") {
+                Ok(stream) => break stream,
+                // The receiver may not be listening yet.
+                Err(_) => thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        };
+        // The receiver only returns once we're gone.
+        drop(stream);
+
+        let (received, vm_state) = receiver.join().unwrap();
+        assert_eq!(vm_state, b"state");
+        // Both ends see the same memory.
+        let mut buf = [0u8; 5];
+        received.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"guest");
+        received.write_all_at(b"new", 0).unwrap();
+        memfd.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"newst");
+    }
+}
@@ -9,7 +9,7 @@ use std::fs::File;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{
-    Bytes, FileOffset, GuestAddress, GuestMemory, GuestMemoryMmap,
+    Bytes, FileOffset, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
     GuestMemoryRegion, MemoryRegionAddress,
 };
 
@@ -45,7 +45,7 @@ where
     /// Describes GuestMemoryMmap through a GuestMemoryState struct.
     fn describe(&self) -> GuestMemoryState;
     /// Dumps all contents of GuestMemoryMmap to a writer.
-    fn dump<T: std::io::Write>(&self, writer: &mut T) ;
+    fn dump<T: std::io::Write>(&self, writer: &mut T) -> Result<(), GuestMemoryError>;
     /// Dumps all pages of GuestMemoryMmap present in `dirty_bitmap` to a writer.
     /// Creates a GuestMemoryMmap given a `file` containing the data
     /// and a `state` containing mapping information.
@@ -74,11 +74,10 @@ impl SnapshotMemory for GuestMemoryMmap {
     }
 
     /// Dumps all contents of GuestMemoryMmap to a writer.
-    fn dump<T: std::io::Write>(&self, writer: &mut T)  {
-        self.iter()
-            .try_for_each(|region| {
-                region.write_all_to(MemoryRegionAddress(0), writer, region.len() as usize)
-            }).unwrap()
+    fn dump<T: std::io::Write>(&self, writer: &mut T) -> Result<(), GuestMemoryError> {
+        self.iter().try_for_each(|region| {
+            region.write_all_to(MemoryRegionAddress(0), writer, region.len() as usize)
+        })
     }
 
 
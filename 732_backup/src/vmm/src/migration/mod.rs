@@ -0,0 +1,856 @@
+// Pre-copy live migration, source side.
+//
+// The guest memory goes out in full once, then only the pages dirtied since the previous
+// iteration, until the dirty rate settles down (see `ConvergencePolicy`). The vCPUs are then
+// stopped and the last dirty pages go out along with their state.
+//
+// Where the dirty pages come from and where they go are behind the `DirtyPageSource` and
+// `MigrationTransport` traits, so that the same loop can run against a KVM guest and a TCP
+// connection, or against the `simulator`.
+
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use devices::virtio::block::DIRTY_BLOCK_SIZE;
+use kvm_ioctls::VmFd;
+use serde::{Deserialize, Serialize};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+use crate::cpu_compat::CpuProfile;
+use crate::dirty_log::{collect_dirty_pages, guest_pages, PAGE_SIZE};
+use crate::page_heat::PageHeat;
+use crate::storage_migration::{DiskMirror, BULK_COPY_BLOCKS};
+use crate::dedup;
+use crate::{Error, Result, Vmm, CHUNK_SIZE};
+
+pub mod simulator;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MigrationMessage {
+    // Iteration the message belongs to, acknowledged by the destination once applied.
+    pub itr: u64,
+    pub data: Vec<u8>,
+    pub data_len: usize,
+    pub dirty_pages: Vec<u64>,
+    pub dirty_pages_len: usize,
+    pub cpu_state_file_path: String,
+    pub is_last: bool,
+    pub init_migration: bool,
+    // Size of the block device backing file, only set when the disk is migrated as well.
+    pub disk_size: Option<u64>,
+    pub disk_blocks: Vec<u64>,
+    pub disk_data: Vec<u8>,
+    // Chunks of memory the destination already has in its dedup store, sent instead of their
+    // pages (in the full copy only) as their offset in the guest pages laid back to back (see
+    // `dirty_log::guest_pages`), and their hash.
+    pub chunk_refs: Vec<(u64, String)>,
+    // State of the vCPUs, in the last iteration of a paused guest. It is sent along rather than
+    // written to `cpu_state_file_path`, as the vCPUs are not running to save it.
+    pub cpu_state: Vec<u8>,
+}
+
+// First message sent by the destination on every migration connection, so that the source can
+// pick up where the previous connection dropped.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MigrationHandshake {
+    // Last iteration the destination applied, none if it didn't get the full memory yet.
+    pub last_acked_itr: Option<u64>,
+    // Hashes of the chunks in the dedup store of the destination. When there are some, the full
+    // copy is sent as a `MigrationMessage` referencing them rather than as raw memory.
+    pub known_chunks: Vec<String>,
+}
+
+// How long either side waits for the other one before considering the connection lost.
+const MIGRATION_TIMEOUT: Duration = Duration::from_secs(30);
+// The destination tries to reconnect this many times, waiting twice as long after every attempt
+// (starting at `MIGRATION_RECONNECT_DELAY`, and up to `MIGRATION_RECONNECT_MAX_DELAY`).
+const MIGRATION_RECONNECT_ATTEMPTS: u32 = 10;
+const MIGRATION_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MIGRATION_RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5);
+// How often the source checks for a destination coming back, when the wait is bounded.
+const MIGRATION_ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Largest handshake, CPU profile or verdict. Also the room left for everything but the pages and
+// disk blocks in a migration message.
+const MAX_CONTROL_FRAME_LEN: u64 = 64 << 20;
+
+// Migration messages are prefixed by their length, as a little endian u64.
+pub(crate) fn write_migration_frame(stream: &mut TcpStream, data: &[u8]) -> io::Result<()> {
+    stream.write_all(&(data.len() as u64).to_le_bytes())?;
+    stream.write_all(data)
+}
+
+// Frames longer than `max_len` are rejected before anything is allocated for them.
+pub(crate) fn read_migration_frame(stream: &mut TcpStream, max_len: u64) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 8];
+    stream.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "migration frame of {} bytes, at most {} expected",
+                len, max_len
+            ),
+        ));
+    }
+    let mut data = vec![0u8; len as usize];
+    stream.read_exact(&mut data)?;
+    Ok(data)
+}
+
+// Largest migration frame for a guest with `guest_memory`: all of its pages along with their
+// numbers, and the disk blocks along with theirs. Blocks that weren't acknowledged go out again
+// next to the new ones, so that's up to twice the disk once its size (`disk_size`) is known, and
+// a bulk copy batch before.
+pub(crate) fn max_migration_frame_len(
+    guest_memory: &GuestMemoryMmap,
+    disk_size: Option<u64>,
+) -> u64 {
+    let num_pages = guest_pages(guest_memory).len() as u64;
+    let disk_blocks = match disk_size {
+        Some(size) => (size / DIRTY_BLOCK_SIZE + 1).saturating_mul(2),
+        None => BULK_COPY_BLOCKS,
+    };
+    num_pages
+        .saturating_mul(PAGE_SIZE as u64 + 8)
+        .saturating_add(disk_blocks.saturating_mul(DIRTY_BLOCK_SIZE + 8))
+        .saturating_add(MAX_CONTROL_FRAME_LEN)
+}
+
+// Writes `data` to `pages` of the guest memory, a page each, on the destination.
+pub(crate) fn write_guest_pages(
+    guest_memory: &GuestMemoryMmap,
+    pages: &[usize],
+    data: &[u8],
+) -> Result<()> {
+    for (page, data) in pages.iter().zip(data.chunks(PAGE_SIZE)) {
+        guest_memory
+            .write_slice(data, GuestAddress((page * PAGE_SIZE) as u64))
+            .map_err(|e| Error::IO(io::Error::new(io::ErrorKind::Other, e)))?;
+    }
+    Ok(())
+}
+
+// Waits for a destination to connect and reads its handshake. The destination then gets the
+// CPU profile of the guest, and refuses the migration if it can't run it.
+pub(crate) fn accept_migration(
+    listener: &TcpListener,
+    cpu: &CpuProfile,
+) -> io::Result<(TcpStream, MigrationHandshake)> {
+    let mut stream = listener.accept()?.0;
+    // The listener may be non-blocking, see `accept_compatible`.
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(MIGRATION_TIMEOUT))?;
+    let handshake =
+        bincode::deserialize(&read_migration_frame(&mut stream, MAX_CONTROL_FRAME_LEN)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let profile =
+        bincode::serialize(cpu).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_migration_frame(&mut stream, &profile)?;
+    let missing: Vec<String> =
+        bincode::deserialize(&read_migration_frame(&mut stream, MAX_CONTROL_FRAME_LEN)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if !missing.is_empty() {
+        for feature in missing.iter() {
+            println!("destination doesn't support {}", feature);
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "destination CPU is not compatible",
+        ));
+    }
+
+    Ok((stream, handshake))
+}
+
+// Checks the CPU profile sent by the source against what `host_cpu` supports, and reports the
+// missing features back to the source.
+pub(crate) fn check_migration_cpu(stream: &mut TcpStream, host_cpu: &CpuProfile) -> io::Result<Vec<String>> {
+    let source_cpu: CpuProfile =
+        bincode::deserialize(&read_migration_frame(stream, MAX_CONTROL_FRAME_LEN)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let missing = source_cpu.missing_features(host_cpu);
+    let verdict =
+        bincode::serialize(&missing).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_migration_frame(stream, &verdict)?;
+    Ok(missing)
+}
+
+// Connects the destination to the source, retrying with an exponential backoff.
+pub(crate) fn connect_migration(
+    addr: &str,
+    last_acked_itr: Option<u64>,
+    known_chunks: &[String],
+    host_cpu: &CpuProfile,
+) -> Result<TcpStream> {
+    let handshake = MigrationHandshake {
+        last_acked_itr,
+        known_chunks: known_chunks.to_vec(),
+    };
+    let handshake = bincode::serialize(&handshake)
+        .map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+    let mut delay = MIGRATION_RECONNECT_DELAY;
+    let mut attempt = 1;
+    loop {
+        let connected = TcpStream::connect(addr).and_then(|mut stream| {
+            stream.set_read_timeout(Some(MIGRATION_TIMEOUT))?;
+            write_migration_frame(&mut stream, &handshake)?;
+            let missing = check_migration_cpu(&mut stream, host_cpu)?;
+            Ok((stream, missing))
+        });
+        match connected {
+            Ok((stream, missing)) if missing.is_empty() => return Ok(stream),
+            Ok((_, missing)) => {
+                for feature in missing.iter() {
+                    println!("this host doesn't support {}", feature);
+                }
+                return Err(Error::IncompatibleCpu(missing));
+            }
+            Err(e) if attempt >= MIGRATION_RECONNECT_ATTEMPTS => return Err(Error::IO(e)),
+            Err(e) => {
+                println!("migration connection attempt {} failed: {}", attempt, e);
+                thread::sleep(delay);
+                delay = std::cmp::min(delay * 2, MIGRATION_RECONNECT_MAX_DELAY);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+
+// Pause between two iterations, giving the guest some time to dirty pages again.
+const ITERATION_DELAY: Duration = Duration::from_millis(500);
+
+// Where the vCPU state of a migrating guest is saved, for the destination to pick it up.
+const CPU_STATE_PATH: &str = "./cpu_live.txt";
+
+/// Guest whose memory is being migrated.
+pub trait DirtyPageSource {
+    /// Number of pages up to the end of guest memory, pages being numbered by guest address.
+    fn num_pages(&self) -> usize;
+    /// Pages of guest memory, in the order the full copy sends them.
+    fn guest_pages(&self) -> Vec<usize>;
+    /// Pages dirtied since the previous call.
+    fn dirty_pages(&mut self) -> Result<Vec<usize>>;
+    /// Reads `buf.len()` bytes of guest memory, starting at `page`.
+    fn read_memory(&self, page: usize, buf: &mut [u8]) -> Result<()>;
+    /// Stops the vCPUs and saves their state, returning the path the destination loads it from.
+    fn stop_vcpus(&mut self) -> Result<String>;
+    /// Lets the guest run for `duration`.
+    fn wait(&mut self, duration: Duration);
+    /// Time since the migration started.
+    fn elapsed(&self) -> Duration;
+}
+
+/// Connection to the migration destination.
+pub trait MigrationTransport {
+    /// Sends iteration `itr` and waits for the destination to acknowledge it.
+    fn send(&mut self, itr: u64, data: &[u8]) -> io::Result<()>;
+    /// Waits for the destination to come back after `send` failed.
+    fn reconnect(&mut self) -> io::Result<MigrationHandshake>;
+}
+
+/// Decides when the dirty rate is low and stable enough to stop the vCPUs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConvergencePolicy {
+    /// The vCPUs keep running for at least this many iterations after the full copy.
+    pub min_iterations: u64,
+    /// The vCPUs are stopped at this iteration, whether the dirty rate settled down or not.
+    pub max_iterations: u64,
+    /// Number of iterations the dirty rate is averaged over.
+    pub window: usize,
+    /// The dirty rate is stable when the number of dirty pages changes by less than this between
+    /// two iterations, on average over `window`.
+    pub threshold: i64,
+}
+
+// Stops the vCPUs at iteration 4, like the VMM always did. The dirty rate only gets a say with a
+// `max_iterations` past `min_iterations + 1`.
+impl Default for ConvergencePolicy {
+    fn default() -> Self {
+        ConvergencePolicy {
+            min_iterations: 3,
+            max_iterations: 4,
+            window: 5,
+            threshold: 100,
+        }
+    }
+}
+
+impl ConvergencePolicy {
+    /// Whether the last `window` iterations of `history` (the number of pages dirtied during each
+    /// iteration) are stable.
+    pub fn is_stable(&self, history: &[i64]) -> bool {
+        if history.len() <= self.window {
+            return false;
+        }
+        let recent = &history[history.len() - self.window - 1..];
+        let diff: i64 = recent.windows(2).map(|w| (w[1] - w[0]).abs()).sum();
+        diff / (self.window.max(1) as i64) < self.threshold
+    }
+
+    /// Whether to stop the vCPUs after iteration `itr`.
+    pub fn should_stop(&self, itr: u64, history: &[i64]) -> bool {
+        itr >= self.max_iterations || (itr > self.min_iterations && self.is_stable(history))
+    }
+}
+
+/// What a migration went through.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MigrationStats {
+    /// Number of iterations sent, including the full copy and the last one.
+    pub iterations: u64,
+    pub pages_sent: u64,
+    /// Pages of the full copy the destination took from its dedup store instead.
+    pub pages_deduped: u64,
+    pub bytes_sent: u64,
+    /// Time between stopping the vCPUs and the destination acknowledging the last iteration,
+    /// the whole migration for a paused guest.
+    pub downtime: Duration,
+    pub total_time: Duration,
+    pub pages_per_iteration: Vec<usize>,
+}
+
+/// Pre-copy migration of the memory of `source` (and of its disk, when mirrored) over
+/// `transport`.
+pub struct PreCopy<S, T> {
+    source: S,
+    transport: T,
+    disk_mirror: Option<DiskMirror>,
+    policy: ConvergencePolicy,
+    // Hashes of the chunks the destination already has.
+    known_chunks: HashSet<String>,
+    // State of the vCPUs, when they were stopped before the migration started.
+    paused_state: Option<Vec<u8>>,
+}
+
+impl<S: DirtyPageSource, T: MigrationTransport> PreCopy<S, T> {
+    pub fn new(
+        source: S,
+        transport: T,
+        disk_mirror: Option<DiskMirror>,
+        policy: ConvergencePolicy,
+    ) -> Self {
+        PreCopy {
+            source,
+            transport,
+            disk_mirror,
+            policy,
+            known_chunks: HashSet::new(),
+            paused_state: None,
+        }
+    }
+
+    /// Migrates a guest whose vCPUs are already stopped, `cpu_state` being their serialized
+    /// state. Its memory doesn't change, so the full copy is followed by a last iteration with
+    /// the state only.
+    pub fn paused(mut self, cpu_state: Vec<u8>) -> Self {
+        self.paused_state = Some(cpu_state);
+        self
+    }
+
+    /// Skips the chunks with these hashes in the full copy, as advertised by the destination in
+    /// its handshake.
+    pub fn known_chunks(mut self, known_chunks: Vec<String>) -> Self {
+        self.known_chunks = known_chunks.into_iter().collect();
+        self
+    }
+
+    // Reads `pages` back to back, a run of consecutive pages at a time.
+    fn read_pages(&self, pages: &[usize]) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; pages.len() * PAGE_SIZE];
+        let mut start = 0;
+        while start < pages.len() {
+            let mut end = start + 1;
+            while end < pages.len() && pages[end] == pages[end - 1] + 1 {
+                end += 1;
+            }
+            self.source
+                .read_memory(pages[start], &mut buf[start * PAGE_SIZE..end * PAGE_SIZE])?;
+            start = end;
+        }
+        Ok(buf)
+    }
+
+    // Full copy of `guest_pages`, referencing the chunks the destination already has. Chunks are
+    // cut from the pages back to back, like the memory of a snapshot in the dedup store.
+    fn deduped_full_copy(&self, guest_pages: &[usize]) -> Result<(MigrationMessage, usize)> {
+        let buf = self.read_pages(guest_pages)?;
+
+        let mut pages = vec![];
+        let mut data = vec![];
+        let mut chunk_refs = vec![];
+        for (index, chunk) in buf.chunks(CHUNK_SIZE).enumerate() {
+            let offset = index * CHUNK_SIZE;
+            let hash = dedup::get_hash(chunk);
+            if self.known_chunks.contains(&hash) {
+                chunk_refs.push((offset as u64, hash));
+                continue;
+            }
+            let first = offset / PAGE_SIZE;
+            let chunk_pages = &guest_pages[first..first + chunk.len() / PAGE_SIZE];
+            pages.extend(chunk_pages.iter().map(|page| *page as u64));
+            data.extend_from_slice(chunk);
+        }
+
+        let pages_deduped = guest_pages.len() - pages.len();
+        let migration_message = MigrationMessage {
+            itr: 0,
+            data_len: data.len(),
+            data,
+            dirty_pages_len: pages.len(),
+            dirty_pages: pages,
+            cpu_state_file_path: String::new(),
+            is_last: false,
+            init_migration: true,
+            disk_size: None,
+            disk_blocks: vec![],
+            disk_data: vec![],
+            chunk_refs,
+            cpu_state: vec![],
+        };
+        Ok((migration_message, pages_deduped))
+    }
+
+    /// Migrates the guest, returning once the destination has everything.
+    pub fn run(mut self) -> Result<MigrationStats> {
+        let num_pages = self.source.num_pages();
+        let guest_pages = self.source.guest_pages();
+        let mut stats = MigrationStats::default();
+
+        let mut itr: u64 = 0;
+        let paused_state = self.paused_state.take();
+        let paused = paused_state.is_some();
+        // Set once the vCPUs are stopped.
+        let mut last_itr: Option<u64> = if paused { Some(1) } else { None };
+        let mut stopped_at = Duration::default();
+        let mut cpu_state_path = String::new();
+
+        // Number of pages the guest dirtied during each iteration.
+        let mut dirty_history: Vec<i64> = vec![];
+        let mut page_heat = PageHeat::new(num_pages);
+
+        // Pages and disk blocks of an iteration that didn't get acknowledged, they are sent again
+        // along with the next attempt.
+        let mut unacked_pages: Vec<usize> = vec![];
+        let mut unacked_blocks: Vec<u64> = vec![];
+
+        loop {
+            let dirty_pages = self.source.dirty_pages()?;
+            let is_last = last_itr == Some(itr);
+
+            let mut pages_deduped = 0;
+            let (payload, pages_sent) = if itr == 0 {
+                // The whole memory goes out, nothing older is worth resending.
+                unacked_pages.clear();
+
+                if self.known_chunks.is_empty() {
+                    // Not serialized, the destination tells it apart by the iteration number.
+                    (self.read_pages(&guest_pages)?, guest_pages.len())
+                } else {
+                    let (migration_message, deduped) = self.deduped_full_copy(&guest_pages)?;
+                    pages_deduped = deduped;
+                    let payload = bincode::serialize(&migration_message)
+                        .map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+                    (payload, guest_pages.len() - deduped)
+                }
+            } else {
+                // Pages that keep getting dirtied are held back until the last iteration, the
+                // others are sent coldest first.
+                let mut pages_to_send = page_heat.next_pages(&dirty_pages, is_last);
+                let mut queued: HashSet<usize> = pages_to_send.iter().copied().collect();
+                pages_to_send.extend(unacked_pages.drain(..).filter(|page| queued.insert(*page)));
+
+                let mut data = vec![0u8; pages_to_send.len() * PAGE_SIZE];
+                for (page, chunk) in pages_to_send.iter().zip(data.chunks_mut(PAGE_SIZE)) {
+                    self.source.read_memory(*page, chunk)?;
+                }
+
+                let (disk_size, disk_blocks, disk_data) = match self.disk_mirror.as_mut() {
+                    Some(mirror) => {
+                        let (mut blocks, mut data) =
+                            mirror.next_batch(is_last).map_err(Error::IO)?;
+                        data.extend(mirror.read_blocks(&unacked_blocks).map_err(Error::IO)?);
+                        blocks.extend(unacked_blocks.iter().copied());
+                        unacked_blocks = blocks.clone();
+                        (Some(mirror.size()), blocks, data)
+                    }
+                    None => (None, vec![], vec![]),
+                };
+
+                let dirty_pages: Vec<u64> = pages_to_send.iter().map(|page| *page as u64).collect();
+                let migration_message = MigrationMessage {
+                    itr,
+                    data_len: data.len(),
+                    data,
+                    dirty_pages_len: dirty_pages.len(),
+                    dirty_pages,
+                    cpu_state_file_path: cpu_state_path.clone(),
+                    is_last,
+                    init_migration: false,
+                    disk_size,
+                    disk_blocks,
+                    disk_data,
+                    chunk_refs: vec![],
+                    cpu_state: match paused_state.as_ref() {
+                        Some(cpu_state) if is_last => cpu_state.clone(),
+                        _ => vec![],
+                    },
+                };
+                let pages_sent = pages_to_send.len();
+                unacked_pages = pages_to_send;
+
+                let payload = bincode::serialize(&migration_message)
+                    .map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+                (payload, pages_sent)
+            };
+
+            if let Err(e) = self.transport.send(itr, &payload) {
+                println!(
+                    "migration connection lost at itr {}: {}, waiting for the destination",
+                    itr, e
+                );
+                let handshake = self.transport.reconnect().map_err(Error::IO)?;
+                println!(
+                    "destination reconnected, last acked itr: {:?}",
+                    handshake.last_acked_itr
+                );
+
+                match handshake.last_acked_itr {
+                    // Only the ack got lost.
+                    Some(acked) if acked >= itr => {}
+                    Some(_) => {
+                        // Try this iteration again, along with whatever gets dirtied in the
+                        // meantime.
+                        continue;
+                    }
+                    None => {
+                        // The destination doesn't have the full memory, start over with
+                        // whatever this one has in its dedup store.
+                        self.known_chunks = handshake.known_chunks.into_iter().collect();
+                        if let Some(mirror) = self.disk_mirror.as_mut() {
+                            mirror.rewind();
+                        }
+                        unacked_blocks.clear();
+                        itr = 0;
+                        // With the vCPUs stopped, nothing changes after the full copy.
+                        if last_itr.is_some() {
+                            last_itr = Some(1);
+                        }
+                        continue;
+                    }
+                }
+            }
+            unacked_pages.clear();
+            unacked_blocks.clear();
+
+            stats.iterations += 1;
+            stats.pages_sent += pages_sent as u64;
+            stats.pages_deduped += pages_deduped as u64;
+            stats.bytes_sent += payload.len() as u64;
+            stats.pages_per_iteration.push(pages_sent);
+            // The convergence check looks at how much the guest dirties, not at what was sent.
+            let dirtied = if itr == 0 {
+                guest_pages.len()
+            } else {
+                dirty_pages.len()
+            };
+            dirty_history.push(dirtied as i64);
+
+            if is_last {
+                break;
+            }
+
+            // Don't stop the vCPUs before the whole disk went out once, otherwise the last
+            // iteration would have to carry the rest of it.
+            let disk_ready = self
+                .disk_mirror
+                .as_ref()
+                .map_or(true, |mirror| mirror.bulk_copy_done());
+
+            if itr > 0
+                && last_itr.is_none()
+                && disk_ready
+                && self.policy.should_stop(itr, &dirty_history)
+            {
+                cpu_state_path = self.source.stop_vcpus()?;
+                stopped_at = self.source.elapsed();
+                last_itr = Some(itr + 1);
+            }
+
+            // Nothing to wait for when the guest isn't running.
+            if !paused {
+                self.source.wait(ITERATION_DELAY);
+            }
+            itr += 1;
+        }
+
+        stats.total_time = self.source.elapsed();
+        stats.downtime = stats.total_time - stopped_at;
+        Ok(stats)
+    }
+}
+
+/// Memory of the KVM guest run by this VMM.
+pub struct KvmGuest {
+    vm_fd: Arc<VmFd>,
+    guest_memory: GuestMemoryMmap,
+    start: Instant,
+}
+
+impl KvmGuest {
+    pub fn new(vm_fd: Arc<VmFd>, guest_memory: GuestMemoryMmap) -> Self {
+        KvmGuest {
+            vm_fd,
+            guest_memory,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl DirtyPageSource for KvmGuest {
+    // Pages are numbered by guest address, so this includes the MMIO gap when the memory goes
+    // past it.
+    fn num_pages(&self) -> usize {
+        (self.guest_memory.last_addr().raw_value() as usize + 1) / PAGE_SIZE
+    }
+
+    // The MMIO gap is left out.
+    fn guest_pages(&self) -> Vec<usize> {
+        guest_pages(&self.guest_memory)
+    }
+
+    fn dirty_pages(&mut self) -> Result<Vec<usize>> {
+        collect_dirty_pages(&self.vm_fd, &self.guest_memory)
+    }
+
+    // The pages in the MMIO gap read as zeroes.
+    fn read_memory(&self, page: usize, buf: &mut [u8]) -> Result<()> {
+        let start = (page * PAGE_SIZE) as u64;
+        let end = start + buf.len() as u64;
+        buf.fill(0);
+        for region in self.guest_memory.iter() {
+            let from = start.max(region.start_addr().raw_value());
+            let to = end.min(region.last_addr().raw_value() + 1);
+            if from >= to {
+                continue;
+            }
+            self.guest_memory
+                .read_slice(
+                    &mut buf[(from - start) as usize..(to - start) as usize],
+                    GuestAddress(from),
+                )
+                .map_err(|e| Error::IO(io::Error::new(io::ErrorKind::Other, e)))?;
+        }
+        Ok(())
+    }
+
+    fn stop_vcpus(&mut self) -> Result<String> {
+        Vmm::save_snapshot_rpc(CPU_STATE_PATH.to_string()).map_err(Error::IO)?;
+        Ok(CPU_STATE_PATH.to_string())
+    }
+
+    fn wait(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// Migration connection accepted from a destination, which is waited for again (for up to
+/// `reconnect_timeout`) when the connection drops.
+pub struct TcpTransport {
+    listener: TcpListener,
+    stream: TcpStream,
+    cpu: CpuProfile,
+    reconnect_timeout: Duration,
+}
+
+impl TcpTransport {
+    /// Waits until a destination that can run a guest with `cpu` connects on `listener`.
+    pub fn accept(
+        listener: TcpListener,
+        cpu: CpuProfile,
+        reconnect_timeout: Duration,
+    ) -> io::Result<(Self, MigrationHandshake)> {
+        let (stream, handshake) = accept_compatible(&listener, &cpu, None)?;
+        let transport = TcpTransport {
+            listener,
+            stream,
+            cpu,
+            reconnect_timeout,
+        };
+        Ok((transport, handshake))
+    }
+}
+
+impl MigrationTransport for TcpTransport {
+    fn send(&mut self, _itr: u64, data: &[u8]) -> io::Result<()> {
+        write_migration_frame(&mut self.stream, data)?;
+        let mut ack = [0u8; 8];
+        self.stream.read_exact(&mut ack)
+    }
+
+    fn reconnect(&mut self) -> io::Result<MigrationHandshake> {
+        let deadline = Instant::now() + self.reconnect_timeout;
+        let (stream, handshake) = accept_compatible(&self.listener, &self.cpu, Some(deadline))?;
+        self.stream = stream;
+        Ok(handshake)
+    }
+}
+
+// Keeps waiting until a destination that can run the guest shows up, or until `deadline` when
+// there's one.
+fn accept_compatible(
+    listener: &TcpListener,
+    cpu: &CpuProfile,
+    deadline: Option<Instant>,
+) -> io::Result<(TcpStream, MigrationHandshake)> {
+    // Polled, as `accept` can't time out.
+    listener.set_nonblocking(deadline.is_some())?;
+    loop {
+        match accept_migration(listener, cpu) {
+            Ok(accepted) => return Ok(accepted),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(MIGRATION_ACCEPT_POLL_INTERVAL)
+            }
+            Err(e) => println!("failed to accept migration connection: {}", e),
+        }
+        if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the migration destination didn't come back",
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_migration_frame_len() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut receiver = listener.accept().unwrap().0;
+
+        write_migration_frame(&mut sender, &[1, 2, 3]).unwrap();
+        assert_eq!(
+            read_migration_frame(&mut receiver, 3).unwrap(),
+            vec![1, 2, 3]
+        );
+        // Rejected from the length alone.
+        sender.write_all(&u64::MAX.to_le_bytes()).unwrap();
+        assert_eq!(
+            read_migration_frame(&mut receiver, 3).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    // Guest with the pages of a chunk, a hole as big, and the pages of another chunk. The pages
+    // are filled with the number of their chunk.
+    struct GappedGuest;
+
+    const CHUNK_PAGES: usize = CHUNK_SIZE / PAGE_SIZE;
+
+    impl DirtyPageSource for GappedGuest {
+        fn num_pages(&self) -> usize {
+            3 * CHUNK_PAGES
+        }
+
+        fn guest_pages(&self) -> Vec<usize> {
+            (0..CHUNK_PAGES)
+                .chain(2 * CHUNK_PAGES..3 * CHUNK_PAGES)
+                .collect()
+        }
+
+        fn dirty_pages(&mut self) -> Result<Vec<usize>> {
+            Ok(vec![])
+        }
+
+        fn read_memory(&self, page: usize, buf: &mut [u8]) -> Result<()> {
+            for (index, buf) in buf.chunks_mut(PAGE_SIZE).enumerate() {
+                assert!(!(CHUNK_PAGES..2 * CHUNK_PAGES).contains(&(page + index)));
+                buf.fill(((page + index) / CHUNK_PAGES) as u8);
+            }
+            Ok(())
+        }
+
+        fn stop_vcpus(&mut self) -> Result<String> {
+            Ok(String::new())
+        }
+
+        fn wait(&mut self, _duration: Duration) {}
+
+        fn elapsed(&self) -> Duration {
+            Duration::default()
+        }
+    }
+
+    // Keeps what is sent.
+    struct RecordingTransport(Rc<RefCell<Vec<Vec<u8>>>>);
+
+    impl MigrationTransport for RecordingTransport {
+        fn send(&mut self, _itr: u64, data: &[u8]) -> io::Result<()> {
+            self.0.borrow_mut().push(data.to_vec());
+            Ok(())
+        }
+
+        fn reconnect(&mut self) -> io::Result<MigrationHandshake> {
+            unreachable!()
+        }
+    }
+
+    fn full_copy(known_chunks: Vec<String>) -> Vec<u8> {
+        let sent = Rc::new(RefCell::new(vec![]));
+        let transport = RecordingTransport(sent.clone());
+        PreCopy::new(GappedGuest, transport, None, ConvergencePolicy::default())
+            .known_chunks(known_chunks)
+            .run()
+            .unwrap();
+        let sent = sent.borrow();
+        sent[0].clone()
+    }
+
+    #[test]
+    fn test_full_copy_skips_gap() {
+        let mut expected = vec![0u8; CHUNK_SIZE];
+        expected.extend(vec![2u8; CHUNK_SIZE]);
+        assert!(full_copy(vec![]) == expected);
+
+        // Chunks are referenced by their offset in the pages back to back.
+        let message: MigrationMessage =
+            bincode::deserialize(&full_copy(vec![dedup::get_hash(&vec![2u8; CHUNK_SIZE])]))
+                .unwrap();
+        assert_eq!(
+            message.chunk_refs,
+            vec![(CHUNK_SIZE as u64, dedup::get_hash(&vec![2u8; CHUNK_SIZE]))]
+        );
+        assert_eq!(
+            message.dirty_pages,
+            (0..CHUNK_PAGES as u64).collect::<Vec<_>>()
+        );
+        assert!(message.data == vec![0u8; CHUNK_SIZE]);
+    }
+
+    #[test]
+    fn test_reconnect_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let deadline = Instant::now() + Duration::from_millis(200);
+        let err = accept_compatible(&listener, &CpuProfile::default(), Some(deadline)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(Instant::now() >= deadline);
+    }
+}
@@ -0,0 +1,414 @@
+// Offline migration simulator: runs `PreCopy` against a guest dirtying its pages according to a
+// trace, over a link of a given bandwidth and latency. Time is simulated, so tuning the
+// `ConvergencePolicy` doesn't need a KVM guest, nor to wait for the migration to happen.
+
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    ConvergencePolicy, DirtyPageSource, MigrationHandshake, MigrationStats, MigrationTransport,
+    PreCopy,
+};
+use crate::Result;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+// Shared between the guest and the link, which both make time go by.
+#[derive(Clone, Default)]
+struct SimClock(Rc<Cell<Duration>>);
+
+impl SimClock {
+    fn now(&self) -> Duration {
+        self.0.get()
+    }
+
+    fn advance(&self, duration: Duration) {
+        self.0.set(self.0.get() + duration);
+    }
+}
+
+/// Pages a guest dirtied during consecutive windows of `window_ms`, as sampled by
+/// `DirtyRateMonitor::record`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordedTrace {
+    pub window_ms: u64,
+    pub windows: Vec<Vec<usize>>,
+}
+
+/// How the simulated guest dirties its memory.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DirtyTrace {
+    /// `pages_per_sec` pages picked at random among the first `working_set` ones.
+    Synthetic {
+        pages_per_sec: u64,
+        working_set: usize,
+        seed: u64,
+    },
+    /// Replayed in a loop, the pages of each window being dirtied evenly over it.
+    Recorded(RecordedTrace),
+}
+
+struct SimulatedGuest {
+    clock: SimClock,
+    trace: DirtyTrace,
+    num_pages: usize,
+    // Dirtied since the previous `dirty_pages`.
+    dirty: BTreeSet<usize>,
+    // The guest dirtied its pages up to that point.
+    dirtied_until: Duration,
+    // Synthetic pages owed to the next update, in billionths of a page.
+    carry: u128,
+    rng: u64,
+    stopped: bool,
+}
+
+impl SimulatedGuest {
+    fn new(clock: SimClock, trace: DirtyTrace, num_pages: usize) -> Self {
+        let rng = match trace {
+            // Xorshift gets stuck on 0.
+            DirtyTrace::Synthetic { seed, .. } => seed.max(1),
+            DirtyTrace::Recorded(_) => 1,
+        };
+        SimulatedGuest {
+            clock,
+            trace,
+            num_pages,
+            dirty: BTreeSet::new(),
+            dirtied_until: Duration::default(),
+            carry: 0,
+            rng,
+            stopped: false,
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    // Dirties what the guest wrote since the last update.
+    fn update(&mut self) {
+        let from = self.dirtied_until;
+        let to = self.clock.now();
+        self.dirtied_until = to;
+        if self.stopped || to <= from {
+            return;
+        }
+
+        match self.trace.clone() {
+            DirtyTrace::Synthetic {
+                pages_per_sec,
+                working_set,
+                ..
+            } => {
+                let working_set = working_set.min(self.num_pages).max(1) as u64;
+                let owed = self.carry + u128::from(pages_per_sec) * (to - from).as_nanos();
+                self.carry = owed % NANOS_PER_SEC;
+                for _ in 0..owed / NANOS_PER_SEC {
+                    let page = (self.next_random() % working_set) as usize;
+                    self.dirty.insert(page);
+                }
+            }
+            DirtyTrace::Recorded(trace) => self.replay(&trace, from, to),
+        }
+    }
+
+    fn replay(&mut self, trace: &RecordedTrace, from: Duration, to: Duration) {
+        let window = u128::from(trace.window_ms.max(1)) * 1_000_000;
+        if trace.windows.is_empty() {
+            return;
+        }
+
+        let (from, to) = (from.as_nanos(), to.as_nanos());
+        let mut start = from;
+        while start < to {
+            let window_start = start - start % window;
+            let end = to.min(window_start + window);
+            let pages = &trace.windows[(window_start / window) as usize % trace.windows.len()];
+            // The part of the window's pages written between `start` and `end`.
+            let first = (pages.len() as u128 * (start - window_start) / window) as usize;
+            let last = (pages.len() as u128 * (end - window_start) / window) as usize;
+            let num_pages = self.num_pages;
+            self.dirty.extend(
+                pages[first..last]
+                    .iter()
+                    .copied()
+                    .filter(|page| *page < num_pages),
+            );
+            start = end;
+        }
+    }
+}
+
+impl DirtyPageSource for SimulatedGuest {
+    fn num_pages(&self) -> usize {
+        self.num_pages
+    }
+
+    fn guest_pages(&self) -> Vec<usize> {
+        (0..self.num_pages).collect()
+    }
+
+    fn dirty_pages(&mut self) -> Result<Vec<usize>> {
+        self.update();
+        let dirty = std::mem::take(&mut self.dirty);
+        Ok(dirty.into_iter().collect())
+    }
+
+    fn read_memory(&self, _page: usize, _buf: &mut [u8]) -> Result<()> {
+        Ok(())
+    }
+
+    fn stop_vcpus(&mut self) -> Result<String> {
+        self.update();
+        self.stopped = true;
+        Ok(String::new())
+    }
+
+    fn wait(&mut self, duration: Duration) {
+        self.clock.advance(duration);
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.clock.now()
+    }
+}
+
+struct SimulatedLink {
+    clock: SimClock,
+    bandwidth: u64,
+    latency: Duration,
+    failed_sends: Vec<u64>,
+    attempts: u64,
+    last_acked_itr: Option<u64>,
+    known_chunks: Vec<String>,
+}
+
+impl SimulatedLink {
+    fn round_trip(&self) -> Duration {
+        self.latency * 2
+    }
+}
+
+impl MigrationTransport for SimulatedLink {
+    fn send(&mut self, itr: u64, data: &[u8]) -> io::Result<()> {
+        let attempt = self.attempts;
+        self.attempts += 1;
+
+        let nanos = data.len() as u128 * NANOS_PER_SEC / u128::from(self.bandwidth.max(1));
+        self.clock
+            .advance(Duration::from_nanos(nanos as u64) + self.round_trip());
+
+        if self.failed_sends.contains(&attempt) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "simulated connection drop",
+            ));
+        }
+        self.last_acked_itr = Some(itr);
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> io::Result<MigrationHandshake> {
+        self.clock.advance(self.round_trip());
+        Ok(MigrationHandshake {
+            last_acked_itr: self.last_acked_itr,
+            known_chunks: self.known_chunks.clone(),
+        })
+    }
+}
+
+/// Migration of a simulated guest.
+pub struct Simulation {
+    pub num_pages: usize,
+    pub trace: DirtyTrace,
+    /// Link bandwidth, in bytes per second.
+    pub bandwidth: u64,
+    /// One way latency of the link.
+    pub latency: Duration,
+    pub policy: ConvergencePolicy,
+    /// Sends that fail (counting every attempt from 0), after which the destination reconnects.
+    pub failed_sends: Vec<u64>,
+    /// Hashes of the chunks in the dedup store of the destination. The simulated guest memory
+    /// is all zeroes.
+    pub known_chunks: Vec<String>,
+    /// The vCPUs are stopped before the migration starts.
+    pub paused: bool,
+}
+
+impl Simulation {
+    /// Runs the migration to the end. `MigrationStats::downtime` is the downtime the guest
+    /// would have seen.
+    pub fn run(self) -> Result<MigrationStats> {
+        let clock = SimClock::default();
+        let mut guest = SimulatedGuest::new(clock.clone(), self.trace, self.num_pages);
+        guest.stopped = self.paused;
+        let link = SimulatedLink {
+            clock,
+            bandwidth: self.bandwidth,
+            latency: self.latency,
+            failed_sends: self.failed_sends,
+            attempts: 0,
+            last_acked_itr: None,
+            known_chunks: self.known_chunks.clone(),
+        };
+        let migration = PreCopy::new(guest, link, None, self.policy).known_chunks(self.known_chunks);
+        if self.paused {
+            migration.paused(vec![]).run()
+        } else {
+            migration.run()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{dedup, CHUNK_SIZE};
+
+    const NUM_PAGES: usize = 16 * 1024;
+    const BANDWIDTH: u64 = 1 << 30;
+
+    fn simulation(trace: DirtyTrace) -> Simulation {
+        Simulation {
+            num_pages: NUM_PAGES,
+            trace,
+            bandwidth: BANDWIDTH,
+            latency: Duration::from_millis(1),
+            policy: ConvergencePolicy::default(),
+            failed_sends: vec![],
+            known_chunks: vec![],
+            paused: false,
+        }
+    }
+
+    // Lets the dirty rate decide when to stop, for up to 13 iterations.
+    fn adaptive_policy() -> ConvergencePolicy {
+        ConvergencePolicy {
+            max_iterations: 13,
+            window: 5,
+            threshold: 100,
+            ..ConvergencePolicy::default()
+        }
+    }
+
+    fn idle_guest() -> DirtyTrace {
+        DirtyTrace::Synthetic {
+            pages_per_sec: 200,
+            working_set: 4096,
+            seed: 42,
+        }
+    }
+
+    #[test]
+    fn test_convergence_policy() {
+        let policy = adaptive_policy();
+        let stable = vec![1000, 100, 110, 100, 105, 100, 110];
+        assert!(policy.is_stable(&stable));
+        // Not enough iterations to tell.
+        assert!(!policy.is_stable(&stable[..5]));
+        // The full copy is part of the window.
+        assert!(!policy.is_stable(&stable[..6]));
+        assert!(!policy.is_stable(&[100, 900, 100, 900, 100, 900]));
+
+        assert!(!policy.should_stop(3, &stable));
+        assert!(policy.should_stop(4, &stable));
+        assert!(!policy.should_stop(12, &[100, 900, 100, 900, 100, 900]));
+        assert!(policy.should_stop(13, &[100, 900, 100, 900, 100, 900]));
+
+        // Iteration 4, whatever the dirty rate.
+        let policy = ConvergencePolicy::default();
+        assert!(!policy.should_stop(3, &stable));
+        assert!(policy.should_stop(4, &[100, 900, 100, 900, 100, 900]));
+    }
+
+    #[test]
+    fn test_simulated_convergence() {
+        let mut sim = simulation(idle_guest());
+        sim.policy = adaptive_policy();
+        let stats = sim.run().unwrap();
+
+        // The dirty rate is stable as soon as the full copy is out of the window.
+        assert_eq!(stats.iterations, 8);
+        assert_eq!(stats.pages_per_iteration.len(), 8);
+        assert_eq!(stats.pages_per_iteration[0], NUM_PAGES);
+        assert_eq!(
+            stats.pages_sent,
+            stats.pages_per_iteration.iter().sum::<usize>() as u64
+        );
+        assert!(stats.bytes_sent >= (NUM_PAGES * 4096) as u64);
+        // Only the pause before the last iteration and a handful of pages.
+        assert!(stats.downtime < Duration::from_millis(600));
+        assert!(stats.downtime < stats.total_time);
+    }
+
+    #[test]
+    fn test_simulated_no_convergence() {
+        // Bursts of writes every other second, the dirty rate never settles.
+        let trace = RecordedTrace {
+            window_ms: 1000,
+            windows: vec![(0..4000).collect(), vec![]],
+        };
+        let mut sim = simulation(DirtyTrace::Recorded(trace.clone()));
+        sim.policy = adaptive_policy();
+        let stats = sim.run().unwrap();
+
+        // Stopped at the last iteration allowed, which is followed by the final one.
+        assert_eq!(stats.iterations, adaptive_policy().max_iterations + 2);
+        assert!(stats.pages_per_iteration[1..].iter().any(|pages| *pages > 1000));
+
+        // The default stops at iteration 4 anyway.
+        let stats = simulation(DirtyTrace::Recorded(trace)).run().unwrap();
+        assert_eq!(stats.iterations, 6);
+    }
+
+    #[test]
+    fn test_simulated_connection_drop() {
+        let expected = simulation(idle_guest()).run().unwrap();
+
+        let mut sim = simulation(idle_guest());
+        // The full copy is lost, then an iteration.
+        sim.failed_sends = vec![0, 3];
+        let stats = sim.run().unwrap();
+
+        assert_eq!(stats.iterations, expected.iterations);
+        assert_eq!(stats.pages_per_iteration[0], NUM_PAGES);
+        // The lost sends took time as well.
+        assert!(stats.total_time > expected.total_time);
+    }
+
+    #[test]
+    fn test_simulated_dedup() {
+        let expected = simulation(idle_guest()).run().unwrap();
+
+        let mut sim = simulation(idle_guest());
+        sim.known_chunks = vec![dedup::get_hash(&vec![0u8; CHUNK_SIZE])];
+        let stats = sim.run().unwrap();
+
+        // Nothing but references in the full copy.
+        assert_eq!(stats.pages_per_iteration[0], 0);
+        assert_eq!(stats.pages_deduped, NUM_PAGES as u64);
+        assert!(stats.bytes_sent < (NUM_PAGES * 4096) as u64);
+        assert_eq!(stats.iterations, expected.iterations);
+    }
+
+    #[test]
+    fn test_simulated_paused() {
+        let mut sim = simulation(idle_guest());
+        sim.paused = true;
+        let stats = sim.run().unwrap();
+
+        // The full copy, then the vCPU state.
+        assert_eq!(stats.pages_per_iteration, vec![NUM_PAGES, 0]);
+        assert_eq!(stats.downtime, stats.total_time);
+        assert!(stats.total_time < Duration::from_millis(100));
+    }
+}
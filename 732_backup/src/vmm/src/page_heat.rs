@@ -0,0 +1,117 @@
+// Orders the pages sent by the pre-copy iterations of live migration according to how often
+// they were dirtied.
+//
+// Pages that keep getting written ("hot" pages) would be sent over and over, only to be
+// overwritten again before the migration completes. Instead, once a page was dirtied in
+// `HOT_THRESHOLD` iterations, it's held back until the final stop-and-copy iteration, where it
+// is sent exactly once. The remaining pages are sent coldest first.
+
+use std::collections::BTreeSet;
+
+// Number of iterations a page has to be dirtied in before it's considered hot.
+pub const HOT_THRESHOLD: u16 = 3;
+// Upper bound on the number of deferred pages (256 MiB worth of 4K pages), so that the final
+// iteration, which runs with the vCPUs stopped, can't grow without limit.
+pub const MAX_DEFERRED_PAGES: usize = 64 * 1024;
+
+pub struct PageHeat {
+    // Number of iterations each page was dirtied in.
+    dirty_counts: Vec<u16>,
+    // Hot pages that are still to be sent.
+    deferred: BTreeSet<usize>,
+    max_deferred: usize,
+}
+
+impl PageHeat {
+    pub fn new(num_pages: usize) -> Self {
+        Self::with_max_deferred(num_pages, MAX_DEFERRED_PAGES)
+    }
+
+    pub fn with_max_deferred(num_pages: usize, max_deferred: usize) -> Self {
+        PageHeat {
+            dirty_counts: vec![0; num_pages],
+            deferred: BTreeSet::new(),
+            max_deferred,
+        }
+    }
+
+    // Number of pages currently held back for the final iteration.
+    pub fn deferred_len(&self) -> usize {
+        self.deferred.len()
+    }
+
+    // Records the pages dirtied in the current iteration and returns the ones to send now,
+    // coldest first. Hot pages are held back, unless `is_last` is set, in which case they are
+    // returned (hottest last) along with everything deferred so far.
+    pub fn next_pages(&mut self, dirty_pages: &[usize], is_last: bool) -> Vec<usize> {
+        for &page in dirty_pages {
+            if let Some(count) = self.dirty_counts.get_mut(page) {
+                *count = count.saturating_add(1);
+            }
+        }
+
+        let mut pages: Vec<usize> = if is_last {
+            let mut pages: BTreeSet<usize> = std::mem::take(&mut self.deferred);
+            pages.extend(dirty_pages.iter().copied());
+            pages.into_iter().collect()
+        } else {
+            let mut pages = Vec::with_capacity(dirty_pages.len());
+            for &page in dirty_pages {
+                if self.deferred.contains(&page) {
+                    continue;
+                }
+                if self.heat(page) >= HOT_THRESHOLD && self.deferred.len() < self.max_deferred {
+                    self.deferred.insert(page);
+                } else {
+                    pages.push(page);
+                }
+            }
+            pages
+        };
+
+        // The sort is stable, so pages with the same heat stay in ascending order.
+        pages.sort_by_key(|&page| self.heat(page));
+        pages
+    }
+
+    fn heat(&self, page: usize) -> u16 {
+        self.dirty_counts.get(page).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_heat() {
+        let mut heat = PageHeat::new(16);
+
+        // Page 1 is dirtied every iteration, page 2 every other one.
+        assert_eq!(heat.next_pages(&[1, 2], false), vec![1, 2]);
+        assert_eq!(heat.next_pages(&[1, 3], false), vec![3, 1]);
+        // Page 1 becomes hot and is held back from now on.
+        assert_eq!(heat.next_pages(&[1, 2], false), vec![2]);
+        assert_eq!(heat.deferred_len(), 1);
+        assert_eq!(heat.next_pages(&[1, 4], false), vec![4]);
+        assert_eq!(heat.deferred_len(), 1);
+
+        // The deferred page goes out with the last iteration even if it's not dirty anymore,
+        // after the colder ones.
+        assert_eq!(heat.next_pages(&[2, 5], true), vec![5, 2, 1]);
+        assert_eq!(heat.deferred_len(), 0);
+    }
+
+    #[test]
+    fn test_page_heat_max_deferred() {
+        let mut heat = PageHeat::with_max_deferred(16, 1);
+        for _ in 0..HOT_THRESHOLD - 1 {
+            heat.next_pages(&[1, 2], false);
+        }
+
+        // Only one of the hot pages can be deferred, the other one is still sent.
+        assert_eq!(heat.next_pages(&[1, 2], false), vec![2]);
+        assert_eq!(heat.deferred_len(), 1);
+        assert_eq!(heat.next_pages(&[], true), vec![1]);
+    }
+}
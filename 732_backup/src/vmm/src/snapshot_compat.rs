@@ -0,0 +1,227 @@
+// Checks that a snapshot can be restored with the configuration the VMM was started with.
+//
+// `KvmVm::from_state` takes the saved `VmState` as it is: restoring it with fewer vCPUs, less
+// memory or another set of devices than the guest was running with only fails later, in the
+// guest or in KVM. So before mapping anything, restore compares the snapshot metadata, then the
+// vm state, to what it's about to create, and lists every difference instead.
+//
+// The devices are only compared by kind and order, since that's what decides where the guest
+// finds them. Their backends (disk images, taps) are free to change.
+
+use std::fmt;
+
+use vm_vcpu::vm::VmState;
+
+use crate::cpu_compat::CpuProfile;
+use crate::snapshot_file::{Result, SnapshotError, SnapshotMetadata};
+
+/// A difference between the snapshot and the VM restoring it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mismatch {
+    Arch {
+        snapshot: String,
+        host: String,
+    },
+    MemorySize {
+        snapshot: u64,
+        config: u64,
+    },
+    VcpuCount {
+        snapshot: usize,
+        config: u8,
+    },
+    Devices {
+        snapshot: Vec<String>,
+        config: Vec<String>,
+    },
+    /// CPU features the vCPUs were using which this host doesn't offer.
+    CpuFeatures(Vec<String>),
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Arch { snapshot, host } => write!(
+                f,
+                "snapshot was taken on {}, this host is {}",
+                snapshot, host
+            ),
+            Mismatch::MemorySize { snapshot, config } => write!(
+                f,
+                "snapshot has {} MiB of memory, configured with {} MiB",
+                snapshot >> 20,
+                config >> 20
+            ),
+            Mismatch::VcpuCount { snapshot, config } => write!(
+                f,
+                "snapshot has {} vCPUs, configured with {}",
+                snapshot, config
+            ),
+            Mismatch::Devices { snapshot, config } => write!(
+                f,
+                "snapshot has devices [{}], configured with [{}]",
+                snapshot.join(", "),
+                config.join(", ")
+            ),
+            Mismatch::CpuFeatures(missing) => {
+                write!(f, "host lacks CPU features {}", missing.join(", "))
+            }
+        }
+    }
+}
+
+/// The VM a snapshot is restored into.
+pub struct RestoreTarget {
+    pub mem_size: u64,
+    pub num_vcpus: u8,
+    pub devices: Vec<String>,
+    /// What the vCPUs can use on this host.
+    pub host_cpu: CpuProfile,
+}
+
+impl RestoreTarget {
+    pub fn metadata_mismatches(&self, metadata: &SnapshotMetadata) -> Vec<Mismatch> {
+        let mut mismatches = vec![];
+        if let Some(arch) = metadata.arch.as_ref() {
+            if arch != std::env::consts::ARCH {
+                mismatches.push(Mismatch::Arch {
+                    snapshot: arch.clone(),
+                    host: std::env::consts::ARCH.to_string(),
+                });
+            }
+        }
+        if metadata.mem_size != self.mem_size {
+            mismatches.push(Mismatch::MemorySize {
+                snapshot: metadata.mem_size,
+                config: self.mem_size,
+            });
+        }
+        if let Some(num_vcpus) = metadata.num_vcpus {
+            if num_vcpus != self.num_vcpus {
+                mismatches.push(Mismatch::VcpuCount {
+                    snapshot: num_vcpus as usize,
+                    config: self.num_vcpus,
+                });
+            }
+        }
+        if let Some(devices) = metadata.devices.as_ref() {
+            if *devices != self.devices {
+                mismatches.push(Mismatch::Devices {
+                    snapshot: devices.clone(),
+                    config: self.devices.clone(),
+                });
+            }
+        }
+        mismatches
+    }
+
+    pub fn vm_state_mismatches(&self, vm_state: &VmState) -> Vec<Mismatch> {
+        let mut mismatches = vec![];
+        if vm_state.vcpus_state.len() != self.num_vcpus as usize {
+            mismatches.push(Mismatch::VcpuCount {
+                snapshot: vm_state.vcpus_state.len(),
+                config: self.num_vcpus,
+            });
+        }
+        #[cfg(target_arch = "x86_64")]
+        if let Some(vcpu) = vm_state.vcpus_state.first() {
+            let missing = CpuProfile::new(&vcpu.cpuid, &vcpu.msrs).missing_features(&self.host_cpu);
+            if !missing.is_empty() {
+                mismatches.push(Mismatch::CpuFeatures(missing));
+            }
+        }
+        mismatches
+    }
+
+    /// Checks what the snapshot says about the VM it was taken of. Comes first, so that a
+    /// snapshot of another architecture is reported as such rather than as a vm state that
+    /// doesn't deserialize.
+    pub fn check_metadata(&self, metadata: &SnapshotMetadata) -> Result<()> {
+        Self::to_result(self.metadata_mismatches(metadata))
+    }
+
+    pub fn check_vm_state(&self, vm_state: &VmState) -> Result<()> {
+        Self::to_result(self.vm_state_mismatches(vm_state))
+    }
+
+    /// Checks the size of a raw memory dump, for legacy snapshots which have no metadata.
+    pub fn check_memory_size(&self, size: u64) -> Result<()> {
+        if size == self.mem_size {
+            return Ok(());
+        }
+        Self::to_result(vec![Mismatch::MemorySize {
+            snapshot: size,
+            config: self.mem_size,
+        }])
+    }
+
+    fn to_result(mismatches: Vec<Mismatch>) -> Result<()> {
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(SnapshotError::Incompatible(mismatches))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target() -> RestoreTarget {
+        RestoreTarget {
+            mem_size: 512 << 20,
+            num_vcpus: 2,
+            devices: vec!["block".to_string()],
+            host_cpu: CpuProfile::default(),
+        }
+    }
+
+    #[test]
+    fn test_metadata_mismatches() {
+        let metadata = SnapshotMetadata::new(512 << 20, 2, vec!["block".to_string()]);
+        assert!(target().metadata_mismatches(&metadata).is_empty());
+
+        let metadata = SnapshotMetadata {
+            arch: Some("riscv64".to_string()),
+            ..SnapshotMetadata::new(1 << 30, 1, vec!["block".to_string(), "net".to_string()])
+        };
+        assert_eq!(
+            target().metadata_mismatches(&metadata),
+            vec![
+                Mismatch::Arch {
+                    snapshot: "riscv64".to_string(),
+                    host: std::env::consts::ARCH.to_string(),
+                },
+                Mismatch::MemorySize {
+                    snapshot: 1 << 30,
+                    config: 512 << 20,
+                },
+                Mismatch::VcpuCount {
+                    snapshot: 1,
+                    config: 2,
+                },
+                Mismatch::Devices {
+                    snapshot: vec!["block".to_string(), "net".to_string()],
+                    config: vec!["block".to_string()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metadata_of_older_vmm() {
+        // Only the memory size was recorded.
+        let metadata = SnapshotMetadata {
+            arch: None,
+            num_vcpus: None,
+            devices: None,
+            ..SnapshotMetadata::new(512 << 20, 8, vec![])
+        };
+        assert!(target().metadata_mismatches(&metadata).is_empty());
+        assert!(matches!(
+            target().check_memory_size(256 << 20),
+            Err(SnapshotError::Incompatible(_))
+        ));
+    }
+}
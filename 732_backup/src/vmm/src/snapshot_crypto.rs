@@ -0,0 +1,283 @@
+// Encryption of snapshots at rest, with ChaCha20-Poly1305.
+//
+// The 256-bit key comes from the file named by `VMM_SNAPSHOT_KEY_FILE` (32 raw bytes, or 64 hex
+// digits), or else from `VMM_SNAPSHOT_KEY` (64 hex digits). With a key, snapshots are written
+// encrypted, and only encrypted snapshots are restored: a plaintext snapshot could otherwise be
+// passed off for one of ours.
+//
+// An encrypted section is cut in blocks of `BLOCK_SIZE` bytes, the last one shorter, each sealed
+// on its own:
+//
+//   nonce (12 bytes, random), ciphertext, tag (16 bytes)
+//
+// with the id of the snapshot, the kind of the section, the index of the block and whether it's
+// the last one as associated data, so that blocks can't be reordered, dropped, or moved to
+// another section or snapshot without failing to open.
+//
+// The dedup store keeps the snapshot files as they are, so the chunks of encrypted snapshots are
+// encrypted as well. They don't share chunks with each other, or with migrations, since the same
+// memory encrypts differently every time.
+
+use std::convert::TryInto;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+/// Names the file holding the key.
+pub const KEY_FILE_ENV: &str = "VMM_SNAPSHOT_KEY_FILE";
+/// Holds the key in hex, when there's no key file.
+pub const KEY_ENV: &str = "VMM_SNAPSHOT_KEY";
+
+pub const KEY_SIZE: usize = 32;
+pub const ID_SIZE: usize = 16;
+/// Bytes of a section sealed together.
+pub const BLOCK_SIZE: usize = 64 << 10;
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+/// What sealing adds to each block.
+pub const BLOCK_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+/// Size of a sealed block, but the last one of a section.
+pub const SEALED_BLOCK_SIZE: usize = BLOCK_SIZE + BLOCK_OVERHEAD;
+
+/// Key of the encrypted snapshots.
+#[derive(Clone)]
+pub struct SnapshotKey(Key);
+
+impl fmt::Debug for SnapshotKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SnapshotKey(..)")
+    }
+}
+
+impl SnapshotKey {
+    pub fn new(bytes: [u8; KEY_SIZE]) -> Self {
+        SnapshotKey(Key::from(bytes))
+    }
+
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.trim();
+        if hex.len() != KEY_SIZE * 2 || !hex.is_ascii() {
+            return None;
+        }
+        let mut bytes = [0u8; KEY_SIZE];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).ok()?;
+        }
+        Some(Self::new(bytes))
+    }
+
+    /// Reads the key file at `path`, holding either the raw key or its hex digits.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let contents = fs::read(path.as_ref())?;
+        if contents.len() == KEY_SIZE {
+            let mut bytes = [0u8; KEY_SIZE];
+            bytes.copy_from_slice(&contents);
+            return Ok(Self::new(bytes));
+        }
+        std::str::from_utf8(&contents)
+            .ok()
+            .and_then(Self::from_hex)
+            .ok_or_else(|| {
+                invalid_key(format!(
+                    "{} holds neither {} bytes nor {} hex digits",
+                    path.as_ref().display(),
+                    KEY_SIZE,
+                    KEY_SIZE * 2
+                ))
+            })
+    }
+
+    /// The key configured through the environment, `None` if snapshots aren't encrypted.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        if let Some(path) = env::var_os(KEY_FILE_ENV) {
+            return Self::from_file(path).map(Some);
+        }
+        let hex = match env::var(KEY_ENV) {
+            Ok(hex) => hex,
+            Err(env::VarError::NotPresent) => return Ok(None),
+            Err(env::VarError::NotUnicode(_)) => String::new(),
+        };
+        Self::from_hex(&hex)
+            .map(Some)
+            .ok_or_else(|| invalid_key(format!("{} is not {} hex digits", KEY_ENV, KEY_SIZE * 2)))
+    }
+
+    /// Encrypts `data`, returning the nonce, the ciphertext and the tag.
+    pub fn seal(&self, aad: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        fill_random(&mut nonce)?;
+        let ciphertext = ChaCha20Poly1305::new(&self.0)
+            .encrypt(&Nonce::from(nonce), Payload { msg: data, aad })
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "failed to encrypt"))?;
+        let mut sealed = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypts what `seal` returned, `None` if it wasn't sealed with this key and `aad`, or was
+    /// modified since.
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < BLOCK_OVERHEAD {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let nonce: [u8; NONCE_SIZE] = nonce.try_into().unwrap();
+        ChaCha20Poly1305::new(&self.0)
+            .decrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+    }
+}
+
+fn invalid_key(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+pub fn fill_random(buf: &mut [u8]) -> io::Result<()> {
+    getrandom::getrandom(buf).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+}
+
+/// Associated data of block `index` of a section of kind `kind`.
+pub fn block_aad(id: &[u8; ID_SIZE], kind: u32, index: u64, last: bool) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(ID_SIZE + 13);
+    aad.extend_from_slice(id);
+    aad.extend_from_slice(&kind.to_le_bytes());
+    aad.extend_from_slice(&index.to_le_bytes());
+    aad.push(last as u8);
+    aad
+}
+
+/// Length of the data sealed in `sealed_len` bytes of blocks, `None` if no data seals to that.
+pub fn data_len(sealed_len: u64) -> Option<u64> {
+    let blocks = (sealed_len + SEALED_BLOCK_SIZE as u64 - 1) / SEALED_BLOCK_SIZE as u64;
+    let last = sealed_len - blocks.saturating_sub(1) * SEALED_BLOCK_SIZE as u64;
+    if blocks == 0 || last < BLOCK_OVERHEAD as u64 {
+        return None;
+    }
+    Some(sealed_len - blocks * BLOCK_OVERHEAD as u64)
+}
+
+/// Seals what's written to it, a block at a time, into `inner`. Nothing is complete until
+/// `finish`, which seals the last block.
+pub struct SealingWriter<'a, W: Write> {
+    key: &'a SnapshotKey,
+    id: &'a [u8; ID_SIZE],
+    kind: u32,
+    inner: W,
+    buf: Vec<u8>,
+    index: u64,
+}
+
+impl<'a, W: Write> SealingWriter<'a, W> {
+    pub fn new(key: &'a SnapshotKey, id: &'a [u8; ID_SIZE], kind: u32, inner: W) -> Self {
+        SealingWriter {
+            key,
+            id,
+            kind,
+            inner,
+            buf: Vec::with_capacity(BLOCK_SIZE),
+            index: 0,
+        }
+    }
+
+    fn seal_block(&mut self, last: bool) -> io::Result<()> {
+        let aad = block_aad(self.id, self.kind, self.index, last);
+        let sealed = self.key.seal(&aad, &self.buf)?;
+        self.inner.write_all(&sealed)?;
+        self.buf.clear();
+        self.index += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal_block(true)?;
+        Ok(self.inner)
+    }
+}
+
+impl<'a, W: Write> Write for SealingWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A full block is only sealed once more data comes, as it could be the last one.
+        if self.buf.len() == BLOCK_SIZE && !buf.is_empty() {
+            self.seal_block(false)?;
+        }
+        let len = buf.len().min(BLOCK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: [u8; ID_SIZE] = [1; ID_SIZE];
+
+    fn seal_section(key: &SnapshotKey, data: &[u8]) -> Vec<u8> {
+        let mut writer = SealingWriter::new(key, &ID, 3, vec![]);
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_sealing_writer() {
+        let key = SnapshotKey::new([7; KEY_SIZE]);
+        for len in [0, 1, BLOCK_SIZE, BLOCK_SIZE + 1, 3 * BLOCK_SIZE] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let sealed = seal_section(&key, &data);
+            assert_eq!(data_len(sealed.len() as u64), Some(len as u64));
+
+            let blocks: Vec<_> = sealed.chunks(SEALED_BLOCK_SIZE).collect();
+            let mut opened = vec![];
+            for (index, block) in blocks.iter().enumerate() {
+                let last = index == blocks.len() - 1;
+                let aad = block_aad(&ID, 3, index as u64, last);
+                opened.extend(key.open(&aad, block).unwrap());
+            }
+            assert_eq!(opened, data);
+        }
+    }
+
+    #[test]
+    fn test_tampering() {
+        let key = SnapshotKey::new([7; KEY_SIZE]);
+        let sealed = seal_section(&key, b"guest secret");
+        let aad = block_aad(&ID, 3, 0, true);
+        assert_eq!(key.open(&aad, &sealed).unwrap(), b"guest secret");
+
+        let mut modified = sealed.clone();
+        modified[NONCE_SIZE] ^= 1;
+        assert!(key.open(&aad, &modified).is_none());
+        // Moved to another section, or cut short.
+        assert!(key.open(&block_aad(&ID, 1, 0, true), &sealed).is_none());
+        assert!(key.open(&block_aad(&ID, 3, 0, false), &sealed).is_none());
+        assert!(SnapshotKey::new([8; KEY_SIZE])
+            .open(&aad, &sealed)
+            .is_none());
+        assert!(data_len(BLOCK_OVERHEAD as u64 - 1).is_none());
+    }
+
+    #[test]
+    fn test_key_from_hex() {
+        let hex = "00".repeat(31) + "ff\n";
+        assert_eq!(SnapshotKey::from_hex(&hex).unwrap().0[31], 0xff);
+        assert!(SnapshotKey::from_hex("ff").is_none());
+        assert!(SnapshotKey::from_hex(&"zz".repeat(32)).is_none());
+    }
+}
@@ -0,0 +1,1197 @@
+// Snapshot container: a single file holding everything needed to restore a guest.
+//
+// Layout (all integers little endian):
+//
+//   header   magic (8 bytes), format version (u32), number of sections (u32),
+//            offset of the section table (u64), CRC64 of the section table (u64)
+//   sections one after the other, the guest memory starting on a dedup chunk boundary so it
+//            can be mapped and shares its chunks with the migration
+//   table    for each section: kind (u32), reserved (u32), offset (u64), length (u64),
+//            CRC64 of the section (u64)
+//
+// The table goes last since the checksums are only known once the sections are written. Readers
+// skip the sections they don't know about. Files without the magic are the raw `VmState` that
+// snapshots used to be made of.
+//
+// A diff snapshot only holds the pages dirtied since the snapshot it's based on, its parent, whose
+// path it records. Its guest memory is rebuilt by layering the diffs over the full snapshot at the
+// root of the chain.
+//
+// A snapshot is written next to its final path and renamed into place once synced, so a failed
+// snapshot never replaces a good one, or leaves a truncated file behind.
+//
+// With a snapshot key configured (see `snapshot_crypto`), every section but the `Encryption` one
+// is encrypted, which its table entry flags. The checksums are those of the encrypted sections.
+// A last `TableSeal` section holds the table entries of all the others, so that the table, and
+// with it every section, can't be modified without restore noticing. The guest memory of an
+// encrypted snapshot can't be mapped from the file, it's decrypted into memory instead.
+
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use versionize::crc::{CRC64Reader, CRC64Writer};
+use versionize::{VersionMap, Versionize, VersionizeError};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+use vm_vcpu::vm;
+
+use crate::dirty_log::PAGE_SIZE;
+use crate::memory_snapshot::{GuestMemoryState, SnapshotMemory};
+use crate::snapshot_compat::Mismatch;
+use crate::snapshot_crypto::{self, SealingWriter, SnapshotKey, ID_SIZE, SEALED_BLOCK_SIZE};
+use crate::CHUNK_SIZE;
+
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"RVMMSNAP";
+/// Version 2 adds encryption, which readers of version 1 would take for plaintext sections.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 2;
+// Oldest version still read.
+const MIN_SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+const HEADER_SIZE: u64 = 32;
+const TABLE_ENTRY_SIZE: usize = 32;
+
+/// Flag of the sections that are encrypted.
+pub const SECTION_ENCRYPTED: u32 = 1;
+
+// Algorithm recorded in the `Encryption` section, the only one so far.
+const CHACHA20_POLY1305: u32 = 1;
+
+/// Errors of taking or restoring a snapshot.
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// I/O error on the snapshot file.
+    Io(io::Error),
+    /// The snapshot is damaged, e.g. truncated or with a checksum that doesn't match.
+    Corrupted(String),
+    /// The snapshot was written in a format this VMM doesn't know.
+    UnsupportedFormat(u32),
+    /// Failed to serialize or deserialize some state.
+    Versionize(VersionizeError),
+    /// Failed to serialize or deserialize the metadata.
+    Metadata(serde_json::Error),
+    /// The snapshot lacks a section needed to restore the guest.
+    MissingSection(SectionKind),
+    /// Failed to copy the guest memory.
+    Memory(GuestMemoryError),
+    /// Failed to save the state of the VM.
+    Vm(vm::Error),
+    /// The vCPUs aren't running, e.g. the guest was restored paused.
+    NotRunning,
+    /// Failed to read the dirty log.
+    DirtyLog(kvm_ioctls::Error),
+    /// No snapshot to base a diff snapshot on: none was taken yet, or the dirty log was read by
+    /// someone else since.
+    NoDiffParent,
+    /// A diff snapshot can't replace its parent, or any snapshot the parent is based on.
+    OverwritesParent,
+    /// A background snapshot is still being written.
+    InProgress,
+    /// The snapshot can't be restored with this configuration, or on this host.
+    Incompatible(Vec<Mismatch>),
+    /// The snapshot is encrypted, and no snapshot key is configured.
+    MissingKey,
+    /// The snapshot was encrypted with another key than the configured one.
+    WrongKey,
+    /// A snapshot key is configured, and the snapshot is not encrypted.
+    NotEncrypted,
+    /// The encrypted snapshot doesn't authenticate: it was modified, or damaged.
+    Tampered(String),
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+impl From<VersionizeError> for SnapshotError {
+    fn from(e: VersionizeError) -> Self {
+        SnapshotError::Versionize(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, SnapshotError>;
+
+/// What a section of the snapshot holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SectionKind {
+    /// `VmState`, preceded by the version it was written at (see `version_map`).
+    VmState = 1,
+    /// Versionized `GuestMemoryState`, with offsets relative to the `MemoryData` section of a
+    /// full snapshot.
+    MemoryLayout = 2,
+    /// Contents of the guest memory regions, or only of the `DirtyPages` in a diff snapshot.
+    /// Left out of the snapshots only used to carry the vCPU state of a migrating guest.
+    MemoryData = 3,
+    /// State of the devices. Not written yet, the devices are set up from scratch on restore.
+    Devices = 4,
+    /// JSON `SnapshotMetadata`.
+    Metadata = 5,
+    /// Path of the parent of a diff snapshot.
+    Parent = 6,
+    /// Page numbers (u64) of the pages saved in a diff snapshot, in the order they're saved.
+    DirtyPages = 7,
+    /// How the snapshot is encrypted: algorithm (u32), id of the snapshot, and an empty message
+    /// sealed with the key, to tell a wrong key from a tampered snapshot. Never encrypted.
+    Encryption = 8,
+    /// Table entries of the other sections of an encrypted snapshot.
+    TableSeal = 9,
+}
+
+impl SectionKind {
+    fn from_u32(kind: u32) -> Option<Self> {
+        match kind {
+            1 => Some(SectionKind::VmState),
+            2 => Some(SectionKind::MemoryLayout),
+            3 => Some(SectionKind::MemoryData),
+            4 => Some(SectionKind::Devices),
+            5 => Some(SectionKind::Metadata),
+            6 => Some(SectionKind::Parent),
+            7 => Some(SectionKind::DirtyPages),
+            8 => Some(SectionKind::Encryption),
+            9 => Some(SectionKind::TableSeal),
+            _ => None,
+        }
+    }
+}
+
+/// Entry of the section table.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Section {
+    pub kind: u32,
+    /// `SECTION_ENCRYPTED` or not.
+    pub flags: u32,
+    pub offset: u64,
+    pub len: u64,
+    pub checksum: u64,
+}
+
+impl Section {
+    fn to_bytes(self) -> [u8; TABLE_ENTRY_SIZE] {
+        let mut bytes = [0u8; TABLE_ENTRY_SIZE];
+        bytes[0..4].copy_from_slice(&self.kind.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.len.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Section {
+            kind: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            flags: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            offset: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            len: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+            checksum: u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & SECTION_ENCRYPTED != 0
+    }
+
+    /// Length of the contents of the section, once decrypted. `None` if the section is too short
+    /// to be encrypted.
+    pub fn data_len(&self) -> Option<u64> {
+        if self.is_encrypted() {
+            snapshot_crypto::data_len(self.len)
+        } else {
+            Some(self.len)
+        }
+    }
+}
+
+// Key and id of an encrypted snapshot.
+struct Encryption {
+    key: SnapshotKey,
+    id: [u8; ID_SIZE],
+}
+
+impl Encryption {
+    fn new(key: SnapshotKey) -> Result<Self> {
+        let mut id = [0u8; ID_SIZE];
+        snapshot_crypto::fill_random(&mut id)?;
+        Ok(Encryption { key, id })
+    }
+
+    fn key_check_aad(id: &[u8; ID_SIZE]) -> Vec<u8> {
+        let mut aad = b"key check".to_vec();
+        aad.extend_from_slice(id);
+        aad
+    }
+
+    // Contents of the `Encryption` section.
+    fn params(&self) -> Result<Vec<u8>> {
+        let mut params = CHACHA20_POLY1305.to_le_bytes().to_vec();
+        params.extend_from_slice(&self.id);
+        params.extend(self.key.seal(&Self::key_check_aad(&self.id), &[])?);
+        Ok(params)
+    }
+
+    fn from_params(key: SnapshotKey, params: &[u8]) -> Result<Self> {
+        if params.len() < 4 + ID_SIZE {
+            return Err(SnapshotError::Corrupted(
+                "encryption section is truncated".to_string(),
+            ));
+        }
+        let algorithm = u32::from_le_bytes(params[0..4].try_into().unwrap());
+        if algorithm != CHACHA20_POLY1305 {
+            return Err(SnapshotError::Corrupted(format!(
+                "unknown encryption algorithm {}",
+                algorithm
+            )));
+        }
+        let id: [u8; ID_SIZE] = params[4..4 + ID_SIZE].try_into().unwrap();
+        key.open(&Self::key_check_aad(&id), &params[4 + ID_SIZE..])
+            .ok_or(SnapshotError::WrongKey)?;
+        Ok(Encryption { key, id })
+    }
+}
+
+// Writes what it's given over `extents` of the guest memory, one after the other.
+struct GuestMemoryWriter<'a> {
+    guest_memory: &'a GuestMemoryMmap,
+    extents: Vec<(GuestAddress, usize)>,
+    // Extent being written, and how much of it already is.
+    current: usize,
+    done: usize,
+}
+
+impl<'a> GuestMemoryWriter<'a> {
+    fn new(guest_memory: &'a GuestMemoryMmap, extents: Vec<(GuestAddress, usize)>) -> Self {
+        GuestMemoryWriter {
+            guest_memory,
+            extents,
+            current: 0,
+            done: 0,
+        }
+    }
+}
+
+impl<'a> Write for GuestMemoryWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (addr, len) = match self.extents.get(self.current) {
+            Some(extent) => *extent,
+            None if buf.is_empty() => return Ok(0),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "more data than guest memory",
+                ))
+            }
+        };
+        let count = buf.len().min(len - self.done);
+        self.guest_memory
+            .write_slice(&buf[..count], GuestAddress(addr.0 + self.done as u64))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+        self.done += count;
+        if self.done == len {
+            self.current += 1;
+            self.done = 0;
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Describes where and when a snapshot was taken, and the VM it was taken of. The fields after
+/// `mem_size` are `None` in snapshots of older VMMs.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotMetadata {
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    pub vmm_version: String,
+    pub mem_size: u64,
+    /// Architecture of the host, as in `std::env::consts::ARCH`.
+    pub arch: Option<String>,
+    pub num_vcpus: Option<u8>,
+    /// Virtio devices of the VM, in the order they were added, e.g. `["block", "net"]`.
+    pub devices: Option<Vec<String>>,
+}
+
+impl SnapshotMetadata {
+    pub fn new(mem_size: u64, num_vcpus: u8, devices: Vec<String>) -> Self {
+        SnapshotMetadata {
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_secs()),
+            vmm_version: env!("CARGO_PKG_VERSION").to_string(),
+            mem_size,
+            arch: Some(std::env::consts::ARCH.to_string()),
+            num_vcpus: Some(num_vcpus),
+            devices: Some(devices),
+        }
+    }
+}
+
+/// Writes a snapshot, one section at a time. Nothing shows up at the path of the snapshot until
+/// `finish` succeeds.
+pub struct SnapshotWriter {
+    file: File,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    sections: Vec<Section>,
+    encryption: Option<Encryption>,
+    finished: bool,
+}
+
+impl SnapshotWriter {
+    /// Creates a snapshot, encrypted with the snapshot key of the environment if there's one.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::create_with_key(path, SnapshotKey::from_env()?)
+    }
+
+    /// Creates a snapshot, encrypted with `key` if given.
+    pub fn create_with_key<P: AsRef<Path>>(path: P, key: Option<SnapshotKey>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        let mut writer = SnapshotWriter {
+            file,
+            path,
+            tmp_path,
+            sections: vec![],
+            encryption: None,
+            finished: false,
+        };
+        // Filled in by `finish`.
+        writer.file.write_all(&[0u8; HEADER_SIZE as usize])?;
+        if let Some(key) = key {
+            let encryption = Encryption::new(key)?;
+            writer.add_section(SectionKind::Encryption, &encryption.params()?)?;
+            writer.encryption = Some(encryption);
+        }
+        Ok(writer)
+    }
+
+    // Writes a section starting at a multiple of `align`, with what `write` outputs, encrypted
+    // if the snapshot is.
+    fn write_section<F>(&mut self, kind: SectionKind, align: u64, write: F) -> Result<()>
+    where
+        F: FnOnce(&mut dyn Write) -> Result<()>,
+    {
+        let end = self.file.seek(SeekFrom::End(0))?;
+        let offset = (end + align - 1) / align * align;
+        self.file.set_len(offset)?;
+        self.file.seek(SeekFrom::Start(offset))?;
+
+        let mut writer = CRC64Writer::new(&mut self.file);
+        let flags = match self.encryption.as_ref() {
+            Some(encryption) => {
+                let mut sealing =
+                    SealingWriter::new(&encryption.key, &encryption.id, kind as u32, &mut writer);
+                write(&mut sealing)?;
+                sealing.finish()?;
+                SECTION_ENCRYPTED
+            }
+            None => {
+                write(&mut writer)?;
+                0
+            }
+        };
+        let checksum = writer.checksum();
+
+        let len = self.file.seek(SeekFrom::Current(0))? - offset;
+        self.sections.push(Section {
+            kind: kind as u32,
+            flags,
+            offset,
+            len,
+            checksum,
+        });
+        Ok(())
+    }
+
+    pub fn add_section(&mut self, kind: SectionKind, data: &[u8]) -> Result<()> {
+        self.write_section(kind, 8, |writer| Ok(writer.write_all(data)?))
+    }
+
+    fn add_layout(&mut self, guest_memory: &GuestMemoryMmap) -> Result<()> {
+        let mut layout = vec![];
+        guest_memory
+            .describe()
+            .serialize(&mut layout, &VersionMap::new(), 1)?;
+        self.add_section(SectionKind::MemoryLayout, &layout)
+    }
+
+    /// Adds the layout and the contents of the guest memory.
+    pub fn add_memory(&mut self, guest_memory: &GuestMemoryMmap) -> Result<()> {
+        self.add_memory_with(guest_memory, |mut writer| {
+            guest_memory.dump(&mut writer).map_err(SnapshotError::Memory)
+        })
+    }
+
+    /// Like `add_memory`, with the contents written by `dump`, in the same order.
+    pub fn add_memory_with<F>(&mut self, guest_memory: &GuestMemoryMmap, dump: F) -> Result<()>
+    where
+        F: FnOnce(&mut dyn Write) -> Result<()>,
+    {
+        self.add_layout(guest_memory)?;
+        self.write_section(SectionKind::MemoryData, CHUNK_SIZE as u64, |writer| dump(writer))
+    }
+
+    /// Makes this a diff snapshot on top of the one at `parent`, holding only `pages` of the
+    /// guest memory.
+    pub fn add_diff(
+        &mut self,
+        parent: &str,
+        guest_memory: &GuestMemoryMmap,
+        pages: &[usize],
+    ) -> Result<()> {
+        self.add_section(SectionKind::Parent, parent.as_bytes())?;
+        self.add_layout(guest_memory)?;
+
+        let mut page_numbers = Vec::with_capacity(pages.len() * 8);
+        for page in pages {
+            page_numbers.extend_from_slice(&(*page as u64).to_le_bytes());
+        }
+        self.add_section(SectionKind::DirtyPages, &page_numbers)?;
+
+        self.write_section(SectionKind::MemoryData, PAGE_SIZE as u64, |writer| {
+            let mut buf = vec![0u8; PAGE_SIZE];
+            for page in pages {
+                guest_memory
+                    .read_slice(&mut buf, GuestAddress((page * PAGE_SIZE) as u64))
+                    .map_err(SnapshotError::Memory)?;
+                writer.write_all(&buf)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Writes the section table and the header, and moves the synced snapshot into place.
+    pub fn finish(mut self) -> Result<()> {
+        if self.encryption.is_some() {
+            let table: Vec<u8> = self
+                .sections
+                .iter()
+                .flat_map(|section| section.to_bytes().to_vec())
+                .collect();
+            self.add_section(SectionKind::TableSeal, &table)?;
+        }
+
+        let table_offset = self.file.seek(SeekFrom::End(0))?;
+        let mut table = Vec::with_capacity(self.sections.len() * TABLE_ENTRY_SIZE);
+        for section in self.sections.iter() {
+            table.extend_from_slice(&section.to_bytes());
+        }
+        let mut writer = CRC64Writer::new(&mut self.file);
+        writer.write_all(&table)?;
+        let table_checksum = writer.checksum();
+
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(&SNAPSHOT_MAGIC);
+        header.extend_from_slice(&SNAPSHOT_FORMAT_VERSION.to_le_bytes());
+        header.extend_from_slice(&(self.sections.len() as u32).to_le_bytes());
+        header.extend_from_slice(&table_offset.to_le_bytes());
+        header.extend_from_slice(&table_checksum.to_le_bytes());
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.sync_all()?;
+
+        fs::rename(&self.tmp_path, &self.path)?;
+        self.finished = true;
+        // Makes the rename itself durable.
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+impl Drop for SnapshotWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+/// Reads the section table of the snapshot in `file`, after checking its checksum. Returns `None`
+/// when the file is not a snapshot container, i.e. a legacy snapshot.
+pub fn read_section_table(mut file: &File) -> Result<Option<Vec<Section>>> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut header = [0u8; HEADER_SIZE as usize];
+    match file.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    if header[0..8] != SNAPSHOT_MAGIC {
+        return Ok(None);
+    }
+
+    let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    if !(MIN_SNAPSHOT_FORMAT_VERSION..=SNAPSHOT_FORMAT_VERSION).contains(&version) {
+        return Err(SnapshotError::UnsupportedFormat(version));
+    }
+    let num_sections = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+    let table_offset = u64::from_le_bytes(header[16..24].try_into().unwrap());
+    let table_checksum = u64::from_le_bytes(header[24..32].try_into().unwrap());
+
+    // Nothing is allocated for a table that can't be in the file.
+    let file_len = file.metadata()?.len();
+    let table_len = num_sections
+        .checked_mul(TABLE_ENTRY_SIZE)
+        .filter(|len| *len as u64 <= file_len.saturating_sub(HEADER_SIZE))
+        .filter(|len| {
+            table_offset
+                .checked_add(*len as u64)
+                .map_or(false, |end| end <= file_len)
+        })
+        .ok_or_else(|| {
+            SnapshotError::Corrupted(format!(
+                "section table of {} entries at {} is past the end of the file",
+                num_sections, table_offset
+            ))
+        })?;
+
+    file.seek(SeekFrom::Start(table_offset))?;
+    let mut reader = CRC64Reader::new(&mut file);
+    let mut table = vec![0u8; table_len];
+    reader.read_exact(&mut table)?;
+    if reader.checksum() != table_checksum {
+        return Err(SnapshotError::Corrupted(
+            "bad checksum of the section table".to_string(),
+        ));
+    }
+
+    Ok(Some(
+        table
+            .chunks_exact(TABLE_ENTRY_SIZE)
+            .map(Section::from_bytes)
+            .collect(),
+    ))
+}
+
+/// Snapshot opened for restoring.
+pub struct SnapshotReader {
+    file: File,
+    sections: Vec<Section>,
+    encryption: Option<Encryption>,
+}
+
+impl SnapshotReader {
+    /// Opens the snapshot at `path` and checks its section table, decrypting it with the snapshot
+    /// key of the environment. Returns `None` when the file is not a snapshot container, i.e. a
+    /// legacy snapshot.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        Self::from_file(File::open(path)?)
+    }
+
+    /// Like `open`, for a snapshot that's already open.
+    pub fn from_file(file: File) -> Result<Option<Self>> {
+        Self::from_file_with_key(file, SnapshotKey::from_env()?)
+    }
+
+    /// Like `from_file`, with `key` as the snapshot key. With a key, only encrypted snapshots
+    /// are opened.
+    pub fn from_file_with_key(file: File, key: Option<SnapshotKey>) -> Result<Option<Self>> {
+        let sections = match read_section_table(&file)? {
+            Some(sections) => sections,
+            None if key.is_some() => return Err(SnapshotError::NotEncrypted),
+            None => return Ok(None),
+        };
+        let mut snapshot = SnapshotReader {
+            file,
+            sections,
+            encryption: None,
+        };
+        snapshot.check_encryption(key)?;
+        Ok(Some(snapshot))
+    }
+
+    // Sets up the decryption of an encrypted snapshot, after checking its section table against
+    // the sealed one.
+    fn check_encryption(&mut self, key: Option<SnapshotKey>) -> Result<()> {
+        let (key, params) = match (key, self.read_section(SectionKind::Encryption)?) {
+            (None, None) => return Ok(()),
+            (None, Some(_)) => return Err(SnapshotError::MissingKey),
+            (Some(_), None) => return Err(SnapshotError::NotEncrypted),
+            (Some(key), Some(params)) => (key, params),
+        };
+        self.encryption = Some(Encryption::from_params(key, &params)?);
+
+        let sealed_table = match self.section(SectionKind::TableSeal) {
+            Some(section) if section.is_encrypted() => self.read_section(SectionKind::TableSeal)?,
+            _ => None,
+        }
+        .ok_or_else(|| SnapshotError::Tampered("the section table isn't sealed".to_string()))?;
+        let table: Vec<u8> = self
+            .sections
+            .iter()
+            .filter(|section| section.kind != SectionKind::TableSeal as u32)
+            .flat_map(|section| section.to_bytes().to_vec())
+            .collect();
+        if sealed_table != table {
+            return Err(SnapshotError::Tampered(
+                "the section table was modified".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    pub fn section(&self, kind: SectionKind) -> Option<Section> {
+        self.sections
+            .iter()
+            .copied()
+            .find(|section| SectionKind::from_u32(section.kind) == Some(kind))
+    }
+
+    // Streams `section` through `out`, checking its checksum on the way, and decrypting it if
+    // it's encrypted.
+    fn copy_section<W: Write>(&self, section: Section, out: &mut W) -> Result<()> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(section.offset))?;
+        let mut reader = CRC64Reader::new(file.take(section.len));
+        let copied = if section.is_encrypted() {
+            self.open_section(section, &mut reader, out)?
+        } else {
+            io::copy(&mut reader, out)?
+        };
+        if copied != section.len {
+            return Err(SnapshotError::Corrupted(format!(
+                "section {} is truncated",
+                section.kind
+            )));
+        }
+        if reader.checksum() != section.checksum {
+            return Err(SnapshotError::Corrupted(format!(
+                "bad checksum of section {}",
+                section.kind
+            )));
+        }
+        Ok(())
+    }
+
+    // Decrypts the blocks of `section`, read from `reader`, into `out`. Returns how much was read,
+    // less than the section when it's truncated.
+    fn open_section<R: Read, W: Write>(
+        &self,
+        section: Section,
+        reader: &mut R,
+        out: &mut W,
+    ) -> Result<u64> {
+        let encryption = self.encryption.as_ref().ok_or_else(|| {
+            SnapshotError::Corrupted(format!(
+                "section {} is encrypted, the snapshot isn't",
+                section.kind
+            ))
+        })?;
+        let mut block = vec![0u8; SEALED_BLOCK_SIZE];
+        let mut read = 0;
+        for index in 0u64.. {
+            let len = (section.len - read).min(SEALED_BLOCK_SIZE as u64) as usize;
+            match reader.read_exact(&mut block[..len]) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            read += len as u64;
+            let last = read == section.len;
+            let aad = snapshot_crypto::block_aad(&encryption.id, section.kind, index, last);
+            let data = encryption.key.open(&aad, &block[..len]).ok_or_else(|| {
+                SnapshotError::Tampered(format!(
+                    "block {} of section {} doesn't authenticate",
+                    index, section.kind
+                ))
+            })?;
+            out.write_all(&data)?;
+            if last {
+                break;
+            }
+        }
+        Ok(read)
+    }
+
+    /// Reads and checks a section, `None` if the snapshot doesn't have it.
+    pub fn read_section(&self, kind: SectionKind) -> Result<Option<Vec<u8>>> {
+        let section = match self.section(kind) {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+        let mut data = Vec::with_capacity(section.len as usize);
+        self.copy_section(section, &mut data)?;
+        Ok(Some(data))
+    }
+
+    /// Checks a section without keeping its contents around.
+    pub fn verify_section(&self, section: Section) -> Result<()> {
+        self.copy_section(section, &mut io::sink())
+    }
+
+    pub fn metadata(&self) -> Result<Option<SnapshotMetadata>> {
+        match self.read_section(SectionKind::Metadata)? {
+            Some(data) => serde_json::from_slice(&data)
+                .map(Some)
+                .map_err(SnapshotError::Metadata),
+            None => Ok(None),
+        }
+    }
+
+    /// Path of the snapshot this one is a diff of, `None` for a full snapshot.
+    pub fn parent(&self) -> Result<Option<String>> {
+        match self.read_section(SectionKind::Parent)? {
+            Some(path) => String::from_utf8(path)
+                .map(Some)
+                .map_err(|_| SnapshotError::Corrupted("parent path is not UTF-8".to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Layout of the guest memory, with the offsets of the regions in the snapshot file, or in
+    /// the decrypted memory data of an encrypted snapshot.
+    pub fn memory_layout(&self) -> Result<Option<GuestMemoryState>> {
+        let (layout, data) = match (
+            self.read_section(SectionKind::MemoryLayout)?,
+            self.section(SectionKind::MemoryData),
+        ) {
+            (Some(layout), Some(data)) => (layout, data),
+            _ => return Ok(None),
+        };
+        let mut state = GuestMemoryState::deserialize(&mut &layout[..], &VersionMap::new(), 1)?;
+        if !data.is_encrypted() {
+            for region in state.regions.iter_mut() {
+                region.offset += data.offset;
+            }
+        }
+        Ok(Some(state))
+    }
+
+    /// Maps the guest memory saved in the snapshot (privately, the snapshot is left as is), after
+    /// checking it. The memory of an encrypted snapshot is decrypted into anonymous memory
+    /// instead. `None` if the snapshot has no memory.
+    pub fn restore_memory(&self, track_dirty_pages: bool) -> Result<Option<GuestMemoryMmap>> {
+        let state = match self.memory_layout()? {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+        let data = self.section(SectionKind::MemoryData).unwrap();
+        if !data.is_encrypted() {
+            self.verify_section(data)?;
+            return Ok(Some(GuestMemoryMmap::restore(
+                Some(&self.file),
+                &state,
+                track_dirty_pages,
+            )));
+        }
+
+        let size = state.regions.iter().map(|region| region.size as u64).sum();
+        if data.data_len() != Some(size) {
+            return Err(SnapshotError::Corrupted(
+                "memory data doesn't match the memory layout".to_string(),
+            ));
+        }
+        let guest_memory = GuestMemoryMmap::restore(None, &state, track_dirty_pages);
+        let extents = state
+            .regions
+            .iter()
+            .map(|region| (GuestAddress(region.base_address), region.size))
+            .collect();
+        self.copy_section(data, &mut GuestMemoryWriter::new(&guest_memory, extents))?;
+        Ok(Some(guest_memory))
+    }
+
+    /// Writes the pages saved in a diff snapshot over `guest_memory`, after checking them.
+    pub fn apply_diff(&self, guest_memory: &GuestMemoryMmap) -> Result<()> {
+        let pages = self
+            .read_section(SectionKind::DirtyPages)?
+            .ok_or(SnapshotError::MissingSection(SectionKind::DirtyPages))?;
+        let data = self
+            .section(SectionKind::MemoryData)
+            .ok_or(SnapshotError::MissingSection(SectionKind::MemoryData))?;
+        if pages.len() % 8 != 0 || data.data_len() != Some((pages.len() / 8 * PAGE_SIZE) as u64) {
+            return Err(SnapshotError::Corrupted(
+                "dirty pages don't match the memory data".to_string(),
+            ));
+        }
+        if data.is_encrypted() {
+            let extents = pages
+                .chunks_exact(8)
+                .map(|page| {
+                    let page = u64::from_le_bytes(page.try_into().unwrap());
+                    (GuestAddress(page * PAGE_SIZE as u64), PAGE_SIZE)
+                })
+                .collect();
+            return self.copy_section(data, &mut GuestMemoryWriter::new(guest_memory, extents));
+        }
+        self.verify_section(data)?;
+
+        let mut buf = vec![0u8; PAGE_SIZE];
+        for (index, page) in pages.chunks_exact(8).enumerate() {
+            let page = u64::from_le_bytes(page.try_into().unwrap());
+            self.file
+                .read_exact_at(&mut buf, data.offset + (index * PAGE_SIZE) as u64)?;
+            guest_memory
+                .write_slice(&buf, GuestAddress(page * PAGE_SIZE as u64))
+                .map_err(SnapshotError::Memory)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vmm_sys_util::tempdir::TempDir;
+
+    fn guest_memory() -> GuestMemoryMmap {
+        let regions = vec![(None, GuestAddress(0), 4 * PAGE_SIZE)];
+        let guest_memory = vm_memory::create_guest_memory(&regions, false).unwrap();
+        guest_memory
+            .write_slice(b"guest", GuestAddress(PAGE_SIZE as u64))
+            .unwrap();
+        guest_memory
+    }
+
+    fn write_snapshot(path: &Path, vm_state: &[u8]) {
+        let mut writer = SnapshotWriter::create(path).unwrap();
+        writer.add_section(SectionKind::VmState, vm_state).unwrap();
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("snapshot");
+        let mut writer = SnapshotWriter::create(&path).unwrap();
+        writer
+            .add_section(SectionKind::VmState, b"vm state")
+            .unwrap();
+        writer.add_memory(&guest_memory()).unwrap();
+        writer.finish().unwrap();
+
+        let snapshot = SnapshotReader::open(&path).unwrap().unwrap();
+        assert_eq!(snapshot.sections().len(), 3);
+        assert_eq!(
+            snapshot.read_section(SectionKind::VmState).unwrap().unwrap(),
+            b"vm state"
+        );
+        assert!(snapshot.read_section(SectionKind::Devices).unwrap().is_none());
+        let data = snapshot.section(SectionKind::MemoryData).unwrap();
+        assert_eq!(data.offset % PAGE_SIZE as u64, 0);
+        assert_eq!(data.len, 4 * PAGE_SIZE as u64);
+
+        let restored = snapshot.restore_memory(false).unwrap().unwrap();
+        let mut buf = [0u8; 5];
+        restored
+            .read_slice(&mut buf, GuestAddress(PAGE_SIZE as u64))
+            .unwrap();
+        assert_eq!(&buf, b"guest");
+    }
+
+    #[test]
+    fn test_snapshot_corruption() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("snapshot");
+        write_snapshot(&path, b"vm state");
+
+        let section = SnapshotReader::open(&path)
+            .unwrap()
+            .unwrap()
+            .section(SectionKind::VmState)
+            .unwrap();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(b"V", section.offset).unwrap();
+        let snapshot = SnapshotReader::open(&path).unwrap().unwrap();
+        assert!(matches!(
+            snapshot.read_section(SectionKind::VmState),
+            Err(SnapshotError::Corrupted(_))
+        ));
+    }
+
+    #[test]
+    fn test_format_version() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("snapshot");
+        write_snapshot(&path, b"vm state");
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+
+        file.write_all_at(&1u32.to_le_bytes(), 8).unwrap();
+        let snapshot = SnapshotReader::open(&path).unwrap().unwrap();
+        assert_eq!(
+            snapshot
+                .read_section(SectionKind::VmState)
+                .unwrap()
+                .unwrap(),
+            b"vm state"
+        );
+
+        file.write_all_at(&(SNAPSHOT_FORMAT_VERSION + 1).to_le_bytes(), 8)
+            .unwrap();
+        assert!(matches!(
+            SnapshotReader::open(&path),
+            Err(SnapshotError::UnsupportedFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_section_table_past_end() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("snapshot");
+        write_snapshot(&path, b"vm state");
+
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&u32::MAX.to_le_bytes(), 12).unwrap();
+        assert!(matches!(
+            SnapshotReader::open(&path),
+            Err(SnapshotError::Corrupted(_))
+        ));
+
+        write_snapshot(&path, b"vm state");
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&u64::MAX.to_le_bytes(), 16).unwrap();
+        assert!(matches!(
+            SnapshotReader::open(&path),
+            Err(SnapshotError::Corrupted(_))
+        ));
+    }
+
+    #[test]
+    fn test_diff_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.as_path().join("base");
+        let diff_path = dir.as_path().join("diff");
+
+        let guest_memory = guest_memory();
+        let mut writer = SnapshotWriter::create(&base_path).unwrap();
+        writer.add_memory(&guest_memory).unwrap();
+        writer.finish().unwrap();
+
+        guest_memory
+            .write_slice(b"dirty", GuestAddress(3 * PAGE_SIZE as u64))
+            .unwrap();
+        let mut writer = SnapshotWriter::create(&diff_path).unwrap();
+        writer
+            .add_diff(base_path.to_str().unwrap(), &guest_memory, &[3])
+            .unwrap();
+        writer.finish().unwrap();
+
+        let base = SnapshotReader::open(&base_path).unwrap().unwrap();
+        let diff = SnapshotReader::open(&diff_path).unwrap().unwrap();
+        assert!(base.parent().unwrap().is_none());
+        assert_eq!(diff.parent().unwrap().unwrap(), base_path.to_str().unwrap());
+        assert_eq!(
+            diff.section(SectionKind::MemoryData).unwrap().len,
+            PAGE_SIZE as u64
+        );
+
+        let restored = base.restore_memory(false).unwrap().unwrap();
+        diff.apply_diff(&restored).unwrap();
+        let mut buf = [0u8; 5];
+        restored
+            .read_slice(&mut buf, GuestAddress(3 * PAGE_SIZE as u64))
+            .unwrap();
+        assert_eq!(&buf, b"dirty");
+        restored
+            .read_slice(&mut buf, GuestAddress(PAGE_SIZE as u64))
+            .unwrap();
+        assert_eq!(&buf, b"guest");
+
+        // The pages of a diff only go on top of its parent.
+        assert!(matches!(
+            base.apply_diff(&restored),
+            Err(SnapshotError::MissingSection(SectionKind::DirtyPages))
+        ));
+    }
+
+    #[test]
+    fn test_failed_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("snapshot");
+        write_snapshot(&path, b"old state");
+
+        // A snapshot that doesn't make it to `finish` leaves the previous one alone.
+        let mut writer = SnapshotWriter::create(&path).unwrap();
+        writer
+            .add_section(SectionKind::VmState, b"new state")
+            .unwrap();
+        drop(writer);
+
+        let snapshot = SnapshotReader::open(&path).unwrap().unwrap();
+        assert_eq!(
+            snapshot.read_section(SectionKind::VmState).unwrap().unwrap(),
+            b"old state"
+        );
+        assert_eq!(fs::read_dir(dir.as_path()).unwrap().count(), 1);
+
+        assert!(matches!(
+            SnapshotWriter::create(dir.as_path().join("missing").join("snapshot")),
+            Err(SnapshotError::Io(_))
+        ));
+    }
+
+    fn open_with_key(path: &Path, key: Option<SnapshotKey>) -> Result<Option<SnapshotReader>> {
+        SnapshotReader::from_file_with_key(File::open(path).unwrap(), key)
+    }
+
+    #[test]
+    fn test_encrypted_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.as_path().join("base");
+        let diff_path = dir.as_path().join("diff");
+        let key = SnapshotKey::new([7; 32]);
+
+        let guest_memory = guest_memory();
+        let mut writer = SnapshotWriter::create_with_key(&base_path, Some(key.clone())).unwrap();
+        writer
+            .add_section(SectionKind::VmState, b"vm state")
+            .unwrap();
+        writer.add_memory(&guest_memory).unwrap();
+        writer.finish().unwrap();
+        guest_memory
+            .write_slice(b"dirty", GuestAddress(3 * PAGE_SIZE as u64))
+            .unwrap();
+        let mut writer = SnapshotWriter::create_with_key(&diff_path, Some(key.clone())).unwrap();
+        writer
+            .add_diff(base_path.to_str().unwrap(), &guest_memory, &[3])
+            .unwrap();
+        writer.finish().unwrap();
+
+        // Nothing of the guest is left in the clear.
+        let contents = fs::read(&base_path).unwrap();
+        assert!(!contents.windows(5).any(|window| window == b"guest"));
+
+        let base = open_with_key(&base_path, Some(key.clone()))
+            .unwrap()
+            .unwrap();
+        let diff = open_with_key(&diff_path, Some(key.clone()))
+            .unwrap()
+            .unwrap();
+        assert!(base.is_encrypted());
+        assert_eq!(
+            base.read_section(SectionKind::VmState).unwrap().unwrap(),
+            b"vm state"
+        );
+        assert_eq!(diff.parent().unwrap().unwrap(), base_path.to_str().unwrap());
+        let restored = base.restore_memory(false).unwrap().unwrap();
+        diff.apply_diff(&restored).unwrap();
+        let mut buf = [0u8; 5];
+        restored
+            .read_slice(&mut buf, GuestAddress(PAGE_SIZE as u64))
+            .unwrap();
+        assert_eq!(&buf, b"guest");
+        restored
+            .read_slice(&mut buf, GuestAddress(3 * PAGE_SIZE as u64))
+            .unwrap();
+        assert_eq!(&buf, b"dirty");
+
+        assert!(matches!(
+            open_with_key(&base_path, None),
+            Err(SnapshotError::MissingKey)
+        ));
+        assert!(matches!(
+            open_with_key(&base_path, Some(SnapshotKey::new([8; 32]))),
+            Err(SnapshotError::WrongKey)
+        ));
+        let plain_path = dir.as_path().join("plain");
+        write_snapshot(&plain_path, b"vm state");
+        assert!(matches!(
+            open_with_key(&plain_path, Some(key)),
+            Err(SnapshotError::NotEncrypted)
+        ));
+    }
+
+    #[test]
+    fn test_encrypted_snapshot_tampering() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("snapshot");
+        let key = SnapshotKey::new([7; 32]);
+        let mut writer = SnapshotWriter::create_with_key(&path, Some(key.clone())).unwrap();
+        writer
+            .add_section(SectionKind::VmState, b"vm state")
+            .unwrap();
+        writer.finish().unwrap();
+        let snapshot = open_with_key(&path, Some(key.clone())).unwrap().unwrap();
+        let section = snapshot.section(SectionKind::VmState).unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+
+        // Modified along with its checksum.
+        let mut data = vec![0u8; section.len as usize];
+        file.read_exact_at(&mut data, section.offset).unwrap();
+        data[section.len as usize - 1] ^= 1;
+        file.write_all_at(&data, section.offset).unwrap();
+        let mut crc = CRC64Writer::new(io::sink());
+        crc.write_all(&data).unwrap();
+        let modified = Section {
+            checksum: crc.checksum(),
+            ..section
+        };
+        let mut header = [0u8; HEADER_SIZE as usize];
+        file.read_exact_at(&mut header, 0).unwrap();
+        let table_offset = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let index = snapshot
+            .sections()
+            .iter()
+            .position(|entry| *entry == section)
+            .unwrap();
+        let mut table = vec![0u8; snapshot.sections().len() * TABLE_ENTRY_SIZE];
+        file.read_exact_at(&mut table, table_offset).unwrap();
+        table[index * TABLE_ENTRY_SIZE..(index + 1) * TABLE_ENTRY_SIZE]
+            .copy_from_slice(&modified.to_bytes());
+        file.write_all_at(&table, table_offset).unwrap();
+        let mut crc = CRC64Writer::new(io::sink());
+        crc.write_all(&table).unwrap();
+        file.write_all_at(&crc.checksum().to_le_bytes(), 24)
+            .unwrap();
+        assert!(matches!(
+            open_with_key(&path, Some(key.clone())),
+            Err(SnapshotError::Tampered(_))
+        ));
+
+        // Modified, with the table left as is.
+        let mut table = vec![0u8; snapshot.sections().len() * TABLE_ENTRY_SIZE];
+        for (index, entry) in snapshot.sections().iter().enumerate() {
+            table[index * TABLE_ENTRY_SIZE..(index + 1) * TABLE_ENTRY_SIZE]
+                .copy_from_slice(&entry.to_bytes());
+        }
+        file.write_all_at(&table, table_offset).unwrap();
+        let mut crc = CRC64Writer::new(io::sink());
+        crc.write_all(&table).unwrap();
+        file.write_all_at(&crc.checksum().to_le_bytes(), 24)
+            .unwrap();
+        let snapshot = open_with_key(&path, Some(key)).unwrap().unwrap();
+        assert!(matches!(
+            snapshot.read_section(SectionKind::VmState),
+            Err(SnapshotError::Tampered(_))
+        ));
+    }
+
+    #[test]
+    fn test_legacy_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("snapshot");
+        fs::write(&path, b"raw vm state").unwrap();
+        assert!(SnapshotReader::open(&path).unwrap().is_none());
+    }
+}
@@ -0,0 +1,512 @@
+// Reading snapshots back for debugging, behind the `snapshot-inspect` tool.
+//
+// The saved state is flattened into named values (`vcpu0.regs.rip`, `pit.channel0.count`, ...)
+// which are both what gets printed and what gets compared between two snapshots. Values that
+// aren't registers, like the raw XSAVE area, are left out.
+//
+// The guest memory of a snapshot is mapped as restore would map it: a diff snapshot shows the
+// memory rebuilt from its whole chain, and legacy snapshots need their memory file. Snapshots
+// only left in the dedup store are read from their chunks, the store itself is left alone.
+
+use std::fs::File;
+use std::io::{self, Write};
+
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vm_vcpu::vm::VmState;
+
+use crate::dirty_log::PAGE_SIZE;
+use crate::lazy_restore;
+use crate::memory_snapshot::{GuestMemoryState, SnapshotMemory};
+use crate::snapshot_file::{Result, SectionKind, SnapshotError, SnapshotMetadata, SnapshotReader};
+use crate::version_map;
+use crate::{get_memory_state, Vmm};
+
+/// A named value of the saved state.
+pub type Value = (String, u64);
+
+/// A snapshot opened for inspection.
+pub struct InspectedSnapshot {
+    pub metadata: Option<SnapshotMetadata>,
+    pub parent: Option<String>,
+    pub encrypted: bool,
+    pub vm_state: VmState,
+    /// `None` when the snapshot has no memory.
+    pub memory: Option<GuestMemoryMmap>,
+}
+
+impl InspectedSnapshot {
+    /// Opens the snapshot at `path`. `memory_path` is the memory file of a legacy snapshot.
+    pub fn open(path: &str, memory_path: Option<&str>) -> Result<Self> {
+        let snapshot = match SnapshotReader::open(path)? {
+            Some(snapshot) => snapshot,
+            None => {
+                let bytes = std::fs::read(path)?;
+                let vm_state = version_map::legacy_vm_state_from_bytes(&bytes)?;
+                let memory = match memory_path {
+                    Some(memory_path) => {
+                        let file = File::open(memory_path)?;
+                        let size = file.metadata()?.len() as usize;
+                        Some(GuestMemoryMmap::restore(
+                            Some(&file),
+                            &get_memory_state(size),
+                            false,
+                        ))
+                    }
+                    None => None,
+                };
+                return Ok(InspectedSnapshot {
+                    metadata: None,
+                    parent: None,
+                    encrypted: false,
+                    vm_state,
+                    memory,
+                });
+            }
+        };
+
+        let vm_state = snapshot
+            .read_section(SectionKind::VmState)?
+            .ok_or(SnapshotError::MissingSection(SectionKind::VmState))?;
+        Ok(InspectedSnapshot {
+            metadata: snapshot.metadata()?,
+            parent: snapshot.parent()?,
+            encrypted: snapshot.is_encrypted(),
+            vm_state: version_map::vm_state_from_bytes(&vm_state)?,
+            memory: Self::open_memory(path)?,
+        })
+    }
+
+    // Maps the memory of the chain ending with the snapshot at `path`. Snapshots only left in the
+    // dedup store are read from there, without bringing them back to disk or changing the store.
+    fn open_memory(path: &str) -> Result<Option<GuestMemoryMmap>> {
+        let dedup_mgr = Vmm::dedup_manager();
+        let chain = Vmm::open_snapshot_chain(path, |path| {
+            let (file, snapshot) = lazy_restore::open_snapshot(path, &dedup_mgr)?;
+            file.load_all()?;
+            Ok(snapshot)
+        })?;
+        match chain {
+            Some(chain) => Vmm::chain_memory(chain, false),
+            None => Ok(None),
+        }
+    }
+
+    pub fn memory_layout(&self) -> Option<GuestMemoryState> {
+        self.memory.as_ref().map(|memory| memory.describe())
+    }
+
+    /// Copies `len` bytes of guest memory at `addr` to `writer`.
+    pub fn extract(&self, addr: u64, len: u64, writer: &mut dyn Write) -> Result<()> {
+        let memory = self
+            .memory
+            .as_ref()
+            .ok_or(SnapshotError::MissingSection(SectionKind::MemoryData))?;
+        let mut buf = vec![0u8; PAGE_SIZE];
+        let mut done = 0;
+        while done < len {
+            let count = (len - done).min(PAGE_SIZE as u64) as usize;
+            memory
+                .read_slice(&mut buf[..count], GuestAddress(addr + done))
+                .map_err(SnapshotError::Memory)?;
+            writer.write_all(&buf[..count])?;
+            done += count as u64;
+        }
+        Ok(())
+    }
+}
+
+/// The state of the VM outside its vCPUs, then of each vCPU.
+pub fn values(vm_state: &VmState) -> Vec<Value> {
+    let mut values = vm_values(vm_state);
+    for (index, vcpu) in vm_state.vcpus_state.iter().enumerate() {
+        let prefix = format!("vcpu{}", index);
+        values.extend(
+            vcpu_values(vcpu)
+                .into_iter()
+                .map(|(name, value)| (format!("{}.{}", prefix, name), value)),
+        );
+    }
+    values
+}
+
+#[cfg(target_arch = "x86_64")]
+fn vm_values(vm_state: &VmState) -> Vec<Value> {
+    let mut values = vec![
+        ("clock.clock".to_string(), vm_state.clock.clock),
+        ("clock.flags".to_string(), vm_state.clock.flags as u64),
+        ("pit.flags".to_string(), vm_state.pitstate.flags as u64),
+    ];
+    for (index, channel) in vm_state.pitstate.channels.iter().enumerate() {
+        let name = |field: &str| format!("pit.channel{}.{}", index, field);
+        values.push((name("count"), channel.count as u64));
+        values.push((name("latched_count"), channel.latched_count as u64));
+        values.push((name("status"), channel.status as u64));
+        values.push((name("rw_mode"), channel.rw_mode as u64));
+        values.push((name("mode"), channel.mode as u64));
+        values.push((name("bcd"), channel.bcd as u64));
+        values.push((name("gate"), channel.gate as u64));
+        values.push((name("count_load_time"), channel.count_load_time as u64));
+    }
+    for (chip, name) in [
+        (&vm_state.pic_master, "pic_master"),
+        (&vm_state.pic_slave, "pic_slave"),
+    ]
+    .iter()
+    {
+        // Safe because the PIC chips hold a `kvm_pic_state`.
+        let pic = unsafe { chip.chip.pic };
+        let name = |field: &str| format!("{}.{}", name, field);
+        values.push((name("irr"), pic.irr as u64));
+        values.push((name("imr"), pic.imr as u64));
+        values.push((name("isr"), pic.isr as u64));
+        values.push((name("irq_base"), pic.irq_base as u64));
+        values.push((name("elcr"), pic.elcr as u64));
+        values.push((name("init_state"), pic.init_state as u64));
+    }
+    // Safe because the IOAPIC chip holds a `kvm_ioapic_state`.
+    let ioapic = unsafe { vm_state.ioapic.chip.ioapic };
+    values.push(("ioapic.id".to_string(), ioapic.id as u64));
+    values.push(("ioapic.irr".to_string(), ioapic.irr as u64));
+    values.push(("ioapic.ioregsel".to_string(), ioapic.ioregsel as u64));
+    for (pin, entry) in ioapic.redirtbl.iter().enumerate() {
+        // Safe because all the fields of the entry are views of `bits`.
+        let bits = unsafe { entry.bits };
+        values.push((format!("ioapic.redirtbl{}", pin), bits));
+    }
+    values
+}
+
+#[cfg(target_arch = "aarch64")]
+fn vm_values(_vm_state: &VmState) -> Vec<Value> {
+    vec![]
+}
+
+// LAPIC registers worth looking at, by offset.
+#[cfg(target_arch = "x86_64")]
+const LAPIC_REGS: &[(&str, usize)] = &[
+    ("id", 0x20),
+    ("version", 0x30),
+    ("tpr", 0x80),
+    ("ldr", 0xd0),
+    ("dfr", 0xe0),
+    ("svr", 0xf0),
+    ("esr", 0x280),
+    ("icr_low", 0x300),
+    ("icr_high", 0x310),
+    ("lvt_timer", 0x320),
+    ("lvt_lint0", 0x350),
+    ("lvt_lint1", 0x360),
+    ("lvt_error", 0x370),
+    ("timer_initial_count", 0x380),
+    ("timer_current_count", 0x390),
+    ("timer_divide", 0x3e0),
+];
+
+#[cfg(target_arch = "x86_64")]
+fn vcpu_values(vcpu: &vm_vcpu::vcpu::VcpuState) -> Vec<Value> {
+    use vm_vcpu_ref::x86_64::interrupts::get_klapic_reg;
+
+    let r = &vcpu.regs;
+    let mut values: Vec<Value> = [
+        ("rax", r.rax),
+        ("rbx", r.rbx),
+        ("rcx", r.rcx),
+        ("rdx", r.rdx),
+        ("rsi", r.rsi),
+        ("rdi", r.rdi),
+        ("rsp", r.rsp),
+        ("rbp", r.rbp),
+        ("r8", r.r8),
+        ("r9", r.r9),
+        ("r10", r.r10),
+        ("r11", r.r11),
+        ("r12", r.r12),
+        ("r13", r.r13),
+        ("r14", r.r14),
+        ("r15", r.r15),
+        ("rip", r.rip),
+        ("rflags", r.rflags),
+    ]
+    .iter()
+    .map(|(name, value)| (format!("regs.{}", name), *value))
+    .collect();
+
+    let s = &vcpu.sregs;
+    for (name, value) in [
+        ("cr0", s.cr0),
+        ("cr2", s.cr2),
+        ("cr3", s.cr3),
+        ("cr4", s.cr4),
+        ("cr8", s.cr8),
+        ("efer", s.efer),
+        ("apic_base", s.apic_base),
+        ("gdt.base", s.gdt.base),
+        ("gdt.limit", s.gdt.limit as u64),
+        ("idt.base", s.idt.base),
+        ("idt.limit", s.idt.limit as u64),
+    ]
+    .iter()
+    {
+        values.push((format!("sregs.{}", name), *value));
+    }
+    for (name, segment) in [
+        ("cs", &s.cs),
+        ("ds", &s.ds),
+        ("es", &s.es),
+        ("fs", &s.fs),
+        ("gs", &s.gs),
+        ("ss", &s.ss),
+        ("tr", &s.tr),
+        ("ldt", &s.ldt),
+    ]
+    .iter()
+    {
+        values.push((format!("sregs.{}.selector", name), segment.selector as u64));
+        values.push((format!("sregs.{}.base", name), segment.base));
+        values.push((format!("sregs.{}.limit", name), segment.limit as u64));
+        values.push((format!("sregs.{}.type", name), segment.type_ as u64));
+    }
+
+    for (name, offset) in LAPIC_REGS {
+        if let Ok(value) = get_klapic_reg(&vcpu.lapic, *offset) {
+            values.push((format!("lapic.{}", name), value as u32 as u64));
+        }
+    }
+    for entry in vcpu.msrs.as_slice() {
+        values.push((format!("msr.{:#x}", entry.index), entry.data));
+    }
+    for (index, db) in vcpu.debug_regs.db.iter().enumerate() {
+        values.push((format!("debug.dr{}", index), *db));
+    }
+    values.push(("debug.dr6".to_string(), vcpu.debug_regs.dr6));
+    values.push(("debug.dr7".to_string(), vcpu.debug_regs.dr7));
+    for xcr in vcpu.xcrs.xcrs.iter().take(vcpu.xcrs.nr_xcrs as usize) {
+        values.push((format!("xcr{}", xcr.xcr), xcr.value));
+    }
+    values.push(("mp_state".to_string(), vcpu.mp_state.mp_state as u64));
+    if let Some(tsc_khz) = vcpu.tsc_khz {
+        values.push(("tsc_khz".to_string(), tsc_khz as u64));
+    }
+    values
+}
+
+#[cfg(target_arch = "aarch64")]
+fn vcpu_values(vcpu: &vm_vcpu::vcpu::VcpuState) -> Vec<Value> {
+    let mut values = vec![
+        ("mp_state".to_string(), vcpu.mp_state.mp_state as u64),
+        ("mpidr".to_string(), vcpu.mpidr),
+    ];
+    for reg in vcpu.regs.iter() {
+        values.push((format!("reg.{:#x}", reg.id), reg.addr));
+    }
+    values
+}
+
+/// Values that differ between `a` and `b`, as (name, value in `a`, value in `b`), `None` for a
+/// value only one of them has.
+pub fn diff_values(a: &[Value], b: &[Value]) -> Vec<(String, Option<u64>, Option<u64>)> {
+    let mut diff = vec![];
+    for (name, value) in a {
+        match b.iter().find(|(other, _)| other == name) {
+            Some((_, other_value)) if other_value == value => {}
+            Some((_, other_value)) => diff.push((name.clone(), Some(*value), Some(*other_value))),
+            None => diff.push((name.clone(), Some(*value), None)),
+        }
+    }
+    for (name, value) in b {
+        if !a.iter().any(|(other, _)| other == name) {
+            diff.push((name.clone(), None, Some(*value)));
+        }
+    }
+    diff
+}
+
+/// Guest addresses of the pages that differ between `a` and `b`, including those only one of
+/// them has.
+pub fn diff_memory(a: &GuestMemoryMmap, b: &GuestMemoryMmap) -> Result<Vec<u64>> {
+    let mut changed = vec![];
+    let mut page_a = vec![0u8; PAGE_SIZE];
+    let mut page_b = vec![0u8; PAGE_SIZE];
+    for region in a.iter() {
+        for offset in (0..region.len()).step_by(PAGE_SIZE) {
+            let addr = region.start_addr().unchecked_add(offset);
+            a.read_slice(&mut page_a, addr)
+                .map_err(SnapshotError::Memory)?;
+            if b.read_slice(&mut page_b, addr).is_err() || page_a != page_b {
+                changed.push(addr.raw_value());
+            }
+        }
+    }
+    for region in b.iter() {
+        for offset in (0..region.len()).step_by(PAGE_SIZE) {
+            let addr = region.start_addr().unchecked_add(offset);
+            if !a.address_in_range(addr) {
+                changed.push(addr.raw_value());
+            }
+        }
+    }
+    changed.sort_unstable();
+    Ok(changed)
+}
+
+/// Merges page addresses, in ascending order, into ranges of contiguous pages, as (start, end)
+/// with `end` excluded.
+pub fn page_ranges(pages: &[u64]) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    for &page in pages {
+        match ranges.last_mut() {
+            Some((_, end)) if *end == page => *end += PAGE_SIZE as u64,
+            _ => ranges.push((page, page + PAGE_SIZE as u64)),
+        }
+    }
+    ranges
+}
+
+/// Writes the snapshot at `path` to `out`, in a human readable form.
+pub fn print(snapshot: &InspectedSnapshot, path: &str, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "snapshot {}", path)?;
+    match snapshot.metadata.as_ref() {
+        Some(metadata) => {
+            writeln!(
+                out,
+                "  created at {} by vmm {}",
+                metadata.created_at, metadata.vmm_version
+            )?;
+            writeln!(out, "  memory size {:#x}", metadata.mem_size)?;
+            if let Some(arch) = metadata.arch.as_ref() {
+                writeln!(out, "  arch {}", arch)?;
+            }
+            if let Some(devices) = metadata.devices.as_ref() {
+                writeln!(out, "  devices [{}]", devices.join(", "))?;
+            }
+        }
+        None => writeln!(out, "  no metadata")?,
+    }
+    if let Some(parent) = snapshot.parent.as_ref() {
+        writeln!(out, "  diff of {}", parent)?;
+    }
+    if snapshot.encrypted {
+        writeln!(out, "  encrypted")?;
+    }
+
+    match snapshot.memory_layout() {
+        Some(layout) => {
+            writeln!(out, "memory layout")?;
+            for region in layout.regions.iter() {
+                writeln!(
+                    out,
+                    "  {:#014x}-{:#014x} ({} MiB)",
+                    region.base_address,
+                    region.base_address + region.size as u64,
+                    region.size >> 20
+                )?;
+            }
+        }
+        None => writeln!(out, "no memory")?,
+    }
+
+    writeln!(
+        out,
+        "vm state ({} vCPUs)",
+        snapshot.vm_state.vcpus_state.len()
+    )?;
+    for (name, value) in values(&snapshot.vm_state) {
+        writeln!(out, "  {:<32} {:#x}", name, value)?;
+    }
+    Ok(())
+}
+
+/// Writes what differs between the snapshots `a` and `b` to `out`: state values, then memory
+/// ranges.
+pub fn print_diff(a: &InspectedSnapshot, b: &InspectedSnapshot, out: &mut dyn Write) -> Result<()> {
+    let format =
+        |value: Option<u64>| value.map_or("-".to_string(), |value| format!("{:#x}", value));
+    let diff = diff_values(&values(&a.vm_state), &values(&b.vm_state));
+    writeln!(out, "{} state value(s) differ", diff.len())?;
+    for (name, value_a, value_b) in diff {
+        writeln!(
+            out,
+            "  {:<32} {} -> {}",
+            name,
+            format(value_a),
+            format(value_b)
+        )?;
+    }
+
+    match (a.memory.as_ref(), b.memory.as_ref()) {
+        (Some(memory_a), Some(memory_b)) => {
+            let pages = diff_memory(memory_a, memory_b)?;
+            writeln!(out, "{} page(s) differ", pages.len())?;
+            for (start, end) in page_ranges(&pages) {
+                writeln!(
+                    out,
+                    "  {:#014x}-{:#014x} ({} pages)",
+                    start,
+                    end,
+                    (end - start) / PAGE_SIZE as u64
+                )?;
+            }
+        }
+        _ => writeln!(out, "memory not compared, missing from a snapshot")?,
+    }
+    Ok(())
+}
+
+/// Parses an address or a length, in hex with a `0x` prefix or in decimal.
+pub fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(name: &str, value: u64) -> Value {
+        (name.to_string(), value)
+    }
+
+    #[test]
+    fn test_diff_values() {
+        let a = vec![value("rip", 0x1000), value("rax", 1), value("msr.0x10", 5)];
+        let b = vec![value("rip", 0x2000), value("rax", 1), value("xcr0", 7)];
+        assert_eq!(
+            diff_values(&a, &b),
+            vec![
+                ("rip".to_string(), Some(0x1000), Some(0x2000)),
+                ("msr.0x10".to_string(), Some(5), None),
+                ("xcr0".to_string(), None, Some(7)),
+            ]
+        );
+        assert!(diff_values(&a, &a).is_empty());
+    }
+
+    #[test]
+    fn test_diff_memory() {
+        let regions = vec![(None, GuestAddress(0), 4 * PAGE_SIZE)];
+        let a = vm_memory::create_guest_memory(&regions, false).unwrap();
+        let regions = vec![
+            (None, GuestAddress(0), 4 * PAGE_SIZE),
+            (None, GuestAddress(0x10000), PAGE_SIZE),
+        ];
+        let b = vm_memory::create_guest_memory(&regions, false).unwrap();
+        b.write_slice(b"x", GuestAddress(0x1000)).unwrap();
+        b.write_slice(b"y", GuestAddress(0x2fff)).unwrap();
+
+        let pages = diff_memory(&a, &b).unwrap();
+        assert_eq!(pages, vec![0x1000, 0x2000, 0x10000]);
+        assert_eq!(
+            page_ranges(&pages),
+            vec![(0x1000, 0x3000), (0x10000, 0x11000)]
+        );
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("0x1000"), Some(0x1000));
+        assert_eq!(parse_number("4096"), Some(4096));
+        assert_eq!(parse_number("0xg"), None);
+    }
+}
@@ -0,0 +1,186 @@
+// Copies the block device backing file to the destination host during live migration, for
+// setups where the two hosts don't share storage.
+//
+// The disk is sent in bulk chunks along the regular memory iterations, while the block device
+// records which blocks the driver writes in the meantime. Blocks written behind the bulk copy
+// cursor are sent again in the next iteration, and whatever is still dirty (or not copied yet)
+// goes out with the last iteration, once the vCPUs are stopped.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use devices::virtio::block::{DirtyBlockTracker, DIRTY_BLOCK_SIZE};
+
+// Number of blocks copied by each non-final iteration (64 MiB).
+pub(crate) const BULK_COPY_BLOCKS: u64 = (64 << 20) / DIRTY_BLOCK_SIZE;
+
+pub struct DiskMirror {
+    file: File,
+    size: u64,
+    num_blocks: u64,
+    // Index of the first block that wasn't bulk copied yet.
+    cursor: u64,
+    dirty_blocks: DirtyBlockTracker,
+}
+
+impl DiskMirror {
+    // Opens the backing file at `path` and starts tracking the writes reported to
+    // `dirty_blocks`. Tracking stops when the mirror is dropped.
+    pub fn new<P: AsRef<Path>>(path: P, dirty_blocks: DirtyBlockTracker) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // Start tracking before reading anything, so no write can slip between the bulk copy
+        // and the dirty block set.
+        dirty_blocks.start();
+        let size = file.metadata()?.len();
+        let num_blocks = (size + DIRTY_BLOCK_SIZE - 1) / DIRTY_BLOCK_SIZE;
+
+        Ok(DiskMirror {
+            file,
+            size,
+            num_blocks,
+            cursor: 0,
+            dirty_blocks,
+        })
+    }
+
+    // Size in bytes of the mirrored disk.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Whether every block was sent at least once.
+    pub fn bulk_copy_done(&self) -> bool {
+        self.cursor >= self.num_blocks
+    }
+
+    // Returns the indices of the blocks to send in this iteration, along with their content
+    // (`DIRTY_BLOCK_SIZE` bytes per block, the tail of the last block is zero filled).
+    // With `is_last` set, everything that is still outstanding is returned.
+    pub fn next_batch(&mut self, is_last: bool) -> io::Result<(Vec<u64>, Vec<u8>)> {
+        // Dirty blocks past the cursor will be picked up by the bulk copy anyway.
+        let mut blocks: Vec<u64> = self
+            .dirty_blocks
+            .drain()
+            .into_iter()
+            .filter(|&block| block < self.cursor)
+            .collect();
+
+        let end = if is_last {
+            self.num_blocks
+        } else {
+            std::cmp::min(self.cursor + BULK_COPY_BLOCKS, self.num_blocks)
+        };
+        blocks.extend(self.cursor..end);
+        self.cursor = end;
+
+        let data = self.read_blocks(&blocks)?;
+        Ok((blocks, data))
+    }
+
+    // Reads the current content of `blocks`, in the layout used by `next_batch`.
+    pub fn read_blocks(&self, blocks: &[u64]) -> io::Result<Vec<u8>> {
+        let mut data = vec![0u8; blocks.len() * DIRTY_BLOCK_SIZE as usize];
+        for (block, buf) in blocks
+            .iter()
+            .zip(data.chunks_exact_mut(DIRTY_BLOCK_SIZE as usize))
+        {
+            let offset = block * DIRTY_BLOCK_SIZE;
+            let len = std::cmp::min(DIRTY_BLOCK_SIZE, self.size - offset) as usize;
+            self.file.read_exact_at(&mut buf[..len], offset)?;
+        }
+        Ok(data)
+    }
+
+    // Starts the bulk copy over, for when the destination lost everything it received.
+    pub fn rewind(&mut self) {
+        self.cursor = 0;
+        self.dirty_blocks.drain();
+    }
+}
+
+impl Drop for DiskMirror {
+    fn drop(&mut self) {
+        self.dirty_blocks.stop();
+    }
+}
+
+// Opens (or creates) the destination copy of the disk, sized to `disk_size` bytes.
+pub fn open_disk_copy<P: AsRef<Path>>(path: P, disk_size: u64) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)?;
+    file.set_len(disk_size)?;
+    Ok(file)
+}
+
+// Writes the blocks produced by `DiskMirror::next_batch` on the source to `file`.
+pub fn apply_disk_blocks(
+    file: &File,
+    disk_size: u64,
+    blocks: &[u64],
+    data: &[u8],
+) -> io::Result<()> {
+    for (block, buf) in blocks
+        .iter()
+        .zip(data.chunks_exact(DIRTY_BLOCK_SIZE as usize))
+    {
+        let offset = block * DIRTY_BLOCK_SIZE;
+        if offset >= disk_size {
+            continue;
+        }
+        let len = std::cmp::min(DIRTY_BLOCK_SIZE, disk_size - offset) as usize;
+        file.write_all_at(&buf[..len], offset)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_disk_mirror() {
+        let src = TempFile::new().unwrap();
+        let size = BULK_COPY_BLOCKS * DIRTY_BLOCK_SIZE + 100;
+        let content: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        src.as_file().write_all_at(&content, 0).unwrap();
+
+        let tracker = DirtyBlockTracker::default();
+        let mut mirror = DiskMirror::new(src.as_path(), tracker.clone()).unwrap();
+        assert_eq!(mirror.size(), size);
+
+        let dst = TempFile::new().unwrap();
+        let dst_file = open_disk_copy(dst.as_path(), size).unwrap();
+
+        let (blocks, data) = mirror.next_batch(false).unwrap();
+        assert_eq!(blocks.len() as u64, BULK_COPY_BLOCKS);
+        assert!(!mirror.bulk_copy_done());
+        apply_disk_blocks(&dst_file, size, &blocks, &data).unwrap();
+
+        // A write behind the cursor is sent again, one past it is covered by the bulk copy.
+        src.as_file().write_all_at(&[0xff; 10], 0).unwrap();
+        tracker.mark(0, 10);
+        tracker.mark(size - 1, 1);
+
+        let (blocks, data) = mirror.next_batch(true).unwrap();
+        assert_eq!(blocks, vec![0, BULK_COPY_BLOCKS]);
+        assert!(mirror.bulk_copy_done());
+        apply_disk_blocks(&dst_file, size, &blocks, &data).unwrap();
+
+        let mut copy = vec![0u8; size as usize];
+        dst_file.read_exact_at(&mut copy, 0).unwrap();
+        let mut expected = content;
+        expected[..10].copy_from_slice(&[0xff; 10]);
+        assert_eq!(copy, expected);
+
+        drop(mirror);
+        tracker.mark(0, 1);
+        assert!(tracker.drain().is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+// Bindings to the parts of userfaultfd (see linux/userfaultfd.h) used to serve the guest memory
+// from userspace: faults on missing pages for lazy restores, and writes to write protected pages
+// for background snapshots.
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+const UFFD_API: u64 = 0xaa;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFD_MSG_SIZE: usize = 32;
+const UFFD_PAGEFAULT_FLAG_WP: u64 = 1 << 1;
+const UFFDIO_WRITEPROTECT_MODE_WP: u64 = 1 << 0;
+
+/// Feature needed to register memory with `REGISTER_MODE_WP`.
+pub const FEATURE_PAGEFAULT_FLAG_WP: u64 = 1 << 0;
+/// Reports faults on pages that aren't mapped yet.
+pub const REGISTER_MODE_MISSING: u64 = 1 << 0;
+/// Reports writes to write protected pages.
+pub const REGISTER_MODE_WP: u64 = 1 << 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioZeropage {
+    range: UffdioRange,
+    mode: u64,
+    zeropage: i64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioWriteprotect {
+    range: UffdioRange,
+    mode: u64,
+}
+
+// _IOWR(UFFDIO, nr, size)
+const fn uffdio_iowr(nr: u64, size: usize) -> u64 {
+    (3 << 30) | ((size as u64) << 16) | (UFFD_API << 8) | nr
+}
+
+const UFFDIO_API: u64 = uffdio_iowr(0x3f, std::mem::size_of::<UffdioApi>());
+const UFFDIO_REGISTER: u64 = uffdio_iowr(0x00, std::mem::size_of::<UffdioRegister>());
+const UFFDIO_COPY: u64 = uffdio_iowr(0x03, std::mem::size_of::<UffdioCopy>());
+const UFFDIO_ZEROPAGE: u64 = uffdio_iowr(0x04, std::mem::size_of::<UffdioZeropage>());
+const UFFDIO_WRITEPROTECT: u64 = uffdio_iowr(0x06, std::mem::size_of::<UffdioWriteprotect>());
+
+/// A page fault reported by the userfaultfd.
+pub struct Fault {
+    /// Host address of the fault.
+    pub addr: u64,
+    /// Whether it's a write to a write protected page, rather than a missing page.
+    pub write_protect: bool,
+}
+
+pub struct Uffd {
+    file: File,
+}
+
+impl Uffd {
+    /// Creates a userfaultfd with `features`, failing if the kernel lacks any of them.
+    pub fn new(features: u64) -> io::Result<Self> {
+        // Safe because we check the return value.
+        let fd = unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we just created the fd, and nothing else owns it.
+        let uffd = Uffd {
+            file: unsafe { File::from_raw_fd(fd as i32) },
+        };
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            features,
+            ..Default::default()
+        };
+        uffd.ioctl(UFFDIO_API, &mut api)?;
+        Ok(uffd)
+    }
+
+    fn ioctl<T>(&self, request: u64, arg: &mut T) -> io::Result<()> {
+        // Safe because `arg` is the struct the kernel expects for `request`, and we check the
+        // return value.
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, arg as *mut T) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Reports the faults of `len` bytes of memory at `start`, as selected by `mode`.
+    pub fn register(&self, start: u64, len: u64, mode: u64) -> io::Result<()> {
+        let mut register = UffdioRegister {
+            range: UffdioRange { start, len },
+            mode,
+            ..Default::default()
+        };
+        self.ioctl(UFFDIO_REGISTER, &mut register)
+    }
+
+    /// Maps `data` at the missing pages at `dst`, waking up whoever faulted on them.
+    pub fn copy(&self, dst: u64, data: &[u8]) -> io::Result<()> {
+        let mut copy = UffdioCopy {
+            dst,
+            src: data.as_ptr() as u64,
+            len: data.len() as u64,
+            ..Default::default()
+        };
+        self.ioctl(UFFDIO_COPY, &mut copy)
+    }
+
+    /// Maps the zero page at the missing pages at `dst`, waking up whoever faulted on them.
+    pub fn zeropage(&self, dst: u64, len: u64) -> io::Result<()> {
+        let mut zeropage = UffdioZeropage {
+            range: UffdioRange { start: dst, len },
+            ..Default::default()
+        };
+        self.ioctl(UFFDIO_ZEROPAGE, &mut zeropage)
+    }
+
+    /// Write protects `len` bytes at `start`, or lifts the protection, waking up whoever
+    /// faulted on them.
+    pub fn write_protect(&self, start: u64, len: u64, protect: bool) -> io::Result<()> {
+        let mut write_protect = UffdioWriteprotect {
+            range: UffdioRange { start, len },
+            mode: if protect {
+                UFFDIO_WRITEPROTECT_MODE_WP
+            } else {
+                0
+            },
+        };
+        self.ioctl(UFFDIO_WRITEPROTECT, &mut write_protect)
+    }
+
+    /// Waits up to `timeout_ms` (forever if negative) for the next page fault. `None` on
+    /// timeout.
+    pub fn read_fault(&self, timeout_ms: i32) -> io::Result<Option<Fault>> {
+        loop {
+            let mut pollfd = libc::pollfd {
+                fd: self.file.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // Safe because we pass a single valid pollfd, and check the return value.
+            let ret = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if ret == 0 {
+                return Ok(None);
+            }
+
+            let mut msg = [0u8; UFFD_MSG_SIZE];
+            if (&self.file).read(&mut msg)? != UFFD_MSG_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "short userfaultfd message",
+                ));
+            }
+            if msg[0] != UFFD_EVENT_PAGEFAULT {
+                continue;
+            }
+            let flags = u64::from_le_bytes(msg[8..16].try_into().unwrap());
+            return Ok(Some(Fault {
+                addr: u64::from_le_bytes(msg[16..24].try_into().unwrap()),
+                write_protect: flags & UFFD_PAGEFAULT_FLAG_WP != 0,
+            }));
+        }
+    }
+}
@@ -0,0 +1,187 @@
+// Versions of the guest state saved in snapshots, and sent to other VMMs when migrating, live
+// updating or failing over.
+//
+// Every change to a versionized struct adds a version to the map, bumping the structs it touches,
+// and marks the new fields with `#[version(start = ..)]` along with a default for older data. The
+// state is always written at the latest version, and read at the version it was written at.
+
+use std::any::TypeId;
+use std::convert::TryInto;
+
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
+use vm_vcpu::vcpu::VcpuState;
+use vm_vcpu::vm::VmState;
+
+/// Version of the state written by this VMM.
+pub const SNAPSHOT_VERSION: u16 = 2;
+
+/// Version of the raw cpu snapshots, which predate the snapshot container.
+pub const LEGACY_SNAPSHOT_VERSION: u16 = 1;
+
+pub fn version_map() -> VersionMap {
+    let mut version_map = VersionMap::new();
+    // 2: VmState::saved_at_ns and VcpuState::tsc_khz, to correct the guest clock on restore.
+    version_map
+        .new_version()
+        .set_type_version(TypeId::of::<VmState>(), 2)
+        .set_type_version(TypeId::of::<VcpuState>(), 2);
+    version_map
+}
+
+/// Serializes the vm state at `SNAPSHOT_VERSION`, which goes first.
+pub fn vm_state_to_bytes(vm_state: &VmState) -> VersionizeResult<Vec<u8>> {
+    let mut bytes = SNAPSHOT_VERSION.to_le_bytes().to_vec();
+    vm_state.serialize(&mut bytes, &version_map(), SNAPSHOT_VERSION)?;
+    Ok(bytes)
+}
+
+/// Deserializes a vm state produced by `vm_state_to_bytes`, by this VMM or an older one.
+pub fn vm_state_from_bytes(bytes: &[u8]) -> VersionizeResult<VmState> {
+    if bytes.len() < 2 {
+        return Err(VersionizeError::Deserialize(
+            "missing vm state version".to_string(),
+        ));
+    }
+    let (version, state) = bytes.split_at(2);
+    vm_state_from_version(state, u16::from_le_bytes(version.try_into().unwrap()))
+}
+
+/// Deserializes a vm state written at `version`.
+pub fn vm_state_from_version(mut bytes: &[u8], version: u16) -> VersionizeResult<VmState> {
+    let version_map = version_map();
+    if version == 0 || version > version_map.latest_version() {
+        return Err(VersionizeError::Deserialize(format!(
+            "unsupported vm state version {}, this VMM supports up to {}",
+            version,
+            version_map.latest_version()
+        )));
+    }
+    VmState::deserialize(&mut bytes, &version_map, version)
+}
+
+/// Deserializes a raw cpu snapshot, which has no version of its own.
+///
+/// They are read at `LEGACY_SNAPSHOT_VERSION`, but the VMMs that added `saved_at_ns` and `tsc_khz`
+/// before they were versioned wrote them in the layout of version 2. The version the state spans
+/// exactly is the one it was written at.
+pub fn legacy_vm_state_from_bytes(bytes: &[u8]) -> VersionizeResult<VmState> {
+    let version_map = version_map();
+    let mut state = bytes;
+    let legacy = VmState::deserialize(&mut state, &version_map, LEGACY_SNAPSHOT_VERSION);
+    if legacy.is_ok() && state.is_empty() {
+        return legacy;
+    }
+    let mut state = bytes;
+    match VmState::deserialize(&mut state, &version_map, 2) {
+        Ok(vm_state) if state.is_empty() => Ok(vm_state),
+        _ => legacy,
+    }
+}
+
+#[cfg(test)]
+#[cfg(target_arch = "x86_64")]
+mod tests {
+    use super::*;
+
+    use kvm_bindings::{kvm_msr_entry, CpuId, Msrs};
+    use vm_vcpu::vcpu::{VcpuConfig, VcpuConfigList};
+    use vm_vcpu::vm::VmConfig;
+    use vm_vcpu_ref::x86_64::msr_index::MSR_IA32_TSC;
+
+    fn vm_state() -> VmState {
+        let msrs = Msrs::from_entries(&[kvm_msr_entry {
+            index: MSR_IA32_TSC,
+            data: 1000,
+            ..Default::default()
+        }])
+        .unwrap();
+        let config = VcpuConfig {
+            id: 0,
+            cpuid: CpuId::new(0).unwrap(),
+            msrs: msrs.clone(),
+            starter_path: "".to_string(),
+        };
+        let vcpu_state = VcpuState {
+            cpuid: CpuId::new(0).unwrap(),
+            msrs,
+            debug_regs: Default::default(),
+            lapic: Default::default(),
+            mp_state: Default::default(),
+            regs: Default::default(),
+            sregs: Default::default(),
+            vcpu_events: Default::default(),
+            xcrs: Default::default(),
+            xsave: Default::default(),
+            config: config.clone(),
+            tsc_khz: Some(2_000_000),
+        };
+        VmState {
+            pitstate: Default::default(),
+            clock: Default::default(),
+            pic_master: Default::default(),
+            pic_slave: Default::default(),
+            ioapic: Default::default(),
+            config: VmConfig {
+                num_vcpus: 1,
+                vcpus_config: VcpuConfigList {
+                    configs: vec![config],
+                },
+            },
+            vcpus_state: vec![vcpu_state],
+            saved_at_ns: 42,
+        }
+    }
+
+    #[test]
+    fn test_vm_state_roundtrip() {
+        let bytes = vm_state_to_bytes(&vm_state()).unwrap();
+        let restored = vm_state_from_bytes(&bytes).unwrap();
+        assert_eq!(restored.saved_at_ns, 42);
+        assert_eq!(restored.vcpus_state[0].tsc_khz, Some(2_000_000));
+        assert_eq!(restored.vcpus_state[0].msrs.as_slice()[0].data, 1000);
+    }
+
+    #[test]
+    fn test_restore_version_1() {
+        // What a VMM from before version 2 wrote.
+        let mut bytes = vec![];
+        vm_state()
+            .serialize(&mut bytes, &version_map(), LEGACY_SNAPSHOT_VERSION)
+            .unwrap();
+
+        let restored = vm_state_from_version(&bytes, LEGACY_SNAPSHOT_VERSION).unwrap();
+        assert_eq!(restored.saved_at_ns, 0);
+        assert!(restored.downtime().is_none());
+        assert_eq!(restored.vcpus_state[0].tsc_khz, None);
+        assert_eq!(restored.vcpus_state[0].msrs.as_slice()[0].data, 1000);
+        assert_eq!(restored.config.num_vcpus, 1);
+    }
+
+    #[test]
+    fn test_restore_unversioned_fields() {
+        // What a VMM that had the fields of version 2, but not the version, wrote.
+        let mut bytes = vec![];
+        vm_state().serialize(&mut bytes, &version_map(), 2).unwrap();
+
+        let restored = legacy_vm_state_from_bytes(&bytes).unwrap();
+        assert_eq!(restored.saved_at_ns, 42);
+        assert_eq!(restored.vcpus_state[0].tsc_khz, Some(2_000_000));
+        assert_eq!(restored.vcpus_state[0].msrs.as_slice()[0].data, 1000);
+
+        let mut bytes = vec![];
+        vm_state()
+            .serialize(&mut bytes, &version_map(), LEGACY_SNAPSHOT_VERSION)
+            .unwrap();
+        let restored = legacy_vm_state_from_bytes(&bytes).unwrap();
+        assert_eq!(restored.saved_at_ns, 0);
+        assert_eq!(restored.vcpus_state[0].tsc_khz, None);
+    }
+
+    #[test]
+    fn test_restore_newer_version() {
+        let mut bytes = vm_state_to_bytes(&vm_state()).unwrap();
+        bytes[0..2].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        assert!(vm_state_from_bytes(&bytes).is_err());
+        assert!(vm_state_from_bytes(&[]).is_err());
+    }
+}
@@ -6,6 +6,8 @@ use fork::{fork, Fork};
 use rocket::http::Status;
 use rocket::serde::{json::Json, Deserialize, Serialize};
 use std::{error::Error, net::IpAddr, net::Ipv4Addr, net::SocketAddr, time::Duration};
+use std::io::Read;
+use std::process::Stdio;
 use tarpc::{client, context, tokio_serde::formats::Json as newJson};
 use tokio::time::sleep;
 
@@ -23,6 +25,26 @@ pub trait World {
         port: u16,
         resume: bool,
     ) -> String;
+    /// Samples the guest dirty rate and returns the report as JSON.
+    async fn measure_dirty_rate(window_ms: u64, num_windows: u32) -> String;
+    /// Hands the guest over to the VMM waiting on `socket_path`.
+    async fn live_update(socket_path: String) -> String;
+    /// Starts the vCPUs of a guest restored paused.
+    async fn resume_vm() -> String;
+    /// Snapshots the pages dirtied since the previous snapshot.
+    async fn diff_snapshot(snapshot_path: String) -> String;
+    /// Snapshots the guest, pausing it only while its vCPU state is saved.
+    async fn background_snapshot(snapshot_path: String) -> String;
+    /// Writes the guest memory and vCPU registers as an ELF core file.
+    async fn core_dump(path: String) -> String;
+    /// Lists the cataloged snapshots as JSON, only those tagged `tag` if there's one.
+    async fn list_snapshots(tag: Option<String>) -> String;
+    /// Describes the cataloged snapshot `id` as JSON.
+    async fn describe_snapshot(id: String) -> String;
+    /// Names (unless `name` is empty) and tags the cataloged snapshot `id`.
+    async fn label_snapshot(id: String, name: String, tags: Vec<String>) -> String;
+    /// Deletes the cataloged snapshot `id`.
+    async fn delete_snapshot(id: String) -> String;
 }
 
 /// error type
@@ -92,6 +114,8 @@ struct CreateRequest<'a> {
     resume: bool,
 }
 
+const SNAPSHOT_DEADLINE: Duration = Duration::from_secs(600);
+
 async fn rpc_call(body: Json<SnapshotRequest<'_>>) -> anyhow::Result<String> {
     let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), body.rpc_port);
     let transport = tarpc::serde_transport::tcp::connect(socket, newJson::default);
@@ -107,29 +131,335 @@ async fn rpc_call(body: Json<SnapshotRequest<'_>>) -> anyhow::Result<String> {
         // }
         // else{
             println!("Sending pause request");
+            // The reply only comes once the snapshot is on disk, which takes a while for a big
+            // guest.
+            let mut ctx = context::current();
+            ctx.deadline = std::time::SystemTime::now() + SNAPSHOT_DEADLINE;
             tokio::select! {
-                hello1 = client.snapshot_and_pause(context::current(), body.cpu_snapshot_path.to_string(), body.memory_snapshot_path.to_string(), body.rpc_port, body.resume) => { hello1 }
+                hello1 = client.snapshot_and_pause(ctx, body.cpu_snapshot_path.to_string(), body.memory_snapshot_path.to_string(), body.rpc_port, body.resume) => { hello1 }
                 // hello2 = client.hello(context::current(), format!("{}2", "Ronak")) => { hello2 }
             }
         // }
     }.await;
     match hello {
+        // Either "Success", or what went wrong.
         Ok(s) => {
-            if s == "Success" {
-                return Ok(s);
-            } else {
-                return Ok("Error".to_string());
-            }
+            return Ok(s);
         }
-        Err(_) => {
-            return Ok("Error".to_string());
+        Err(e) => {
+            return Ok(format!("Error: {}", e));
         }
     }
 }
+async fn dirty_rate_call(rpc_port: u16, window_ms: u64, num_windows: u32) -> anyhow::Result<String> {
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), rpc_port);
+    let transport = tarpc::serde_transport::tcp::connect(socket, newJson::default);
+    let client = WorldClient::new(client::Config::default(), transport.await?).spawn();
+    // The call blocks for the whole measurement, so don't let the default deadline cut it short.
+    let mut ctx = context::current();
+    ctx.deadline = std::time::SystemTime::now()
+        + Duration::from_millis(window_ms * num_windows as u64)
+        + Duration::from_secs(10);
+    Ok(client.measure_dirty_rate(ctx, window_ms, num_windows).await?)
+}
+
+async fn live_update_call(rpc_port: u16, socket_path: String) -> anyhow::Result<String> {
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), rpc_port);
+    let transport = tarpc::serde_transport::tcp::connect(socket, newJson::default);
+    let client = WorldClient::new(client::Config::default(), transport.await?).spawn();
+    Ok(client.live_update(context::current(), socket_path).await?)
+}
+
+async fn resume_vm_call(rpc_port: u16) -> anyhow::Result<String> {
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), rpc_port);
+    let transport = tarpc::serde_transport::tcp::connect(socket, newJson::default);
+    let client = WorldClient::new(client::Config::default(), transport.await?).spawn();
+    Ok(client.resume_vm(context::current()).await?)
+}
+
+async fn diff_snapshot_call(rpc_port: u16, snapshot_path: String) -> anyhow::Result<String> {
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), rpc_port);
+    let transport = tarpc::serde_transport::tcp::connect(socket, newJson::default);
+    let client = WorldClient::new(client::Config::default(), transport.await?).spawn();
+    let mut ctx = context::current();
+    ctx.deadline = std::time::SystemTime::now() + SNAPSHOT_DEADLINE;
+    Ok(client.diff_snapshot(ctx, snapshot_path).await?)
+}
+
+async fn background_snapshot_call(rpc_port: u16, snapshot_path: String) -> anyhow::Result<String> {
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), rpc_port);
+    let transport = tarpc::serde_transport::tcp::connect(socket, newJson::default);
+    let client = WorldClient::new(client::Config::default(), transport.await?).spawn();
+    let mut ctx = context::current();
+    ctx.deadline = std::time::SystemTime::now() + SNAPSHOT_DEADLINE;
+    Ok(client.background_snapshot(ctx, snapshot_path).await?)
+}
+
+async fn core_dump_call(rpc_port: u16, path: String) -> anyhow::Result<String> {
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), rpc_port);
+    let transport = tarpc::serde_transport::tcp::connect(socket, newJson::default);
+    let client = WorldClient::new(client::Config::default(), transport.await?).spawn();
+    let mut ctx = context::current();
+    ctx.deadline = std::time::SystemTime::now() + SNAPSHOT_DEADLINE;
+    Ok(client.core_dump(ctx, path).await?)
+}
+
+async fn list_snapshots_call(rpc_port: u16, tag: Option<String>) -> anyhow::Result<String> {
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), rpc_port);
+    let transport = tarpc::serde_transport::tcp::connect(socket, newJson::default);
+    let client = WorldClient::new(client::Config::default(), transport.await?).spawn();
+    Ok(client.list_snapshots(context::current(), tag).await?)
+}
+
+async fn describe_snapshot_call(rpc_port: u16, id: String) -> anyhow::Result<String> {
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), rpc_port);
+    let transport = tarpc::serde_transport::tcp::connect(socket, newJson::default);
+    let client = WorldClient::new(client::Config::default(), transport.await?).spawn();
+    Ok(client.describe_snapshot(context::current(), id).await?)
+}
+
+async fn label_snapshot_call(
+    rpc_port: u16,
+    id: String,
+    name: String,
+    tags: Vec<String>,
+) -> anyhow::Result<String> {
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), rpc_port);
+    let transport = tarpc::serde_transport::tcp::connect(socket, newJson::default);
+    let client = WorldClient::new(client::Config::default(), transport.await?).spawn();
+    Ok(client
+        .label_snapshot(context::current(), id, name, tags)
+        .await?)
+}
+
+async fn delete_snapshot_call(rpc_port: u16, id: String) -> anyhow::Result<String> {
+    let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), rpc_port);
+    let transport = tarpc::serde_transport::tcp::connect(socket, newJson::default);
+    let client = WorldClient::new(client::Config::default(), transport.await?).spawn();
+    Ok(client.delete_snapshot(context::current(), id).await?)
+}
+
+// Starts `count` VMMs from the snapshot at `snapshot_path`, which share its clean pages through
+// the page cache. Clone `i` listens for RPCs on `first_port + i`, and gets the tap
+// `<tap_prefix><i>` along with a MAC address of its own. Its output goes to `clone-<i>.log`.
+fn start_clones(
+    snapshot_path: &str,
+    count: u16,
+    first_port: u16,
+    tap_prefix: &str,
+    vmm: &str,
+    vmm_args: &[String],
+) -> anyhow::Result<()> {
+    // Locally administered addresses, the same for every clone of this launch but the last two
+    // bytes.
+    let mut mac_prefix = [0u8; 3];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut mac_prefix)?;
+    for id in 0..count {
+        let port = first_port
+            .checked_add(id)
+            .ok_or_else(|| anyhow::anyhow!("no RPC port left for clone {}", id))?;
+        let tap = format!("{}{}", tap_prefix, id);
+        let mac = format!(
+            "06:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            mac_prefix[0],
+            mac_prefix[1],
+            mac_prefix[2],
+            id >> 8,
+            id & 0xff
+        );
+        let log = std::fs::File::create(format!("clone-{}.log", id))?;
+        let child = std::process::Command::new(vmm)
+            .args(vmm_args)
+            .arg("--cpu_path")
+            .arg(snapshot_path)
+            .arg("--memory_path")
+            .arg("")
+            .arg("--port")
+            .arg(port.to_string())
+            .arg("--net")
+            .arg(format!("tap={},mac={}", tap, mac))
+            .arg("--clone")
+            .arg(format!("id={}", id))
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .spawn()?;
+        println!(
+            "clone {}: pid {}, rpc port {}, tap {}, mac {}",
+            id,
+            child.id(),
+            port,
+            tap,
+            mac
+        );
+    }
+    Ok(())
+}
+
 // import env
 // use env;
 pub fn main() {
     let func = std::env::args().nth(1).unwrap();
+    if func == "dirty_rate" {
+        // dirty_rate <rpc port> [window ms] [number of windows]
+        let rpc_port = std::env::args().nth(2).unwrap().parse::<u16>().unwrap();
+        let window_ms = std::env::args()
+            .nth(3)
+            .map(|s| s.parse::<u64>().unwrap())
+            .unwrap_or(1000);
+        let num_windows = std::env::args()
+            .nth(4)
+            .map(|s| s.parse::<u32>().unwrap())
+            .unwrap_or(1);
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(dirty_rate_call(rpc_port, window_ms, num_windows));
+        match result {
+            Ok(report) => println!("{}", report),
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
+    if func == "live_update" {
+        // live_update <rpc port> <socket path of the new vmm>
+        let rpc_port = std::env::args().nth(2).unwrap().parse::<u16>().unwrap();
+        let socket_path = std::env::args().nth(3).unwrap();
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(live_update_call(rpc_port, socket_path));
+        match result {
+            Ok(s) => println!("{}", s),
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
+    if func == "resume_vm" {
+        // resume_vm <rpc port>
+        let rpc_port = std::env::args().nth(2).unwrap().parse::<u16>().unwrap();
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(resume_vm_call(rpc_port));
+        match result {
+            Ok(s) => println!("{}", s),
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
+    if func == "diff_snapshot" {
+        // diff_snapshot <rpc port> <snapshot path>
+        let rpc_port = std::env::args().nth(2).unwrap().parse::<u16>().unwrap();
+        let snapshot_path = std::env::args().nth(3).unwrap();
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(diff_snapshot_call(rpc_port, snapshot_path));
+        match result {
+            Ok(s) => println!("{}", s),
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
+    if func == "background_snapshot" {
+        // background_snapshot <rpc port> <snapshot path>
+        let rpc_port = std::env::args().nth(2).unwrap().parse::<u16>().unwrap();
+        let snapshot_path = std::env::args().nth(3).unwrap();
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(background_snapshot_call(rpc_port, snapshot_path));
+        match result {
+            Ok(s) => println!("{}", s),
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
+    if func == "list_snapshots" {
+        // list_snapshots <rpc port> [tag]
+        let rpc_port = std::env::args().nth(2).unwrap().parse::<u16>().unwrap();
+        let tag = std::env::args().nth(3);
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(list_snapshots_call(rpc_port, tag));
+        match result {
+            Ok(s) => println!("{}", s),
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
+    if func == "describe_snapshot" {
+        // describe_snapshot <rpc port> <id>
+        let rpc_port = std::env::args().nth(2).unwrap().parse::<u16>().unwrap();
+        let id = std::env::args().nth(3).unwrap();
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(describe_snapshot_call(rpc_port, id));
+        match result {
+            Ok(s) => println!("{}", s),
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
+    if func == "label_snapshot" {
+        // label_snapshot <rpc port> <id> <name, "" to keep it> [tags...]
+        let rpc_port = std::env::args().nth(2).unwrap().parse::<u16>().unwrap();
+        let id = std::env::args().nth(3).unwrap();
+        let name = std::env::args().nth(4).unwrap();
+        let tags = std::env::args().skip(5).collect();
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(label_snapshot_call(rpc_port, id, name, tags));
+        match result {
+            Ok(s) => println!("{}", s),
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
+    if func == "delete_snapshot" {
+        // delete_snapshot <rpc port> <id>
+        let rpc_port = std::env::args().nth(2).unwrap().parse::<u16>().unwrap();
+        let id = std::env::args().nth(3).unwrap();
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(delete_snapshot_call(rpc_port, id));
+        match result {
+            Ok(s) => println!("{}", s),
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
+    if func == "clone" {
+        // clone <snapshot path> <count> <first rpc port> <tap prefix> <vmm binary> [vmm args...]
+        // The vmm args are those of the guest the snapshot was taken of, but for the network.
+        let snapshot_path = std::env::args().nth(2).unwrap();
+        let count = std::env::args().nth(3).unwrap().parse::<u16>().unwrap();
+        let first_port = std::env::args().nth(4).unwrap().parse::<u16>().unwrap();
+        let tap_prefix = std::env::args().nth(5).unwrap();
+        let vmm = std::env::args().nth(6).unwrap();
+        let vmm_args: Vec<String> = std::env::args().skip(7).collect();
+        let result = start_clones(
+            &snapshot_path,
+            count,
+            first_port,
+            &tap_prefix,
+            &vmm,
+            &vmm_args,
+        );
+        if let Err(e) = result {
+            println!("Error: {}", e);
+        }
+        return;
+    }
+    if func == "core_dump" {
+        // core_dump <rpc port> <core path>
+        let rpc_port = std::env::args().nth(2).unwrap().parse::<u16>().unwrap();
+        let path = std::env::args().nth(3).unwrap();
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(core_dump_call(rpc_port, path));
+        match result {
+            Ok(s) => println!("{}", s),
+            Err(e) => println!("Error: {}", e),
+        }
+        return;
+    }
     // if func is snapshot
     let cpu_snapshot_path = std::env::args().nth(2).unwrap();
     let memory_snapshot_path = std::env::args().nth(3).unwrap();
@@ -145,15 +475,7 @@ pub fn main() {
         .unwrap()
         .block_on(rpc_call(body));
     match result {
-        Ok(s) => {
-            if s == "Success" {
-                println!("Success");
-            } else {
-                println!("Error");
-            }
-        }
-        Err(_) => {
-            println!("Error");
-        }
+        Ok(s) => println!("{}", s),
+        Err(e) => println!("Error: {}", e),
     }
 }